@@ -0,0 +1,4 @@
+//! The guest agent's wire protocol, also used by the daemon's Firecracker provider so both
+//! sides are built from the same definitions.
+
+pub mod protocol;
@@ -0,0 +1,341 @@
+//! Guest agent for Crucible's Firecracker microVMs. It runs inside the guest (typically started
+//! by init), listens on vsock port 52 and carries out execs and file transfers for the daemon.
+//! The VM is the isolation boundary, so the agent runs commands directly.
+
+use anyhow::Result;
+use crucible_agent::protocol::{self, ErrorBody, ExecEnd, PutEnd, Request};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+/// How long past its deadline an exec may run before the agent kills it itself. The host
+/// normally kills it first; this only matters if the host went away.
+const TIMEOUT_GRACE: Duration = Duration::from_secs(5);
+const CHUNK: usize = 64 * 1024;
+
+/// Process group of each running exec, for `Kill`
+fn running() -> &'static Mutex<HashMap<String, i32>> {
+    static RUNNING: OnceLock<Mutex<HashMap<String, i32>>> = OnceLock::new();
+    RUNNING.get_or_init(Default::default)
+}
+
+fn main() -> Result<()> {
+    let listener = vsock::listen(protocol::AGENT_PORT)?;
+    println!("crucible-agent listening on vsock port {}", protocol::AGENT_PORT);
+    loop {
+        let conn = vsock::accept(&listener)?;
+        thread::spawn(move || {
+            if let Err(e) = handle(conn) {
+                eprintln!("crucible-agent: connection failed: {}", e);
+            }
+        });
+    }
+}
+
+fn handle(mut conn: File) -> Result<()> {
+    let (tag, payload) = protocol::read_frame(&mut conn)?;
+    if tag != protocol::REQUEST {
+        anyhow::bail!("Expected a request frame, got tag {}", tag);
+    }
+    let request: Request = match serde_json::from_slice(&payload) {
+        Ok(request) => request,
+        Err(e) => {
+            let body = ErrorBody { kind: "invalid".to_string(), message: format!("Bad request: {}", e) };
+            protocol::write_json(&mut conn, protocol::ERROR, &body)?;
+            return Ok(());
+        }
+    };
+
+    let result = match request {
+        Request::Ping => end(&mut conn, &serde_json::json!({ "version": env!("CARGO_PKG_VERSION") })),
+        Request::Exec { exec_id, argv, env, cwd, timeout_ms } => {
+            exec(&mut conn, exec_id, argv, env, cwd, Duration::from_millis(timeout_ms))
+        }
+        Request::Kill { exec_id } => {
+            if let Some(pgid) = running().lock().unwrap().get(&exec_id) {
+                kill_group(*pgid);
+            }
+            end(&mut conn, &serde_json::json!({}))
+        }
+        Request::PutFile { path, overwrite } => put_file(&mut conn, &path, overwrite),
+        Request::GetFile { path } => get_file(&mut conn, &path),
+        Request::ListDir { path } => list_dir(&mut conn, &path),
+        Request::Usage => end(&mut conn, &usage()),
+    };
+
+    // Operation failures go back to the host; only a broken connection ends up here
+    match result {
+        Err(e) if e.kind() != io::ErrorKind::BrokenPipe => {
+            protocol::write_json(&mut conn, protocol::ERROR, &ErrorBody::from_io(&e))?;
+            Ok(())
+        }
+        other => Ok(other?),
+    }
+}
+
+fn end(conn: &mut File, value: &impl serde::Serialize) -> io::Result<()> {
+    protocol::write_json(conn, protocol::END, value)
+}
+
+fn exec(
+    conn: &mut File,
+    exec_id: String,
+    argv: Vec<String>,
+    env: Vec<(String, String)>,
+    cwd: Option<String>,
+    timeout: Duration,
+) -> io::Result<()> {
+    let Some((program, args)) = argv.split_first() else {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "argv is empty"));
+    };
+    let mut cmd = Command::new(program);
+    cmd.args(args)
+        .envs(env)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        // Its own process group, so a kill takes every descendant with it
+        .process_group(0);
+    if let Some(cwd) = cwd {
+        cmd.current_dir(cwd);
+    }
+    let mut child = match cmd.spawn() {
+        Ok(child) => child,
+        // Report like a shell would, so a typo is an ordinary failed exec rather than an agent error
+        Err(e) if matches!(e.kind(), io::ErrorKind::NotFound | io::ErrorKind::PermissionDenied) => {
+            let code = if e.kind() == io::ErrorKind::NotFound { 127 } else { 126 };
+            protocol::write_frame(conn, protocol::STDERR, format!("{}: {}\n", program, e).as_bytes())?;
+            return protocol::write_json(conn, protocol::END, &ExecEnd { exit_code: code, timed_out: false });
+        }
+        Err(e) => return Err(e),
+    };
+    let pgid = child.id() as i32;
+    running().lock().unwrap().insert(exec_id.clone(), pgid);
+
+    let writer = Arc::new(Mutex::new(conn.try_clone()?));
+    let pumps = [
+        (child.stdout.take().map(|p| Box::new(p) as Box<dyn Read + Send>), protocol::STDOUT),
+        (child.stderr.take().map(|p| Box::new(p) as Box<dyn Read + Send>), protocol::STDERR),
+    ]
+    .into_iter()
+    .filter_map(|(pipe, tag)| pipe.map(|pipe| (pipe, tag)))
+    .map(|(mut pipe, tag)| {
+        let writer = writer.clone();
+        thread::spawn(move || {
+            let mut buf = vec![0u8; CHUNK];
+            // Keep draining even if the host is gone so the process never blocks on a full pipe
+            let mut host_gone = false;
+            while let Ok(n) = pipe.read(&mut buf) {
+                if n == 0 {
+                    break;
+                }
+                if !host_gone {
+                    host_gone = protocol::write_frame(&mut *writer.lock().unwrap(), tag, &buf[..n]).is_err();
+                }
+            }
+        })
+    })
+    .collect::<Vec<_>>();
+
+    let deadline = Instant::now() + timeout + TIMEOUT_GRACE;
+    let mut timed_out = false;
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if !timed_out && Instant::now() >= deadline {
+            kill_group(pgid);
+            timed_out = true;
+        }
+        thread::sleep(Duration::from_millis(20));
+    };
+    // Background children holding the pipes open would keep the pumps alive forever
+    kill_group(pgid);
+    for pump in pumps {
+        let _ = pump.join();
+    }
+    running().lock().unwrap().remove(&exec_id);
+
+    let exit_code = status.code().unwrap_or_else(|| 128 + status.signal().unwrap_or(0));
+    let result = ExecEnd { exit_code, timed_out };
+    protocol::write_json(&mut *writer.lock().unwrap(), protocol::END, &result)
+}
+
+fn kill_group(pgid: i32) {
+    // SAFETY: kill(2) has no memory-safety preconditions
+    unsafe {
+        libc::kill(-pgid, libc::SIGKILL);
+    }
+}
+
+fn put_file(conn: &mut File, path: &str, overwrite: bool) -> io::Result<()> {
+    if let Some(parent) = std::path::Path::new(path).parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut file = if overwrite {
+        OpenOptions::new().write(true).create(true).truncate(true).open(path)?
+    } else {
+        OpenOptions::new().write(true).create_new(true).open(path)?
+    };
+    // Tell the host to start sending
+    end(conn, &serde_json::json!({}))?;
+
+    let mut written = 0u64;
+    loop {
+        let (tag, data) = protocol::read_frame(conn)?;
+        if tag != protocol::DATA {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Expected a data frame"));
+        }
+        if data.is_empty() {
+            break;
+        }
+        file.write_all(&data)?;
+        written += data.len() as u64;
+    }
+    file.sync_all()?;
+    end(conn, &PutEnd { bytes: written })
+}
+
+fn get_file(conn: &mut File, path: &str) -> io::Result<()> {
+    if !fs::metadata(path)?.is_file() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, format!("{} is not a file", path)));
+    }
+    let mut file = File::open(path)?;
+    let mut buf = vec![0u8; CHUNK];
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        protocol::write_frame(conn, protocol::DATA, &buf[..n])?;
+    }
+    end(conn, &serde_json::json!({}))
+}
+
+fn list_dir(conn: &mut File, path: &str) -> io::Result<()> {
+    if !fs::metadata(path)?.is_dir() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, format!("{} is not a directory", path)));
+    }
+    let mut entries = Vec::new();
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        let meta = entry.metadata()?;
+        entries.push(protocol::DirEntry {
+            name: entry.file_name().to_string_lossy().into_owned(),
+            is_dir: meta.is_dir(),
+            size_bytes: meta.len(),
+            modified_unix: meta.modified().ok()
+                .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                .map_or(0.0, |d| d.as_secs_f64()),
+        });
+    }
+    end(conn, &entries)
+}
+
+/// Whole-guest usage: the VM belongs to a single sandbox
+fn usage() -> protocol::Usage {
+    let before = cpu_times();
+    thread::sleep(Duration::from_millis(200));
+    let cpu_percent = match (before, cpu_times()) {
+        (Some((busy0, total0)), Some((busy1, total1))) if total1 > total0 => {
+            100.0 * (busy1 - busy0) as f64 / (total1 - total0) as f64
+        }
+        _ => 0.0,
+    };
+    let meminfo = fs::read_to_string("/proc/meminfo").unwrap_or_default();
+    let field = |name: &str| meminfo.lines()
+        .find_map(|l| l.strip_prefix(name))
+        .and_then(|rest| rest.split_whitespace().next())
+        .and_then(|kb| kb.parse::<u64>().ok())
+        .unwrap_or(0);
+    let memory_mb = field("MemTotal:").saturating_sub(field("MemAvailable:")) / 1024;
+
+    // SAFETY: statvfs only writes into the zeroed struct we pass
+    let disk_mb = unsafe {
+        let mut st: libc::statvfs = std::mem::zeroed();
+        if libc::statvfs(c"/".as_ptr(), &mut st) == 0 {
+            (st.f_blocks - st.f_bfree) as u64 * st.f_frsize as u64 / (1024 * 1024)
+        } else {
+            0
+        }
+    };
+    protocol::Usage { cpu_percent, memory_mb, disk_mb }
+}
+
+/// (busy, total) jiffies from the aggregate line of /proc/stat
+fn cpu_times() -> Option<(u64, u64)> {
+    let stat = fs::read_to_string("/proc/stat").ok()?;
+    let fields: Vec<u64> = stat.lines().next()?
+        .split_whitespace().skip(1)
+        .filter_map(|f| f.parse().ok())
+        .collect();
+    let total: u64 = fields.iter().sum();
+    // idle + iowait
+    let idle = fields.get(3)? + fields.get(4).copied().unwrap_or(0);
+    Some((total - idle, total))
+}
+
+#[cfg(target_os = "linux")]
+mod vsock {
+    use std::fs::File;
+    use std::io;
+    use std::os::fd::{FromRawFd, OwnedFd, AsRawFd};
+
+    pub fn listen(port: u32) -> io::Result<OwnedFd> {
+        // SAFETY: plain socket syscalls; the fd is owned by the returned OwnedFd
+        unsafe {
+            let fd = libc::socket(libc::AF_VSOCK, libc::SOCK_STREAM | libc::SOCK_CLOEXEC, 0);
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let socket = OwnedFd::from_raw_fd(fd);
+            let mut addr: libc::sockaddr_vm = std::mem::zeroed();
+            addr.svm_family = libc::AF_VSOCK as libc::sa_family_t;
+            addr.svm_cid = libc::VMADDR_CID_ANY;
+            addr.svm_port = port;
+            let len = std::mem::size_of::<libc::sockaddr_vm>() as libc::socklen_t;
+            if libc::bind(fd, &addr as *const _ as *const libc::sockaddr, len) < 0 || libc::listen(fd, 64) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(socket)
+        }
+    }
+
+    pub fn accept(listener: &OwnedFd) -> io::Result<File> {
+        loop {
+            // SAFETY: accept4 with null address pointers only returns a new fd
+            let fd = unsafe {
+                libc::accept4(listener.as_raw_fd(), std::ptr::null_mut(), std::ptr::null_mut(), libc::SOCK_CLOEXEC)
+            };
+            if fd >= 0 {
+                // SAFETY: fd is a freshly accepted socket nobody else owns
+                return Ok(unsafe { File::from_raw_fd(fd) });
+            }
+            let err = io::Error::last_os_error();
+            if err.kind() != io::ErrorKind::Interrupted {
+                return Err(err);
+            }
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod vsock {
+    use std::fs::File;
+    use std::io;
+
+    pub struct Unsupported;
+
+    pub fn listen(_port: u32) -> io::Result<Unsupported> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "crucible-agent only runs inside Linux guests"))
+    }
+
+    pub fn accept(_listener: &Unsupported) -> io::Result<File> {
+        unreachable!()
+    }
+}
@@ -0,0 +1,133 @@
+//! Wire format between the agent and the daemon's Firecracker provider (`provider/agent.rs`),
+//! which speaks it through these same types.
+//!
+//! Every message is a frame: a tag byte, a big-endian u32 length and that many payload bytes.
+//! The host opens one connection per operation and sends a REQUEST frame; the agent answers
+//! with output or data frames and finishes with END (JSON result) or ERROR (JSON `ErrorBody`).
+
+use serde::{Deserialize, Serialize};
+use std::io::{self, Read, Write};
+
+/// vsock port the agent listens on
+pub const AGENT_PORT: u32 = 52;
+
+pub const REQUEST: u8 = 0;
+pub const STDOUT: u8 = 1;
+pub const STDERR: u8 = 2;
+pub const DATA: u8 = 3;
+pub const END: u8 = 4;
+pub const ERROR: u8 = 5;
+
+/// Largest payload either side accepts in one frame
+pub const MAX_FRAME: usize = 16 * 1024 * 1024;
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Request {
+    Ping,
+    Exec {
+        exec_id: String,
+        argv: Vec<String>,
+        env: Vec<(String, String)>,
+        cwd: Option<String>,
+        timeout_ms: u64,
+    },
+    Kill {
+        exec_id: String,
+    },
+    /// Answered with END `{}` once the file is open; DATA frames follow, an empty one ends the file
+    PutFile {
+        path: String,
+        overwrite: bool,
+    },
+    GetFile {
+        path: String,
+    },
+    ListDir {
+        path: String,
+    },
+    Usage,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ErrorBody {
+    /// "not_found", "already_exists", "invalid" or "io"
+    pub kind: String,
+    pub message: String,
+}
+
+impl ErrorBody {
+    pub fn from_io(e: &io::Error) -> Self {
+        let kind = match e.kind() {
+            io::ErrorKind::NotFound => "not_found",
+            io::ErrorKind::AlreadyExists => "already_exists",
+            io::ErrorKind::InvalidInput => "invalid",
+            _ => "io",
+        };
+        Self { kind: kind.to_string(), message: e.to_string() }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ExecEnd {
+    /// 128 + signal for processes killed by a signal, as a shell would report
+    pub exit_code: i32,
+    pub timed_out: bool,
+}
+
+/// END of a PUT_FILE, once the last DATA frame is written
+#[derive(Serialize, Deserialize)]
+pub struct PutEnd {
+    pub bytes: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct DirEntry {
+    pub name: String,
+    pub is_dir: bool,
+    pub size_bytes: u64,
+    pub modified_unix: f64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Usage {
+    pub cpu_percent: f64,
+    pub memory_mb: u64,
+    pub disk_mb: u64,
+}
+
+/// Frame header: the tag and the payload length
+pub fn frame_header(tag: u8, len: usize) -> [u8; 5] {
+    let mut header = [0u8; 5];
+    header[0] = tag;
+    header[1..].copy_from_slice(&(len as u32).to_be_bytes());
+    header
+}
+
+/// Tag and payload length of a frame header, refusing payloads over `MAX_FRAME`
+pub fn parse_header(header: [u8; 5]) -> io::Result<(u8, usize)> {
+    let len = u32::from_be_bytes([header[1], header[2], header[3], header[4]]) as usize;
+    if len > MAX_FRAME {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Frame of {} bytes is too large", len)));
+    }
+    Ok((header[0], len))
+}
+
+pub fn write_frame(w: &mut impl Write, tag: u8, payload: &[u8]) -> io::Result<()> {
+    w.write_all(&frame_header(tag, payload.len()))?;
+    w.write_all(payload)?;
+    w.flush()
+}
+
+pub fn write_json(w: &mut impl Write, tag: u8, value: &impl Serialize) -> io::Result<()> {
+    write_frame(w, tag, &serde_json::to_vec(value)?)
+}
+
+pub fn read_frame(r: &mut impl Read) -> io::Result<(u8, Vec<u8>)> {
+    let mut header = [0u8; 5];
+    r.read_exact(&mut header)?;
+    let (tag, len) = parse_header(header)?;
+    let mut payload = vec![0u8; len];
+    r.read_exact(&mut payload)?;
+    Ok((tag, payload))
+}
@@ -3,12 +3,15 @@ pub mod pb {
 }
 
 use clap::{Parser, Subcommand};
+use std::io::Write;
 use pb::sandboxes_client::SandboxesClient;
 use pb::{CreateSandboxRequest, SandboxSpec};
 use pb::execution_client::ExecutionClient;
 use pb::{ExecRequest, ExecSpec};
 use pb::snapshots_client::SnapshotsClient;
 use pb::{CreateSnapshotRequest, SnapshotSpec, RestoreSnapshotRequest, RestoreSpec, GarbageCollectSnapshotsRequest};
+use pb::{DeleteSnapshotRequest, FsckSnapshotsRequest, GetSnapshotTreeRequest, ListSnapshotsRequest, Paging, SnapshotTreeNode, VerifySnapshotRequest};
+use std::collections::HashMap;
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
@@ -33,6 +36,10 @@ enum Commands {
     Exec {
         #[arg(short, long)]
         id: String,
+
+        /// Run a shell snippet instead of an argv (pipes and redirects allowed)
+        #[arg(long, conflicts_with = "cmd")]
+        shell: Option<String>,
         
         /// The command arguments to execute
         #[arg(last = true)]
@@ -49,6 +56,9 @@ enum SandboxCommands {
         /// Request hardware acceleration (GPU) for the sandbox
         #[arg(short, long)]
         gpu: bool,
+        /// Named policy to apply; network and mounts then come from the policy
+        #[arg(short, long)]
+        policy: Option<String>,
     },
 }
 
@@ -66,6 +76,36 @@ enum SnapshotCommands {
         #[arg(short, long)]
         snapshot_id: String,
     },
+    /// List snapshots, newest first
+    List {
+        /// Only snapshots of this sandbox
+        #[arg(short, long)]
+        sandbox_id: Option<String>,
+    },
+    /// Delete a snapshot
+    Delete {
+        #[arg(short, long)]
+        snapshot_id: String,
+        /// Delete even if child snapshots or refs depend on it
+        #[arg(short, long)]
+        force: bool,
+    },
+    /// Show the lineage tree a snapshot belongs to
+    Tree {
+        #[arg(short, long)]
+        snapshot_id: String,
+    },
+    /// Check a snapshot's files against the sha256 recorded when it was taken
+    Verify {
+        #[arg(short, long)]
+        snapshot_id: String,
+    },
+    /// Check snapshot rows, directories and chunks against each other after a crash
+    Fsck {
+        /// Repair what is found instead of only reporting it
+        #[arg(short, long)]
+        repair: bool,
+    },
     /// Garbage collect unreachable snapshots
     Gc {
         #[arg(short, long, default_value_t = 5)]
@@ -86,7 +126,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     match cli.command {
         Commands::Sandbox { action } => match action {
-            SandboxCommands::Create { image, gpu } => {
+            SandboxCommands::Create { image, gpu, policy } => {
                 println!("Creating sandbox from image: {} (GPU: {})", image, gpu);
                 
                 let request = tonic::Request::new(CreateSandboxRequest {
@@ -97,17 +137,18 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                         labels: None,
                         limits: None,
                         policy: Some(pb::SandboxPolicy {
-                            policy_id: String::new(),
-                            network: Some(pb::NetworkPolicy {
+                            network: policy.is_none().then_some(pb::NetworkPolicy {
                                 deny_all: true,
                                 allow_domains: vec![],
                                 allow_cidrs: vec![],
-                                allow_loopback: true,
+                                allow_loopback: Some(true),
                             }),
-                            mounts: Some(pb::MountPolicy { mounts: vec![] }),
+                            mounts: policy.is_none().then_some(pb::MountPolicy { mounts: vec![] }),
+                            policy_id: policy.unwrap_or_default(),
                             enable_gpu: gpu,
                             enable_snapshotting: false,
                             strict_no_fallback: true,
+                            seccomp_profile: String::new(),
                         }),
                         allow_pool_reuse: false,
                         init_cmd: vec![],
@@ -120,31 +161,43 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 println!("ID: {}", sandbox.sandbox_id);
             }
         },
-        Commands::Exec { id, cmd } => {
+        Commands::Exec { id, shell, cmd } => {
             println!("Executing command in sandbox: {}", id);
             
             let request = tonic::Request::new(ExecRequest {
                 spec: Some(ExecSpec {
                     sandbox_id: id,
                     argv: cmd,
-                    shell: String::new(),
+                    shell: shell.unwrap_or_default(),
                     env: std::collections::HashMap::new(),
                     cwd: "/work".to_string(),
                     timeout_ms: 30000,
-                    stream_stdout: false,
-                    stream_stderr: false,
+                    stream_stdout: true,
+                    stream_stderr: true,
                     input_artifact_ids: vec![],
                 })
             });
 
-            let response = execution.exec(request).await?;
-            let result = response.into_inner();
-            
-            println!("Exec ID: {}", result.exec_id);
-            println!("Exit Code: {}", result.exit_code);
-            
-            // We're currently just relying on the provider to execute it. 
-            // In a full implementation, `exec_stream` would yield these.
+            let mut stream = execution.exec_stream(request).await?.into_inner();
+            while let Some(msg) = stream.message().await? {
+                match msg.payload {
+                    Some(pb::exec_stream_response::Payload::Chunk(chunk)) => {
+                        if chunk.stream() == pb::output_chunk::Stream::Stderr {
+                            std::io::stderr().write_all(&chunk.data)?;
+                        } else {
+                            std::io::stdout().write_all(&chunk.data)?;
+                        }
+                    }
+                    Some(pb::exec_stream_response::Payload::Final(result)) => {
+                        println!("Exec ID: {}", result.exec_id);
+                        println!("Exit Code: {}", result.exit_code);
+                        if !result.last_error.is_empty() {
+                            println!("Error: {}", result.last_error);
+                        }
+                    }
+                    _ => {}
+                }
+            }
         },
         Commands::Snapshot { action } => match action {
             SnapshotCommands::Create { sandbox_id, name } => {
@@ -172,6 +225,60 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 let response = snapshots.restore_snapshot(request).await?;
                 println!("Restored into new Sandbox ID: {}", response.into_inner().sandbox_id);
             },
+            SnapshotCommands::List { sandbox_id } => {
+                let mut page_token = String::new();
+                loop {
+                    let request = tonic::Request::new(ListSnapshotsRequest {
+                        paging: Some(Paging { page_size: 0, page_token }),
+                        sandbox_id: sandbox_id.clone().unwrap_or_default(),
+                    });
+                    let response = snapshots.list_snapshots(request).await?.into_inner();
+                    for snapshot in response.snapshots {
+                        println!("{}  sandbox={}  size={}  name={}", snapshot.snapshot_id, snapshot.sandbox_id, snapshot.size_bytes, snapshot.name);
+                    }
+                    page_token = response.page.map(|p| p.next_page_token).unwrap_or_default();
+                    if page_token.is_empty() {
+                        break;
+                    }
+                }
+            },
+            SnapshotCommands::Delete { snapshot_id, force } => {
+                let request = tonic::Request::new(DeleteSnapshotRequest { snapshot_id, force });
+                let response = snapshots.delete_snapshot(request).await?;
+                println!("Deleted Snapshot: {}", response.into_inner().snapshot_id);
+            },
+            SnapshotCommands::Tree { snapshot_id } => {
+                let request = tonic::Request::new(GetSnapshotTreeRequest { snapshot_id });
+                let tree = snapshots.get_snapshot_tree(request).await?.into_inner();
+                let nodes: HashMap<&str, &SnapshotTreeNode> = tree.nodes.iter()
+                    .filter_map(|node| node.snapshot.as_ref().map(|s| (s.snapshot_id.as_str(), node)))
+                    .collect();
+                print_tree(&nodes, &tree.root_snapshot_id, 0);
+            },
+            SnapshotCommands::Verify { snapshot_id } => {
+                let request = tonic::Request::new(VerifySnapshotRequest { snapshot_id });
+                let report = snapshots.verify_snapshot(request).await?.into_inner();
+                for file in &report.files {
+                    println!("{}  {}  size={}", file.sha256, file.name, file.size_bytes);
+                }
+                println!("Snapshot {} verified: {} file(s) intact.", report.snapshot_id, report.files.len());
+            },
+            SnapshotCommands::Fsck { repair } => {
+                let request = tonic::Request::new(FsckSnapshotsRequest { repair });
+                let report = snapshots.fsck_snapshots(request).await?.into_inner();
+                for issue in &report.issues {
+                    let kind = pb::fsck_issue::Kind::try_from(issue.kind).unwrap_or_default().as_str_name();
+                    let status = if issue.repaired { "repaired" } else { "found" };
+                    println!("{}  {}  {}  ({})", status, kind, issue.id, issue.detail);
+                }
+                if report.issues.is_empty() {
+                    println!("Snapshot store is consistent.");
+                } else if repair {
+                    println!("{} issue(s), reclaimed {} bytes.", report.issues.len(), report.reclaimed_bytes);
+                } else {
+                    println!("{} issue(s); run with --repair to fix them.", report.issues.len());
+                }
+            },
             SnapshotCommands::Gc { keep_latest, dry_run } => {
                 println!("Garbage Collecting (dry_run: {})", dry_run);
                 let request = tonic::Request::new(GarbageCollectSnapshotsRequest {
@@ -182,9 +289,31 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 let response = snapshots.garbage_collect_snapshots(request).await?;
                 let stats = response.into_inner();
                 println!("Deleted {} snapshots, reclaimed {} bytes.", stats.deleted_snapshot_ids.len(), stats.reclaimed_bytes);
+                for error in &stats.errors {
+                    println!("Could not delete {}", error);
+                }
             }
         }
     }
 
     Ok(())
 }
+
+/// Print a snapshot and its descendants, one indented line each
+fn print_tree(nodes: &HashMap<&str, &SnapshotTreeNode>, snapshot_id: &str, depth: usize) {
+    let Some(node) = nodes.get(snapshot_id) else {
+        return;
+    };
+    let snapshot = node.snapshot.clone().unwrap_or_default();
+    let mut line = format!("{}{}  {}  size={}", "  ".repeat(depth), snapshot.snapshot_id, node.state, snapshot.size_bytes);
+    if !snapshot.name.is_empty() {
+        line.push_str(&format!("  name={}", snapshot.name));
+    }
+    if !node.restored_sandbox_ids.is_empty() {
+        line.push_str(&format!("  sandboxes={}", node.restored_sandbox_ids.join(",")));
+    }
+    println!("{}", line);
+    for child in &node.child_snapshot_ids {
+        print_tree(nodes, child, depth + 1);
+    }
+}
@@ -0,0 +1,186 @@
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+
+/// Path used when `CRUCIBLE_CONFIG` is not set
+const DEFAULT_CONFIG_PATH: &str = "crucible.toml";
+
+/// Daemon settings, read from a TOML file. Every field has a default so the file is optional.
+#[derive(Clone, Deserialize)]
+#[serde(default)]
+pub struct DaemonConfig {
+    pub listen_addr: String,
+    pub db_url: String,
+    pub snapshot_dir: PathBuf,
+    pub artifact_dir: PathBuf,
+    /// Sandbox backend: "lima", "firecracker" or "krunvm"
+    pub provider: String,
+    pub lima_instance: String,
+    pub firecracker: FirecrackerConfig,
+    pub krunvm: KrunvmConfig,
+    /// TOML file with named sandbox policies; see `policy::registry`
+    pub policy_file: PathBuf,
+    pub exec: ExecConfig,
+    pub mounts: MountConfig,
+    pub files: FilesConfig,
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(default)]
+pub struct ExecConfig {
+    /// Interpreter prefix for `ExecSpec.shell`; the script is appended as the last argument
+    pub shell: Vec<String>,
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(default)]
+pub struct FirecrackerConfig {
+    pub binary: PathBuf,
+    /// Uncompressed guest kernel (vmlinux)
+    pub kernel_image: PathBuf,
+    pub boot_args: String,
+    /// ext4 root filesystems with crucible-agent installed. `base_image` "name:tag" maps to
+    /// `name-tag.ext4`; an empty `base_image` uses `default.ext4`.
+    pub image_dir: PathBuf,
+    /// Per-sandbox rootfs copies, sockets and logs
+    pub state_dir: PathBuf,
+    /// Bridge the sandbox tap devices are attached to; empty leaves them unattached
+    pub bridge: String,
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(default)]
+pub struct KrunvmConfig {
+    pub binary: PathBuf,
+    /// OCI image for sandboxes whose spec sets no `base_image`
+    pub default_image: String,
+    /// Per-sandbox working directories, shared into the VMs
+    pub state_dir: PathBuf,
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(default)]
+pub struct FilesConfig {
+    /// Keep a copy of every PutFile upload in the artifact store and return its artifact id,
+    /// as PutFile is documented to. Copies are never cleaned up, so hosts that don't need them
+    /// can turn this off; PutFile results then carry no artifact id.
+    pub keep_uploads: bool,
+}
+
+#[derive(Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct MountConfig {
+    /// Host directories sandboxes may bind-mount from, including their subdirectories.
+    /// Empty means no host mounts are allowed.
+    pub allowed_host_roots: Vec<PathBuf>,
+}
+
+impl Default for DaemonConfig {
+    fn default() -> Self {
+        Self {
+            listen_addr: "[::1]:7171".to_string(),
+            db_url: "sqlite:crucible.db?mode=rwc".to_string(),
+            snapshot_dir: PathBuf::from("/tmp/crucible_snapshots"),
+            artifact_dir: PathBuf::from("/tmp/crucible_artifacts"),
+            provider: "lima".to_string(),
+            lima_instance: "crucible-worker".to_string(),
+            firecracker: FirecrackerConfig::default(),
+            krunvm: KrunvmConfig::default(),
+            policy_file: PathBuf::from("policies.toml"),
+            exec: ExecConfig::default(),
+            mounts: MountConfig::default(),
+            files: FilesConfig::default(),
+        }
+    }
+}
+
+impl Default for FirecrackerConfig {
+    fn default() -> Self {
+        Self {
+            binary: PathBuf::from("firecracker"),
+            kernel_image: PathBuf::from("/var/lib/crucible/firecracker/vmlinux"),
+            boot_args: "console=ttyS0 reboot=k panic=1 pci=off".to_string(),
+            image_dir: PathBuf::from("/var/lib/crucible/firecracker/images"),
+            state_dir: PathBuf::from("/var/lib/crucible/firecracker/sandboxes"),
+            bridge: "crucible0".to_string(),
+        }
+    }
+}
+
+impl Default for KrunvmConfig {
+    fn default() -> Self {
+        Self {
+            binary: PathBuf::from("krunvm"),
+            default_image: "docker.io/library/debian:stable-slim".to_string(),
+            state_dir: PathBuf::from("/var/lib/crucible/krunvm/sandboxes"),
+        }
+    }
+}
+
+impl Default for FilesConfig {
+    fn default() -> Self {
+        Self { keep_uploads: true }
+    }
+}
+
+impl Default for ExecConfig {
+    fn default() -> Self {
+        Self {
+            shell: vec!["/bin/sh".to_string(), "-c".to_string()],
+        }
+    }
+}
+
+impl DaemonConfig {
+    /// Load from `$CRUCIBLE_CONFIG`, falling back to `./crucible.toml` and then to defaults
+    pub fn load() -> Result<Self> {
+        match std::env::var_os("CRUCIBLE_CONFIG") {
+            Some(path) => Self::from_file(Path::new(&path)),
+            None if Path::new(DEFAULT_CONFIG_PATH).exists() => Self::from_file(Path::new(DEFAULT_CONFIG_PATH)),
+            None => Ok(Self::default()),
+        }
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let raw = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config {}", path.display()))?;
+        let config: Self = toml::from_str(&raw).with_context(|| format!("Invalid config {}", path.display()))?;
+        config.validate().with_context(|| format!("Invalid config {}", path.display()))?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<()> {
+        // With no interpreter, shell execs would try to run the script text itself as a program
+        if self.exec.shell.first().is_none_or(|program| program.is_empty()) {
+            bail!("exec.shell must name an interpreter, e.g. [\"/bin/sh\", \"-c\"]");
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    fn load(toml: &str) -> Result<DaemonConfig> {
+        let dir = TempDir::new("config");
+        std::fs::write(dir.join("crucible.toml"), toml).unwrap();
+        DaemonConfig::from_file(&dir.join("crucible.toml"))
+    }
+
+    #[test]
+    fn empty_shell_is_rejected() {
+        assert_eq!(load("").unwrap().exec.shell, ["/bin/sh", "-c"]);
+        assert_eq!(load("[exec]\nshell = [\"/bin/bash\", \"-c\"]").unwrap().exec.shell, ["/bin/bash", "-c"]);
+        assert!(load("[exec]\nshell = []").is_err());
+        assert!(load("[exec]\nshell = [\"\", \"-c\"]").is_err());
+    }
+
+    #[test]
+    fn uploads_are_kept_unless_turned_off() {
+        assert!(load("").unwrap().files.keep_uploads);
+        assert!(load("[files]").unwrap().files.keep_uploads);
+        assert!(!load("[files]\nkeep_uploads = false").unwrap().files.keep_uploads);
+    }
+}
@@ -1,6 +1,85 @@
-use sqlx::{sqlite::SqlitePoolOptions, SqlitePool};
-use std::sync::Arc;
+use sqlx::{sqlite::SqlitePoolOptions, QueryBuilder, Sqlite, SqlitePool};
 use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+#[derive(Clone, sqlx::FromRow)]
+pub struct ArtifactRecord {
+    pub artifact_id: String,
+    pub kind: String,
+    pub filename: String,
+    pub mime_type: String,
+    pub size_bytes: i64,
+    pub sha256: String,
+    pub sandbox_id: Option<String>,
+    pub exec_id: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Clone, sqlx::FromRow)]
+pub struct ExecRecord {
+    pub exec_id: String,
+    pub sandbox_id: String,
+    pub state: String,
+    pub argv: String,
+    pub exit_code: Option<i32>,
+    pub created_at: DateTime<Utc>,
+    pub started_at: Option<DateTime<Utc>>,
+    pub finished_at: Option<DateTime<Utc>>,
+    pub output_artifact_ids: String,
+    pub stdout_preview: String,
+    pub stderr_preview: String,
+    pub violations: String, // JSON list of ViolationRecord
+    pub last_error: Option<String>,
+}
+
+/// A policy violation as stored in `execs.violations`
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ViolationRecord {
+    pub kind: String,
+    pub message: String,
+    pub ts: DateTime<Utc>,
+}
+
+#[derive(Clone, sqlx::FromRow)]
+pub struct SandboxRecord {
+    pub sandbox_id: String,
+    pub provider: String,
+    pub state: String,
+    pub spec: Vec<u8>, // encoded pb::SandboxSpec, after merging in its named policy
+    pub labels: String, // JSON, duplicated from the spec for filtering
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+    pub last_error: Option<String>,
+}
+
+#[derive(Clone, sqlx::FromRow)]
+pub struct SnapshotRecord {
+    pub snapshot_id: String,
+    pub provider: String,
+    pub source_sandbox_id: String,
+    pub created_at: DateTime<Utc>,
+    pub mode: String,
+    pub name: Option<String>,
+    pub labels: Option<String>, // JSON
+    pub parent_snapshot_id: Option<String>,
+    pub root_snapshot_id: String,
+    pub state: String,
+    pub size_bytes: i64,
+    pub components: Option<String>, // JSON
+    pub pinned: bool,
+    pub ttl_expires_at: Option<DateTime<Utc>>,
+    pub last_error: Option<String>,
+}
+
+#[derive(Clone, sqlx::FromRow)]
+pub struct PolicyRecord {
+    pub name: String,
+    pub policy: String, // JSON of policy::registry::NamedPolicy
+    pub source: String, // file the policy was loaded from
+    pub updated_at: DateTime<Utc>,
+}
 
 #[derive(Clone)]
 pub struct Db {
@@ -53,6 +132,67 @@ impl Db {
                 PRIMARY KEY (snapshot_id, ref_type, ref_id),
                 FOREIGN KEY (snapshot_id) REFERENCES snapshots (snapshot_id) ON DELETE CASCADE
             );
+
+            -- Chunks of the snapshot store; refcount is the number of READY snapshots using one
+            CREATE TABLE IF NOT EXISTS chunks (
+                sha256 TEXT PRIMARY KEY,
+                size_bytes INTEGER NOT NULL,
+                refcount INTEGER NOT NULL DEFAULT 0
+            );
+
+            CREATE INDEX IF NOT EXISTS idx_chunks_unreferenced ON chunks (refcount) WHERE refcount <= 0;
+
+            CREATE TABLE IF NOT EXISTS artifacts (
+                artifact_id TEXT PRIMARY KEY,
+                kind TEXT NOT NULL,
+                filename TEXT NOT NULL,
+                mime_type TEXT NOT NULL,
+                size_bytes INTEGER NOT NULL DEFAULT 0,
+                sha256 TEXT NOT NULL,
+                sandbox_id TEXT,
+                exec_id TEXT,
+                created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP
+            );
+
+            CREATE INDEX IF NOT EXISTS idx_artifacts_exec ON artifacts (exec_id);
+
+            CREATE TABLE IF NOT EXISTS execs (
+                exec_id TEXT PRIMARY KEY,
+                sandbox_id TEXT NOT NULL,
+                state TEXT NOT NULL,
+                argv TEXT NOT NULL, -- JSON
+                exit_code INTEGER,
+                created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+                started_at DATETIME,
+                finished_at DATETIME,
+                output_artifact_ids TEXT NOT NULL DEFAULT '[]', -- JSON
+                stdout_preview TEXT NOT NULL DEFAULT '',
+                stderr_preview TEXT NOT NULL DEFAULT '',
+                violations TEXT NOT NULL DEFAULT '[]', -- JSON
+                last_error TEXT
+            );
+
+            CREATE INDEX IF NOT EXISTS idx_execs_sandbox ON execs (sandbox_id, created_at);
+
+            CREATE TABLE IF NOT EXISTS sandboxes (
+                sandbox_id TEXT PRIMARY KEY,
+                provider TEXT NOT NULL,
+                state TEXT NOT NULL,
+                spec BLOB NOT NULL,
+                labels TEXT NOT NULL DEFAULT '{}', -- JSON
+                created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+                updated_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+                last_error TEXT
+            );
+
+            CREATE INDEX IF NOT EXISTS idx_sandboxes_state ON sandboxes (state);
+
+            CREATE TABLE IF NOT EXISTS policies (
+                name TEXT PRIMARY KEY,
+                policy TEXT NOT NULL, -- JSON
+                source TEXT NOT NULL,
+                updated_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP
+            );
             "#
         )
         .execute(pool)
@@ -61,36 +201,60 @@ impl Db {
         Ok(())
     }
 
-    pub async fn insert_snapshot(
-        &self,
-        snapshot_id: &str,
-        provider: &str,
-        source_sandbox_id: &str,
-        mode: &str,
-        root_snapshot_id: &str,
-    ) -> Result<()> {
+    pub async fn insert_snapshot(&self, snapshot: &SnapshotRecord) -> Result<()> {
         sqlx::query(
-            "INSERT INTO snapshots (snapshot_id, provider, source_sandbox_id, mode, root_snapshot_id, state) VALUES (?, ?, ?, ?, ?, 'CREATING')"
+            r#"
+            INSERT INTO snapshots (snapshot_id, provider, source_sandbox_id, created_at, mode, name, labels,
+                                   parent_snapshot_id, root_snapshot_id, state, size_bytes, components, pinned,
+                                   ttl_expires_at, last_error)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+            "#
         )
-        .bind(snapshot_id)
-        .bind(provider)
-        .bind(source_sandbox_id)
-        .bind(mode)
-        .bind(root_snapshot_id)
+        .bind(&snapshot.snapshot_id)
+        .bind(&snapshot.provider)
+        .bind(&snapshot.source_sandbox_id)
+        .bind(snapshot.created_at)
+        .bind(&snapshot.mode)
+        .bind(&snapshot.name)
+        .bind(&snapshot.labels)
+        .bind(&snapshot.parent_snapshot_id)
+        .bind(&snapshot.root_snapshot_id)
+        .bind(&snapshot.state)
+        .bind(snapshot.size_bytes)
+        .bind(&snapshot.components)
+        .bind(snapshot.pinned)
+        .bind(snapshot.ttl_expires_at)
+        .bind(&snapshot.last_error)
         .execute(&self.pool)
         .await?;
-        
+
         Ok(())
     }
 
-    pub async fn set_snapshot_ready(&self, snapshot_id: &str, size_bytes: u64) -> Result<()> {
+    /// Mark a snapshot READY with its manifest, taking a ref on each of its (sha256, size) chunks
+    pub async fn set_snapshot_ready(&self, snapshot_id: &str, size_bytes: u64, components: &str, chunks: &[(String, u64)]) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
         sqlx::query(
-            "UPDATE snapshots SET state = 'READY', size_bytes = ? WHERE snapshot_id = ?"
+            "UPDATE snapshots SET state = 'READY', size_bytes = ?, components = ? WHERE snapshot_id = ?"
         )
         .bind(size_bytes as i64)
+        .bind(components)
         .bind(snapshot_id)
-        .execute(&self.pool)
+        .execute(&mut *tx)
         .await?;
+        for (sha256, size) in chunks {
+            sqlx::query(
+                r#"
+                INSERT INTO chunks (sha256, size_bytes, refcount) VALUES (?, ?, 1)
+                ON CONFLICT (sha256) DO UPDATE SET refcount = refcount + 1
+                "#
+            )
+            .bind(sha256)
+            .bind(*size as i64)
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await?;
 
         Ok(())
     }
@@ -106,6 +270,128 @@ impl Db {
         Ok(rec.0)
     }
 
+    pub async fn get_snapshot(&self, snapshot_id: &str) -> Result<Option<SnapshotRecord>> {
+        let row = sqlx::query_as::<_, SnapshotRecord>("SELECT * FROM snapshots WHERE snapshot_id = ?")
+            .bind(snapshot_id)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(row)
+    }
+
+    /// Newest first, optionally restricted to one source sandbox; `hidden_state` is left out
+    pub async fn list_snapshots(&self, sandbox_id: Option<&str>, hidden_state: &str, limit: i64, offset: i64) -> Result<Vec<SnapshotRecord>> {
+        let rows = sqlx::query_as::<_, SnapshotRecord>(
+            r#"
+            SELECT * FROM snapshots
+            WHERE (?1 IS NULL OR source_sandbox_id = ?1) AND state != ?2
+            ORDER BY created_at DESC, snapshot_id
+            LIMIT ?3 OFFSET ?4
+            "#
+        )
+        .bind(sandbox_id)
+        .bind(hidden_state)
+        .bind(limit)
+        .bind(offset)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows)
+    }
+
+    /// The sandbox's newest snapshot in `state`
+    pub async fn latest_snapshot(&self, sandbox_id: &str, state: &str) -> Result<Option<SnapshotRecord>> {
+        let row = sqlx::query_as::<_, SnapshotRecord>(
+            r#"
+            SELECT * FROM snapshots
+            WHERE source_sandbox_id = ? AND state = ?
+            ORDER BY created_at DESC, snapshot_id
+            LIMIT 1
+            "#
+        )
+        .bind(sandbox_id)
+        .bind(state)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(row)
+    }
+
+    /// Every snapshot sharing a lineage root, oldest first
+    pub async fn snapshot_tree(&self, root_snapshot_id: &str) -> Result<Vec<SnapshotRecord>> {
+        let rows = sqlx::query_as::<_, SnapshotRecord>(
+            "SELECT * FROM snapshots WHERE root_snapshot_id = ? ORDER BY created_at, snapshot_id"
+        )
+        .bind(root_snapshot_id)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows)
+    }
+
+    pub async fn add_snapshot_ref(&self, snapshot_id: &str, ref_type: &str, ref_id: &str) -> Result<()> {
+        sqlx::query(
+            "INSERT OR IGNORE INTO snapshot_refs (snapshot_id, ref_type, ref_id, created_at) VALUES (?, ?, ?, ?)"
+        )
+        .bind(snapshot_id)
+        .bind(ref_type)
+        .bind(ref_id)
+        .bind(Utc::now())
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// (snapshot_id, ref_id) of every ref of `ref_type` into a lineage tree
+    pub async fn tree_refs(&self, root_snapshot_id: &str, ref_type: &str) -> Result<Vec<(String, String)>> {
+        let rows = sqlx::query_as::<_, (String, String)>(
+            r#"
+            SELECT r.snapshot_id, r.ref_id
+            FROM snapshot_refs r JOIN snapshots s ON s.snapshot_id = r.snapshot_id
+            WHERE s.root_snapshot_id = ? AND r.ref_type = ?
+            ORDER BY r.created_at
+            "#
+        )
+        .bind(root_snapshot_id)
+        .bind(ref_type)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows)
+    }
+
+    /// The snapshot something of `ref_type` (e.g. a restored sandbox) refers to
+    pub async fn referenced_snapshot(&self, ref_type: &str, ref_id: &str) -> Result<Option<String>> {
+        let row: Option<(String,)> = sqlx::query_as(
+            "SELECT snapshot_id FROM snapshot_refs WHERE ref_type = ? AND ref_id = ? LIMIT 1"
+        )
+        .bind(ref_type)
+        .bind(ref_id)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(row.map(|(id,)| id))
+    }
+
+    pub async fn drop_snapshot_refs(&self, ref_type: &str, ref_id: &str) -> Result<()> {
+        sqlx::query("DELETE FROM snapshot_refs WHERE ref_type = ? AND ref_id = ?")
+            .bind(ref_type)
+            .bind(ref_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// (child snapshots not in `hidden_state`, refs) that depend on a snapshot
+    pub async fn snapshot_dependents(&self, snapshot_id: &str, hidden_state: &str) -> Result<(i64, i64)> {
+        let row: (i64, i64) = sqlx::query_as(
+            r#"
+            SELECT
+              (SELECT COUNT(*) FROM snapshots WHERE parent_snapshot_id = ?1 AND state != ?2),
+              (SELECT COUNT(*) FROM snapshot_refs WHERE snapshot_id = ?1)
+            "#
+        )
+        .bind(snapshot_id)
+        .bind(hidden_state)
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(row)
+    }
+
     pub async fn get_gc_candidates(&self, keep_latest_per_sandbox: u32) -> Result<Vec<(String, u64)>> {
         let rows = sqlx::query_as::<_, (String, i64)>(
             r#"
@@ -143,12 +429,425 @@ impl Db {
         Ok(rows.into_iter().map(|(id, size)| (id, size as u64)).collect())
     }
 
-    pub async fn mark_snapshot_deleted(&self, snapshot_id: &str) -> Result<()> {
+    /// Mark a snapshot deleted, drop the refs to it and release its chunks; the row stays for
+    /// lineage
+    pub async fn mark_snapshot_deleted(&self, snapshot_id: &str, chunks: &[String]) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+        // Only READY snapshots hold chunk refs; releasing others' would free chunks still in use
+        let was_ready = sqlx::query("UPDATE snapshots SET state = 'DELETED' WHERE snapshot_id = ? AND state = 'READY'")
+            .bind(snapshot_id)
+            .execute(&mut *tx)
+            .await?
+            .rows_affected() > 0;
         sqlx::query("UPDATE snapshots SET state = 'DELETED' WHERE snapshot_id = ?")
+            .bind(snapshot_id)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("DELETE FROM snapshot_refs WHERE snapshot_id = ?")
+            .bind(snapshot_id)
+            .execute(&mut *tx)
+            .await?;
+        if was_ready {
+            Self::release_chunks(&mut tx, chunks).await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Move a snapshot from `from_state` to FAILED, releasing its chunks if it was READY.
+    /// Returns false if it wasn't in `from_state`.
+    pub async fn mark_snapshot_failed(&self, snapshot_id: &str, from_state: &str, error: &str, chunks: &[String]) -> Result<bool> {
+        let mut tx = self.pool.begin().await?;
+        let moved = sqlx::query("UPDATE snapshots SET state = 'FAILED', last_error = ? WHERE snapshot_id = ? AND state = ?")
+            .bind(error)
+            .bind(snapshot_id)
+            .bind(from_state)
+            .execute(&mut *tx)
+            .await?
+            .rows_affected() > 0;
+        if moved && from_state == "READY" {
+            Self::release_chunks(&mut tx, chunks).await?;
+        }
+        tx.commit().await?;
+        Ok(moved)
+    }
+
+    pub async fn snapshots_in_state(&self, state: &str) -> Result<Vec<SnapshotRecord>> {
+        let rows = sqlx::query_as::<_, SnapshotRecord>("SELECT * FROM snapshots WHERE state = ? ORDER BY created_at")
+            .bind(state)
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(rows)
+    }
+
+    /// Record (or with None, clear) a problem found with a snapshot, leaving its state alone
+    pub async fn set_snapshot_error(&self, snapshot_id: &str, error: Option<&str>) -> Result<()> {
+        sqlx::query("UPDATE snapshots SET last_error = ? WHERE snapshot_id = ?")
+            .bind(error)
             .bind(snapshot_id)
             .execute(&self.pool)
             .await?;
         Ok(())
     }
+
+    /// State of every snapshot row, deleted ones included
+    pub async fn snapshot_states(&self) -> Result<HashMap<String, String>> {
+        let rows = sqlx::query_as::<_, (String, String)>("SELECT snapshot_id, state FROM snapshots")
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(rows.into_iter().collect())
+    }
+
+    /// sha256 of every chunk the chunk table knows, referenced or not
+    pub async fn known_chunks(&self) -> Result<Vec<String>> {
+        let rows = sqlx::query_as::<_, (String,)>("SELECT sha256 FROM chunks")
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(rows.into_iter().map(|(sha256,)| sha256).collect())
+    }
+
+    async fn release_chunks(tx: &mut sqlx::Transaction<'_, Sqlite>, chunks: &[String]) -> Result<()> {
+        for sha256 in chunks {
+            sqlx::query("UPDATE chunks SET refcount = refcount - 1 WHERE sha256 = ?")
+                .bind(sha256)
+                .execute(&mut **tx)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Bytes of chunks nothing would reference once `chunks` are released; changes nothing
+    pub async fn reclaimable_chunk_bytes(&self, chunks: &[String]) -> Result<u64> {
+        let mut tx = self.pool.begin().await?;
+        Self::release_chunks(&mut tx, chunks).await?;
+        let (bytes,): (i64,) = sqlx::query_as("SELECT COALESCE(SUM(size_bytes), 0) FROM chunks WHERE refcount <= 0")
+            .fetch_one(&mut *tx)
+            .await?;
+        tx.rollback().await?;
+        Ok(bytes as u64)
+    }
+
+    /// (sha256, size) of every chunk no snapshot references
+    pub async fn unreferenced_chunks(&self) -> Result<Vec<(String, u64)>> {
+        let rows = sqlx::query_as::<_, (String, i64)>("SELECT sha256, size_bytes FROM chunks WHERE refcount <= 0")
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(rows.into_iter().map(|(sha256, size)| (sha256, size as u64)).collect())
+    }
+
+    /// Forget chunks whose files were removed, unless something took a ref to them meanwhile
+    pub async fn drop_chunks(&self, chunks: &[String]) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+        for sha256 in chunks {
+            sqlx::query("DELETE FROM chunks WHERE sha256 = ? AND refcount <= 0")
+                .bind(sha256)
+                .execute(&mut *tx)
+                .await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    pub async fn insert_artifact(&self, artifact: &ArtifactRecord) -> Result<()> {
+        sqlx::query(
+            "INSERT INTO artifacts (artifact_id, kind, filename, mime_type, size_bytes, sha256, sandbox_id, exec_id, created_at) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)"
+        )
+        .bind(&artifact.artifact_id)
+        .bind(&artifact.kind)
+        .bind(&artifact.filename)
+        .bind(&artifact.mime_type)
+        .bind(artifact.size_bytes)
+        .bind(&artifact.sha256)
+        .bind(&artifact.sandbox_id)
+        .bind(&artifact.exec_id)
+        .bind(artifact.created_at)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn get_artifact(&self, artifact_id: &str) -> Result<Option<ArtifactRecord>> {
+        let rec = sqlx::query_as::<_, ArtifactRecord>(
+            "SELECT * FROM artifacts WHERE artifact_id = ?"
+        )
+        .bind(artifact_id)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(rec)
+    }
+
+    pub async fn insert_exec(&self, exec_id: &str, sandbox_id: &str, state: &str, argv: &[String]) -> Result<()> {
+        sqlx::query(
+            "INSERT INTO execs (exec_id, sandbox_id, state, argv, created_at) VALUES (?, ?, ?, ?, ?)"
+        )
+        .bind(exec_id)
+        .bind(sandbox_id)
+        .bind(state)
+        .bind(serde_json::to_string(argv)?)
+        .bind(Utc::now())
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn set_exec_running(&self, exec_id: &str, state: &str, started_at: DateTime<Utc>) -> Result<()> {
+        sqlx::query("UPDATE execs SET state = ?, started_at = ? WHERE exec_id = ?")
+            .bind(state)
+            .bind(started_at)
+            .bind(exec_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Record the terminal state of an exec along with whatever output was captured
+    pub async fn finish_exec(&self, exec: &ExecRecord) -> Result<()> {
+        sqlx::query(
+            r#"
+            UPDATE execs
+            SET state = ?, exit_code = ?, started_at = COALESCE(?, started_at), finished_at = ?,
+                output_artifact_ids = ?, stdout_preview = ?, stderr_preview = ?, violations = ?, last_error = ?
+            WHERE exec_id = ?
+            "#
+        )
+        .bind(&exec.state)
+        .bind(exec.exit_code)
+        .bind(exec.started_at)
+        .bind(exec.finished_at)
+        .bind(&exec.output_artifact_ids)
+        .bind(&exec.stdout_preview)
+        .bind(&exec.stderr_preview)
+        .bind(&exec.violations)
+        .bind(&exec.last_error)
+        .bind(&exec.exec_id)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn get_exec(&self, exec_id: &str) -> Result<Option<ExecRecord>> {
+        let rec = sqlx::query_as::<_, ExecRecord>("SELECT * FROM execs WHERE exec_id = ?")
+            .bind(exec_id)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(rec)
+    }
+
+    /// Newest first, optionally restricted to one sandbox
+    pub async fn list_execs(&self, sandbox_id: Option<&str>, limit: i64, offset: i64) -> Result<Vec<ExecRecord>> {
+        let rows = sqlx::query_as::<_, ExecRecord>(
+            r#"
+            SELECT * FROM execs
+            WHERE (?1 IS NULL OR sandbox_id = ?1)
+            ORDER BY created_at DESC, exec_id
+            LIMIT ?2 OFFSET ?3
+            "#
+        )
+        .bind(sandbox_id)
+        .bind(limit)
+        .bind(offset)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows)
+    }
+
+    /// Execs that were in flight when the daemon stopped can never finish; mark them failed
+    pub async fn fail_interrupted_execs(&self, failed_state: &str, active_states: &[&str]) -> Result<u64> {
+        let mut total = 0;
+        for state in active_states {
+            let res = sqlx::query(
+                "UPDATE execs SET state = ?, finished_at = ?, last_error = 'Daemon restarted while exec was in flight' WHERE state = ?"
+            )
+            .bind(failed_state)
+            .bind(Utc::now())
+            .bind(state)
+            .execute(&self.pool)
+            .await?;
+            total += res.rows_affected();
+        }
+        Ok(total)
+    }
+
+    pub async fn insert_sandbox(
+        &self,
+        sandbox_id: &str,
+        provider: &str,
+        state: &str,
+        spec: &[u8],
+        labels: &HashMap<String, String>,
+    ) -> Result<()> {
+        let now = Utc::now();
+        sqlx::query(
+            "INSERT INTO sandboxes (sandbox_id, provider, state, spec, labels, created_at, updated_at) VALUES (?, ?, ?, ?, ?, ?, ?)"
+        )
+        .bind(sandbox_id)
+        .bind(provider)
+        .bind(state)
+        .bind(spec)
+        .bind(serde_json::to_string(labels)?)
+        .bind(now)
+        .bind(now)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn set_sandbox_state(&self, sandbox_id: &str, state: &str, last_error: Option<&str>) -> Result<()> {
+        sqlx::query(
+            "UPDATE sandboxes SET state = ?, updated_at = ?, last_error = COALESCE(?, last_error) WHERE sandbox_id = ?"
+        )
+        .bind(state)
+        .bind(Utc::now())
+        .bind(last_error)
+        .bind(sandbox_id)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn get_sandbox(&self, sandbox_id: &str) -> Result<Option<SandboxRecord>> {
+        let row = sqlx::query_as::<_, SandboxRecord>("SELECT * FROM sandboxes WHERE sandbox_id = ?")
+            .bind(sandbox_id)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(row)
+    }
+
+    /// Sandboxes matching every given filter, newest first. Without a state filter
+    /// `hidden_state` (destroyed sandboxes) is left out.
+    pub async fn list_sandboxes(
+        &self,
+        state: Option<&str>,
+        hidden_state: &str,
+        provider: Option<&str>,
+        labels: &HashMap<String, String>,
+        limit: i64,
+        offset: i64,
+    ) -> Result<Vec<SandboxRecord>> {
+        let mut query = QueryBuilder::<Sqlite>::new("SELECT * FROM sandboxes WHERE 1 = 1");
+        match state {
+            Some(state) => query.push(" AND state = ").push_bind(state),
+            None => query.push(" AND state != ").push_bind(hidden_state),
+        };
+        if let Some(provider) = provider {
+            query.push(" AND provider = ").push_bind(provider);
+        }
+        for (key, value) in labels {
+            query.push(" AND EXISTS (SELECT 1 FROM json_each(sandboxes.labels) WHERE json_each.key = ")
+                .push_bind(key)
+                .push(" AND json_each.value = ")
+                .push_bind(value)
+                .push(")");
+        }
+        query.push(" ORDER BY created_at DESC, sandbox_id LIMIT ")
+            .push_bind(limit)
+            .push(" OFFSET ")
+            .push_bind(offset);
+
+        let rows = query.build_query_as::<SandboxRecord>().fetch_all(&self.pool).await?;
+        Ok(rows)
+    }
+
+    /// Every sandbox not in one of `states`, for startup reconciliation
+    pub async fn sandboxes_not_in(&self, states: &[&str]) -> Result<Vec<SandboxRecord>> {
+        let mut query = QueryBuilder::<Sqlite>::new("SELECT * FROM sandboxes WHERE state NOT IN (");
+        let mut separated = query.separated(", ");
+        for state in states {
+            separated.push_bind(*state);
+        }
+        query.push(")");
+
+        let rows = query.build_query_as::<SandboxRecord>().fetch_all(&self.pool).await?;
+        Ok(rows)
+    }
+
+    /// Make the stored policies exactly `policies` (name, JSON), all loaded from `source`
+    pub async fn replace_policies(&self, policies: &[(String, String)], source: &str) -> Result<()> {
+        let now = Utc::now();
+        let mut tx = self.pool.begin().await?;
+        sqlx::query("DELETE FROM policies").execute(&mut *tx).await?;
+        for (name, policy) in policies {
+            sqlx::query("INSERT INTO policies (name, policy, source, updated_at) VALUES (?, ?, ?, ?)")
+                .bind(name)
+                .bind(policy)
+                .bind(source)
+                .bind(now)
+                .execute(&mut *tx)
+                .await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    pub async fn get_policy(&self, name: &str) -> Result<Option<PolicyRecord>> {
+        let row = sqlx::query_as::<_, PolicyRecord>("SELECT * FROM policies WHERE name = ?")
+            .bind(name)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(row)
+    }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, TempDir};
+
+    async fn ready_snapshot(db: &Db, snapshot_id: &str, chunks: &[&str]) {
+        db.insert_snapshot(&SnapshotRecord {
+            snapshot_id: snapshot_id.to_string(),
+            provider: "test".to_string(),
+            source_sandbox_id: "sandbox".to_string(),
+            created_at: Utc::now(),
+            mode: "FULL".to_string(),
+            name: None,
+            labels: None,
+            parent_snapshot_id: None,
+            root_snapshot_id: snapshot_id.to_string(),
+            state: "CREATING".to_string(),
+            size_bytes: 0,
+            components: None,
+            pinned: false,
+            ttl_expires_at: None,
+            last_error: None,
+        }).await.unwrap();
+        let chunks: Vec<_> = chunks.iter().map(|c| (c.to_string(), 10)).collect();
+        db.set_snapshot_ready(snapshot_id, 20, "[]", &chunks).await.unwrap();
+    }
+
+    fn names(chunks: Vec<(String, u64)>) -> Vec<String> {
+        let mut names: Vec<_> = chunks.into_iter().map(|(sha256, _)| sha256).collect();
+        names.sort();
+        names
+    }
+
+    #[tokio::test]
+    async fn chunks_are_freed_once_no_snapshot_references_them() {
+        let dir = TempDir::new("db");
+        let db = testing::db(&dir).await;
+        ready_snapshot(&db, "a", &["x", "shared"]).await;
+        ready_snapshot(&db, "b", &["shared", "y"]).await;
+        assert!(db.unreferenced_chunks().await.unwrap().is_empty());
+
+        db.mark_snapshot_deleted("a", &["x".into(), "shared".into()]).await.unwrap();
+        assert_eq!(names(db.unreferenced_chunks().await.unwrap()), ["x"]);
+        assert_eq!(db.reclaimable_chunk_bytes(&["shared".into(), "y".into()]).await.unwrap(), 30);
+        // Deleting again releases nothing twice
+        db.mark_snapshot_deleted("a", &["x".into(), "shared".into()]).await.unwrap();
+        assert_eq!(names(db.unreferenced_chunks().await.unwrap()), ["x"]);
+
+        db.mark_snapshot_deleted("b", &["shared".into(), "y".into()]).await.unwrap();
+        assert_eq!(names(db.unreferenced_chunks().await.unwrap()), ["shared", "x", "y"]);
+
+        // A chunk taken up again by a new snapshot survives the drop
+        ready_snapshot(&db, "c", &["y"]).await;
+        db.drop_chunks(&["x".into(), "shared".into(), "y".into()]).await.unwrap();
+        let mut known = db.known_chunks().await.unwrap();
+        known.sort();
+        assert_eq!(known, ["y"]);
+    }
+}
@@ -1,38 +1,71 @@
+// tonic::Status is large by design and is the natural error type for request validation helpers
+#![allow(clippy::result_large_err)]
+
 pub mod pb {
     tonic::include_proto!("crucible.daemon.v1");
 }
 
+pub mod config;
+pub mod policy;
 pub mod provider;
 pub mod server;
 pub mod db;
 pub mod store;
+#[cfg(test)]
+mod testing;
 
 use crate::pb::sandboxes_server::SandboxesServer;
 use crate::pb::execution_server::ExecutionServer;
 use crate::pb::snapshots_server::SnapshotsServer;
+use crate::pb::files_server::FilesServer;
 use tonic::transport::Server;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let addr = "[::1]:7171".parse()?;
+    let config = config::DaemonConfig::load()?;
+    let addr = config.listen_addr.parse()?;
     
     // Initialize Database
-    let db_url = "sqlite:crucible.db?mode=rwc";
-    let db = db::Db::new(db_url).await?;
-    println!("Crucible Database initialized at {}", db_url);
+    let db = db::Db::new(&config.db_url).await?;
+    println!("Crucible Database initialized at {}", config.db_url);
 
     // Initialize Store
-    let store_path = std::path::PathBuf::from("/tmp/crucible_snapshots");
-    let store = std::sync::Arc::new(store::SnapshotStore::new(&store_path).await?);
-    println!("Crucible Store initialized at {:?}", store_path);
+    let store = std::sync::Arc::new(store::SnapshotStore::new(&config.snapshot_dir).await?);
+    println!("Crucible Store initialized at {:?}", config.snapshot_dir);
+
+    let artifacts = std::sync::Arc::new(store::ArtifactStore::new(&config.artifact_dir).await?);
+    println!("Crucible Artifacts initialized at {:?}", config.artifact_dir);
+
+    // Pick the sandbox backend
+    let backend: std::sync::Arc<dyn provider::SandboxProvider> = match config.provider.as_str() {
+        "lima" => std::sync::Arc::new(provider::lima::LimaProvider::new(config.lima_instance.clone())),
+        "firecracker" => std::sync::Arc::new(provider::firecracker::FirecrackerProvider::new(config.firecracker.clone())),
+        "krunvm" => std::sync::Arc::new(provider::krunvm::KrunvmProvider::new(config.krunvm.clone())),
+        other => return Err(format!("Unknown provider {:?} (expected \"lima\", \"firecracker\" or \"krunvm\")", other).into()),
+    };
+    match backend.probe().await {
+        Ok(health) if health.healthy => println!("Provider {} ready ({})", backend.provider_name(), health.version.unwrap_or_default()),
+        Ok(_) => println!("Provider Warning: {} is not healthy", backend.provider_name()),
+        Err(e) => println!("Provider Warning: probing {} failed: {}", backend.provider_name(), e),
+    }
+
+    // Named policies referenced by SandboxPolicy.policy_id
+    let policies = std::sync::Arc::new(policy::registry::PolicyRegistry::load(db.clone(), &config.policy_file).await?);
+
+    // All sandbox state changes go through one lifecycle shared by the services
+    let lifecycle = std::sync::Arc::new(server::lifecycle::Lifecycle::new(db.clone()));
 
-    // Initialize our simple Lima provider as the backend
-    let lima_backend = std::sync::Arc::new(provider::lima::LimaProvider::new("crucible-worker"));
-    
     // Create the gRPC services
-    let sandbox_service = server::sandboxes::SandboxService::new(lima_backend.clone());
-    let execution_service = server::execution::ExecutionService::new(lima_backend.clone());
-    let snapshot_service = server::snapshots::SnapshotService::new(lima_backend.clone(), db.clone(), store.clone());
+    let execution_service = server::execution::ExecutionService::new(backend.clone(), db.clone(), artifacts.clone(), lifecycle.clone(), config.exec.clone());
+    let sandbox_service = server::sandboxes::SandboxService::new(backend.clone(), db.clone(), lifecycle.clone(), &config.mounts, policies.clone(), execution_service.clone());
+    sandbox_service.reconcile().await?;
+    execution_service.recover().await?;
+    let snapshot_service = server::snapshots::SnapshotService::new(backend.clone(), db.clone(), store.clone(), sandbox_service.clone(), lifecycle.clone());
+    snapshot_service.recover().await?;
+    let file_service = server::files::FileService::new(backend.clone(), db.clone(), artifacts.clone(), lifecycle.clone(), &config.files);
+
+    // Enforce sandbox_ttl / idle_ttl in the background
+    server::reaper::Reaper::new(db.clone(), sandbox_service.clone(), lifecycle.clone()).spawn();
 
     println!("Crucible Daemon listening on {}", addr);
 
@@ -40,6 +73,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .add_service(SandboxesServer::new(sandbox_service))
         .add_service(ExecutionServer::new(execution_service))
         .add_service(SnapshotsServer::new(snapshot_service))
+        .add_service(FilesServer::new(file_service))
         .serve(addr)
         .await?;
 
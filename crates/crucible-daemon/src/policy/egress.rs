@@ -0,0 +1,439 @@
+use crate::provider::{NetworkPolicy, Violation, ViolationKind};
+use anyhow::{anyhow, bail, Result};
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{lookup_host, TcpListener, TcpStream};
+use tokio::task::AbortHandle;
+
+/// Largest request head the proxy reads before deciding where a connection goes
+const MAX_HEAD_BYTES: usize = 8 * 1024;
+
+/// Parsed egress allowlist of a sandbox
+pub struct EgressRules {
+    domains: Vec<DomainRule>,
+    cidrs: Vec<Cidr>,
+}
+
+enum DomainRule {
+    Exact(String),
+    // `*.example.com`: example.com itself and every subdomain
+    Suffix(String),
+}
+
+#[derive(Clone, Copy)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl EgressRules {
+    pub fn from_policy(policy: &NetworkPolicy) -> Result<Self> {
+        Self::new(&policy.allow_domains, &policy.allow_cidrs)
+    }
+
+    pub fn new(allow_domains: &[String], allow_cidrs: &[String]) -> Result<Self> {
+        let domains = allow_domains.iter()
+            .map(|d| DomainRule::parse(d))
+            .collect::<Result<_>>()?;
+        let cidrs = allow_cidrs.iter()
+            .map(|c| c.parse())
+            .collect::<Result<_>>()?;
+        Ok(Self { domains, cidrs })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.domains.is_empty() && self.cidrs.is_empty()
+    }
+
+    pub fn cidrs(&self) -> &[Cidr] {
+        &self.cidrs
+    }
+
+    pub fn allows_domain(&self, host: &str) -> bool {
+        let host = normalize_host(host);
+        self.domains.iter().any(|rule| match rule {
+            DomainRule::Exact(name) => host == *name,
+            DomainRule::Suffix(suffix) => {
+                host == *suffix || host.strip_suffix(suffix.as_str()).is_some_and(|rest| rest.ends_with('.'))
+            }
+        })
+    }
+
+    pub fn allows_ip(&self, ip: IpAddr) -> bool {
+        self.cidrs.iter().any(|cidr| cidr.contains(ip))
+    }
+
+    /// Whether an address an allowed name resolved to may be dialed. Loopback, link-local and
+    /// private ranges only are when an `allow_cidrs` entry admits them, so a public name
+    /// pointed at 127.0.0.1 or 169.254.169.254 can't reach the host or its network.
+    pub fn may_dial(&self, ip: IpAddr) -> bool {
+        self.allows_ip(ip) || !is_internal(ip.to_canonical())
+    }
+
+    /// Whether every host the `allow_domains` entry admits is admitted here too
+    pub fn covers_domain(&self, entry: &str) -> Result<bool> {
+        Ok(match DomainRule::parse(entry)? {
+            DomainRule::Exact(name) => self.allows_domain(&name),
+            DomainRule::Suffix(suffix) => self.domains.iter().any(|rule| match rule {
+                DomainRule::Suffix(ours) => {
+                    suffix == *ours || suffix.strip_suffix(ours.as_str()).is_some_and(|rest| rest.ends_with('.'))
+                }
+                DomainRule::Exact(_) => false,
+            }),
+        })
+    }
+
+    /// Whether every address of the `allow_cidrs` entry is admitted here too
+    pub fn covers_cidr(&self, entry: &str) -> Result<bool> {
+        let cidr: Cidr = entry.parse()?;
+        Ok(self.cidrs.iter().any(|ours| ours.prefix <= cidr.prefix && ours.contains(cidr.addr)))
+    }
+}
+
+fn is_internal(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                // 100.64.0.0/10, carrier-grade NAT
+                || (ip.octets()[0] == 100 && ip.octets()[1] & 0xc0 == 64)
+        }
+        IpAddr::V6(ip) => {
+            ip.is_loopback() || ip.is_unspecified() || ip.is_unique_local() || ip.is_unicast_link_local()
+        }
+    }
+}
+
+fn normalize_host(host: &str) -> String {
+    host.trim().trim_matches('.').to_ascii_lowercase()
+}
+
+impl DomainRule {
+    fn parse(raw: &str) -> Result<Self> {
+        let (suffix, name) = match raw.trim().strip_prefix("*.") {
+            Some(rest) => (true, normalize_host(rest)),
+            None => (false, normalize_host(raw)),
+        };
+        let valid = !name.is_empty()
+            && name.split('.').all(|label| {
+                !label.is_empty() && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+            });
+        if !valid {
+            bail!("Invalid allow_domains entry {:?}", raw);
+        }
+        Ok(if suffix { DomainRule::Suffix(name) } else { DomainRule::Exact(name) })
+    }
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl std::str::FromStr for Cidr {
+    type Err = anyhow::Error;
+
+    fn from_str(raw: &str) -> Result<Self> {
+        let invalid = || anyhow!("Invalid allow_cidrs entry {:?}", raw);
+        let (addr, prefix) = match raw.trim().split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (raw.trim(), None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|_| invalid())?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(p) => p.parse::<u8>().ok().filter(|p| *p <= max).ok_or_else(invalid)?,
+            None => max,
+        };
+        Ok(Self { addr, prefix })
+    }
+}
+
+/// Canonical `addr/prefix`, safe to hand to other tools
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+/// Filtering HTTP proxy for one sandbox. It accepts CONNECT and absolute-form HTTP requests and
+/// only dials destinations the sandbox's rules allow; everything else gets a 403 and is
+/// recorded as an EGRESS_BLOCKED violation.
+pub struct EgressProxy {
+    port: u16,
+    blocked: Arc<Mutex<Vec<Violation>>>,
+    task: AbortHandle,
+}
+
+impl EgressProxy {
+    pub async fn start(rules: EgressRules) -> Result<Self> {
+        let listener = TcpListener::bind(SocketAddr::from((Ipv4Addr::LOCALHOST, 0))).await?;
+        let port = listener.local_addr()?.port();
+        let rules = Arc::new(rules);
+        let blocked = Arc::new(Mutex::new(Vec::new()));
+
+        let task_blocked = blocked.clone();
+        let task = tokio::spawn(async move {
+            while let Ok((conn, _)) = listener.accept().await {
+                let rules = rules.clone();
+                let blocked = task_blocked.clone();
+                tokio::spawn(async move {
+                    let _ = proxy_connection(conn, &rules, &blocked).await;
+                });
+            }
+        });
+
+        Ok(Self { port, blocked, task: task.abort_handle() })
+    }
+
+    /// Host port the guest has to reach the proxy on
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Blocked attempts since the last call
+    pub fn take_violations(&self) -> Vec<Violation> {
+        std::mem::take(&mut *self.blocked.lock().unwrap())
+    }
+}
+
+impl Drop for EgressProxy {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+async fn proxy_connection(mut conn: TcpStream, rules: &EgressRules, blocked: &Mutex<Vec<Violation>>) -> Result<()> {
+    let mut head = Vec::with_capacity(1024);
+    let mut buf = [0u8; 1024];
+    let head_len = loop {
+        let n = conn.read(&mut buf).await?;
+        if n == 0 {
+            return Ok(());
+        }
+        head.extend_from_slice(&buf[..n]);
+        if let Some(pos) = head.windows(4).position(|w| w == b"\r\n\r\n") {
+            break pos + 4;
+        }
+        if head.len() > MAX_HEAD_BYTES {
+            conn.write_all(b"HTTP/1.1 431 Request Header Fields Too Large\r\nConnection: close\r\n\r\n").await?;
+            return Ok(());
+        }
+    };
+
+    let request_line = String::from_utf8_lossy(&head[..head.iter().position(|b| *b == b'\r').unwrap_or(0)]).to_string();
+    let mut parts = request_line.split_whitespace();
+    let (method, target) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+    let connect = method.eq_ignore_ascii_case("CONNECT");
+    let Some((host, port)) = request_target(target, connect) else {
+        conn.write_all(b"HTTP/1.1 400 Bad Request\r\nConnection: close\r\n\r\n").await?;
+        return Ok(());
+    };
+
+    // Names are resolved here and the checked address is dialed, so what the name resolves to
+    // can't change between the check and the connect
+    let dest = match host.parse::<IpAddr>() {
+        Ok(ip) => rules.allows_ip(ip).then(|| SocketAddr::new(ip, port)).ok_or("not in egress allowlist"),
+        Err(_) if !rules.allows_domain(&host) => Err("not in egress allowlist"),
+        Err(_) => {
+            let Ok(mut addrs) = lookup_host((host.as_str(), port)).await else {
+                conn.write_all(b"HTTP/1.1 502 Bad Gateway\r\nConnection: close\r\n\r\n").await?;
+                return Ok(());
+            };
+            addrs.find(|addr| rules.may_dial(addr.ip())).ok_or("resolves only to internal addresses")
+        }
+    };
+    let dest = match dest {
+        Ok(dest) => dest,
+        Err(reason) => {
+            blocked.lock().unwrap().push(Violation {
+                kind: ViolationKind::EgressBlocked,
+                message: format!("Blocked connection to {}:{} ({})", host, port, reason),
+                ts: SystemTime::now(),
+            });
+            conn.write_all(b"HTTP/1.1 403 Forbidden\r\nConnection: close\r\n\r\nBlocked by sandbox egress policy\n").await?;
+            return Ok(());
+        }
+    };
+
+    let mut upstream = match TcpStream::connect(dest).await {
+        Ok(upstream) => upstream,
+        Err(_) => {
+            conn.write_all(b"HTTP/1.1 502 Bad Gateway\r\nConnection: close\r\n\r\n").await?;
+            return Ok(());
+        }
+    };
+    if connect {
+        conn.write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n").await?;
+        upstream.write_all(&head[head_len..]).await?;
+    } else {
+        upstream.write_all(&head).await?;
+    }
+    tokio::io::copy_bidirectional(&mut conn, &mut upstream).await?;
+    Ok(())
+}
+
+/// Destination of a proxy request: `host:port` for CONNECT, an absolute `http://` URI otherwise
+fn request_target(target: &str, connect: bool) -> Option<(String, u16)> {
+    let (authority, default_port) = if connect {
+        (target, None)
+    } else {
+        let rest = target.strip_prefix("http://")?;
+        (rest.split(['/', '?', '#']).next()?, Some(80))
+    };
+    let authority = authority.rsplit_once('@').map_or(authority, |(_, host)| host);
+
+    let (host, port) = match authority.strip_prefix('[') {
+        // [v6]:port
+        Some(rest) => {
+            let (host, after) = rest.split_once(']')?;
+            (host, after.strip_prefix(':'))
+        }
+        None => match authority.rsplit_once(':') {
+            Some((host, port)) => (host, Some(port)),
+            None => (authority, None),
+        },
+    };
+    let port = match port {
+        Some(p) => p.parse().ok()?,
+        None => default_port?,
+    };
+    if host.is_empty() {
+        return None;
+    }
+    Some((host.to_string(), port))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(domains: &[&str], cidrs: &[&str]) -> EgressRules {
+        let strings = |v: &[&str]| v.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        EgressRules::new(&strings(domains), &strings(cidrs)).unwrap()
+    }
+
+    #[test]
+    fn allows_domain() {
+        let rules = rules(&["pypi.org", "*.github.com"], &[]);
+        assert!(rules.allows_domain("pypi.org"));
+        assert!(rules.allows_domain("PyPI.org."));
+        assert!(!rules.allows_domain("files.pypi.org"));
+        assert!(!rules.allows_domain("evilpypi.org"));
+        // `*.x` also matches `x`
+        assert!(rules.allows_domain("github.com"));
+        assert!(rules.allows_domain("api.github.com"));
+        assert!(rules.allows_domain("a.b.github.com"));
+        assert!(!rules.allows_domain("evilgithub.com"));
+        assert!(!rules.allows_domain("github.com.evil.net"));
+    }
+
+    #[test]
+    fn rejects_invalid_entries() {
+        for entry in ["", "*.", "exa mple.com", "a..b", "http://pypi.org", "*.*.pypi.org"] {
+            assert!(EgressRules::new(&[entry.to_string()], &[]).is_err(), "{:?}", entry);
+        }
+        for entry in ["10.0.0.0/33", "::1/129", "pypi.org", "10.0.0.0/"] {
+            assert!(EgressRules::new(&[], &[entry.to_string()]).is_err(), "{:?}", entry);
+        }
+    }
+
+    #[test]
+    fn covers_domain() {
+        let rules = rules(&["pypi.org", "*.github.com"], &[]);
+        assert!(rules.covers_domain("pypi.org").unwrap());
+        assert!(rules.covers_domain("github.com").unwrap());
+        assert!(rules.covers_domain("api.github.com").unwrap());
+        assert!(rules.covers_domain("*.github.com").unwrap());
+        assert!(rules.covers_domain("*.api.github.com").unwrap());
+        assert!(!rules.covers_domain("*.pypi.org").unwrap());
+        assert!(!rules.covers_domain("*.com").unwrap());
+        assert!(!rules.covers_domain("crates.io").unwrap());
+        assert!(rules.covers_domain("not a domain").is_err());
+    }
+
+    #[test]
+    fn covers_cidr() {
+        let rules = rules(&[], &["10.0.0.0/8", "2001:db8::/32"]);
+        assert!(rules.covers_cidr("10.1.0.0/16").unwrap());
+        assert!(rules.covers_cidr("10.1.2.3").unwrap());
+        assert!(!rules.covers_cidr("0.0.0.0/0").unwrap());
+        assert!(!rules.covers_cidr("11.0.0.0/8").unwrap());
+        assert!(rules.covers_cidr("2001:db8:1::/48").unwrap());
+        assert!(!rules.covers_cidr("2001:db8::/16").unwrap());
+    }
+
+    #[test]
+    fn may_dial() {
+        let open = rules(&["example.com"], &[]);
+        for internal in ["127.0.0.1", "10.1.2.3", "172.16.0.1", "192.168.1.1", "169.254.169.254", "100.64.0.1", "0.0.0.0", "::1", "fd00::1", "fe80::1", "::ffff:127.0.0.1"] {
+            assert!(!open.may_dial(internal.parse().unwrap()), "{}", internal);
+        }
+        assert!(open.may_dial("93.184.216.34".parse().unwrap()));
+        assert!(open.may_dial("2606:2800:220:1::1".parse().unwrap()));
+
+        let lan = rules(&["example.com"], &["10.0.0.0/8"]);
+        assert!(lan.may_dial("10.1.2.3".parse().unwrap()));
+        assert!(!lan.may_dial("192.168.1.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn request_target() {
+        let target = |t: &str, connect| super::request_target(t, connect);
+        assert_eq!(target("pypi.org:443", true), Some(("pypi.org".into(), 443)));
+        assert_eq!(target("[::1]:443", true), Some(("::1".into(), 443)));
+        assert_eq!(target("pypi.org", true), None);
+        assert_eq!(target(":443", true), None);
+        assert_eq!(target("http://pypi.org/simple/", false), Some(("pypi.org".into(), 80)));
+        assert_eq!(target("http://pypi.org:8080?q", false), Some(("pypi.org".into(), 8080)));
+        assert_eq!(target("http://user:pw@pypi.org/", false), Some(("pypi.org".into(), 80)));
+        assert_eq!(target("http://[2001:db8::1]/", false), Some(("2001:db8::1".into(), 80)));
+        assert_eq!(target("https://pypi.org/", false), None);
+        assert_eq!(target("/relative", false), None);
+        assert_eq!(target("http://pypi.org:x/", false), None);
+    }
+
+    async fn connect_through(proxy: &EgressProxy, target: &str) -> String {
+        let mut conn = TcpStream::connect((Ipv4Addr::LOCALHOST, proxy.port())).await.unwrap();
+        conn.write_all(format!("CONNECT {} HTTP/1.1\r\n\r\n", target).as_bytes()).await.unwrap();
+        let mut reply = [0u8; 64];
+        let n = conn.read(&mut reply).await.unwrap();
+        String::from_utf8_lossy(&reply[..n]).lines().next().unwrap_or("").to_string()
+    }
+
+    #[tokio::test]
+    async fn proxy_refuses_names_resolving_to_internal_addresses() {
+        let upstream = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let port = upstream.local_addr().unwrap().port();
+
+        let proxy = EgressProxy::start(rules(&["localhost"], &[])).await.unwrap();
+        assert_eq!(connect_through(&proxy, &format!("localhost:{}", port)).await, "HTTP/1.1 403 Forbidden");
+        assert_eq!(connect_through(&proxy, &format!("127.0.0.1:{}", port)).await, "HTTP/1.1 403 Forbidden");
+        assert_eq!(connect_through(&proxy, "pypi.org:443").await, "HTTP/1.1 403 Forbidden");
+        let violations = proxy.take_violations();
+        assert_eq!(violations.len(), 3);
+        assert!(violations[0].message.contains("internal"));
+
+        // An allow_cidrs entry opts the range back in
+        let proxy = EgressProxy::start(rules(&["localhost"], &["127.0.0.0/8", "::1"])).await.unwrap();
+        assert_eq!(connect_through(&proxy, &format!("localhost:{}", port)).await, "HTTP/1.1 200 Connection Established");
+        assert!(proxy.take_violations().is_empty());
+    }
+}
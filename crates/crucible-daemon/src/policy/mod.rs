@@ -0,0 +1,4 @@
+pub mod egress;
+pub mod mounts;
+pub mod registry;
+pub mod seccomp;
@@ -0,0 +1,149 @@
+use crate::config::MountConfig;
+use crate::provider::{sandbox_root, MountSpec, Violation, ViolationKind};
+use std::path::{Component, Path, PathBuf};
+use std::time::SystemTime;
+
+/// Guest paths no mount may cover: the sandbox relies on its own /proc and /dev
+const RESERVED_GUEST_PATHS: &[&str] = &["/proc", "/dev", "/sys"];
+
+/// Checks sandbox mounts against the operator's host-path allowlist
+pub struct MountValidator {
+    allowed_host_roots: Vec<PathBuf>,
+}
+
+impl MountValidator {
+    pub fn new(config: &MountConfig) -> Self {
+        Self { allowed_host_roots: config.allowed_host_roots.clone() }
+    }
+
+    /// One MOUNT_DENIED violation per problem found; empty when every mount is acceptable.
+    /// `working_dir` is the spec's, resolved like the providers do; no mount may replace it.
+    pub fn validate(&self, mounts: &[MountSpec], working_dir: &Path) -> Vec<Violation> {
+        let sandbox_root = sandbox_root(working_dir);
+        let mut violations = Vec::new();
+        let mut deny = |message: String| violations.push(Violation {
+            kind: ViolationKind::MountDenied,
+            message,
+            ts: SystemTime::now(),
+        });
+
+        for m in mounts {
+            let (host, guest) = (&m.host_path, &m.guest_path);
+            // Compared with symlinks resolved: a link under an allowed root can point anywhere
+            if !is_plain_absolute(host) {
+                deny(format!("Mount host_path {:?} must be an absolute path without '..'", host));
+            } else {
+                match std::fs::canonicalize(host) {
+                    Err(e) => deny(format!("Mount host_path {:?} cannot be resolved: {}", host, e)),
+                    Ok(real) if !self.is_allowed(&real) => {
+                        deny(format!("Mount host_path {:?} is outside the allowed host roots", host));
+                    }
+                    Ok(_) => {}
+                }
+            }
+
+            if !is_plain_absolute(guest) {
+                deny(format!("Mount guest_path {:?} must be an absolute path without '..'", guest));
+            } else if guest == Path::new("/") {
+                deny("Mount guest_path \"/\" would replace the sandbox filesystem".to_string());
+            } else if let Some(reserved) = RESERVED_GUEST_PATHS.iter().find(|r| guest.starts_with(r)) {
+                deny(format!("Mount guest_path {:?} would shadow {}", guest, reserved));
+            } else if sandbox_root.starts_with(guest) {
+                deny(format!("Mount guest_path {:?} would shadow the sandbox root {:?}", guest, sandbox_root));
+            }
+        }
+
+        // Later mounts stack on earlier ones, so the same target twice is ambiguous
+        for (i, m) in mounts.iter().enumerate() {
+            if mounts[..i].iter().any(|prev| prev.guest_path == m.guest_path) {
+                deny(format!("Mount guest_path {:?} is mounted more than once", m.guest_path));
+            }
+        }
+        violations
+    }
+
+    /// Whether an already resolved host path lies under an allowed root
+    fn is_allowed(&self, host: &Path) -> bool {
+        self.allowed_host_roots.iter().any(|root| {
+            host.starts_with(std::fs::canonicalize(root).unwrap_or_else(|_| root.clone()))
+        })
+    }
+}
+
+/// Absolute, with no `..` components that could walk out of an allowed root
+fn is_plain_absolute(path: &Path) -> bool {
+    path.is_absolute()
+        && path.components().all(|c| !matches!(c, Component::ParentDir))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::provider::DEFAULT_WORKING_DIR;
+    use crate::testing::TempDir;
+
+    struct Fixture {
+        dir: TempDir,
+        validator: MountValidator,
+    }
+
+    impl Fixture {
+        /// An allowed root `<tmp>/allowed` with a `data` directory, next to a disallowed `<tmp>/secret`
+        fn new() -> Self {
+            let dir = TempDir::new("mounts");
+            std::fs::create_dir_all(dir.join("allowed/data")).unwrap();
+            std::fs::create_dir_all(dir.join("secret")).unwrap();
+            let config = MountConfig { allowed_host_roots: vec![dir.join("allowed")] };
+            Self { validator: MountValidator::new(&config), dir }
+        }
+
+        fn check(&self, host: &str, guest: &str, working_dir: &str) -> Vec<String> {
+            let mount = MountSpec { host_path: self.dir.join(host), guest_path: guest.into(), read_only: true };
+            self.validator.validate(&[mount], Path::new(working_dir)).into_iter().map(|v| v.message).collect()
+        }
+    }
+
+    #[test]
+    fn allows_mounts_under_an_allowed_root() {
+        let f = Fixture::new();
+        assert!(f.check("allowed/data", "/data", "/work").is_empty());
+    }
+
+    #[test]
+    fn denies_host_paths_outside_the_allowlist() {
+        let f = Fixture::new();
+        assert!(f.check("secret", "/data", "/work")[0].contains("outside the allowed host roots"));
+        assert!(f.check("allowed/missing", "/data", "/work")[0].contains("cannot be resolved"));
+    }
+
+    #[test]
+    fn denies_parent_dir_components() {
+        let f = Fixture::new();
+        assert!(f.check("allowed/../secret", "/data", "/work")[0].contains("without '..'"));
+        assert!(f.check("allowed/data", "/data/../etc", "/work")[0].contains("without '..'"));
+    }
+
+    #[test]
+    fn denies_symlinks_out_of_an_allowed_root() {
+        let f = Fixture::new();
+        std::os::unix::fs::symlink(f.dir.join("secret"), f.dir.join("allowed/link")).unwrap();
+        assert!(f.check("allowed/link", "/data", "/work")[0].contains("outside the allowed host roots"));
+    }
+
+    #[test]
+    fn denies_reserved_guest_paths() {
+        let f = Fixture::new();
+        assert!(f.check("allowed/data", "/proc/self", "/work")[0].contains("would shadow /proc"));
+        assert!(f.check("allowed/data", "/", "/work")[0].contains("replace the sandbox filesystem"));
+    }
+
+    #[test]
+    fn denies_shadowing_the_sandbox_root() {
+        let f = Fixture::new();
+        assert!(f.check("allowed/data", "/work", "/work/app")[0].contains("sandbox root"));
+        // A relative or empty working_dir still puts the sandbox at the default root
+        assert!(f.check("allowed/data", DEFAULT_WORKING_DIR, "")[0].contains("sandbox root"));
+        assert!(f.check("allowed/data", DEFAULT_WORKING_DIR, "work")[0].contains("sandbox root"));
+        assert!(f.check("allowed/data", "/workspace/data", "").is_empty());
+    }
+}
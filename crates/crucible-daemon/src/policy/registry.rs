@@ -0,0 +1,432 @@
+//! Named sandbox policies, referenced through `SandboxPolicy.policy_id`.
+//!
+//! Operators define policies in a TOML file, e.g.
+//!
+//! ```toml
+//! [policies.untrusted-agent]
+//! seccomp = "strict"
+//! enable_gpu = false
+//! network = { deny_all = false, allow_domains = ["pypi.org", "*.pythonhosted.org"], allow_loopback = false }
+//! limits = { vcpu = 1, memory_mb = 1024, pids_max = 256, sandbox_ttl_sec = 3600 }
+//! ```
+//!
+//! The file is mirrored into SQLite at startup; `default` and `strict` exist even without it.
+//!
+//! Precedence: the named policy is the base and the spec's inline fields are applied on top, but
+//! only where they tighten it. Anything that would loosen the policy fails CreateSandbox:
+//! - network: `deny_all` from either side wins. An inline allowlist replaces the policy's, and
+//!   every entry must already be allowed by it. A policy with `deny_all` accepts no allowlist.
+//!   Loopback can't be turned on inline when the policy turns it off; left unset on both
+//!   sides, it follows the sandbox default (on, but off once there is an allowlist).
+//! - mounts: if the policy lists mounts, inline mounts must be among them (same guest path,
+//!   host path at or below the policy's once symlinks are resolved, with no `..`) and may not
+//!   turn read-only ones writable. Without inline mounts the policy's apply.
+//! - seccomp: the inline profile may only be stricter.
+//! - limits: policy values are maximums; inline values must not exceed them, and unset (zero)
+//!   inline values take the policy's.
+//! - GPU: a policy with `enable_gpu = false` can't be overridden.
+//!
+//! Fields a policy leaves out don't constrain the spec.
+
+use crate::db::Db;
+use crate::pb::{self, mount_policy, SandboxSpec};
+use crate::policy::egress::EgressRules;
+use crate::policy::seccomp::SeccompProfile;
+use crate::provider::ResourceLimits;
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Component, Path, PathBuf};
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PolicyFile {
+    #[serde(default)]
+    policies: BTreeMap<String, NamedPolicy>,
+}
+
+/// One named policy. Every field is optional; a missing one leaves that aspect to the spec.
+#[derive(Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NamedPolicy {
+    pub network: Option<NetworkRules>,
+    pub mounts: Option<Vec<MountRule>>,
+    pub seccomp: Option<String>,
+    pub limits: Option<LimitRules>,
+    pub enable_gpu: Option<bool>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NetworkRules {
+    #[serde(default)]
+    pub deny_all: bool,
+    #[serde(default)]
+    pub allow_domains: Vec<String>,
+    #[serde(default)]
+    pub allow_cidrs: Vec<String>,
+    #[serde(default)]
+    pub allow_loopback: Option<bool>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MountRule {
+    pub host_path: PathBuf,
+    pub guest_path: PathBuf,
+    #[serde(default = "default_true")]
+    pub read_only: bool,
+}
+
+/// Upper bounds; unset means unbounded
+#[derive(Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LimitRules {
+    pub vcpu: Option<u32>,
+    pub memory_mb: Option<u64>,
+    pub disk_mb: Option<u64>,
+    pub pids_max: Option<u32>,
+    pub sandbox_ttl_sec: Option<u64>,
+    pub idle_ttl_sec: Option<u64>,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+pub struct PolicyRegistry {
+    db: Db,
+}
+
+impl PolicyRegistry {
+    /// Sync the stored policies with `path`. Without the file, the policies stored last time stay.
+    pub async fn load(db: Db, path: &Path) -> Result<Self> {
+        if path.exists() {
+            let raw = std::fs::read_to_string(path)
+                .with_context(|| format!("Failed to read policy file {}", path.display()))?;
+            let file: PolicyFile = toml::from_str(&raw)
+                .with_context(|| format!("Invalid policy file {}", path.display()))?;
+
+            let mut rows = Vec::with_capacity(file.policies.len());
+            for (name, policy) in &file.policies {
+                policy.validate().with_context(|| format!("Invalid policy {:?} in {}", name, path.display()))?;
+                rows.push((name.clone(), serde_json::to_string(policy)?));
+            }
+            db.replace_policies(&rows, &path.display().to_string()).await?;
+            println!("Loaded {} named policies from {}", rows.len(), path.display());
+        }
+        Ok(Self { db })
+    }
+
+    /// The stored policy called `name`, falling back to the built-in ones
+    pub async fn get(&self, name: &str) -> Result<Option<NamedPolicy>> {
+        if let Some(record) = self.db.get_policy(name).await? {
+            return Ok(Some(serde_json::from_str(&record.policy)?));
+        }
+        Ok(builtin(name))
+    }
+
+    /// `spec` with its named policy merged in, or an error naming what is unknown or would loosen it
+    pub async fn resolve(&self, mut spec: SandboxSpec) -> Result<SandboxSpec> {
+        let Some(name) = spec.policy.as_ref().map(|p| p.policy_id.clone()).filter(|id| !id.is_empty()) else {
+            return Ok(spec);
+        };
+        let Some(named) = self.get(&name).await? else {
+            bail!("Unknown policy {:?}", name);
+        };
+        named.apply(&mut spec).with_context(|| format!("Spec conflicts with policy {:?}", name))?;
+        Ok(spec)
+    }
+}
+
+fn builtin(name: &str) -> Option<NamedPolicy> {
+    let profile = SeccompProfile::from_name(name).ok().filter(|_| !name.is_empty())?;
+    Some(NamedPolicy { seccomp: Some(profile.name().to_string()), ..Default::default() })
+}
+
+impl NamedPolicy {
+    fn validate(&self) -> Result<()> {
+        if let Some(network) = &self.network {
+            EgressRules::new(&network.allow_domains, &network.allow_cidrs)?;
+        }
+        if let Some(seccomp) = &self.seccomp {
+            SeccompProfile::from_name(seccomp)?;
+        }
+        for m in self.mounts.iter().flatten() {
+            if !m.host_path.is_absolute() || !m.guest_path.is_absolute() {
+                bail!("Mount {:?} -> {:?} must use absolute paths", m.host_path, m.guest_path);
+            }
+        }
+        Ok(())
+    }
+
+    fn apply(&self, spec: &mut SandboxSpec) -> Result<()> {
+        if let Some(limits) = &self.limits {
+            spec.limits = Some(limits.apply(spec.limits.take())?);
+        }
+
+        let policy = spec.policy.get_or_insert_with(Default::default);
+        if let Some(rules) = &self.network {
+            policy.network = Some(rules.apply(policy.network.take())?);
+        }
+        if let Some(rules) = &self.mounts {
+            let inline = policy.mounts.take().map(|m| m.mounts).unwrap_or_default();
+            policy.mounts = Some(pb::MountPolicy { mounts: apply_mounts(rules, inline)? });
+        }
+        if let Some(seccomp) = &self.seccomp {
+            let ours = SeccompProfile::from_name(seccomp)?;
+            let inline = SeccompProfile::from_name(&policy.seccomp_profile)?;
+            if !policy.seccomp_profile.is_empty() && inline < ours {
+                bail!("seccomp_profile {:?} is less strict than {:?}", inline.name(), ours.name());
+            }
+            policy.seccomp_profile = inline.max(ours).name().to_string();
+        }
+        if self.enable_gpu == Some(false) && policy.enable_gpu {
+            bail!("enable_gpu is not allowed");
+        }
+        Ok(())
+    }
+}
+
+impl NetworkRules {
+    fn apply(&self, inline: Option<pb::NetworkPolicy>) -> Result<pb::NetworkPolicy> {
+        let inline = inline.unwrap_or_default();
+        if inline.allow_loopback == Some(true) && self.allow_loopback == Some(false) {
+            bail!("allow_loopback is not allowed");
+        }
+        let loopback = match self.allow_loopback {
+            Some(false) => Some(false),
+            Some(true) => Some(inline.allow_loopback.unwrap_or(true)),
+            None => inline.allow_loopback,
+        };
+
+        let inline_list = !inline.allow_domains.is_empty() || !inline.allow_cidrs.is_empty();
+        let (deny_all, allow_domains, allow_cidrs) = if inline.deny_all {
+            // Denying everything is as tight as it gets, whatever the policy allows
+            (true, vec![], vec![])
+        } else if !inline_list {
+            (self.deny_all, self.allow_domains.clone(), self.allow_cidrs.clone())
+        } else if self.deny_all {
+            bail!("the policy denies all egress, allow_domains/allow_cidrs are not allowed");
+        } else if self.allow_domains.is_empty() && self.allow_cidrs.is_empty() {
+            // Unrestricted policy: any allowlist narrows it
+            (false, inline.allow_domains, inline.allow_cidrs)
+        } else {
+            let ours = EgressRules::new(&self.allow_domains, &self.allow_cidrs)?;
+            for domain in &inline.allow_domains {
+                if !ours.covers_domain(domain)? {
+                    bail!("allow_domains entry {:?} is not allowed", domain);
+                }
+            }
+            for cidr in &inline.allow_cidrs {
+                if !ours.covers_cidr(cidr)? {
+                    bail!("allow_cidrs entry {:?} is not allowed", cidr);
+                }
+            }
+            (false, inline.allow_domains, inline.allow_cidrs)
+        };
+
+        Ok(pb::NetworkPolicy { deny_all, allow_domains, allow_cidrs, allow_loopback: loopback })
+    }
+}
+
+fn apply_mounts(rules: &[MountRule], inline: Vec<mount_policy::Mount>) -> Result<Vec<mount_policy::Mount>> {
+    if inline.is_empty() {
+        return Ok(rules.iter().map(|r| mount_policy::Mount {
+            host_path: r.host_path.display().to_string(),
+            guest_path: r.guest_path.display().to_string(),
+            read_only: Some(r.read_only),
+        }).collect());
+    }
+
+    inline.into_iter().map(|mut m| {
+        let host = Path::new(&m.host_path);
+        if !host.is_absolute() || host.components().any(|c| c == Component::ParentDir) {
+            bail!("mount {:?} -> {:?} must use an absolute host path without ..", m.host_path, m.guest_path);
+        }
+        let host = resolved(host);
+        let rule = rules.iter()
+            .find(|r| Path::new(&m.guest_path) == r.guest_path && host.starts_with(resolved(&r.host_path)))
+            .with_context(|| format!("mount {:?} -> {:?} is not allowed", m.host_path, m.guest_path))?;
+        if rule.read_only && m.read_only == Some(false) {
+            bail!("mount {:?} -> {:?} must be read-only", m.host_path, m.guest_path);
+        }
+        m.read_only = Some(m.read_only.unwrap_or(true) || rule.read_only);
+        Ok(m)
+    }).collect()
+}
+
+/// `path` with symlinks resolved, so a link under an allowed host path can't lead out of it.
+/// Paths that don't exist (yet) are compared as written; mount validation rejects them later.
+fn resolved(path: &Path) -> PathBuf {
+    std::fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+impl LimitRules {
+    fn apply(&self, inline: Option<pb::ResourceLimits>) -> Result<pb::ResourceLimits> {
+        // Without inline limits, fall back to the ones a sandbox would get anyway
+        let unset = inline.is_none();
+        let inline = inline.unwrap_or_default();
+        let defaults = ResourceLimits::default();
+        let fallback = |default: u64| if unset { default } else { 0 };
+
+        Ok(pb::ResourceLimits {
+            vcpu: cap("vcpu", self.vcpu.map(u64::from), inline.vcpu.into(), fallback(defaults.vcpu.into()))? as u32,
+            memory_mb: cap("memory_mb", self.memory_mb, inline.memory_mb, fallback(defaults.memory_mb))?,
+            disk_mb: cap("disk_mb", self.disk_mb, inline.disk_mb, fallback(defaults.disk_mb))?,
+            pids_max: cap("pids_max", self.pids_max.map(u64::from), inline.pids_max.into(), fallback(defaults.pids_max.into()))? as u32,
+            sandbox_ttl_sec: cap("sandbox_ttl_sec", self.sandbox_ttl_sec, inline.sandbox_ttl_sec, 0)?,
+            idle_ttl_sec: cap("idle_ttl_sec", self.idle_ttl_sec, inline.idle_ttl_sec, 0)?,
+        })
+    }
+}
+
+/// One limit after merging: zero means unlimited, so an inline zero defers to the policy
+fn cap(field: &str, max: Option<u64>, inline: u64, fallback: u64) -> Result<u64> {
+    match max {
+        Some(max) if inline > max => bail!("{} {} exceeds the policy maximum of {}", field, inline, max),
+        Some(max) if inline == 0 => Ok(if fallback > 0 { fallback.min(max) } else { max }),
+        _ if inline == 0 => Ok(fallback),
+        _ => Ok(inline),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    fn spec(policy: pb::SandboxPolicy, limits: Option<pb::ResourceLimits>) -> SandboxSpec {
+        SandboxSpec { policy: Some(policy), limits, ..Default::default() }
+    }
+
+    fn network(deny_all: bool, allow_domains: &[&str], allow_cidrs: &[&str], allow_loopback: Option<bool>) -> pb::SandboxPolicy {
+        pb::SandboxPolicy {
+            network: Some(pb::NetworkPolicy {
+                deny_all,
+                allow_domains: allow_domains.iter().map(|d| d.to_string()).collect(),
+                allow_cidrs: allow_cidrs.iter().map(|c| c.to_string()).collect(),
+                allow_loopback,
+            }),
+            ..Default::default()
+        }
+    }
+
+    fn merged_network(named: &NamedPolicy, inline: pb::SandboxPolicy) -> Result<pb::NetworkPolicy> {
+        let mut spec = spec(inline, None);
+        named.apply(&mut spec)?;
+        Ok(spec.policy.unwrap().network.unwrap())
+    }
+
+    #[test]
+    fn network_may_only_be_tightened() {
+        let named = NamedPolicy {
+            network: Some(NetworkRules {
+                deny_all: false,
+                allow_domains: vec!["*.pythonhosted.org".into(), "pypi.org".into()],
+                allow_cidrs: vec!["10.0.0.0/8".into()],
+                allow_loopback: Some(false),
+            }),
+            ..Default::default()
+        };
+
+        let merged = merged_network(&named, pb::SandboxPolicy::default()).unwrap();
+        assert_eq!(merged.allow_domains, ["*.pythonhosted.org", "pypi.org"]);
+        assert_eq!(merged.allow_loopback, Some(false));
+
+        let merged = merged_network(&named, network(false, &["files.pythonhosted.org"], &["10.1.0.0/16"], None)).unwrap();
+        assert_eq!(merged.allow_domains, ["files.pythonhosted.org"]);
+        assert_eq!(merged.allow_cidrs, ["10.1.0.0/16"]);
+        assert!(merged_network(&named, network(true, &[], &[], None)).unwrap().deny_all);
+
+        assert!(merged_network(&named, network(false, &["example.com"], &[], None)).is_err());
+        assert!(merged_network(&named, network(false, &["*.org"], &[], None)).is_err());
+        assert!(merged_network(&named, network(false, &[], &["0.0.0.0/0"], None)).is_err());
+        assert!(merged_network(&named, network(false, &[], &[], Some(true))).is_err());
+
+        let closed = NamedPolicy {
+            network: Some(NetworkRules { deny_all: true, allow_domains: vec![], allow_cidrs: vec![], allow_loopback: None }),
+            ..Default::default()
+        };
+        assert!(merged_network(&closed, pb::SandboxPolicy::default()).unwrap().deny_all);
+        assert!(merged_network(&closed, network(false, &["pypi.org"], &[], None)).is_err());
+
+        // Unset on both sides, loopback is left to the sandbox default
+        let open = NamedPolicy {
+            network: Some(NetworkRules { deny_all: false, allow_domains: vec![], allow_cidrs: vec![], allow_loopback: None }),
+            ..Default::default()
+        };
+        assert_eq!(merged_network(&open, pb::SandboxPolicy::default()).unwrap().allow_loopback, None);
+        assert_eq!(merged_network(&open, network(false, &[], &[], Some(true))).unwrap().allow_loopback, Some(true));
+    }
+
+    #[test]
+    fn limits_are_maximums() {
+        let named = NamedPolicy {
+            limits: Some(LimitRules { memory_mb: Some(1024), sandbox_ttl_sec: Some(3600), ..Default::default() }),
+            ..Default::default()
+        };
+        let merged = |limits: Option<pb::ResourceLimits>| {
+            let mut spec = spec(pb::SandboxPolicy::default(), limits);
+            named.apply(&mut spec).map(|_| spec.limits.unwrap())
+        };
+
+        // Without inline limits the defaults apply, capped by the policy
+        let limits = merged(None).unwrap();
+        assert_eq!(limits.memory_mb, 1024);
+        assert_eq!(limits.vcpu, ResourceLimits::default().vcpu);
+        assert_eq!(limits.sandbox_ttl_sec, 3600);
+
+        let limits = merged(Some(pb::ResourceLimits { memory_mb: 512, vcpu: 4, ..Default::default() })).unwrap();
+        assert_eq!((limits.memory_mb, limits.vcpu, limits.sandbox_ttl_sec), (512, 4, 3600));
+
+        assert!(merged(Some(pb::ResourceLimits { memory_mb: 2048, ..Default::default() })).is_err());
+        assert!(merged(Some(pb::ResourceLimits { sandbox_ttl_sec: 7200, ..Default::default() })).is_err());
+    }
+
+    #[test]
+    fn seccomp_may_only_get_stricter() {
+        let merged = |ours: &str, inline: &str| {
+            let named = NamedPolicy { seccomp: Some(ours.into()), ..Default::default() };
+            let mut spec = spec(pb::SandboxPolicy { seccomp_profile: inline.into(), ..Default::default() }, None);
+            named.apply(&mut spec).map(|_| spec.policy.unwrap().seccomp_profile)
+        };
+        assert_eq!(merged("strict", "").unwrap(), "strict");
+        assert_eq!(merged("strict", "strict").unwrap(), "strict");
+        assert_eq!(merged("default", "strict").unwrap(), "strict");
+        assert_eq!(merged("default", "").unwrap(), "default");
+        assert!(merged("strict", "default").is_err());
+    }
+
+    #[test]
+    fn mounts_stay_within_the_policy() {
+        let dir = TempDir::new("policy");
+        std::fs::create_dir_all(dir.join("data/sub")).unwrap();
+        std::fs::create_dir_all(dir.join("secret")).unwrap();
+        std::os::unix::fs::symlink(dir.join("secret"), dir.join("data/link")).unwrap();
+
+        let rules = vec![
+            MountRule { host_path: dir.join("data"), guest_path: "/data".into(), read_only: false },
+            MountRule { host_path: dir.join("data"), guest_path: "/ro".into(), read_only: true },
+        ];
+        let mount = |host: PathBuf, guest: &str, read_only: Option<bool>| mount_policy::Mount {
+            host_path: host.display().to_string(),
+            guest_path: guest.into(),
+            read_only,
+        };
+        let merged = |m: mount_policy::Mount| apply_mounts(&rules, vec![m]);
+
+        // The policy's mounts apply when the spec has none
+        assert_eq!(apply_mounts(&rules, vec![]).unwrap().len(), 2);
+
+        let m = merged(mount(dir.join("data/sub"), "/data", Some(false))).unwrap();
+        assert_eq!(m[0].read_only, Some(false));
+        assert_eq!(merged(mount(dir.join("data"), "/data", None)).unwrap()[0].read_only, Some(true));
+        assert!(merged(mount(dir.join("data"), "/ro", Some(false))).is_err());
+        assert!(merged(mount(dir.join("data"), "/elsewhere", None)).is_err());
+        assert!(merged(mount(dir.join("secret"), "/data", None)).is_err());
+        assert!(merged(mount(dir.join("data/../secret"), "/data", None)).is_err());
+        assert!(merged(mount(dir.join("data/link"), "/data", None)).is_err());
+        assert!(merged(mount("data".into(), "/data", None)).is_err());
+    }
+}
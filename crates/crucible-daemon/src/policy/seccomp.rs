@@ -0,0 +1,314 @@
+use anyhow::{bail, Result};
+
+// Classic BPF as understood by seccomp (linux/filter.h, linux/seccomp.h, linux/audit.h)
+const BPF_LD_W_ABS: u16 = 0x20;
+const BPF_JEQ_K: u16 = 0x15;
+const BPF_JGE_K: u16 = 0x35;
+const BPF_JSET_K: u16 = 0x45;
+const BPF_RET_K: u16 = 0x06;
+const SECCOMP_RET_KILL_PROCESS: u32 = 0x8000_0000;
+const SECCOMP_RET_ERRNO: u32 = 0x0005_0000;
+const SECCOMP_RET_ALLOW: u32 = 0x7fff_0000;
+const ENOSYS: u32 = 38;
+const OFFSET_NR: u32 = 0;
+const OFFSET_ARCH: u32 = 4;
+/// Low word of the first syscall argument; both supported guests are little-endian
+const OFFSET_ARG0_LO: u32 = 16;
+/// CLONE_NEWTIME | CLONE_NEWNS | CLONE_NEWCGROUP | CLONE_NEWUTS | CLONE_NEWIPC | CLONE_NEWUSER |
+/// CLONE_NEWPID | CLONE_NEWNET: `clone` with any of these does what `unshare` does
+const CLONE_NEW_FLAGS: u32 = 0x0000_0080 | 0x0002_0000 | 0x0200_0000 | 0x0400_0000 | 0x0800_0000
+    | 0x1000_0000 | 0x2000_0000 | 0x4000_0000;
+const AUDIT_ARCH_X86_64: u32 = 0xc000_003e;
+const AUDIT_ARCH_AARCH64: u32 = 0xc000_00b7;
+/// x32 syscalls on x86_64 carry this bit; they'd bypass a filter keyed on 64-bit numbers
+const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+
+/// Exit status of a process the filter killed (128 + SIGSYS), as reported by bwrap and shells
+pub const SECCOMP_KILL_EXIT_CODE: i32 = 128 + 31;
+
+/// Syscalls that reconfigure the kernel or the machine rather than the sandbox
+const DEFAULT_DENY: &[&str] = &[
+    "kexec_load", "kexec_file_load", "init_module", "finit_module", "delete_module", "reboot",
+    "swapon", "swapoff", "acct", "settimeofday", "clock_settime", "iopl", "ioperm",
+    "open_by_handle_at", "name_to_handle_at", "userfaultfd", "perf_event_open",
+];
+
+/// On top of the default: debugging other processes, namespaces and mounts, BPF and keyrings.
+/// The strict profile also kills `clone` with namespace flags and fails `clone3`, whose flags
+/// live in memory a filter can't read, with ENOSYS so libc falls back to `clone`.
+const STRICT_DENY: &[&str] = &[
+    "ptrace", "process_vm_readv", "process_vm_writev", "mount", "umount2", "pivot_root", "chroot",
+    "open_tree", "move_mount", "fsopen", "fsconfig", "fsmount", "fspick", "mount_setattr", "unshare",
+    "setns", "bpf", "keyctl", "add_key", "request_key", "personality",
+];
+
+/// (name, x86_64 number, aarch64 number); `None` where the architecture lacks the syscall
+type SyscallNumbers = (&'static str, Option<u32>, Option<u32>);
+
+const SYSCALLS: &[SyscallNumbers] = &[
+    ("umount2", Some(166), Some(39)),
+    ("mount", Some(165), Some(40)),
+    ("pivot_root", Some(155), Some(41)),
+    ("chroot", Some(161), Some(51)),
+    ("acct", Some(163), Some(89)),
+    ("personality", Some(135), Some(92)),
+    ("unshare", Some(272), Some(97)),
+    ("kexec_load", Some(246), Some(104)),
+    ("init_module", Some(175), Some(105)),
+    ("delete_module", Some(176), Some(106)),
+    ("clock_settime", Some(227), Some(112)),
+    ("ptrace", Some(101), Some(117)),
+    ("reboot", Some(169), Some(142)),
+    ("settimeofday", Some(164), Some(170)),
+    ("add_key", Some(248), Some(217)),
+    ("request_key", Some(249), Some(218)),
+    ("keyctl", Some(250), Some(219)),
+    ("swapon", Some(167), Some(224)),
+    ("swapoff", Some(168), Some(225)),
+    ("perf_event_open", Some(298), Some(241)),
+    ("name_to_handle_at", Some(303), Some(264)),
+    ("open_by_handle_at", Some(304), Some(265)),
+    ("setns", Some(308), Some(268)),
+    ("process_vm_readv", Some(310), Some(270)),
+    ("process_vm_writev", Some(311), Some(271)),
+    ("finit_module", Some(313), Some(273)),
+    ("bpf", Some(321), Some(280)),
+    ("userfaultfd", Some(323), Some(282)),
+    ("kexec_file_load", Some(320), Some(294)),
+    ("iopl", Some(172), None),
+    ("ioperm", Some(173), None),
+    ("open_tree", Some(428), Some(428)),
+    ("move_mount", Some(429), Some(429)),
+    ("fsopen", Some(430), Some(430)),
+    ("fsconfig", Some(431), Some(431)),
+    ("fsmount", Some(432), Some(432)),
+    ("fspick", Some(433), Some(433)),
+    ("mount_setattr", Some(442), Some(442)),
+    ("clone", Some(56), Some(220)),
+    ("clone3", Some(435), Some(435)),
+];
+
+/// Named syscall filter applied to every process of a sandbox, ordered from least to most strict
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum SeccompProfile {
+    Default,
+    Strict,
+}
+
+impl SeccompProfile {
+    /// Profile for a `SandboxPolicy.seccomp_profile`; empty selects the default profile
+    pub fn from_name(name: &str) -> Result<Self> {
+        match name {
+            "" | "default" => Ok(Self::Default),
+            "strict" => Ok(Self::Strict),
+            other => bail!("Unknown seccomp profile {:?} (expected \"default\" or \"strict\")", other),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Default => "default",
+            Self::Strict => "strict",
+        }
+    }
+
+    pub fn denied_syscalls(&self) -> Vec<&'static str> {
+        match self {
+            Self::Default => DEFAULT_DENY.to_vec(),
+            Self::Strict => DEFAULT_DENY.iter().chain(STRICT_DENY).copied().collect(),
+        }
+    }
+
+    /// The filter as a `struct sock_filter[]` image, ready for `bwrap --seccomp`. It covers
+    /// x86_64 and aarch64 guests; any other ABI (i386, x32 on x86_64) is killed outright.
+    pub fn compile(&self) -> Vec<u8> {
+        let denied = self.denied_syscalls();
+        let numbers = |arch: fn(&SyscallNumbers) -> Option<u32>| -> Vec<u32> {
+            SYSCALLS.iter().filter(|s| denied.contains(&s.0)).filter_map(arch).collect()
+        };
+        let number = |name: &str, arch: fn(&SyscallNumbers) -> Option<u32>| {
+            SYSCALLS.iter().find(|s| s.0 == name).and_then(arch).expect("syscall missing from SYSCALLS")
+        };
+        let x86_64: fn(&SyscallNumbers) -> Option<u32> = |s| s.1;
+        let aarch64: fn(&SyscallNumbers) -> Option<u32> = |s| s.2;
+
+        let mut program = vec![Insn::stmt(BPF_LD_W_ABS, OFFSET_ARCH)];
+        for (arch, nrs, x32_bit) in [(AUDIT_ARCH_X86_64, x86_64, true), (AUDIT_ARCH_AARCH64, aarch64, false)] {
+            let mut block = vec![Insn::stmt(BPF_LD_W_ABS, OFFSET_NR)];
+            if x32_bit {
+                block.push(Insn::jump_to(Ret::Kill, BPF_JGE_K, X32_SYSCALL_BIT));
+            }
+            block.extend(numbers(nrs).into_iter().map(|nr| Insn::jump_to(Ret::Kill, BPF_JEQ_K, nr)));
+            if *self == Self::Strict {
+                block.push(Insn::jump_to(Ret::Enosys, BPF_JEQ_K, number("clone3", nrs)));
+                // Last, as it replaces the syscall number in the accumulator with the flags
+                block.push(Insn::jump(BPF_JEQ_K, number("clone", nrs), 0, 2));
+                block.push(Insn::stmt(BPF_LD_W_ABS, OFFSET_ARG0_LO));
+                block.push(Insn::jump_to(Ret::Kill, BPF_JSET_K, CLONE_NEW_FLAGS));
+            }
+            block.push(Insn::stmt(BPF_RET_K, SECCOMP_RET_ALLOW));
+
+            program.push(Insn::jump(BPF_JEQ_K, arch, 0, block.len() as u8));
+            program.extend(block);
+        }
+        // Unknown architectures fall through to the kill, so ENOSYS has to come after it
+        program.push(Insn::stmt(BPF_RET_K, SECCOMP_RET_KILL_PROCESS));
+        program.push(Insn::stmt(BPF_RET_K, SECCOMP_RET_ERRNO | ENOSYS));
+
+        // Resolve the jumps to the shared returns now that their positions are known
+        let (kill, enosys) = (program.len() - 2, program.len() - 1);
+        let mut image = Vec::with_capacity(program.len() * 8);
+        for (i, insn) in program.iter().enumerate() {
+            let jt = match insn.target {
+                Some(target) => {
+                    let to = if target == Ret::Kill { kill } else { enosys };
+                    u8::try_from(to - i - 1).expect("seccomp filter too long for BPF jump offsets")
+                }
+                None => insn.jt,
+            };
+            image.extend_from_slice(&insn.code.to_le_bytes());
+            image.push(jt);
+            image.push(insn.jf);
+            image.extend_from_slice(&insn.k.to_le_bytes());
+        }
+        image
+    }
+}
+
+/// Name of a syscall from its number in a kernel audit record, for violation messages
+pub fn syscall_name(audit_arch: u32, nr: u32) -> Option<&'static str> {
+    SYSCALLS.iter()
+        .find(|s| match audit_arch {
+            AUDIT_ARCH_X86_64 => s.1 == Some(nr),
+            AUDIT_ARCH_AARCH64 => s.2 == Some(nr),
+            _ => false,
+        })
+        .map(|s| s.0)
+}
+
+/// Shared return instructions at the end of the program
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Ret {
+    Kill,
+    Enosys,
+}
+
+struct Insn {
+    code: u16,
+    jt: u8,
+    jf: u8,
+    k: u32,
+    // Jump-if-true goes to this return instead of `jt`
+    target: Option<Ret>,
+}
+
+impl Insn {
+    fn stmt(code: u16, k: u32) -> Self {
+        Self { code, jt: 0, jf: 0, k, target: None }
+    }
+
+    fn jump(code: u16, k: u32, jt: u8, jf: u8) -> Self {
+        Self { code, jt, jf, k, target: None }
+    }
+
+    fn jump_to(target: Ret, code: u16, k: u32) -> Self {
+        Self { code, jt: 0, jf: 0, k, target: Some(target) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Outcome of running a compiled filter the way the kernel would
+    fn run(image: &[u8], arch: u32, nr: u32, arg0: u64) -> u32 {
+        let mut data = [0u8; 64];
+        data[0..4].copy_from_slice(&nr.to_le_bytes());
+        data[4..8].copy_from_slice(&arch.to_le_bytes());
+        data[16..24].copy_from_slice(&arg0.to_le_bytes());
+
+        let insns: Vec<(u16, u8, u8, u32)> = image.chunks(8).map(|c| {
+            (u16::from_le_bytes([c[0], c[1]]), c[2], c[3], u32::from_le_bytes([c[4], c[5], c[6], c[7]]))
+        }).collect();
+        let (mut pc, mut acc) = (0usize, 0u32);
+        loop {
+            let (code, jt, jf, k) = insns[pc];
+            let jump = |taken: bool| pc + 1 + if taken { jt as usize } else { jf as usize };
+            pc = match code {
+                BPF_LD_W_ABS => {
+                    acc = u32::from_le_bytes(data[k as usize..k as usize + 4].try_into().unwrap());
+                    pc + 1
+                }
+                BPF_JEQ_K => jump(acc == k),
+                BPF_JGE_K => jump(acc >= k),
+                BPF_JSET_K => jump(acc & k != 0),
+                BPF_RET_K => return k,
+                other => panic!("unexpected BPF instruction {:#x}", other),
+            };
+            assert!(pc < insns.len(), "jump past the end of the filter");
+        }
+    }
+
+    const X86: (u32, fn(&SyscallNumbers) -> Option<u32>) = (AUDIT_ARCH_X86_64, |s| s.1);
+    const ARM: (u32, fn(&SyscallNumbers) -> Option<u32>) = (AUDIT_ARCH_AARCH64, |s| s.2);
+    const KILL: u32 = SECCOMP_RET_KILL_PROCESS;
+    const ALLOW: u32 = SECCOMP_RET_ALLOW;
+    /// What glibc passes for `pthread_create`
+    const THREAD_FLAGS: u64 = 0x003d_0f00;
+    const CLONE_NEWUSER: u64 = 0x1000_0000;
+
+    fn call(image: &[u8], (arch, nrs): (u32, fn(&SyscallNumbers) -> Option<u32>), name: &str, arg0: u64) -> u32 {
+        let nr = SYSCALLS.iter().find(|s| s.0 == name).and_then(nrs).unwrap();
+        run(image, arch, nr, arg0)
+    }
+
+    #[test]
+    fn image_is_whole_instructions_ending_in_returns() {
+        for profile in [SeccompProfile::Default, SeccompProfile::Strict] {
+            let image = profile.compile();
+            assert_eq!(image.len() % 8, 0);
+            let tail: Vec<u32> = image.rchunks(8).take(2).map(|c| u32::from_le_bytes([c[4], c[5], c[6], c[7]])).collect();
+            assert_eq!(tail, [SECCOMP_RET_ERRNO | ENOSYS, KILL]);
+        }
+    }
+
+    #[test]
+    fn default_profile_on_both_architectures() {
+        let image = SeccompProfile::Default.compile();
+        for arch in [X86, ARM] {
+            for name in DEFAULT_DENY.iter().filter(|n| SYSCALLS.iter().any(|s| s.0 == **n && (arch.1)(s).is_some())) {
+                assert_eq!(call(&image, arch, name, 0), KILL, "{}", name);
+            }
+            assert_eq!(call(&image, arch, "mount", 0), ALLOW);
+            assert_eq!(call(&image, arch, "clone", CLONE_NEWUSER), ALLOW);
+            assert_eq!(call(&image, arch, "clone3", 0), ALLOW);
+        }
+        assert_eq!(run(&image, AUDIT_ARCH_X86_64, 1, 0), ALLOW);
+        assert_eq!(run(&image, AUDIT_ARCH_AARCH64, 64, 0), ALLOW);
+    }
+
+    #[test]
+    fn strict_profile_on_both_architectures() {
+        let image = SeccompProfile::Strict.compile();
+        for arch in [X86, ARM] {
+            for name in ["unshare", "setns", "mount", "fsopen", "fsconfig", "fsmount", "fspick", "mount_setattr", "move_mount"] {
+                assert_eq!(call(&image, arch, name, 0), KILL, "{}", name);
+            }
+            assert_eq!(call(&image, arch, "clone3", 0), SECCOMP_RET_ERRNO | ENOSYS);
+            assert_eq!(call(&image, arch, "clone", THREAD_FLAGS), ALLOW);
+            assert_eq!(call(&image, arch, "clone", THREAD_FLAGS | CLONE_NEWUSER), KILL);
+            assert_eq!(call(&image, arch, "clone", 0x4000_0000), KILL);
+        }
+        assert_eq!(run(&image, AUDIT_ARCH_X86_64, 1, 0), ALLOW);
+        assert_eq!(run(&image, AUDIT_ARCH_AARCH64, 64, 0), ALLOW);
+    }
+
+    #[test]
+    fn other_abis_are_killed() {
+        for profile in [SeccompProfile::Default, SeccompProfile::Strict] {
+            let image = profile.compile();
+            assert_eq!(run(&image, 0x4000_0003, 1, 0), KILL);
+            assert_eq!(run(&image, AUDIT_ARCH_X86_64, X32_SYSCALL_BIT | 1, 0), KILL);
+        }
+    }
+}
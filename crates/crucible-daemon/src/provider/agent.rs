@@ -0,0 +1,289 @@
+//! Host side of the guest agent protocol, spoken over a Firecracker vsock. The frames and
+//! messages are the agent's own (`crucible_agent::protocol`); each operation gets its own
+//! connection: a REQUEST frame, then output or data frames, then END or ERROR.
+
+use super::{DirEntry, OutputChunk, OutputSink, OutputStream, SandboxUsage};
+use anyhow::{anyhow, bail, Result};
+use crucible_agent::protocol::{
+    self, ErrorBody, ExecEnd, PutEnd, Request, AGENT_PORT, DATA, END, ERROR, REQUEST, STDERR, STDOUT,
+};
+use serde::Deserialize;
+use std::io;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::UnixStream;
+
+const CHUNK: usize = 64 * 1024;
+
+/// Client for the agent of one microVM, reached through Firecracker's vsock Unix socket
+pub struct AgentClient {
+    uds_path: PathBuf,
+}
+
+impl AgentClient {
+    pub fn new(uds_path: PathBuf) -> Self {
+        Self { uds_path }
+    }
+
+    /// Open a connection to the agent and send `request`
+    async fn open(&self, request: &Request) -> Result<BufReader<UnixStream>> {
+        let mut conn = BufReader::new(UnixStream::connect(&self.uds_path).await?);
+        // Firecracker's host-initiated vsock handshake
+        conn.get_mut().write_all(format!("CONNECT {}\n", AGENT_PORT).as_bytes()).await?;
+        let mut ack = String::new();
+        conn.read_line(&mut ack).await?;
+        if !ack.starts_with("OK ") {
+            bail!("Guest agent unreachable: {:?}", ack.trim());
+        }
+        write_frame(conn.get_mut(), REQUEST, &serde_json::to_vec(request)?).await?;
+        Ok(conn)
+    }
+
+    /// Read frames until END, returning its payload; ERROR becomes an error
+    async fn finish<T: for<'de> Deserialize<'de>>(conn: &mut BufReader<UnixStream>) -> Result<T> {
+        let (tag, payload) = read_frame(conn).await?;
+        match tag {
+            END => Ok(serde_json::from_slice(&payload)?),
+            ERROR => Err(agent_error(&payload)),
+            _ => bail!("Unexpected frame {} from guest agent", tag),
+        }
+    }
+
+    /// Whether the agent is up and answering
+    pub async fn ping(&self) -> Result<()> {
+        let mut conn = self.open(&Request::Ping).await?;
+        Self::finish::<serde_json::Value>(&mut conn).await?;
+        Ok(())
+    }
+
+    /// Run a command to completion, forwarding its output; returns (exit code, timed out)
+    pub async fn exec(
+        &self,
+        exec_id: &str,
+        argv: &[String],
+        env: &[(String, String)],
+        cwd: Option<&str>,
+        timeout: Duration,
+        output: OutputSink,
+    ) -> Result<(i32, bool)> {
+        let request = Request::Exec {
+            exec_id: exec_id.to_string(),
+            argv: argv.to_vec(),
+            env: env.to_vec(),
+            cwd: cwd.map(str::to_string),
+            timeout_ms: timeout.as_millis() as u64,
+        };
+        let mut conn = self.open(&request).await?;
+        loop {
+            let (tag, data) = read_frame(&mut conn).await?;
+            let stream = match tag {
+                STDOUT => OutputStream::Stdout,
+                STDERR => OutputStream::Stderr,
+                END => {
+                    let end: ExecEnd = serde_json::from_slice(&data)?;
+                    return Ok((end.exit_code, end.timed_out));
+                }
+                ERROR => return Err(agent_error(&data)),
+                _ => bail!("Unexpected frame {} from guest agent", tag),
+            };
+            // The receiver may be gone; keep reading so the guest never blocks
+            let _ = output.send(OutputChunk { stream, data, ts: SystemTime::now() }).await;
+        }
+    }
+
+    /// Kill an exec's whole process group; a no-op for unknown execs
+    pub async fn kill(&self, exec_id: &str) -> Result<()> {
+        let mut conn = self.open(&Request::Kill { exec_id: exec_id.to_string() }).await?;
+        Self::finish::<serde_json::Value>(&mut conn).await?;
+        Ok(())
+    }
+
+    pub async fn put_file(&self, path: &str, content: &mut (dyn AsyncRead + Send + Unpin), overwrite: bool) -> Result<u64> {
+        let mut conn = self.open(&Request::PutFile { path: path.to_string(), overwrite }).await?;
+        // Wait for the agent to have the file open, so refusals arrive before any data is sent
+        Self::finish::<serde_json::Value>(&mut conn).await?;
+
+        let mut buf = vec![0u8; CHUNK];
+        loop {
+            let n = content.read(&mut buf).await?;
+            write_frame(conn.get_mut(), DATA, &buf[..n]).await?;
+            if n == 0 {
+                break;
+            }
+        }
+        Ok(Self::finish::<PutEnd>(&mut conn).await?.bytes)
+    }
+
+    pub async fn get_file(&self, path: &str, sink: &mut (dyn AsyncWrite + Send + Unpin)) -> Result<u64> {
+        let mut conn = self.open(&Request::GetFile { path: path.to_string() }).await?;
+        let mut total = 0u64;
+        loop {
+            let (tag, data) = read_frame(&mut conn).await?;
+            match tag {
+                DATA => {
+                    sink.write_all(&data).await?;
+                    total += data.len() as u64;
+                }
+                END => break,
+                ERROR => return Err(agent_error(&data)),
+                _ => bail!("Unexpected frame {} from guest agent", tag),
+            }
+        }
+        sink.flush().await?;
+        Ok(total)
+    }
+
+    pub async fn list_dir(&self, path: &str) -> Result<Vec<DirEntry>> {
+        let mut conn = self.open(&Request::ListDir { path: path.to_string() }).await?;
+        let entries: Vec<protocol::DirEntry> = Self::finish(&mut conn).await?;
+        Ok(entries.into_iter().map(|e| DirEntry {
+            name: e.name,
+            is_dir: e.is_dir,
+            size_bytes: e.size_bytes,
+            modified_at: UNIX_EPOCH + Duration::from_secs_f64(e.modified_unix.max(0.0)),
+        }).collect())
+    }
+
+    pub async fn usage(&self) -> Result<SandboxUsage> {
+        let mut conn = self.open(&Request::Usage).await?;
+        let usage: protocol::Usage = Self::finish(&mut conn).await?;
+        Ok(SandboxUsage {
+            cpu_percent: usage.cpu_percent,
+            memory_mb: usage.memory_mb,
+            disk_mb: usage.disk_mb,
+            ..Default::default()
+        })
+    }
+}
+
+/// Agent errors keep their kind as `io::ErrorKind` so the gRPC layer can map them
+fn agent_error(payload: &[u8]) -> anyhow::Error {
+    let Ok(body) = serde_json::from_slice::<ErrorBody>(payload) else {
+        return anyhow!("Guest agent error: {}", String::from_utf8_lossy(payload));
+    };
+    let kind = match body.kind.as_str() {
+        "not_found" => io::ErrorKind::NotFound,
+        "already_exists" => io::ErrorKind::AlreadyExists,
+        "invalid" => io::ErrorKind::InvalidInput,
+        _ => io::ErrorKind::Other,
+    };
+    io::Error::new(kind, body.message).into()
+}
+
+async fn write_frame(w: &mut (impl AsyncWrite + Unpin), tag: u8, payload: &[u8]) -> Result<()> {
+    w.write_all(&protocol::frame_header(tag, payload.len())).await?;
+    w.write_all(payload).await?;
+    w.flush().await?;
+    Ok(())
+}
+
+async fn read_frame(r: &mut (impl AsyncRead + Unpin)) -> Result<(u8, Vec<u8>)> {
+    let mut header = [0u8; 5];
+    r.read_exact(&mut header).await?;
+    let (tag, len) = protocol::parse_header(header)?;
+    let mut payload = vec![0u8; len];
+    r.read_exact(&mut payload).await?;
+    Ok((tag, payload))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    /// A guest agent that answers one operation with `frames`, handing back its request
+    fn agent_answering(dir: &TempDir, frames: Vec<(u8, Vec<u8>)>) -> (AgentClient, tokio::task::JoinHandle<Request>) {
+        let socket = dir.join("vsock.sock");
+        let listener = tokio::net::UnixListener::bind(&socket).unwrap();
+        let agent = tokio::spawn(async move {
+            let (conn, _) = listener.accept().await.unwrap();
+            let mut conn = BufReader::new(conn);
+            let mut connect = String::new();
+            conn.read_line(&mut connect).await.unwrap();
+            assert_eq!(connect, format!("CONNECT {}\n", AGENT_PORT));
+            conn.get_mut().write_all(b"OK 1073741824\n").await.unwrap();
+
+            let (tag, payload) = read_frame(&mut conn).await.unwrap();
+            assert_eq!(tag, REQUEST);
+            for (tag, payload) in frames {
+                write_frame(conn.get_mut(), tag, &payload).await.unwrap();
+            }
+            // Whatever the client sends after the request, until it hangs up
+            tokio::io::copy(&mut conn, &mut tokio::io::sink()).await.unwrap();
+            serde_json::from_slice(&payload).unwrap()
+        });
+        (AgentClient::new(socket), agent)
+    }
+
+    fn json(value: &impl serde::Serialize) -> Vec<u8> {
+        serde_json::to_vec(value).unwrap()
+    }
+
+    #[tokio::test]
+    async fn frames_match_the_agents_encoding() {
+        let mut ours = Vec::new();
+        write_frame(&mut ours, STDOUT, b"hello").await.unwrap();
+        let mut theirs = Vec::new();
+        protocol::write_frame(&mut theirs, STDOUT, b"hello").unwrap();
+        assert_eq!(ours, theirs);
+        assert_eq!(ours[..5], [STDOUT, 0, 0, 0, 5]);
+
+        assert_eq!(protocol::read_frame(&mut ours.as_slice()).unwrap(), (STDOUT, b"hello".to_vec()));
+        assert_eq!(read_frame(&mut theirs.as_slice()).await.unwrap(), (STDOUT, b"hello".to_vec()));
+
+        let oversized = protocol::frame_header(DATA, protocol::MAX_FRAME + 1);
+        assert!(read_frame(&mut oversized.as_slice()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn exec_forwards_output_until_end() {
+        let dir = TempDir::new("agent-exec");
+        let (client, agent) = agent_answering(&dir, vec![
+            (STDOUT, b"out".to_vec()),
+            (STDERR, b"err".to_vec()),
+            (END, json(&ExecEnd { exit_code: 3, timed_out: false })),
+        ]);
+        let (sink, mut chunks) = tokio::sync::mpsc::channel(8);
+        let argv = vec!["sh".to_string(), "-c".to_string(), "exit 3".to_string()];
+        let env = vec![("A".to_string(), "1".to_string())];
+        let result = client.exec("exec-1", &argv, &env, Some("/work"), Duration::from_secs(5), sink).await.unwrap();
+        assert_eq!(result, (3, false));
+
+        assert_eq!(agent.await.unwrap(), Request::Exec {
+            exec_id: "exec-1".to_string(),
+            argv,
+            env,
+            cwd: Some("/work".to_string()),
+            timeout_ms: 5000,
+        });
+        let first = chunks.recv().await.unwrap();
+        assert!(first.stream == OutputStream::Stdout && first.data == b"out");
+        let second = chunks.recv().await.unwrap();
+        assert!(second.stream == OutputStream::Stderr && second.data == b"err");
+    }
+
+    #[tokio::test]
+    async fn agent_errors_keep_their_kind() {
+        let dir = TempDir::new("agent-error");
+        let missing = io::Error::new(io::ErrorKind::NotFound, "no such file");
+        let (client, agent) = agent_answering(&dir, vec![(ERROR, json(&ErrorBody::from_io(&missing)))]);
+        let Err(err) = client.list_dir("/nope").await else { panic!("list_dir succeeded") };
+        assert_eq!(err.downcast_ref::<io::Error>().unwrap().kind(), io::ErrorKind::NotFound);
+        assert_eq!(agent.await.unwrap(), Request::ListDir { path: "/nope".to_string() });
+
+        assert!(agent_error(b"not json").to_string().contains("not json"));
+    }
+
+    #[tokio::test]
+    async fn put_file_streams_data_and_reads_the_count() {
+        let dir = TempDir::new("agent-put");
+        let (client, agent) = agent_answering(&dir, vec![
+            (END, b"{}".to_vec()),
+            (END, json(&PutEnd { bytes: 11 })),
+        ]);
+        let written = client.put_file("/work/f", &mut &b"hello world"[..], false).await.unwrap();
+        assert_eq!(written, 11);
+        assert_eq!(agent.await.unwrap(), Request::PutFile { path: "/work/f".to_string(), overwrite: false });
+    }
+}
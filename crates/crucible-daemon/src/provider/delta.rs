@@ -0,0 +1,141 @@
+//! Block deltas for snapshot files. A delta is a sparse file as long as the file it stands for,
+//! holding data only where that file differs from its base and holes everywhere else, which is
+//! also the shape of Firecracker's diff memory files. Applying one copies its data extents over
+//! a copy of the base.
+//!
+//! Holes are found with `SEEK_DATA`/`SEEK_HOLE`, so deltas must live on a filesystem that keeps
+//! files sparse and reports holes (ext4, xfs, btrfs, tmpfs).
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read};
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+
+/// Granularity of disk deltas; the ext4 block size and the guest page size
+pub const BLOCK: usize = 4096;
+
+const CHUNK: usize = 256 * BLOCK;
+
+/// Write the blocks of `current` that differ from `base` into a new sparse file at `out`.
+/// Returns the number of bytes written.
+pub fn write_block_diff(current: &Path, base: &Path, out: &Path) -> io::Result<u64> {
+    let mut current = File::open(current)?;
+    let mut base = File::open(base)?;
+    let out = OpenOptions::new().write(true).create_new(true).open(out)?;
+    out.set_len(current.metadata()?.len())?;
+
+    let mut cur_buf = vec![0u8; CHUNK];
+    let mut base_buf = vec![0u8; CHUNK];
+    let mut offset = 0u64;
+    let mut written = 0u64;
+    loop {
+        let n = read_full(&mut current, &mut cur_buf)?;
+        if n == 0 {
+            break;
+        }
+        // Past the end of the base everything counts as changed
+        let m = read_full(&mut base, &mut base_buf[..n])?;
+        base_buf[m..n].fill(0);
+        for start in (0..n).step_by(BLOCK) {
+            let end = (start + BLOCK).min(n);
+            if start >= m || cur_buf[start..end] != base_buf[start..end] {
+                out.write_all_at(&cur_buf[start..end], offset + start as u64)?;
+                written += (end - start) as u64;
+            }
+        }
+        offset += n as u64;
+    }
+    out.sync_all()?;
+    Ok(written)
+}
+
+/// Copy the data extents of the delta at `diff` over `target`, growing it to the delta's length
+pub fn apply_diff(diff: &Path, target: &Path) -> io::Result<()> {
+    let diff = File::open(diff)?;
+    let target = OpenOptions::new().write(true).open(target)?;
+    let len = diff.metadata()?.len();
+    if target.metadata()?.len() < len {
+        target.set_len(len)?;
+    }
+
+    let mut buf = vec![0u8; CHUNK];
+    for (start, end) in data_extents(&diff)? {
+        let mut at = start;
+        while at < end {
+            let want = ((end - at) as usize).min(CHUNK);
+            diff.read_exact_at(&mut buf[..want], at)?;
+            target.write_all_at(&buf[..want], at)?;
+            at += want as u64;
+        }
+    }
+    target.sync_all()
+}
+
+/// The (start, end) byte ranges of a file that hold data, in order
+pub fn data_extents(file: &File) -> io::Result<Vec<(u64, u64)>> {
+    let len = file.metadata()?.len();
+    let mut extents = Vec::new();
+    let mut pos = 0u64;
+    while pos < len {
+        let Some(data) = seek(file, pos, libc::SEEK_DATA)? else {
+            break;
+        };
+        let hole = seek(file, data, libc::SEEK_HOLE)?.unwrap_or(len).min(len);
+        extents.push((data, hole));
+        pos = hole;
+    }
+    Ok(extents)
+}
+
+/// `lseek` with SEEK_DATA/SEEK_HOLE; None when there is no more data past `pos`
+fn seek(file: &File, pos: u64, whence: libc::c_int) -> io::Result<Option<u64>> {
+    // SAFETY: lseek on a descriptor we own; it doesn't touch memory
+    let at = unsafe { libc::lseek(file.as_raw_fd(), pos as libc::off_t, whence) };
+    if at < 0 {
+        let err = io::Error::last_os_error();
+        return match err.raw_os_error() {
+            Some(libc::ENXIO) => Ok(None),
+            _ => Err(err),
+        };
+    }
+    Ok(Some(at as u64))
+}
+
+/// Read until `buf` is full or the file ends
+fn read_full(file: &mut File, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match file.read(&mut buf[filled..])? {
+            0 => break,
+            n => filled += n,
+        }
+    }
+    Ok(filled)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn diff_applied_to_base_gives_current() {
+        let dir = TempDir::new("delta");
+        let (base, current, diff, rebuilt) = (dir.join("base"), dir.join("current"), dir.join("diff"), dir.join("rebuilt"));
+
+        let old: Vec<u8> = (0..BLOCK * 40).map(|i| (i % 251) as u8).collect();
+        let mut new = old.clone();
+        new[BLOCK * 3 + 7] ^= 0xff;
+        new[BLOCK * 30..BLOCK * 32].fill(0);
+        new.extend_from_slice(&[9u8; 100]);
+        std::fs::write(&base, &old).unwrap();
+        std::fs::write(&current, &new).unwrap();
+
+        let written = write_block_diff(&current, &base, &diff).unwrap();
+        assert_eq!(written, (BLOCK * 3 + 100) as u64);
+        std::fs::copy(&base, &rebuilt).unwrap();
+        apply_diff(&diff, &rebuilt).unwrap();
+        assert!(std::fs::read(&rebuilt).unwrap() == new);
+    }
+}
@@ -0,0 +1,1052 @@
+//! One Firecracker microVM per sandbox, driven through the VMM's API socket.
+//!
+//! Each sandbox gets a directory under `state_dir` holding its own copy of the base image
+//! (`rootfs.ext4`, grown to `disk_mb`), the API and vsock sockets and the VMM log. vCPUs and
+//! memory come from the sandbox's `ResourceLimits`. Commands and file transfers go through the
+//! guest agent (`crates/crucible-agent`) over vsock; the VM itself is the isolation boundary.
+//!
+//! Networking: a sandbox with open egress gets a tap device attached to `bridge`; addressing is
+//! up to the image (e.g. DHCP on the bridge). Sandboxes that deny egress get no NIC at all, and
+//! so do allowlisted ones, since the allowlist can't be enforced here yet.
+//!
+//! Snapshots pause the guest, have Firecracker write its memory and device state and, in FULL
+//! mode, copy the disk before resuming. Restores boot a new sandbox straight from those files.
+//! When the sandbox's last snapshot (or the one it was restored from) is among the new one's
+//! ancestors, only the memory pages dirtied since and the changed disk blocks are stored; see
+//! `provider::delta`. Restores rebuild whole files from the chain of deltas.
+//! A MEMORY_ONLY snapshot has no disk of its own: its restores copy the source sandbox's disk as
+//! it is at that point, so they only work while the source exists.
+
+use crate::config::FirecrackerConfig;
+use crate::provider::agent::AgentClient;
+use crate::provider::{
+    DirEntry, ExecId, ExecResult, ExecSpec, NetworkPolicy, OutputSink, ProviderHealth, ResourceLimits, SandboxId,
+    SandboxPolicy, SandboxProvider, SandboxSpec, SandboxUsage, SnapshotComponent, SnapshotId, SnapshotMeta, SnapshotMode,
+    SnapshotRef, sandbox_root,
+};
+use crate::provider::delta;
+use crate::policy::seccomp::SeccompProfile;
+use anyhow::{anyhow, bail, Context, Result};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::RwLock;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::UnixStream;
+use tokio::process::Command;
+
+// Per-sandbox files, relative to the sandbox directory
+const ROOTFS: &str = "rootfs.ext4";
+const API_SOCKET: &str = "api.sock";
+const VSOCK_SOCKET: &str = "vsock.sock";
+/// Which snapshots the sandbox's memory and disk can be stored as deltas against
+const LINEAGE: &str = "lineage.json";
+/// The disk as of `Lineage::disk_base`, which disk deltas are taken against
+const BASE_DISK: &str = "base.ext4";
+/// The disk as captured by a snapshot in progress
+const CAPTURED_DISK: &str = "captured.ext4";
+/// Memory rebuilt from a chain of diff snapshots, backing a restored VM
+const RESTORED_MEMORY: &str = "memory";
+// Snapshot files, written into the snapshot store's directory
+const SNAPSHOT_VMSTATE: &str = "vmstate";
+const SNAPSHOT_MEMORY: &str = "memory";
+const SNAPSHOT_ROOTFS: &str = "rootfs.ext4";
+const SNAPSHOT_ROOTFS_DIFF: &str = "rootfs.diff";
+const SNAPSHOT_INFO: &str = "firecracker.json";
+
+/// Guest context id of every VM; each VM has its own vsock device, so they never clash
+const GUEST_CID: u32 = 3;
+/// How long a booted guest gets to bring up its agent
+const AGENT_BOOT_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long a graceful stop waits for the guest to shut down before killing the VMM
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long a freshly spawned VMM gets to open its API socket
+const API_SOCKET_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub struct FirecrackerProvider {
+    config: FirecrackerConfig,
+    // Specs of known sandboxes; a stopped VM is reconfigured from them on start
+    specs: RwLock<HashMap<SandboxId, SandboxSpec>>,
+}
+
+impl FirecrackerProvider {
+    pub fn new(config: FirecrackerConfig) -> Self {
+        Self { config, specs: RwLock::new(HashMap::new()) }
+    }
+
+    fn sandbox_dir(&self, id: &SandboxId) -> PathBuf {
+        self.config.state_dir.join(id)
+    }
+
+    fn agent(&self, id: &SandboxId) -> AgentClient {
+        AgentClient::new(self.sandbox_dir(id).join(VSOCK_SOCKET))
+    }
+
+    fn api(&self, id: &SandboxId) -> ApiClient {
+        ApiClient { socket: self.sandbox_dir(id).join(API_SOCKET) }
+    }
+
+    fn spec(&self, id: &SandboxId) -> Result<SandboxSpec> {
+        self.specs.read().unwrap().get(id).cloned().ok_or_else(|| anyhow!("Unknown sandbox {}", id))
+    }
+
+    async fn lineage(&self, id: &SandboxId) -> Lineage {
+        let raw = tokio::fs::read(self.sandbox_dir(id).join(LINEAGE)).await.unwrap_or_default();
+        serde_json::from_slice(&raw).unwrap_or_default()
+    }
+
+    async fn set_lineage(&self, id: &SandboxId, lineage: &Lineage) -> Result<()> {
+        tokio::fs::write(self.sandbox_dir(id).join(LINEAGE), serde_json::to_vec(lineage)?).await?;
+        Ok(())
+    }
+
+    /// Base image file for a `base_image` such as "python:3.12"
+    fn image_path(&self, base_image: &str) -> PathBuf {
+        let name = if base_image.is_empty() { "default" } else { base_image };
+        let file: String = name
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() || c == '.' || c == '_' { c } else { '-' })
+            .collect();
+        self.config.image_dir.join(format!("{}.ext4", file))
+    }
+
+    /// Copy the base image into the sandbox directory and grow it to the disk limit
+    async fn prepare_rootfs(&self, id: &SandboxId, spec: &SandboxSpec) -> Result<()> {
+        let image = self.image_path(&spec.base_image);
+        if !image.exists() {
+            bail!("Base image {:?} not found at {}", spec.base_image, image.display());
+        }
+        let rootfs = self.sandbox_dir(id).join(ROOTFS);
+        copy_disk(&image, &rootfs).await?;
+
+        let wanted = spec.limits.disk_mb * 1024 * 1024;
+        let current = tokio::fs::metadata(&rootfs).await?.len();
+        if wanted > current {
+            tokio::fs::OpenOptions::new().write(true).open(&rootfs).await?.set_len(wanted).await?;
+            run_host("resize2fs", &["-f", &rootfs.display().to_string()]).await?;
+        }
+        Ok(())
+    }
+
+    /// Create the sandbox's tap device, if its egress policy gives it a NIC
+    async fn create_tap(&self, id: &SandboxId, spec: &SandboxSpec) -> Result<Option<String>> {
+        let network = &spec.policy.network;
+        if network.deny_all {
+            return Ok(None);
+        }
+        if !network.allow_domains.is_empty() || !network.allow_cidrs.is_empty() {
+            // No filtering on this path yet; fail closed
+            println!("Provider Warning: egress allowlists are not enforced by the Firecracker provider, sandbox {} gets no network", id);
+            return Ok(None);
+        }
+
+        let tap = tap_name(id);
+        if !Path::new("/sys/class/net").join(&tap).exists() {
+            run_host("ip", &["tuntap", "add", "dev", &tap, "mode", "tap"]).await?;
+        }
+        if !self.config.bridge.is_empty() {
+            run_host("ip", &["link", "set", "dev", &tap, "master", &self.config.bridge]).await?;
+        }
+        run_host("ip", &["link", "set", "dev", &tap, "up"]).await?;
+        Ok(Some(tap))
+    }
+
+    /// Start an unconfigured VMM for the sandbox and wait for its API socket
+    async fn launch_vmm(&self, id: &SandboxId) -> Result<()> {
+        let dir = self.sandbox_dir(id);
+        let api = self.api(id);
+        // Sockets of an earlier VMM would make Firecracker refuse to start
+        for stale in [API_SOCKET, VSOCK_SOCKET] {
+            let _ = tokio::fs::remove_file(dir.join(stale)).await;
+        }
+
+        let log = std::fs::OpenOptions::new().create(true).append(true).open(dir.join("firecracker.log"))?;
+        let child = Command::new(&self.config.binary)
+            .arg("--api-sock").arg(&api.socket)
+            .arg("--id").arg(id)
+            // Guest-visible paths are relative to the sandbox directory, so a snapshot restored
+            // into another sandbox opens that sandbox's disk and vsock rather than the source's
+            .current_dir(&dir)
+            .stdin(Stdio::null())
+            .stdout(log.try_clone()?)
+            .stderr(log)
+            // Its own process group, so signals aimed at the daemon don't take VMs down with it
+            .process_group(0)
+            .spawn()
+            .with_context(|| format!("Failed to start {}", self.config.binary.display()))?;
+        let pid = child.id().ok_or_else(|| anyhow!("Firecracker exited immediately"))?;
+        tokio::fs::write(dir.join("firecracker.pid"), pid.to_string()).await?;
+
+        let deadline = tokio::time::Instant::now() + API_SOCKET_TIMEOUT;
+        while UnixStream::connect(&api.socket).await.is_err() {
+            if tokio::time::Instant::now() >= deadline {
+                bail!("Firecracker API socket for sandbox {} did not come up", id);
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        Ok(())
+    }
+
+    /// Start a VMM for the sandbox and configure its machine, drives, NIC and vsock. The guest
+    /// is not booted yet.
+    async fn spawn_vmm(&self, id: &SandboxId, spec: &SandboxSpec, tap: Option<&str>) -> Result<()> {
+        self.launch_vmm(id).await?;
+        let api = self.api(id);
+        let limits = &spec.limits;
+        let defaults = ResourceLimits::default();
+        api.put("/boot-source", json!({
+            "kernel_image_path": self.config.kernel_image,
+            "boot_args": self.config.boot_args,
+        })).await?;
+        api.put("/machine-config", json!({
+            "vcpu_count": if limits.vcpu > 0 { limits.vcpu } else { defaults.vcpu },
+            "mem_size_mib": if limits.memory_mb > 0 { limits.memory_mb } else { defaults.memory_mb },
+            "track_dirty_pages": true,
+        })).await?;
+        // Freshly booted memory has nothing in common with any snapshot
+        let lineage = Lineage { memory_base: None, ..self.lineage(id).await };
+        self.set_lineage(id, &lineage).await?;
+        api.put("/drives/rootfs", json!({
+            "drive_id": "rootfs",
+            "path_on_host": ROOTFS,
+            "is_root_device": true,
+            "is_read_only": false,
+        })).await?;
+        if let Some(tap) = tap {
+            api.put("/network-interfaces/eth0", json!({
+                "iface_id": "eth0",
+                "host_dev_name": tap,
+                "guest_mac": guest_mac(id),
+            })).await?;
+        }
+        api.put("/vsock", json!({
+            "guest_cid": GUEST_CID,
+            "uds_path": VSOCK_SOCKET,
+        })).await?;
+        Ok(())
+    }
+
+    /// Boot a configured VM and wait until its agent answers
+    async fn boot(&self, id: &SandboxId, spec: &SandboxSpec) -> Result<()> {
+        self.api(id).put("/actions", json!({ "action_type": "InstanceStart" })).await?;
+        self.wait_for_agent(id).await?;
+
+        let working_dir = working_dir(spec).display().to_string();
+        let argv = ["mkdir".to_string(), "-p".to_string(), working_dir];
+        let (sink, _) = tokio::sync::mpsc::channel(1);
+        let (code, _) = self.agent(id).exec("setup", &argv, &[], None, Duration::from_secs(30), sink).await?;
+        if code != 0 {
+            bail!("Failed to create the working directory of sandbox {}", id);
+        }
+        Ok(())
+    }
+
+    async fn wait_for_agent(&self, id: &SandboxId) -> Result<()> {
+        let agent = self.agent(id);
+        let deadline = tokio::time::Instant::now() + AGENT_BOOT_TIMEOUT;
+        loop {
+            match agent.ping().await {
+                Ok(()) => return Ok(()),
+                Err(e) if tokio::time::Instant::now() >= deadline => {
+                    return Err(e.context(format!("Guest agent of sandbox {} did not come up", id)));
+                }
+                Err(_) => tokio::time::sleep(Duration::from_millis(100)).await,
+            }
+        }
+    }
+
+    /// Pid of the sandbox's VMM, if it is still running
+    async fn vmm_pid(&self, id: &SandboxId) -> Option<u32> {
+        let raw = tokio::fs::read_to_string(self.sandbox_dir(id).join("firecracker.pid")).await.ok()?;
+        let pid: u32 = raw.trim().parse().ok()?;
+        process_alive(pid).then_some(pid)
+    }
+
+    /// Shut the VM down, gracefully unless `force`. A no-op if it isn't running.
+    async fn shutdown(&self, id: &SandboxId, force: bool) -> Result<()> {
+        let Some(pid) = self.vmm_pid(id).await else {
+            return Ok(());
+        };
+        // With `reboot=k` the guest's reboot makes Firecracker exit
+        if !force && self.api(id).put("/actions", json!({ "action_type": "SendCtrlAltDel" })).await.is_ok() {
+            let deadline = tokio::time::Instant::now() + SHUTDOWN_TIMEOUT;
+            while process_alive(pid) && tokio::time::Instant::now() < deadline {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        }
+        if process_alive(pid) {
+            run_host("kill", &["-KILL", &pid.to_string()]).await?;
+            while process_alive(pid) {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        }
+        let _ = tokio::fs::remove_file(self.sandbox_dir(id).join("firecracker.pid")).await;
+        Ok(())
+    }
+
+    /// Guest path for a client-supplied one; relative paths are taken from the working directory
+    fn resolve_guest_path(&self, id: &SandboxId, guest_path: &Path) -> Result<String> {
+        let path = if guest_path.is_absolute() {
+            guest_path.to_path_buf()
+        } else {
+            working_dir(&self.spec(id)?).join(guest_path)
+        };
+        path.to_str().map(str::to_string).ok_or_else(|| anyhow!("Path is not valid UTF-8: {}", guest_path.display()))
+    }
+}
+
+#[async_trait]
+impl SandboxProvider for FirecrackerProvider {
+    fn provider_name(&self) -> &'static str {
+        "local_firecracker"
+    }
+
+    async fn probe(&self) -> Result<ProviderHealth> {
+        // KVM must be usable by the daemon's user, not merely present
+        let kvm = std::fs::OpenOptions::new().read(true).write(true).open("/dev/kvm").is_ok();
+        let version = match Command::new(&self.config.binary).arg("--version").output().await {
+            Ok(output) if output.status.success() => {
+                String::from_utf8_lossy(&output.stdout).lines().next().map(|l| l.trim().to_string())
+            }
+            _ => None,
+        };
+        if !kvm {
+            println!("Provider Warning: /dev/kvm is not available, Firecracker can't run");
+        }
+
+        Ok(ProviderHealth {
+            healthy: kvm && version.is_some() && self.config.kernel_image.exists() && self.config.image_dir.is_dir(),
+            version,
+            snapshot_capable: true,
+            gpu_capable: false,
+        })
+    }
+
+    // --- Lifecycle ---
+    async fn list_sandboxes(&self) -> Result<Vec<SandboxId>> {
+        // Sandbox directories are the ground truth; `specs` is lost when the daemon restarts
+        let mut entries = match tokio::fs::read_dir(&self.config.state_dir).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(e.into()),
+        };
+        let mut ids = Vec::new();
+        while let Some(entry) = entries.next_entry().await? {
+            if entry.path().join(ROOTFS).exists() {
+                ids.push(entry.file_name().to_string_lossy().to_string());
+            }
+        }
+        Ok(ids)
+    }
+
+    async fn create_sandbox(&self, id: &SandboxId, spec: SandboxSpec) -> Result<()> {
+        if !spec.policy.mounts.is_empty() {
+            bail!("Host mounts are not supported by the Firecracker provider");
+        }
+        if spec.policy.enable_gpu {
+            println!("Provider Warning: GPU acceleration requested but Firecracker has no GPU passthrough.");
+        }
+
+        tokio::fs::create_dir_all(self.sandbox_dir(id)).await?;
+        let created = async {
+            self.prepare_rootfs(id, &spec).await?;
+            let tap = self.create_tap(id, &spec).await?;
+            self.spawn_vmm(id, &spec, tap.as_deref()).await?;
+            self.boot(id, &spec).await
+        }.await;
+        if let Err(e) = created {
+            // Don't leave a half-built VM behind
+            let _ = self.destroy_sandbox(id, true).await;
+            return Err(e);
+        }
+
+        self.specs.write().unwrap().insert(id.clone(), spec);
+        Ok(())
+    }
+
+    async fn adopt_sandbox(&self, id: &SandboxId, spec: SandboxSpec) -> Result<bool> {
+        // Nothing is booted here: a VM that didn't outlive the daemon waits for StartSandbox
+        self.specs.write().unwrap().insert(id.clone(), spec);
+        if self.vmm_pid(id).await.is_none() {
+            return Ok(false);
+        }
+        let info = self.api(id).get("/").await
+            .with_context(|| format!("Firecracker of sandbox {} no longer answers", id))?;
+        Ok(info["state"].as_str() == Some("Running"))
+    }
+
+    async fn start_sandbox(&self, id: &SandboxId) -> Result<()> {
+        let spec = self.spec(id)?;
+        if self.vmm_pid(id).await.is_none() {
+            let tap = self.create_tap(id, &spec).await?;
+            self.spawn_vmm(id, &spec, tap.as_deref()).await?;
+        }
+
+        let info = self.api(id).get("/").await?;
+        match info["state"].as_str() {
+            Some("Not started") => self.boot(id, &spec).await,
+            Some("Paused") => {
+                self.api(id).patch("/vm", json!({ "state": "Resumed" })).await?;
+                self.wait_for_agent(id).await
+            }
+            _ => Ok(()),
+        }
+    }
+
+    async fn stop_sandbox(&self, id: &SandboxId, force: bool) -> Result<()> {
+        self.shutdown(id, force).await
+    }
+
+    async fn destroy_sandbox(&self, id: &SandboxId, _force: bool) -> Result<()> {
+        // The disk is discarded anyway, so there is nothing to shut down cleanly for
+        self.shutdown(id, true).await?;
+        let tap = tap_name(id);
+        if Path::new("/sys/class/net").join(&tap).exists() {
+            run_host("ip", &["link", "del", "dev", &tap]).await?;
+        }
+        match tokio::fs::remove_dir_all(self.sandbox_dir(id)).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+        self.specs.write().unwrap().remove(id);
+        Ok(())
+    }
+
+    async fn usage(&self, id: &SandboxId) -> Result<SandboxUsage> {
+        self.agent(id).usage().await
+    }
+
+    // --- Execution ---
+    async fn exec_stream(&self, id: &SandboxId, spec: ExecSpec, output: OutputSink) -> Result<ExecResult> {
+        let root = working_dir(&self.spec(id)?);
+        let cwd = match &spec.cwd {
+            Some(cwd) => root.join(cwd),
+            None => root,
+        };
+        let cwd = cwd.display().to_string();
+
+        // The agent has its own, later deadline in case the host never gets to kill the exec
+        let agent = self.agent(id);
+        let run = agent.exec(&spec.exec_id, &spec.argv, &spec.env, Some(&cwd), spec.timeout, output);
+        let (exit_code, timed_out) = match tokio::time::timeout(spec.timeout, run).await {
+            Ok(result) => result?,
+            Err(_) => {
+                if let Err(e) = agent.kill(&spec.exec_id).await {
+                    println!("Provider Warning: failed to kill timed out exec {}: {}", spec.exec_id, e);
+                }
+                (-1, true)
+            }
+        };
+
+        Ok(ExecResult {
+            exec_id: spec.exec_id,
+            exit_code,
+            timed_out,
+            violations: vec![],
+        })
+    }
+
+    async fn cancel_exec(&self, id: &SandboxId, exec_id: &ExecId) -> Result<()> {
+        // Killing the process group ends the agent's exec, which unblocks `exec_stream`
+        self.agent(id).kill(exec_id).await
+    }
+
+    // --- Snapshot ---
+    async fn create_snapshot(
+        &self,
+        id: &SandboxId,
+        dst_path: &std::path::Path,
+        mode: SnapshotMode,
+        ancestors: &[SnapshotId],
+    ) -> Result<SnapshotMeta> {
+        let spec = self.spec(id)?;
+        if self.vmm_pid(id).await.is_none() {
+            bail!("Sandbox {} is not running", id);
+        }
+        // The store names snapshot directories after the snapshot
+        let snapshot_id = dst_path.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+        let dir = self.sandbox_dir(id);
+
+        // Deltas are only worth storing against a snapshot restores will get back
+        let mut lineage = self.lineage(id).await;
+        let known = |base: &Option<SnapshotId>| {
+            base.clone().filter(|base| ancestors.contains(base))
+        };
+        let memory_delta_of = known(&lineage.memory_base);
+        let disk_delta_of = known(&lineage.disk_base).filter(|_| dir.join(BASE_DISK).exists());
+        // Creating a snapshot resets dirty page tracking, so until this one succeeds memory has
+        // no base
+        lineage.memory_base = None;
+        self.set_lineage(id, &lineage).await?;
+
+        // Everything is captured while the guest is paused, so memory and disk agree
+        let api = self.api(id);
+        let mut disk_version = None;
+        api.patch("/vm", json!({ "state": "Paused" })).await?;
+        let captured = async {
+            api.put("/snapshot/create", json!({
+                "snapshot_type": if memory_delta_of.is_some() { "Diff" } else { "Full" },
+                "snapshot_path": dst_path.join(SNAPSHOT_VMSTATE),
+                "mem_file_path": dst_path.join(SNAPSHOT_MEMORY),
+            })).await?;
+            match mode {
+                SnapshotMode::Full => copy_disk(&dir.join(ROOTFS), &dir.join(CAPTURED_DISK)).await?,
+                SnapshotMode::MemoryOnly => disk_version = Some(disk_version_of(&dir.join(ROOTFS)).await?),
+            }
+            Ok::<_, anyhow::Error>(())
+        }.await;
+        let resumed = api.patch("/vm", json!({ "state": "Resumed" })).await;
+        captured?;
+        resumed?;
+
+        // The disk is diffed against the last captured one once the guest runs again
+        if mode == SnapshotMode::Full {
+            let captured = dir.join(CAPTURED_DISK);
+            if disk_delta_of.is_some() {
+                let (current, base, out) = (captured.clone(), dir.join(BASE_DISK), dst_path.join(SNAPSHOT_ROOTFS_DIFF));
+                tokio::task::spawn_blocking(move || delta::write_block_diff(&current, &base, &out)).await??;
+            } else {
+                copy_disk(&captured, &dst_path.join(SNAPSHOT_ROOTFS)).await?;
+            }
+            tokio::fs::rename(&captured, dir.join(BASE_DISK)).await?;
+            lineage.disk_base = Some(snapshot_id.clone());
+        }
+        lineage.memory_base = Some(snapshot_id.clone());
+        self.set_lineage(id, &lineage).await?;
+
+        let network = Path::new("/sys/class/net").join(tap_name(id)).exists();
+        let mut info = SnapshotInfo::new(id, mode, &spec, network);
+        info.memory_delta_of = memory_delta_of.clone();
+        info.disk_delta_of = disk_delta_of.clone();
+        info.disk_version = disk_version;
+        tokio::fs::write(dst_path.join(SNAPSHOT_INFO), serde_json::to_vec_pretty(&info)?).await?;
+
+        let mut components = vec![
+            SnapshotComponent {
+                name: "memory".to_string(),
+                size_bytes: stored_size(&dst_path.join(SNAPSHOT_MEMORY)).await?,
+                delta_of: memory_delta_of,
+            },
+            SnapshotComponent {
+                name: "vmstate".to_string(),
+                size_bytes: stored_size(&dst_path.join(SNAPSHOT_VMSTATE)).await?,
+                delta_of: None,
+            },
+        ];
+        if mode == SnapshotMode::Full {
+            let file = if disk_delta_of.is_some() { SNAPSHOT_ROOTFS_DIFF } else { SNAPSHOT_ROOTFS };
+            components.push(SnapshotComponent {
+                name: "disk".to_string(),
+                size_bytes: stored_size(&dst_path.join(file)).await?,
+                delta_of: disk_delta_of,
+            });
+        }
+        let size_bytes = components.iter().map(|c| c.size_bytes).sum();
+        Ok(SnapshotMeta { snapshot_id, sandbox_id: id.clone(), size_bytes, components })
+    }
+
+    async fn restore_snapshot(
+        &self,
+        snapshot_id: &SnapshotId,
+        new_sandbox_id: &SandboxId,
+        snapshot_dir: &std::path::Path,
+        ancestors: &[SnapshotRef],
+    ) -> Result<()> {
+        let info = read_info(snapshot_dir).await
+            .with_context(|| format!("Snapshot {} was not taken by the Firecracker provider", snapshot_id))?;
+        let memory_only = info.mode == "memory_only";
+        if memory_only && !self.sandbox_dir(&info.sandbox_id).join(ROOTFS).exists() {
+            bail!("Disk for snapshot {} is gone (source sandbox {} was destroyed?)", snapshot_id, info.sandbox_id);
+        }
+
+        let id = new_sandbox_id;
+        let spec = info.spec();
+        let dir = self.sandbox_dir(id);
+        tokio::fs::create_dir_all(&dir).await?;
+        let restored = async {
+            if memory_only {
+                // The memory only fits the disk as it was when the snapshot was taken; checked
+                // after the copy so a write during it is caught too
+                let source = self.sandbox_dir(&info.sandbox_id).join(ROOTFS);
+                copy_disk(&source, &dir.join(ROOTFS)).await?;
+                if info.disk_version.as_ref() != Some(&disk_version_of(&source).await?) {
+                    bail!(
+                        "Disk of sandbox {} changed since memory-only snapshot {} was taken; only a FULL snapshot can be restored now",
+                        info.sandbox_id, snapshot_id,
+                    );
+                }
+            } else {
+                materialize(Part::Disk, snapshot_id, snapshot_dir, &info, ancestors, &dir.join(ROOTFS)).await?;
+                // Later snapshots of this sandbox store their disk as a delta against this one
+                copy_disk(&dir.join(ROOTFS), &dir.join(BASE_DISK)).await?;
+            }
+            // Firecracker maps the memory file privately, so a whole one is used in place; the
+            // mapping outlives the checkout
+            let memory = if info.memory_delta_of.is_some() {
+                materialize(Part::Memory, snapshot_id, snapshot_dir, &info, ancestors, &dir.join(RESTORED_MEMORY)).await?;
+                dir.join(RESTORED_MEMORY)
+            } else {
+                snapshot_dir.join(SNAPSHOT_MEMORY)
+            };
+
+            let tap = self.create_tap(id, &spec).await?;
+            self.launch_vmm(id).await?;
+            let mut load = json!({
+                "snapshot_path": snapshot_dir.join(SNAPSHOT_VMSTATE),
+                "mem_backend": { "backend_type": "File", "backend_path": memory },
+                "track_dirty_pages": true,
+                "resume_vm": true,
+            });
+            if let Some(tap) = &tap {
+                load["network_overrides"] = json!([{ "iface_id": "eth0", "host_dev_name": tap }]);
+            }
+            self.api(id).put("/snapshot/load", load).await?;
+            self.set_lineage(id, &Lineage {
+                memory_base: Some(snapshot_id.clone()),
+                disk_base: (!memory_only).then(|| snapshot_id.clone()),
+            }).await?;
+            self.wait_for_agent(id).await
+        }.await;
+        if let Err(e) = restored {
+            let _ = self.destroy_sandbox(id, true).await;
+            return Err(e);
+        }
+
+        self.specs.write().unwrap().insert(id.clone(), spec);
+        Ok(())
+    }
+
+    async fn delete_snapshot(&self, _snapshot_id: &SnapshotId) -> Result<()> {
+        // Snapshots live entirely in the snapshot store's directory; nothing else to clean up
+        Ok(())
+    }
+
+    // --- Files ---
+    async fn put_file(
+        &self,
+        id: &SandboxId,
+        guest_path: PathBuf,
+        content: &mut (dyn AsyncRead + Send + Unpin),
+        overwrite: bool,
+    ) -> Result<u64> {
+        let path = self.resolve_guest_path(id, &guest_path)?;
+        self.agent(id).put_file(&path, content, overwrite).await
+    }
+
+    async fn get_file(
+        &self,
+        id: &SandboxId,
+        guest_path: PathBuf,
+        sink: &mut (dyn AsyncWrite + Send + Unpin),
+    ) -> Result<u64> {
+        let path = self.resolve_guest_path(id, &guest_path)?;
+        self.agent(id).get_file(&path, sink).await
+    }
+
+    async fn list_dir(&self, id: &SandboxId, guest_path: PathBuf) -> Result<Vec<DirEntry>> {
+        let path = self.resolve_guest_path(id, &guest_path)?;
+        self.agent(id).list_dir(&path).await
+    }
+}
+
+/// Minimal HTTP/1.1 client for Firecracker's API socket, one connection per request
+struct ApiClient {
+    socket: PathBuf,
+}
+
+impl ApiClient {
+    async fn get(&self, path: &str) -> Result<serde_json::Value> {
+        let body = self.request("GET", path, None).await?;
+        Ok(serde_json::from_str(&body)?)
+    }
+
+    async fn put(&self, path: &str, body: serde_json::Value) -> Result<()> {
+        self.request("PUT", path, Some(body)).await.map(|_| ())
+    }
+
+    async fn patch(&self, path: &str, body: serde_json::Value) -> Result<()> {
+        self.request("PATCH", path, Some(body)).await.map(|_| ())
+    }
+
+    async fn request(&self, method: &str, path: &str, body: Option<serde_json::Value>) -> Result<String> {
+        let mut conn = BufReader::new(UnixStream::connect(&self.socket).await?);
+        let body = body.map(|b| b.to_string()).unwrap_or_default();
+        let request = format!(
+            "{} {} HTTP/1.1\r\nHost: localhost\r\nAccept: application/json\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+            method, path, body.len(), body
+        );
+        conn.get_mut().write_all(request.as_bytes()).await?;
+
+        let mut status_line = String::new();
+        conn.read_line(&mut status_line).await?;
+        let status: u16 = status_line
+            .split_whitespace()
+            .nth(1)
+            .and_then(|s| s.parse().ok())
+            .ok_or_else(|| anyhow!("Malformed response from Firecracker: {:?}", status_line.trim()))?;
+
+        let mut content_length = 0;
+        loop {
+            let mut header = String::new();
+            if conn.read_line(&mut header).await? == 0 || header.trim().is_empty() {
+                break;
+            }
+            if let Some((name, value)) = header.split_once(':')
+                && name.trim().eq_ignore_ascii_case("content-length")
+            {
+                content_length = value.trim().parse()?;
+            }
+        }
+        let mut payload = vec![0u8; content_length];
+        conn.read_exact(&mut payload).await?;
+        let payload = String::from_utf8_lossy(&payload).to_string();
+
+        if !(200..300).contains(&status) {
+            let fault = serde_json::from_str::<serde_json::Value>(&payload)
+                .ok()
+                .and_then(|v| v["fault_message"].as_str().map(str::to_string))
+                .unwrap_or(payload);
+            bail!("Firecracker {} {} failed ({}): {}", method, path, status, fault);
+        }
+        Ok(payload)
+    }
+}
+
+/// What a restore needs to know about the snapshotted sandbox, stored next to the snapshot
+#[derive(Serialize, Deserialize)]
+struct SnapshotInfo {
+    sandbox_id: SandboxId,
+    mode: String,
+    working_dir: PathBuf,
+    vcpu: u32,
+    memory_mb: u64,
+    disk_mb: u64,
+    // Whether the VM has a NIC, which the restored VM must get too
+    network: bool,
+    // Ancestors the memory and disk files are deltas against, if they are
+    #[serde(default)]
+    memory_delta_of: Option<SnapshotId>,
+    #[serde(default)]
+    disk_delta_of: Option<SnapshotId>,
+    // Memory-only snapshots: version of the source sandbox's disk the memory goes with
+    #[serde(default)]
+    disk_version: Option<String>,
+}
+
+impl SnapshotInfo {
+    fn new(id: &SandboxId, mode: SnapshotMode, spec: &SandboxSpec, network: bool) -> Self {
+        Self {
+            sandbox_id: id.clone(),
+            mode: match mode {
+                SnapshotMode::Full => "full",
+                SnapshotMode::MemoryOnly => "memory_only",
+            }.to_string(),
+            working_dir: spec.working_dir.clone(),
+            vcpu: spec.limits.vcpu,
+            memory_mb: spec.limits.memory_mb,
+            disk_mb: spec.limits.disk_mb,
+            network,
+            memory_delta_of: None,
+            disk_delta_of: None,
+            disk_version: None,
+        }
+    }
+
+    /// Spec of a sandbox restored from the snapshot; the VM's shape is fixed by the snapshot
+    fn spec(&self) -> SandboxSpec {
+        SandboxSpec {
+            base_image: String::new(),
+            working_dir: self.working_dir.clone(),
+            limits: ResourceLimits { vcpu: self.vcpu, memory_mb: self.memory_mb, disk_mb: self.disk_mb, ..Default::default() },
+            policy: SandboxPolicy {
+                network: NetworkPolicy {
+                    deny_all: !self.network,
+                    allow_domains: vec![],
+                    allow_cidrs: vec![],
+                    allow_loopback: true,
+                },
+                mounts: vec![],
+                seccomp: SeccompProfile::Strict,
+                enable_gpu: false,
+                enable_snapshotting: true,
+            },
+        }
+    }
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct Lineage {
+    memory_base: Option<SnapshotId>,
+    disk_base: Option<SnapshotId>,
+}
+
+/// A snapshot file that may be stored as a delta
+#[derive(Clone, Copy)]
+enum Part {
+    Memory,
+    Disk,
+}
+
+impl Part {
+    fn whole_file(self) -> &'static str {
+        match self {
+            Part::Memory => SNAPSHOT_MEMORY,
+            Part::Disk => SNAPSHOT_ROOTFS,
+        }
+    }
+
+    // Firecracker writes diff memory to the usual file, just sparse
+    fn delta_file(self) -> &'static str {
+        match self {
+            Part::Memory => SNAPSHOT_MEMORY,
+            Part::Disk => SNAPSHOT_ROOTFS_DIFF,
+        }
+    }
+
+    fn delta_of(self, info: &SnapshotInfo) -> Option<&SnapshotId> {
+        match self {
+            Part::Memory => info.memory_delta_of.as_ref(),
+            Part::Disk => info.disk_delta_of.as_ref(),
+        }
+    }
+}
+
+async fn read_info(snapshot_dir: &Path) -> Result<SnapshotInfo> {
+    Ok(serde_json::from_slice(&tokio::fs::read(snapshot_dir.join(SNAPSHOT_INFO)).await?)?)
+}
+
+/// Write a snapshot's `part` whole to `target`: follow its deltas back through `ancestors` to a
+/// whole file, copy that and apply the deltas oldest first
+async fn materialize(
+    part: Part,
+    snapshot_id: &SnapshotId,
+    snapshot_dir: &Path,
+    info: &SnapshotInfo,
+    ancestors: &[SnapshotRef],
+    target: &Path,
+) -> Result<()> {
+    let mut deltas = Vec::new();
+    let mut dir = snapshot_dir.to_path_buf();
+    let mut current = snapshot_id.clone();
+    let mut delta_of = part.delta_of(info).cloned();
+    while let Some(base) = delta_of {
+        deltas.push(dir.join(part.delta_file()));
+        let ancestor = ancestors
+            .iter()
+            .find(|a| a.snapshot_id == base)
+            .ok_or_else(|| anyhow!("Snapshot {} is a delta against {}, which is not available", current, base))?;
+        delta_of = part.delta_of(&read_info(&ancestor.dir).await?).cloned();
+        dir = ancestor.dir.clone();
+        current = base;
+    }
+
+    copy_disk(&dir.join(part.whole_file()), target).await?;
+    for diff in deltas.into_iter().rev() {
+        let target = target.to_path_buf();
+        tokio::task::spawn_blocking(move || delta::apply_diff(&diff, &target)).await??;
+    }
+    Ok(())
+}
+
+/// Bytes a file occupies on disk; deltas are sparse
+async fn stored_size(path: &Path) -> Result<u64> {
+    use std::os::unix::fs::MetadataExt;
+    Ok(tokio::fs::metadata(path).await?.blocks() * 512)
+}
+
+/// Changes whenever the disk file is written to: its size and modification time
+async fn disk_version_of(path: &Path) -> Result<String> {
+    use std::os::unix::fs::MetadataExt;
+    let meta = tokio::fs::metadata(path).await?;
+    Ok(format!("{}:{}.{:09}", meta.size(), meta.mtime(), meta.mtime_nsec()))
+}
+
+fn working_dir(spec: &SandboxSpec) -> PathBuf {
+    sandbox_root(&spec.working_dir)
+}
+
+/// Tap device of a sandbox; interface names are limited to 15 bytes, so it is named after a
+/// hash of the id rather than the id itself
+fn tap_name(id: &SandboxId) -> String {
+    let digest = Sha256::digest(id.as_bytes());
+    let hex: String = digest.iter().map(|b| format!("{:02x}", b)).collect();
+    format!("fc{}", &hex[..13])
+}
+
+/// Stable, locally administered MAC for the sandbox's NIC, from the same hash as its tap
+fn guest_mac(id: &SandboxId) -> String {
+    let digest = Sha256::digest(id.as_bytes());
+    let octets: Vec<String> = digest[..4].iter().map(|b| format!("{:02x}", b)).collect();
+    format!("06:00:{}", octets.join(":"))
+}
+
+/// Whether `pid` is a live (non-zombie) process
+fn process_alive(pid: u32) -> bool {
+    let Ok(stat) = std::fs::read_to_string(format!("/proc/{}/stat", pid)) else {
+        return false;
+    };
+    // The state follows the parenthesised command name, which may itself contain spaces
+    stat.rsplit_once(')').and_then(|(_, rest)| rest.split_whitespace().next()) != Some("Z")
+}
+
+/// Copy a disk image, sharing blocks with the source where the filesystem allows
+async fn copy_disk(src: &Path, dst: &Path) -> Result<()> {
+    run_host("cp", &["--reflink=auto", "--sparse=always", &src.display().to_string(), &dst.display().to_string()]).await?;
+    Ok(())
+}
+
+/// Run a host command, failing with its stderr
+async fn run_host(program: &str, args: &[&str]) -> Result<String> {
+    let output = Command::new(program).args(args).output().await
+        .with_context(|| format!("Failed to run {}", program))?;
+    if output.status.success() {
+        Ok(String::from_utf8_lossy(&output.stdout).to_string())
+    } else {
+        Err(anyhow!("{} failed: {}", program, String::from_utf8_lossy(&output.stderr).trim()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn tap_and_mac_come_from_the_whole_id() {
+        let (a, b) = ("test-1".to_string(), "west-1".to_string());
+        assert_ne!(tap_name(&a), tap_name(&b));
+        assert_ne!(guest_mac(&a), guest_mac(&b));
+        assert_eq!(tap_name(&a), tap_name(&"test-1".to_string()));
+
+        let tap = tap_name(&a);
+        assert!(tap.len() <= 15 && tap.starts_with("fc"), "{}", tap);
+        let mac = guest_mac(&a);
+        assert_eq!(mac.len(), 17);
+        assert!(mac.starts_with("06:00:"), "{}", mac);
+    }
+
+    #[tokio::test]
+    async fn disk_version_changes_with_the_disk() {
+        let dir = TempDir::new("disk-version");
+        let disk = dir.join(ROOTFS);
+        std::fs::write(&disk, b"before").unwrap();
+        let before = disk_version_of(&disk).await.unwrap();
+        assert_eq!(disk_version_of(&disk).await.unwrap(), before);
+        std::fs::write(&disk, b"after, longer").unwrap();
+        assert_ne!(disk_version_of(&disk).await.unwrap(), before);
+    }
+
+    /// An API socket that answers one request with `response`, handing back the request
+    fn api_answering(dir: &TempDir, response: String) -> (ApiClient, tokio::task::JoinHandle<String>) {
+        let socket = dir.join(API_SOCKET);
+        let listener = tokio::net::UnixListener::bind(&socket).unwrap();
+        let server = tokio::spawn(async move {
+            let (conn, _) = listener.accept().await.unwrap();
+            let mut conn = BufReader::new(conn);
+            let mut request = String::new();
+            while conn.read_line(&mut request).await.unwrap() > 2 {}
+            conn.get_mut().write_all(response.as_bytes()).await.unwrap();
+            request
+        });
+        (ApiClient { socket }, server)
+    }
+
+    fn response(status: &str, body: &str) -> String {
+        format!("HTTP/1.1 {}\r\nContent-Type: application/json\r\ncontent-length: {}\r\n\r\n{}", status, body.len(), body)
+    }
+
+    #[tokio::test]
+    async fn api_request_parses_the_response() {
+        let dir = TempDir::new("fc-api");
+        let (api, server) = api_answering(&dir, response("200 OK", r#"{"state":"Not started"}"#));
+        let info = api.get("/").await.unwrap();
+        assert_eq!(info["state"], "Not started");
+        assert!(server.await.unwrap().starts_with("GET / HTTP/1.1\r\n"));
+
+        let dir = TempDir::new("fc-api");
+        let (api, _) = api_answering(&dir, "HTTP/1.1 204 No Content\r\n\r\n".to_string());
+        api.put("/actions", json!({ "action_type": "InstanceStart" })).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn api_request_reports_faults() {
+        let dir = TempDir::new("fc-api");
+        let (api, _) = api_answering(&dir, response("400 Bad Request", r#"{"fault_message":"Invalid state"}"#));
+        let err = api.patch("/vm", json!({ "state": "Paused" })).await.unwrap_err().to_string();
+        assert_eq!(err, "Firecracker PATCH /vm failed (400): Invalid state");
+
+        let dir = TempDir::new("fc-api");
+        let (api, _) = api_answering(&dir, "garbage\r\n\r\n".to_string());
+        assert!(api.get("/").await.unwrap_err().to_string().contains("Malformed response"));
+    }
+
+    fn snapshot_info(network: bool) -> SnapshotInfo {
+        SnapshotInfo {
+            sandbox_id: "sb-1".to_string(),
+            mode: "full".to_string(),
+            working_dir: PathBuf::from("/work"),
+            vcpu: 2,
+            memory_mb: 512,
+            disk_mb: 2048,
+            network,
+            memory_delta_of: None,
+            disk_delta_of: None,
+            disk_version: None,
+        }
+    }
+
+    #[test]
+    fn snapshot_info_gives_the_restored_spec() {
+        let spec = snapshot_info(false).spec();
+        assert_eq!(spec.working_dir, PathBuf::from("/work"));
+        assert_eq!((spec.limits.vcpu, spec.limits.memory_mb, spec.limits.disk_mb), (2, 512, 2048));
+        assert!(spec.policy.network.deny_all);
+        assert!(spec.policy.mounts.is_empty());
+
+        // Taking a snapshot of the restored sandbox records the same shape again
+        let again = SnapshotInfo::new(&"sb-2".to_string(), SnapshotMode::MemoryOnly, &snapshot_info(true).spec(), true);
+        assert_eq!(again.mode, "memory_only");
+        assert_eq!((again.working_dir, again.vcpu, again.memory_mb, again.disk_mb), (PathBuf::from("/work"), 2, 512, 2048));
+        assert!(!snapshot_info(true).spec().policy.network.deny_all);
+    }
+
+    #[tokio::test]
+    async fn materialize_applies_a_delta_chain_oldest_first() {
+        let dir = TempDir::new("fc-materialize");
+        let versions: Vec<Vec<u8>> = (0..3u8)
+            .map(|v| (0..delta::BLOCK * 8).map(|i| if i / delta::BLOCK == v as usize { 0xa0 + v } else { 1 }).collect())
+            .collect();
+        let mut ancestors = Vec::new();
+        for (n, version) in versions.iter().enumerate() {
+            let (id, snapshot_dir) = (format!("s{}", n), dir.join(format!("s{}", n)));
+            std::fs::create_dir_all(&snapshot_dir).unwrap();
+            let mut info = snapshot_info(false);
+            if n == 0 {
+                std::fs::write(snapshot_dir.join(SNAPSHOT_ROOTFS), version).unwrap();
+            } else {
+                let current = dir.join("current");
+                std::fs::write(&current, version).unwrap();
+                std::fs::write(dir.join("base"), &versions[n - 1]).unwrap();
+                delta::write_block_diff(&current, &dir.join("base"), &snapshot_dir.join(SNAPSHOT_ROOTFS_DIFF)).unwrap();
+                info.disk_delta_of = Some(format!("s{}", n - 1));
+            }
+            std::fs::write(snapshot_dir.join(SNAPSHOT_INFO), serde_json::to_vec(&info).unwrap()).unwrap();
+            ancestors.push(SnapshotRef { snapshot_id: id, dir: snapshot_dir });
+        }
+
+        let latest = &ancestors[2];
+        let info = read_info(&latest.dir).await.unwrap();
+        let target = dir.join("restored");
+        materialize(Part::Disk, &latest.snapshot_id, &latest.dir, &info, &ancestors[..2], &target).await.unwrap();
+        assert!(std::fs::read(&target).unwrap() == versions[2]);
+
+        // Without the snapshot the chain starts at, there is nothing to apply the deltas to
+        let err = materialize(Part::Disk, &latest.snapshot_id, &latest.dir, &info, &ancestors[1..2], &dir.join("partial")).await;
+        assert!(err.unwrap_err().to_string().contains("s0, which is not available"));
+    }
+}
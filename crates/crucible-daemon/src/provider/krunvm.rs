@@ -0,0 +1,409 @@
+//! krunvm (libkrun) backend: one lightweight VM per sandbox, built from the OCI image in
+//! `SandboxSpec.base_image`.
+//!
+//! krunvm keeps the VM's root filesystem between runs but only boots the VM for the command it
+//! is asked to run, so every exec is its own short-lived VM on the sandbox's persistent rootfs.
+//! The sandbox working directory is a host directory shared into the VM, which is where file
+//! transfers go. vCPUs and memory come from `ResourceLimits`; libkrun has no disk or process
+//! caps, and its transparent socket networking can't be restricted, so sandboxes that limit
+//! egress are refused. libkrun forwards the guest console, so stderr arrives as stdout.
+
+use crate::config::KrunvmConfig;
+use crate::provider::{
+    pump_output, DirEntry, ExecId, ExecResult, ExecSpec, OutputSink, OutputStream, ProviderHealth, SandboxId,
+    SandboxProvider, SandboxSpec, SandboxUsage, SnapshotId, SnapshotMeta, SnapshotMode, SnapshotRef, sandbox_root,
+};
+use anyhow::{anyhow, bail, Context, Result};
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::process::Stdio;
+use std::sync::{Mutex, RwLock};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::process::Command;
+
+
+// Runs `$3..` in directory `$1` with the `$2` NAME=VALUE pairs that follow exported. The command
+// only ever appears in "$@", so no shell re-parses it.
+const EXEC_SCRIPT: &str = r#"cd "$1" || exit 125; n=$2; shift 2
+while [ "$n" -gt 0 ]; do export "$1" || exit 125; shift; n=$((n - 1)); done
+exec "$@""#;
+
+pub struct KrunvmProvider {
+    config: KrunvmConfig,
+    specs: RwLock<HashMap<SandboxId, SandboxSpec>>,
+    // Process group of each running exec's VM, for cancellation
+    running: Mutex<HashMap<ExecId, u32>>,
+}
+
+impl KrunvmProvider {
+    pub fn new(config: KrunvmConfig) -> Self {
+        Self { config, specs: RwLock::new(HashMap::new()), running: Mutex::new(HashMap::new()) }
+    }
+
+    fn workspace(&self, id: &SandboxId) -> PathBuf {
+        self.config.state_dir.join(id).join("workspace")
+    }
+
+    /// Host path for a client-supplied guest path, treating the working directory as the root.
+    /// Symlinks are refused: the guest controls them and they'd resolve on the host.
+    fn host_path(&self, id: &SandboxId, guest_path: &Path) -> Result<PathBuf> {
+        let working_dir = self.specs.read().unwrap().get(id).map(working_dir);
+        let relative = match &working_dir {
+            Some(dir) => guest_path.strip_prefix(dir).unwrap_or(guest_path),
+            None => guest_path,
+        };
+
+        let mut path = self.workspace(id);
+        for component in relative.components() {
+            match component {
+                Component::Normal(part) => path.push(part),
+                Component::RootDir | Component::CurDir => {}
+                Component::ParentDir | Component::Prefix(_) => {
+                    bail!("Path escapes the sandbox: {}", guest_path.display());
+                }
+            }
+            if std::fs::symlink_metadata(&path).is_ok_and(|m| m.file_type().is_symlink()) {
+                bail!("Path goes through a symlink: {}", guest_path.display());
+            }
+        }
+        Ok(path)
+    }
+
+    /// Names of the krunvm VMs on this host
+    async fn list_vms(&self) -> Result<Vec<String>> {
+        let out = run_host(&self.config.binary, &["list"]).await?;
+        // VM names start a line; their settings follow indented
+        Ok(out
+            .lines()
+            .filter(|line| !line.is_empty() && !line.starts_with(char::is_whitespace))
+            .map(|line| line.trim().to_string())
+            .collect())
+    }
+}
+
+#[async_trait]
+impl SandboxProvider for KrunvmProvider {
+    fn provider_name(&self) -> &'static str {
+        "local_krunvm"
+    }
+
+    async fn probe(&self) -> Result<ProviderHealth> {
+        // KVM must be usable by the daemon's user, not merely present
+        let kvm = std::fs::OpenOptions::new().read(true).write(true).open("/dev/kvm").is_ok();
+        let version = match Command::new(&self.config.binary).arg("--version").output().await {
+            Ok(output) if output.status.success() => {
+                String::from_utf8_lossy(&output.stdout).lines().next().map(|l| l.trim().to_string())
+            }
+            _ => None,
+        };
+
+        Ok(ProviderHealth {
+            healthy: kvm && version.is_some(),
+            version,
+            snapshot_capable: false,
+            gpu_capable: false,
+        })
+    }
+
+    // --- Lifecycle ---
+    async fn list_sandboxes(&self) -> Result<Vec<SandboxId>> {
+        Ok(self
+            .list_vms()
+            .await?
+            .iter()
+            .filter_map(|name| name.strip_prefix("crucible-"))
+            .map(str::to_string)
+            .collect())
+    }
+
+    async fn create_sandbox(&self, id: &SandboxId, spec: SandboxSpec) -> Result<()> {
+        let network = &spec.policy.network;
+        if network.deny_all || !network.allow_domains.is_empty() || !network.allow_cidrs.is_empty() {
+            bail!("The krunvm provider can't restrict egress; use the lima or firecracker provider");
+        }
+        if !spec.policy.mounts.is_empty() {
+            bail!("Host mounts are not supported by the krunvm provider");
+        }
+        if spec.policy.enable_gpu {
+            println!("Provider Warning: GPU acceleration requested but the krunvm provider does not set up Venus passthrough.");
+        }
+
+        let workspace = self.workspace(id);
+        tokio::fs::create_dir_all(&workspace).await?;
+        let root = working_dir(&spec).display().to_string();
+        let image = if spec.base_image.is_empty() { &self.config.default_image } else { &spec.base_image };
+        let mut args = vec![
+            "create".to_string(),
+            "--name".to_string(), vm_name(id),
+            "--workdir".to_string(), root.clone(),
+            "--volume".to_string(), format!("{}:{}", workspace.display(), root),
+        ];
+        if spec.limits.vcpu > 0 {
+            args.extend(["--cpus".to_string(), spec.limits.vcpu.to_string()]);
+        }
+        if spec.limits.memory_mb > 0 {
+            args.extend(["--mem".to_string(), spec.limits.memory_mb.to_string()]);
+        }
+        args.push(image.clone());
+
+        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+        if let Err(e) = run_host(&self.config.binary, &args).await {
+            let _ = tokio::fs::remove_dir_all(self.config.state_dir.join(id)).await;
+            return Err(e.context(format!("Failed to create krunvm VM from {:?}", image)));
+        }
+
+        self.specs.write().unwrap().insert(id.clone(), spec);
+        Ok(())
+    }
+
+    async fn adopt_sandbox(&self, id: &SandboxId, spec: SandboxSpec) -> Result<bool> {
+        if !self.list_vms().await?.contains(&vm_name(id)) {
+            bail!("krunvm VM {} is gone", vm_name(id));
+        }
+        self.specs.write().unwrap().insert(id.clone(), spec);
+        Ok(true)
+    }
+
+    async fn start_sandbox(&self, _id: &SandboxId) -> Result<()> {
+        // VMs only run for the duration of an exec
+        Ok(())
+    }
+
+    async fn stop_sandbox(&self, _id: &SandboxId, _force: bool) -> Result<()> {
+        // VMs only run for the duration of an exec
+        Ok(())
+    }
+
+    async fn destroy_sandbox(&self, id: &SandboxId, _force: bool) -> Result<()> {
+        if self.list_vms().await?.contains(&vm_name(id)) {
+            run_host(&self.config.binary, &["delete", &vm_name(id)]).await?;
+        }
+        match tokio::fs::remove_dir_all(self.config.state_dir.join(id)).await {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+        self.specs.write().unwrap().remove(id);
+        Ok(())
+    }
+
+    async fn usage(&self, id: &SandboxId) -> Result<SandboxUsage> {
+        // VMs come and go with execs; only the working directory is attributable
+        let out = run_host(Path::new("du"), &["-sm", &self.workspace(id).display().to_string()]).await?;
+        let disk_mb = out.split_whitespace().next().and_then(|n| n.parse().ok()).unwrap_or(0);
+        Ok(SandboxUsage { disk_mb, ..Default::default() })
+    }
+
+    // --- Execution ---
+    async fn exec_stream(&self, id: &SandboxId, spec: ExecSpec, output: OutputSink) -> Result<ExecResult> {
+        let root = self.specs.read().unwrap().get(id).map(working_dir)
+            .ok_or_else(|| anyhow!("Unknown sandbox {}", id))?;
+        let cwd = match &spec.cwd {
+            Some(cwd) => root.join(cwd),
+            None => root,
+        };
+
+        let mut cmd = Command::new(&self.config.binary);
+        cmd.args(["start", &vm_name(id), "/bin/sh", "--", "-c", EXEC_SCRIPT, "sh"]);
+        cmd.arg(&cwd).arg(spec.env.len().to_string());
+        cmd.args(spec.env.iter().map(|(key, value)| format!("{}={}", key, value)));
+        cmd.args(&spec.argv);
+        // The VM lives in its own process group so a kill takes krunvm and the VM down together
+        cmd.stdin(Stdio::null()).stdout(Stdio::piped()).stderr(Stdio::piped()).process_group(0);
+        cmd.kill_on_drop(true);
+
+        let mut child = cmd.spawn().with_context(|| format!("Failed to run {}", self.config.binary.display()))?;
+        let pgid = child.id().ok_or_else(|| anyhow!("krunvm exited immediately"))?;
+        self.running.lock().unwrap().insert(spec.exec_id.clone(), pgid);
+
+        let stdout = child.stdout.take().ok_or_else(|| anyhow!("Exec stdout unavailable"))?;
+        let stderr = child.stderr.take().ok_or_else(|| anyhow!("Exec stderr unavailable"))?;
+        let stdout_pump = tokio::spawn(pump_output(stdout, OutputStream::Stdout, output.clone()));
+        let stderr_pump = tokio::spawn(pump_output(stderr, OutputStream::Stderr, output));
+
+        let waited = tokio::time::timeout(spec.timeout, child.wait()).await;
+        self.running.lock().unwrap().remove(&spec.exec_id);
+        let (exit_code, timed_out) = match waited {
+            Ok(status) => (status?.code().unwrap_or(-1), false),
+            Err(_) => {
+                let _ = kill_group(pgid).await;
+                let _ = child.kill().await;
+                (-1, true)
+            }
+        };
+        let _ = tokio::join!(stdout_pump, stderr_pump);
+
+        Ok(ExecResult {
+            exec_id: spec.exec_id,
+            exit_code,
+            timed_out,
+            violations: vec![],
+        })
+    }
+
+    async fn cancel_exec(&self, _id: &SandboxId, exec_id: &ExecId) -> Result<()> {
+        let pgid = self.running.lock().unwrap().get(exec_id).copied();
+        match pgid {
+            Some(pgid) => kill_group(pgid).await,
+            None => Ok(()),
+        }
+    }
+
+    // --- Snapshot ---
+    async fn create_snapshot(&self, _id: &SandboxId, _dst_path: &std::path::Path, _mode: SnapshotMode, _ancestors: &[SnapshotId]) -> Result<SnapshotMeta> {
+        Err(anyhow!("Snapshots are not supported by the krunvm provider"))
+    }
+
+    async fn restore_snapshot(&self, _snapshot_id: &SnapshotId, _new_sandbox_id: &SandboxId, _snapshot_dir: &std::path::Path, _ancestors: &[SnapshotRef]) -> Result<()> {
+        Err(anyhow!("Snapshots are not supported by the krunvm provider"))
+    }
+
+    async fn delete_snapshot(&self, _snapshot_id: &SnapshotId) -> Result<()> {
+        Err(anyhow!("Snapshots are not supported by the krunvm provider"))
+    }
+
+    // --- Files ---
+    async fn put_file(
+        &self,
+        id: &SandboxId,
+        guest_path: PathBuf,
+        content: &mut (dyn AsyncRead + Send + Unpin),
+        overwrite: bool,
+    ) -> Result<u64> {
+        let path = self.host_path(id, &guest_path)?;
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        let mut file = tokio::fs::OpenOptions::new()
+            .write(true)
+            .create(overwrite)
+            .create_new(!overwrite)
+            .truncate(overwrite)
+            .open(&path)
+            .await?;
+        let copied = tokio::io::copy(content, &mut file).await?;
+        file.flush().await?;
+        Ok(copied)
+    }
+
+    async fn get_file(
+        &self,
+        id: &SandboxId,
+        guest_path: PathBuf,
+        sink: &mut (dyn AsyncWrite + Send + Unpin),
+    ) -> Result<u64> {
+        let path = self.host_path(id, &guest_path)?;
+        if !tokio::fs::metadata(&path).await.is_ok_and(|m| m.is_file()) {
+            return Err(io::Error::new(io::ErrorKind::NotFound, format!("{} is not a file", guest_path.display())).into());
+        }
+        let mut file = tokio::fs::File::open(&path).await?;
+        let copied = tokio::io::copy(&mut file, sink).await?;
+        sink.flush().await?;
+        Ok(copied)
+    }
+
+    async fn list_dir(&self, id: &SandboxId, guest_path: PathBuf) -> Result<Vec<DirEntry>> {
+        let path = self.host_path(id, &guest_path)?;
+        let mut entries = match tokio::fs::read_dir(&path).await {
+            Ok(entries) => entries,
+            Err(e) if matches!(e.kind(), io::ErrorKind::NotFound | io::ErrorKind::NotADirectory) => {
+                return Err(io::Error::new(io::ErrorKind::NotFound, format!("{} is not a directory", guest_path.display())).into());
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut listing = Vec::new();
+        while let Some(entry) = entries.next_entry().await? {
+            // Don't follow the guest's symlinks on the host
+            let meta = tokio::fs::symlink_metadata(entry.path()).await?;
+            listing.push(DirEntry {
+                name: entry.file_name().to_string_lossy().to_string(),
+                is_dir: meta.is_dir(),
+                size_bytes: meta.len(),
+                modified_at: meta.modified()?,
+            });
+        }
+        Ok(listing)
+    }
+}
+
+fn vm_name(id: &SandboxId) -> String {
+    format!("crucible-{}", id)
+}
+
+fn working_dir(spec: &SandboxSpec) -> PathBuf {
+    sandbox_root(&spec.working_dir)
+}
+
+async fn kill_group(pgid: u32) -> Result<()> {
+    run_host(Path::new("kill"), &["-KILL", "--", &format!("-{}", pgid)]).await?;
+    Ok(())
+}
+
+/// Run a host command, failing with its stderr
+async fn run_host(program: &Path, args: &[&str]) -> Result<String> {
+    let output = Command::new(program).args(args).output().await
+        .with_context(|| format!("Failed to run {}", program.display()))?;
+    if output.status.success() {
+        Ok(String::from_utf8_lossy(&output.stdout).to_string())
+    } else {
+        Err(anyhow!("{} failed: {}", program.display(), String::from_utf8_lossy(&output.stderr).trim()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::policy::seccomp::SeccompProfile;
+    use crate::provider::{NetworkPolicy, ResourceLimits, SandboxPolicy};
+    use std::time::Duration;
+
+    /// Boots a real VM; run with `cargo test -- --ignored` where KVM and krunvm are available
+    #[tokio::test]
+    #[ignore = "boots a real VM, needs KVM and krunvm"]
+    async fn exec_and_files_round_trip() {
+        let state_dir = std::env::temp_dir().join(format!("crucible_krunvm_{}", uuid::Uuid::new_v4()));
+        let provider = KrunvmProvider::new(KrunvmConfig { state_dir: state_dir.clone(), ..Default::default() });
+        assert!(provider.probe().await.unwrap().healthy, "KVM or krunvm unavailable");
+
+        let id = uuid::Uuid::new_v4().to_string();
+        let spec = SandboxSpec {
+            base_image: String::new(),
+            working_dir: PathBuf::new(),
+            limits: ResourceLimits::default(),
+            policy: SandboxPolicy {
+                network: NetworkPolicy { deny_all: false, allow_domains: vec![], allow_cidrs: vec![], allow_loopback: true },
+                mounts: vec![],
+                seccomp: SeccompProfile::Default,
+                enable_gpu: false,
+                enable_snapshotting: false,
+            },
+        };
+        provider.create_sandbox(&id, spec).await.unwrap();
+
+        let mut content: &[u8] = b"hello from the host";
+        provider.put_file(&id, PathBuf::from("in.txt"), &mut content, false).await.unwrap();
+        let (tx, mut rx) = tokio::sync::mpsc::channel(16);
+        let result = provider.exec_stream(&id, ExecSpec {
+            exec_id: "e1".to_string(),
+            argv: vec!["sh".to_string(), "-c".to_string(), "cat in.txt > out.txt; echo $GREETING".to_string()],
+            env: vec![("GREETING".to_string(), "hi there".to_string())],
+            cwd: None,
+            timeout: Duration::from_secs(60),
+        }, tx).await.unwrap();
+        let mut stdout = Vec::new();
+        while let Some(chunk) = rx.recv().await {
+            stdout.extend(chunk.data);
+        }
+
+        let mut copied = Vec::new();
+        provider.get_file(&id, PathBuf::from("/workspace/out.txt"), &mut copied).await.unwrap();
+        provider.destroy_sandbox(&id, true).await.unwrap();
+        let _ = std::fs::remove_dir_all(&state_dir);
+
+        assert_eq!(result.exit_code, 0);
+        assert!(String::from_utf8_lossy(&stdout).contains("hi there"));
+        assert_eq!(copied, b"hello from the host");
+    }
+}
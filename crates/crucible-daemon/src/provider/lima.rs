@@ -1,13 +1,20 @@
 use crate::provider::{
-    ExecResult, ExecSpec, ProviderHealth, SandboxId, SandboxProvider, SandboxSpec, SnapshotId,
-    SnapshotMeta,
+    pump_output, DirEntry, ExecId, ExecResult, ExecSpec, OutputSink, OutputStream, ProviderHealth,
+    NetworkPolicy, ResourceLimits, SandboxId, SandboxProvider, SandboxSpec, SandboxUsage, SnapshotId, SnapshotMeta, SnapshotMode, SnapshotRef,
+    Violation, ViolationKind, DEFAULT_WORKING_DIR, sandbox_root,
 };
+use crate::policy::egress::{EgressProxy, EgressRules};
+use crate::policy::seccomp::{syscall_name, SeccompProfile, SECCOMP_KILL_EXIT_CODE};
 use anyhow::{anyhow, Result};
 use async_trait::async_trait;
-use std::path::PathBuf;
-use std::process::Stdio;
+use sha2::{Digest, Sha256};
 use std::collections::HashMap;
-use std::sync::RwLock;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::process::Stdio;
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
 use tokio::process::Command;
 
 pub struct LimaProvider {
@@ -15,6 +22,44 @@ pub struct LimaProvider {
     pub instance_name: String,
     // Store sandbox specs to retrieve policy configurations during exec
     specs: RwLock<HashMap<SandboxId, SandboxSpec>>,
+    // How each sandbox's resource limits and egress policy are enforced
+    enforcement: Mutex<HashMap<SandboxId, Enforcement>>,
+}
+
+struct Enforcement {
+    // cgroup v2 directory in the guest; None when the guest lacks cgroup v2 and prlimit is used
+    cgroup: Option<String>,
+    egress: Egress,
+    seccomp: SeccompProfile,
+    // Guest path of the compiled filter handed to bwrap
+    seccomp_filter: String,
+    // Counters already reported, so each violation is attributed to one exec
+    seen: LimitCounters,
+}
+
+enum Egress {
+    // Unrestricted guest networking
+    Open,
+    // No network at all (`--unshare-net`)
+    Isolated,
+    // Only allowlisted CIDRs and the sandbox's filtering proxy are reachable
+    Allowlist {
+        proxy: EgressProxy,
+        // Proxy URL as seen from inside the guest
+        proxy_url: String,
+        // nftables chain holding the sandbox's rules
+        chain: String,
+    },
+}
+
+#[derive(Default, Clone, Copy)]
+struct LimitCounters {
+    oom_kills: u64,
+    pids_max_hits: u64,
+    // Free space on the working directory filesystem; None when it isn't size-capped
+    disk_avail_kb: Option<u64>,
+    // Packets the egress allowlist dropped
+    egress_drops: u64,
 }
 
 impl LimaProvider {
@@ -22,18 +67,201 @@ impl LimaProvider {
         Self {
             instance_name: instance_name.into(),
             specs: RwLock::new(HashMap::new()),
+            enforcement: Mutex::new(HashMap::new()),
         }
     }
 
-    /// Helper to run a raw command inside the Lima guest
-    async fn run_in_guest(&self, args: &[&str]) -> Result<String> {
+    /// Set up the working directory filesystem, cgroup and egress rules for a sandbox.
+    /// Idempotent, so it also re-establishes enforcement for sandboxes adopted after a restart.
+    async fn provision(&self, id: &SandboxId, spec: &SandboxSpec) -> Result<()> {
+        let limits = &spec.limits;
+        let guest_dir = sandbox_dir(id);
+        let cgroup = sandbox_cgroup(id);
+        let cpu_max = if limits.vcpu > 0 {
+            format!("{} {}", limits.vcpu as u64 * CPU_PERIOD_US, CPU_PERIOD_US)
+        } else {
+            format!("max {}", CPU_PERIOD_US)
+        };
+        let mem_max = if limits.memory_mb > 0 { (limits.memory_mb * 1024 * 1024).to_string() } else { "max".to_string() };
+        let pids_max = if limits.pids_max > 0 { limits.pids_max.to_string() } else { "max".to_string() };
+
+        let mode = self.run_in_guest(&[
+            "sh", "-c", PROVISION_SCRIPT, "sh",
+            &guest_dir, &sandbox_disk_image(id), &limits.disk_mb.to_string(),
+            &cgroup, &cpu_max, &mem_max, &pids_max,
+        ]).await?;
+        let cgroup = (mode.trim() == "cgroup").then_some(cgroup);
+        if cgroup.is_none() {
+            println!("Provider Warning: guest has no cgroup v2, sandbox {} falls back to prlimit", id);
+        }
+
+        let egress = self.provision_egress(id, &spec.policy.network, cgroup.as_deref()).await?;
+        let seccomp = spec.policy.seccomp;
+        let seccomp_filter = seccomp_filter_path(seccomp);
+        self.run_in_guest_with_input(&["sh", "-c", INSTALL_FILE_SCRIPT, "sh", &seccomp_filter], &seccomp.compile()).await?;
+
+        let chain = match &egress {
+            Egress::Allowlist { chain, .. } => Some(chain.as_str()),
+            _ => None,
+        };
+        let seen = self.read_counters(&guest_dir, cgroup.as_deref(), chain, limits.disk_mb > 0).await?;
+        self.enforcement.lock().unwrap().insert(id.clone(), Enforcement { cgroup, egress, seccomp, seccomp_filter, seen });
+        Ok(())
+    }
+
+    async fn provision_egress(&self, id: &SandboxId, network: &NetworkPolicy, cgroup: Option<&str>) -> Result<Egress> {
+        let rules = EgressRules::from_policy(network)?;
+        if rules.is_empty() {
+            return Ok(if network.deny_all { Egress::Isolated } else { Egress::Open });
+        }
+        let Some(cgroup) = cgroup else {
+            // Rules are matched on the sandbox cgroup; without one, fail closed
+            println!("Provider Warning: egress allowlist for sandbox {} needs cgroup v2, denying all egress", id);
+            return Ok(Egress::Isolated);
+        };
+
+        let cidrs: Vec<String> = rules.cidrs().iter().map(|c| c.to_string()).collect();
+        let proxy = EgressProxy::start(rules).await?;
+        let chain = egress_chain(id);
+        let cgroup_path = cgroup.trim_start_matches("/sys/fs/cgroup/");
+        // Sandboxes share the guest's network namespace, so their loopback traffic is marked and
+        // only delivered to sockets of the sandbox's own cgroup
+        let loopback = if network.allow_loopback { format!("{:#010x}", loopback_mark(id)) } else { String::new() };
+        let port = proxy.port().to_string();
+
+        let mut args = vec![
+            "sh", "-c", EGRESS_SCRIPT, "sh", id.as_str(), &chain, cgroup_path, &port, &loopback,
+        ];
+        args.extend(cidrs.iter().map(String::as_str));
+        let proxy_ip = self.run_in_guest(&args).await?;
+
+        Ok(Egress::Allowlist {
+            proxy,
+            proxy_url: format!("http://{}:{}", proxy_ip.trim(), port),
+            chain,
+        })
+    }
+
+    async fn read_counters(&self, guest_dir: &str, cgroup: Option<&str>, chain: Option<&str>, disk_capped: bool) -> Result<LimitCounters> {
+        let out = self.run_in_guest(&[
+            "sh", "-c", LIMIT_COUNTERS_SCRIPT, "sh", guest_dir, cgroup.unwrap_or(""), chain.unwrap_or(""),
+        ]).await?;
+        let mut fields = out.split_whitespace().map(|f| f.parse::<u64>().ok());
+        Ok(LimitCounters {
+            oom_kills: fields.next().flatten().unwrap_or(0),
+            pids_max_hits: fields.next().flatten().unwrap_or(0),
+            disk_avail_kb: fields.next().flatten().filter(|_| disk_capped),
+            egress_drops: fields.next().flatten().unwrap_or(0),
+        })
+    }
+
+    /// Limits and egress rules the sandbox ran into since the last check
+    async fn collect_violations(&self, id: &SandboxId, guest_dir: &str, limits: &ResourceLimits) -> Result<Vec<Violation>> {
+        let (cgroup, chain, seen, mut violations) = match self.enforcement.lock().unwrap().get(id) {
+            Some(e) => match &e.egress {
+                Egress::Allowlist { proxy, chain, .. } => {
+                    (e.cgroup.clone(), Some(chain.clone()), e.seen, proxy.take_violations())
+                }
+                _ => (e.cgroup.clone(), None, e.seen, vec![]),
+            },
+            None => return Ok(vec![]),
+        };
+        let now = self.read_counters(guest_dir, cgroup.as_deref(), chain.as_deref(), limits.disk_mb > 0).await?;
+        if let Some(e) = self.enforcement.lock().unwrap().get_mut(id) {
+            e.seen = now;
+        }
+
+        let mut violation = |kind: ViolationKind, message: String| violations.push(Violation {
+            kind,
+            message,
+            ts: SystemTime::now(),
+        });
+        if now.oom_kills > seen.oom_kills {
+            violation(ViolationKind::ResourceLimit, format!(
+                "Memory limit of {} MB exceeded, {} process(es) killed",
+                limits.memory_mb, now.oom_kills - seen.oom_kills
+            ));
+        }
+        if now.pids_max_hits > seen.pids_max_hits {
+            violation(ViolationKind::ResourceLimit, format!(
+                "Process limit of {} reached, {} fork(s) refused",
+                limits.pids_max, now.pids_max_hits - seen.pids_max_hits
+            ));
+        }
+        if now.disk_avail_kb.is_some_and(|kb| kb < DISK_FULL_KB) {
+            violation(ViolationKind::ResourceLimit, format!("Working directory filesystem is full ({} MB limit)", limits.disk_mb));
+        }
+        if now.egress_drops > seen.egress_drops {
+            violation(ViolationKind::EgressBlocked, format!(
+                "Dropped {} packet(s) to destinations outside the egress allowlist",
+                now.egress_drops - seen.egress_drops
+            ));
+        }
+        Ok(violations)
+    }
+
+    /// Build a `limactl shell` command for the guest without spawning it
+    fn guest_command(&self, args: &[&str]) -> Command {
         let mut cmd = Command::new("limactl");
         cmd.arg("shell").arg(&self.instance_name);
         for arg in args {
             cmd.arg(arg);
         }
+        cmd.kill_on_drop(true);
+        cmd
+    }
+
+    /// Resolve a client-supplied guest path, treating the sandbox `working_dir` as the sandbox root
+    fn resolve_guest_path(&self, id: &SandboxId, guest_path: &Path) -> Result<String> {
+        let working_dir = self.specs.read().unwrap().get(id).map(|s| s.working_dir.clone());
+        let relative = match working_dir {
+            Some(dir) if dir.is_absolute() => guest_path.strip_prefix(&dir).unwrap_or(guest_path),
+            _ => guest_path,
+        };
+        sandbox_path(id, relative)
+    }
+
+    /// SIGKILL a running exec inside the guest, taking its whole sandbox process tree with it
+    async fn kill_exec(&self, exec_id: &str) -> Result<()> {
+        let pidfile = exec_pidfile(exec_id);
+        self.run_in_guest(&["sh", "-c", KILL_EXEC_SCRIPT, "sh", &pidfile]).await?;
+        Ok(())
+    }
+
+    /// Like `run_in_guest`, feeding `input` to the command's stdin
+    async fn run_in_guest_with_input(&self, args: &[&str], input: &[u8]) -> Result<String> {
+        let mut child = self.guest_command(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        let mut stdin = child.stdin.take().ok_or_else(|| anyhow!("Guest stdin unavailable"))?;
+        stdin.write_all(input).await?;
+        drop(stdin);
+
+        let output = child.wait_with_output().await?;
+        if output.status.success() {
+            Ok(String::from_utf8_lossy(&output.stdout).to_string())
+        } else {
+            Err(anyhow!("Lima command failed: {}", String::from_utf8_lossy(&output.stderr)))
+        }
+    }
+
+    /// The syscall behind each seccomp kill of an exec's processes, from the kernel's audit
+    /// records for the exec's audit session; `None` if the exec couldn't be given a session
+    async fn seccomp_kills(&self, session_file: &str) -> Option<Vec<Option<&'static str>>> {
+        let records = self.run_in_guest(&["sh", "-c", SECCOMP_AUDIT_SCRIPT, "sh", session_file]).await.ok()?;
+        Some(records.lines().map(|record| {
+            let field = |name: &str| record.split_whitespace().find_map(|f| f.strip_prefix(name));
+            let arch = u32::from_str_radix(field("arch=")?, 16).ok()?;
+            let nr = field("syscall=")?.parse().ok()?;
+            syscall_name(arch, nr)
+        }).collect())
+    }
 
-        let output = cmd.output().await?;
+    /// Helper to run a raw command inside the Lima guest
+    async fn run_in_guest(&self, args: &[&str]) -> Result<String> {
+        let output = self.guest_command(args).output().await?;
         if output.status.success() {
             Ok(String::from_utf8_lossy(&output.stdout).to_string())
         } else {
@@ -57,7 +285,7 @@ impl SandboxProvider for LimaProvider {
                 
                 // Check if the specific instance is running
                 let list_out = Command::new("limactl")
-                    .args(&["list", "--json"])
+                    .args(["list", "--json"])
                     .output()
                     .await?;
                 
@@ -81,26 +309,38 @@ impl SandboxProvider for LimaProvider {
     }
 
     // --- Lifecycle ---
-    async fn list_sandboxes(&self) -> Result<Vec<(SandboxId, SandboxSpec)>> {
-        let specs = self.specs.read().unwrap();
-        Ok(specs.iter().map(|(k, v)| (k.clone(), v.clone())).collect())
+    async fn list_sandboxes(&self) -> Result<Vec<SandboxId>> {
+        // The guest directories are the ground truth; `specs` is lost when the daemon restarts
+        let out = self.run_in_guest(&[
+            "find", "/tmp", "-mindepth", "1", "-maxdepth", "1", "-type", "d",
+            "-name", "crucible_sandbox_*", "-printf", "%f\\n",
+        ]).await?;
+        Ok(out
+            .lines()
+            .filter_map(|name| name.strip_prefix("crucible_sandbox_"))
+            .map(str::to_string)
+            .collect())
     }
 
-    async fn create_sandbox(&self, spec: SandboxSpec) -> Result<SandboxId> {
-        let id = uuid::Uuid::new_v4().to_string();
-        
+    async fn create_sandbox(&self, id: &SandboxId, spec: SandboxSpec) -> Result<()> {
+        // For the Lima mock provider, a "sandbox" is just an isolated directory in the guest,
+        // backed by its own size-capped filesystem and confined to its own cgroup.
+        // In a real krunvm/firecracker setup, this boots an actual isolated VM.
+        self.provision(id, &spec).await?;
+
         // Save the spec for later policy enforcement during `exec`
         {
             let mut specs = self.specs.write().unwrap();
             specs.insert(id.clone(), spec);
         }
+        Ok(())
+    }
 
-        // For the Lima mock provider, a "sandbox" is just an isolated directory in the guest
-        // In a real krunvm/firecracker setup, this boots an actual isolated VM.
-        let guest_dir = format!("/tmp/crucible_sandbox_{}", id);
-        self.run_in_guest(&["mkdir", "-p", &guest_dir]).await?;
-
-        Ok(id)
+    async fn adopt_sandbox(&self, id: &SandboxId, spec: SandboxSpec) -> Result<bool> {
+        // The guest directory is still there; make sure limits and egress rules still apply
+        self.provision(id, &spec).await?;
+        self.specs.write().unwrap().insert(id.clone(), spec);
+        Ok(true)
     }
 
     async fn start_sandbox(&self, _id: &SandboxId) -> Result<()> {
@@ -113,41 +353,89 @@ impl SandboxProvider for LimaProvider {
         Ok(())
     }
 
+    fn stop_reclaims(&self) -> bool {
+        // Nothing runs between execs; the directory, disk image and cgroup stay until destroy
+        false
+    }
+
     async fn destroy_sandbox(&self, id: &SandboxId, _force: bool) -> Result<()> {
-        let guest_dir = format!("/tmp/crucible_sandbox_{}", id);
-        self.run_in_guest(&["rm", "-rf", &guest_dir]).await?;
+        let guest_dir = sandbox_dir(id);
+        self.run_in_guest(&[
+            "sh", "-c", DESTROY_SCRIPT, "sh",
+            &guest_dir, &sandbox_disk_image(id), &sandbox_cgroup(id), id, &egress_chain(id),
+        ]).await?;
         
         {
             let mut specs = self.specs.write().unwrap();
             specs.remove(id);
         }
+        self.enforcement.lock().unwrap().remove(id);
         
         Ok(())
     }
 
+    async fn usage(&self, id: &SandboxId) -> Result<SandboxUsage> {
+        // Directory isolates share the guest's CPU and memory; only disk is attributable
+        let guest_dir = sandbox_dir(id);
+        let out = self.run_in_guest(&["du", "-sm", &guest_dir]).await?;
+        let disk_mb = out.split_whitespace().next().and_then(|n| n.parse().ok()).unwrap_or(0);
+        Ok(SandboxUsage { disk_mb, ..Default::default() })
+    }
+
     // --- Execution ---
-    async fn exec(&self, id: &SandboxId, spec: ExecSpec) -> Result<ExecResult> {
-        let guest_dir = format!("/tmp/crucible_sandbox_{}", id);
-        let exec_id = uuid::Uuid::new_v4().to_string();
-
-        let mut bwrap_args = vec![
-            "bwrap".to_string(),
-            "--bind".to_string(), "/".to_string(), "/".to_string(),
-            "--dev".to_string(), "/dev".to_string(),
-            "--proc".to_string(), "/proc".to_string(),
-            "--chdir".to_string(), guest_dir.clone(),
-        ];
+    async fn exec_stream(&self, id: &SandboxId, spec: ExecSpec, output: OutputSink) -> Result<ExecResult> {
+        let root = self.specs.read().unwrap().get(id).map(|s| sandbox_root(&s.working_dir))
+            .unwrap_or_else(|| PathBuf::from(DEFAULT_WORKING_DIR));
+        let workdir = match &spec.cwd {
+            Some(cwd) => exec_workdir(&root, cwd)?,
+            None => root.clone(),
+        };
+
+        let mut bwrap_args = vec!["bwrap".to_string()];
+        bwrap_args.extend(rootfs_args(&sandbox_dir(id), &root));
+        bwrap_args.extend([
+            // A private PID namespace lets a single kill take down every descendant
+            "--unshare-pid".to_string(),
+            "--unshare-ipc".to_string(),
+            "--unshare-uts".to_string(),
+            "--hostname".to_string(), "crucible".to_string(),
+            "--cap-drop".to_string(), "ALL".to_string(),
+            // No controlling terminal, so sandboxed code can't inject input into ours (TIOCSTI)
+            "--new-session".to_string(),
+            "--die-with-parent".to_string(),
+            "--chdir".to_string(), workdir.display().to_string(),
+            "--setenv".to_string(), "HOME".to_string(), root.display().to_string(),
+            "--setenv".to_string(), "TMPDIR".to_string(), "/tmp".to_string(),
+        ]);
+
+        // Egress Network Isolation: allowlisted sandboxes are steered to their filtering proxy;
+        // the nftables rules drop anything that bypasses it. Set before the request env so a
+        // caller can still pick e.g. NO_PROXY.
+        match self.enforcement.lock().unwrap().get(id).map(|e| &e.egress) {
+            Some(Egress::Isolated) => bwrap_args.push("--unshare-net".to_string()),
+            Some(Egress::Allowlist { proxy_url, .. }) => {
+                for key in ["HTTP_PROXY", "HTTPS_PROXY", "http_proxy", "https_proxy"] {
+                    bwrap_args.extend(["--setenv".to_string(), key.to_string(), proxy_url.clone()]);
+                }
+                for key in ["NO_PROXY", "no_proxy"] {
+                    bwrap_args.extend(["--setenv".to_string(), key.to_string(), "localhost,127.0.0.1,::1".to_string()]);
+                }
+            }
+            Some(Egress::Open) | None => {}
+        }
+
+        for (key, value) in &spec.env {
+            bwrap_args.push("--setenv".to_string());
+            bwrap_args.push(key.clone());
+            bwrap_args.push(value.clone());
+        }
 
         // Retrieve sandbox policy to enforce security boundaries
         {
             let specs = self.specs.read().unwrap();
             if let Some(sandbox_spec) = specs.get(id) {
-                // Egress Network Isolation
-                if sandbox_spec.policy.network.deny_all {
-                    bwrap_args.push("--unshare-net".to_string());
-                }
 
-                // Mount Enforcement
+                // Mount Enforcement: validated at create time, stacked on top of the base layout
                 for m in &sandbox_spec.policy.mounts {
                     if m.read_only {
                         bwrap_args.push("--ro-bind".to_string());
@@ -165,32 +453,116 @@ impl SandboxProvider for LimaProvider {
                 }
             }
         }
+        bwrap_args.extend(["--remount-ro".to_string(), "/".to_string()]);
+
+        // Syscall filter: the wrapper opens the compiled profile on SECCOMP_FD for bwrap
+        let seccomp = self.enforcement.lock().unwrap().get(id).map(|e| (e.seccomp, e.seccomp_filter.clone()));
+        if seccomp.is_some() {
+            bwrap_args.extend(["--seccomp".to_string(), SECCOMP_FD.to_string()]);
+        }
 
-        bwrap_args.push("--".to_string());
-        for a in &spec.argv {
-            bwrap_args.push(a.clone());
+        // Resource limits: the wrapper joins the sandbox cgroup before starting anything, so
+        // every process of the exec is accounted there. Without cgroup v2, fall back to prlimit.
+        let limits = self.specs.read().unwrap().get(id).map(|s| s.limits.clone());
+        let cgroup = self.enforcement.lock().unwrap().get(id).and_then(|e| e.cgroup.clone());
+        if let Some(limits) = limits.as_ref().filter(|_| cgroup.is_none()) {
+            bwrap_args.splice(0..0, prlimit_args(limits));
         }
 
+        // The guest records the pid of `timeout` so the host can kill it on timeout or cancel;
+        // bwrap's `--die-with-parent` then tears down the whole PID namespace. `timeout` itself
+        // is a guest-side backstop in case the host never gets to do that.
+        let pidfile = exec_pidfile(&spec.exec_id);
+        let session_file = match seccomp {
+            Some(_) => exec_session_file(&spec.exec_id),
+            None => String::new(),
+        };
+        let backstop_secs = spec.timeout.as_secs() + TIMEOUT_GRACE_SECS;
+        let guest_argv = exec_argv(
+            &session_file,
+            &pidfile,
+            backstop_secs,
+            cgroup.as_deref().unwrap_or(""),
+            seccomp.as_ref().map_or("", |(_, filter)| filter.as_str()),
+            bwrap_args,
+            &spec.argv,
+        );
+
         let mut cmd = Command::new("limactl");
         cmd.arg("shell").arg(&self.instance_name);
-        
-        cmd.arg("sh").arg("-c").arg(bwrap_args.join(" "));
+        cmd.args(&guest_argv);
+        cmd.stdin(Stdio::null()).stdout(Stdio::piped()).stderr(Stdio::piped());
+        cmd.kill_on_drop(true);
+
+        let mut child = cmd.spawn()?;
+        let stdout = child.stdout.take().ok_or_else(|| anyhow!("Exec stdout unavailable"))?;
+        let stderr = child.stderr.take().ok_or_else(|| anyhow!("Exec stderr unavailable"))?;
 
-        // Note: Real implementation needs handling of `spec.timeout` and `spec.env`
-        let output = cmd.output().await?;
+        let stdout_pump = tokio::spawn(pump_output(stdout, OutputStream::Stdout, output.clone()));
+        let stderr_pump = tokio::spawn(pump_output(stderr, OutputStream::Stderr, output));
+
+        let (exit_code, timed_out) = match tokio::time::timeout(spec.timeout, child.wait()).await {
+            Ok(status) => (status?.code().unwrap_or(-1), false),
+            Err(_) => {
+                if let Err(e) = self.kill_exec(&spec.exec_id).await {
+                    println!("Provider Warning: failed to kill timed out exec {}: {}", spec.exec_id, e);
+                }
+                let _ = child.kill().await;
+                (-1, true)
+            }
+        };
+        let _ = tokio::join!(stdout_pump, stderr_pump);
+
+        let mut violations = match &limits {
+            Some(limits) => {
+                let guest_dir = sandbox_dir(id);
+                self.collect_violations(id, &guest_dir, limits).await.unwrap_or_else(|e| {
+                    println!("Provider Warning: failed to read limit counters for {}: {}", id, e);
+                    vec![]
+                })
+            }
+            None => vec![],
+        };
+        if let Some((profile, _)) = seccomp {
+            // Without its own audit session the exec's kills can't be told from other sandboxes';
+            // all that is left then is the exit status of the top-level process
+            let kills = match self.seccomp_kills(&session_file).await {
+                Some(kills) => kills,
+                None if exit_code == SECCOMP_KILL_EXIT_CODE => vec![None],
+                None => vec![],
+            };
+            for syscall in kills {
+                violations.push(Violation {
+                    kind: ViolationKind::SyscallDenied,
+                    message: format!(
+                        "Process killed by seccomp profile {:?} for calling {}",
+                        profile.name(),
+                        syscall.unwrap_or("a denied syscall"),
+                    ),
+                    ts: SystemTime::now(),
+                });
+            }
+        }
 
         Ok(ExecResult {
-            exec_id,
-            exit_code: output.status.code().unwrap_or(-1),
+            exec_id: spec.exec_id,
+            exit_code,
+            timed_out,
+            violations,
         })
     }
 
+    async fn cancel_exec(&self, _id: &SandboxId, exec_id: &ExecId) -> Result<()> {
+        // The pidfile is keyed by exec id alone; killing it unblocks `exec_stream`'s wait
+        self.kill_exec(exec_id).await
+    }
+
     // --- Snapshot ---
-    async fn create_snapshot(&self, _id: &SandboxId, _dst_path: &std::path::Path) -> Result<SnapshotMeta> {
+    async fn create_snapshot(&self, _id: &SandboxId, _dst_path: &std::path::Path, _mode: SnapshotMode, _ancestors: &[SnapshotId]) -> Result<SnapshotMeta> {
         Err(anyhow!("Snapshots are not supported by the Lima provider"))
     }
 
-    async fn restore_snapshot(&self, _snapshot_id: &SnapshotId, _new_sandbox_id: &SandboxId, _snapshot_dir: &std::path::Path) -> Result<()> {
+    async fn restore_snapshot(&self, _snapshot_id: &SnapshotId, _new_sandbox_id: &SandboxId, _snapshot_dir: &std::path::Path, _ancestors: &[SnapshotRef]) -> Result<()> {
         Err(anyhow!("Snapshots are not supported by the Lima provider"))
     }
 
@@ -199,48 +571,655 @@ impl SandboxProvider for LimaProvider {
     }
 
     // --- Files ---
-    async fn put_file(&self, id: &SandboxId, guest_path: PathBuf, content: Vec<u8>) -> Result<()> {
-        let guest_dir = format!("/tmp/crucible_sandbox_{}", id);
-        let full_path = format!("{}/{}", guest_dir, guest_path.display());
-        
-        // We write the file locally to a temp path, then use `limactl cp` to move it in
-        let temp_local = format!("/tmp/crucible_host_{}.tmp", uuid::Uuid::new_v4());
-        std::fs::write(&temp_local, content)?;
-
-        let dest = format!("{}:{}", self.instance_name, full_path);
-        let status: std::process::ExitStatus = Command::new("limactl")
-            .args(&["cp", &temp_local, &dest])
-            .status()
-            .await?;
+    async fn put_file(
+        &self,
+        id: &SandboxId,
+        guest_path: PathBuf,
+        content: &mut (dyn AsyncRead + Send + Unpin),
+        overwrite: bool,
+    ) -> Result<u64> {
+        let full_path = self.resolve_guest_path(id, &guest_path)?;
 
-        // Cleanup local temp
-        let _ = std::fs::remove_file(&temp_local);
+        // Stream straight into the guest over stdin instead of staging a host temp file. The
+        // script only keeps the upload if it ends with `trailer`, which is sent once all of
+        // `content` has been, so an upload cut short never replaces anything.
+        let trailer = uuid::Uuid::new_v4().simple().to_string();
+        let mut child = self.guest_command(&[
+            "sh", "-c", PUT_FILE_SCRIPT, "sh", &sandbox_dir(id), &full_path, if overwrite { "1" } else { "0" }, &trailer,
+        ])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()?;
 
-        if status.success() {
-            Ok(())
-        } else {
-            Err(anyhow!("Failed to copy file into Lima guest"))
+        let mut stdin = child.stdin.take().ok_or_else(|| anyhow!("Guest stdin unavailable"))?;
+        let copied = match tokio::io::copy(content, &mut stdin).await {
+            Ok(n) => stdin.write_all(trailer.as_bytes()).await.map(|_| n),
+            Err(e) => Err(e),
+        };
+        drop(stdin);
+
+        let output = child.wait_with_output().await?;
+        match output.status.code() {
+            Some(0) => Ok(copied?),
+            Some(EXIT_ESCAPES) => Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("Path escapes the sandbox: {}", guest_path.display()),
+            )
+            .into()),
+            Some(EXIT_ALREADY_EXISTS) => Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} already exists", guest_path.display()),
+            )
+            .into()),
+            _ => {
+                copied?;
+                Err(anyhow!(
+                    "Failed to write file into Lima guest: {}",
+                    String::from_utf8_lossy(&output.stderr).trim()
+                ))
+            }
         }
     }
 
-    async fn get_file(&self, id: &SandboxId, guest_path: PathBuf) -> Result<Vec<u8>> {
-        let guest_dir = format!("/tmp/crucible_sandbox_{}", id);
-        let full_path = format!("{}/{}", guest_dir, guest_path.display());
-        
-        let temp_local = format!("/tmp/crucible_host_{}.tmp", uuid::Uuid::new_v4());
-        
-        let src = format!("{}:{}", self.instance_name, full_path);
-        let status: std::process::ExitStatus = Command::new("limactl")
-            .args(&["cp", &src, &temp_local])
-            .status()
+    async fn get_file(
+        &self,
+        id: &SandboxId,
+        guest_path: PathBuf,
+        sink: &mut (dyn AsyncWrite + Send + Unpin),
+    ) -> Result<u64> {
+        let full_path = self.resolve_guest_path(id, &guest_path)?;
+
+        let mut child = self.guest_command(&["sh", "-c", GET_FILE_SCRIPT, "sh", &sandbox_dir(id), &full_path])
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+
+        let mut stdout = child.stdout.take().ok_or_else(|| anyhow!("Guest stdout unavailable"))?;
+        let copied = tokio::io::copy(&mut stdout, sink).await?;
+
+        let output = child.wait_with_output().await?;
+        match output.status.code() {
+            Some(0) => Ok(copied),
+            Some(EXIT_ESCAPES) => Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("Path escapes the sandbox: {}", guest_path.display()),
+            )
+            .into()),
+            Some(EXIT_NOT_FOUND) => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} is not a file", guest_path.display()),
+            )
+            .into()),
+            _ => Err(anyhow!(
+                "Failed to read file from Lima guest: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            )),
+        }
+    }
+
+    async fn list_dir(&self, id: &SandboxId, guest_path: PathBuf) -> Result<Vec<DirEntry>> {
+        let full_path = self.resolve_guest_path(id, &guest_path)?;
+
+        let output = self.guest_command(&["sh", "-c", LIST_DIR_SCRIPT, "sh", &sandbox_dir(id), &full_path])
+            .output()
             .await?;
+        match output.status.code() {
+            Some(0) => Ok(parse_find_entries(&output.stdout)),
+            Some(EXIT_ESCAPES) => Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("Path escapes the sandbox: {}", guest_path.display()),
+            )
+            .into()),
+            Some(EXIT_NOT_FOUND) => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} is not a directory", guest_path.display()),
+            )
+            .into()),
+            _ => Err(anyhow!(
+                "Failed to list directory in Lima guest: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            )),
+        }
+    }
+}
 
-        if status.success() {
-            let content = std::fs::read(&temp_local)?;
-            let _ = std::fs::remove_file(&temp_local);
-            Ok(content)
-        } else {
-            Err(anyhow!("Failed to copy file from Lima guest"))
+// Guest-side helpers. User-controlled paths are only ever passed as positional parameters.
+const EXIT_NOT_FOUND: i32 = 66;
+/// Extra seconds the guest-side `timeout` allows beyond the host-enforced deadline
+const TIMEOUT_GRACE_SECS: u64 = 5;
+const EXIT_ALREADY_EXISTS: i32 = 67;
+
+const EXIT_ESCAPES: i32 = 68;
+
+// The file scripts run as the guest user outside bwrap, so a symlink planted by sandboxed code
+// would take them anywhere the guest user can reach. Each resolves `$2` with every symlink
+// followed and refuses (68) anything outside sandbox directory `$1`, and opens the result
+// without following links, so one swapped in for the last component fails rather than escapes.
+//
+// PUT_FILE_SCRIPT doesn't open the path at all. It walks down from the root one directory at a
+// time with `cd`, creating what's missing and checking after each step that it is still inside,
+// so a directory swapped for a symlink is caught wherever it is. Everything after that is
+// relative to the directory it ended up in: stdin goes to a fresh temp file, and only if it ends
+// with trailer `$4` is the file cut back to the data and moved into place, replacing the
+// target if `$3` is 1 and otherwise linked there, which fails (67) if the name is taken.
+const PUT_FILE_SCRIPT: &str = r#"set -f
+root=$(realpath -e -- "$1") && p=$(realpath -m -- "$2") || exit 68
+case $p in "$root"/*) ;; *) exit 68 ;; esac
+name=${p##*/}; rel=${p#"$root"/}
+cd -P -- "$root" && [ "$(pwd -P)" = "$root" ] || exit 68
+IFS=/
+for d in ${rel%"$name"}; do
+  mkdir -- "./$d" 2>/dev/null; cd -P -- "./$d" || exit 1
+  case $(pwd -P) in "$root"/*) ;; *) exit 68 ;; esac
+done
+unset IFS
+if [ "$3" != 1 ] && { [ -e "./$name" ] || [ -L "./$name" ]; }; then exit 67; fi
+tmp=$(mktemp ./.crucible-put.XXXXXX) || exit 1
+trap 'rm -f -- "$tmp"' EXIT
+dd of="$tmp" oflag=nofollow bs=64K status=none || exit 1
+n=$(( $(stat -c %s -- "$tmp") - ${#4} ))
+[ "$n" -ge 0 ] && [ "$(dd if="$tmp" iflag=nofollow bs=1 skip="$n" status=none)" = "$4" ] || exit 1
+dd if=/dev/null of="$tmp" oflag=nofollow bs=1 seek="$n" status=none || exit 1
+if [ "$3" = 1 ]; then mv -fT -- "$tmp" "./$name"; else ln -T -- "$tmp" "./$name" 2>/dev/null || exit 67; fi"#;
+const GET_FILE_SCRIPT: &str = r#"root=$(realpath -e -- "$1") && p=$(realpath -e -- "$2") || exit 66
+case $p in "$root"/*) ;; *) exit 68 ;; esac
+[ -f "$p" ] || exit 66; exec dd if="$p" iflag=nofollow bs=64K status=none"#;
+const LIST_DIR_SCRIPT: &str = r#"root=$(realpath -e -- "$1") && p=$(realpath -e -- "$2") || exit 66
+case $p in "$root" | "$root"/*) ;; *) exit 68 ;; esac
+[ -d "$p" ] || exit 66; exec find "$p" -mindepth 1 -maxdepth 1 -printf '%y %s %T@ %f\0'"#;
+
+// Runs `$5..` under a guest-side `timeout`, recording its pid in `$1`. If `$3` names a cgroup
+// the wrapper moves itself there first so everything it starts is limited; if `$4` names a
+// seccomp filter it is opened on fd 10 for bwrap. The sandbox command only ever appears in "$@",
+// so no shell ever re-parses it.
+const EXEC_WRAPPER_SCRIPT: &str = r#"pidfile=$1; secs=$2; cg=$3; seccomp=$4; shift 4
+if [ -n "$cg" ]; then echo $$ | sudo tee "$cg/cgroup.procs" >/dev/null || exit 125; fi
+if [ -n "$seccomp" ]; then exec 10<"$seccomp" || exit 125; fi
+timeout -s KILL "$secs" "$@" &
+echo $! > "$pidfile"; wait $!; rc=$?; rm -f "$pidfile"; exit $rc"#;
+
+/// Descriptor EXEC_WRAPPER_SCRIPT opens the seccomp filter on
+const SECCOMP_FD: u32 = 10;
+
+// Writes stdin to root-owned `$1`, replacing it atomically
+const INSTALL_FILE_SCRIPT: &str =
+    r#"set -e; sudo mkdir -p "$(dirname "$1")"; sudo tee "$1.tmp" >/dev/null; sudo mv -f "$1.tmp" "$1""#;
+
+// Runs as root: gives the exec a fresh audit session by rewriting its loginuid, records the
+// session id in `$1`, then drops back to the invoking user to run `$2..`. Every process the exec
+// starts inherits the session and none can leave it without CAP_AUDIT_CONTROL, so the session
+// id marks the exec's seccomp kills in the kernel log. If the loginuid can't be changed the
+// file stays absent and the exec runs anyway.
+const EXEC_SESSION_SCRIPT: &str = r#"sesfile=$1; shift
+rm -f "$sesfile"
+if echo "$SUDO_UID" > /proc/self/loginuid 2>/dev/null; then cat /proc/self/sessionid > "$sesfile"; fi
+exec setpriv --reuid="$SUDO_UID" --regid="$SUDO_GID" --init-groups -- "$@""#;
+
+// Prints every seccomp kill audit record (type 1326) of the audit session recorded in `$1` and
+// removes the file; exits 3 if there is no session
+const SECCOMP_AUDIT_SCRIPT: &str = r#"ses=$(sudo cat "$1" 2>/dev/null); sudo rm -f "$1"
+[ -n "$ses" ] || exit 3
+sudo dmesg 2>/dev/null | awk -v ses="ses=$ses" '/type=1326/ { for (i = 1; i <= NF; i++) if ($i == ses) { print; break } }'"#;
+
+const KILL_EXEC_SCRIPT: &str =
+    r#"pid=$(cat "$1" 2>/dev/null) && kill -KILL "$pid" 2>/dev/null; rm -f "$1""#;
+
+// Mounts a size-capped ext4 image on the working directory and creates the sandbox cgroup with
+// its cpu/memory/pids limits. Prints which enforcement mode is in effect. Safe to re-run.
+const PROVISION_SCRIPT: &str = r#"set -e
+dir=$1; img=$2; disk_mb=$3; cg=$4; cpu_max=$5; mem_max=$6; pids_max=$7
+mkdir -p "$dir"
+if [ "$disk_mb" -gt 0 ] && ! mountpoint -q "$dir"; then
+  sudo mkdir -p "${img%/*}"
+  if [ ! -e "$img" ]; then
+    sudo truncate -s "${disk_mb}M" "$img"
+    sudo mkfs.ext4 -q -F -m 0 "$img"
+  fi
+  sudo mount -o loop "$img" "$dir"
+  sudo chown "$(id -u):$(id -g)" "$dir"
+fi
+if [ -f /sys/fs/cgroup/cgroup.controllers ]; then
+  echo "+cpu +memory +pids" | sudo tee /sys/fs/cgroup/cgroup.subtree_control >/dev/null
+  sudo mkdir -p "${cg%/*}"
+  echo "+cpu +memory +pids" | sudo tee "${cg%/*}/cgroup.subtree_control" >/dev/null
+  sudo mkdir -p "$cg"
+  echo "$cpu_max" | sudo tee "$cg/cpu.max" >/dev/null
+  echo "$mem_max" | sudo tee "$cg/memory.max" >/dev/null
+  [ "$mem_max" = max ] || echo 0 | sudo tee "$cg/memory.swap.max" >/dev/null 2>&1 || true
+  echo "$pids_max" | sudo tee "$cg/pids.max" >/dev/null
+  echo cgroup
+else
+  echo prlimit
+fi"#;
+
+// Prints "<oom kills> <refused forks> <free KiB on the working directory filesystem> <egress drops>"
+const LIMIT_COUNTERS_SCRIPT: &str = r#"dir=$1; cg=$2; chain=$3
+oom=$(awk '$1 == "oom_kill" { print $2 }' "$cg/memory.events" 2>/dev/null)
+pids=$(awk '$1 == "max" { print $2 }' "$cg/pids.events" 2>/dev/null)
+avail=$(df -Pk "$dir" 2>/dev/null | awk 'NR == 2 { print $4 }')
+if [ -n "$chain" ]; then
+  drops=$(sudo nft list chain inet crucible "$chain" 2>/dev/null \
+    | awk '{ for (i = 1; i < NF; i++) if ($i == "packets") print $(i + 1) }')
+fi
+echo "${oom:-0} ${pids:-0} ${avail:-x} ${drops:-0}""#;
+
+// Installs the egress allowlist of sandbox `$1` as nftables chain `$2`, applied to every socket
+// of cgroup `$3`: the sandbox proxy on the host at port `$4`, and the CIDRs in `$6..`.
+// Everything else is counted and dropped, including DNS: the proxy resolves names itself. If
+// `$5` is a mark, loopback packets get it on the way out and the input hook only lets them
+// reach sockets of the same cgroup, so the guest's resolver and other sandboxes' listeners stay
+// out of reach. Replaces any previous rules for the sandbox and prints the address the guest
+// reaches the host proxy on.
+const EGRESS_SCRIPT: &str = r#"set -e
+id=$1; chain=$2; cg=$3; port=$4; mark=$5; shift 5
+proxy_ip=$(getent ahostsv4 host.lima.internal | awk 'NR == 1 { print $1 }')
+[ -n "$proxy_ip" ] || { echo "cannot resolve host.lima.internal" >&2; exit 1; }
+sudo nft add table inet crucible
+sudo nft add chain inet crucible output '{ type filter hook output priority 0; policy accept; }'
+sudo nft add chain inet crucible input '{ type filter hook input priority 0; policy accept; }'
+for hook in output input; do
+  for h in $(sudo nft -a list chain inet crucible "$hook" | awk -v id="$id" 'index($0, "comment \"" id "\"") { print $NF }'); do
+    sudo nft delete rule inet crucible "$hook" handle "$h"
+  done
+done
+sudo nft delete chain inet crucible "$chain" 2>/dev/null || true
+sudo nft add chain inet crucible "$chain"
+if [ -n "$mark" ]; then
+  sudo nft add rule inet crucible "$chain" oifname lo meta mark set "$mark" accept
+  sudo nft add rule inet crucible input iifname lo meta mark "$mark" socket cgroupv2 level 2 "\"$cg\"" accept comment "\"$id\""
+  sudo nft add rule inet crucible input iifname lo meta mark "$mark" drop comment "\"$id\""
+fi
+sudo nft add rule inet crucible "$chain" ip daddr "$proxy_ip" tcp dport "$port" accept
+for cidr in "$@"; do
+  case $cidr in *:*) family=ip6 ;; *) family=ip ;; esac
+  sudo nft add rule inet crucible "$chain" "$family" daddr "$cidr" accept
+done
+sudo nft add rule inet crucible "$chain" counter drop
+sudo nft add rule inet crucible output socket cgroupv2 level 2 "\"$cg\"" jump "$chain" comment "\"$id\""
+echo "$proxy_ip""#;
+
+// Kills whatever is left in the sandbox cgroup, then removes it, the egress rules, the mount
+// and the disk image
+const DESTROY_SCRIPT: &str = r#"dir=$1; img=$2; cg=$3; id=$4; chain=$5
+if command -v nft >/dev/null; then
+  for hook in output input; do
+    for h in $(sudo nft -a list chain inet crucible "$hook" 2>/dev/null | awk -v id="$id" 'index($0, "comment \"" id "\"") { print $NF }'); do
+      sudo nft delete rule inet crucible "$hook" handle "$h"
+    done
+  done
+  sudo nft delete chain inet crucible "$chain" 2>/dev/null
+fi
+if [ -d "$cg" ]; then
+  echo 1 | sudo tee "$cg/cgroup.kill" >/dev/null 2>&1
+  for _ in 1 2 3 4 5 6 7 8 9 10; do sudo rmdir "$cg" 2>/dev/null && break; sleep 0.2; done
+fi
+if mountpoint -q "$dir"; then sudo umount -l "$dir"; fi
+rm -rf "$dir" && sudo rm -f "$img""#;
+
+/// cgroup v2 CPU accounting period; `cpu.max` grants `vcpu` periods' worth of runtime per period
+const CPU_PERIOD_US: u64 = 100_000;
+/// Free space below which a size-capped working directory counts as full
+const DISK_FULL_KB: u64 = 1024;
+
+/// Guest system directories that make up the read-only base image root
+const ROOTFS_DIRS: &[&str] = &["/usr", "/bin", "/sbin", "/lib", "/lib32", "/lib64", "/etc", "/opt"];
+
+/// Resolve an exec's `cwd` inside the sandbox root as sandboxed code sees it. Relative paths
+/// are taken from the root; absolute ones must already lie under it. `..` is never allowed.
+fn exec_workdir(root: &Path, cwd: &Path) -> Result<PathBuf> {
+    let escapes = || io::Error::new(io::ErrorKind::PermissionDenied, format!("cwd escapes the sandbox: {}", cwd.display()));
+    let relative = match cwd.is_absolute() {
+        true => cwd.strip_prefix(root).map_err(|_| escapes())?,
+        false => cwd,
+    };
+    let mut workdir = root.to_path_buf();
+    for component in relative.components() {
+        match component {
+            Component::Normal(part) => workdir.push(part),
+            Component::CurDir => {}
+            _ => return Err(escapes().into()),
         }
     }
+    Ok(workdir)
+}
+
+/// bwrap filesystem layout: the guest's system directories read-only, fresh /dev, /proc and
+/// /tmp, and only this sandbox's own directory writable, at `root`. Nothing else of the guest
+/// (other sandboxes, home directories, the daemon's state) is visible. The caller remounts `/`
+/// read-only once all mount points exist.
+fn rootfs_args(sandbox_dir: &str, root: &Path) -> Vec<String> {
+    let mut args = Vec::new();
+    for dir in ROOTFS_DIRS {
+        // `-try`: merged-/usr guests have symlinks here, others may lack e.g. /lib32
+        args.extend(["--ro-bind-try".to_string(), dir.to_string(), dir.to_string()]);
+    }
+    args.extend([
+        // /etc/resolv.conf usually points here
+        "--ro-bind-try".to_string(), "/run/systemd/resolve".to_string(), "/run/systemd/resolve".to_string(),
+        "--dev".to_string(), "/dev".to_string(),
+        "--proc".to_string(), "/proc".to_string(),
+        "--tmpfs".to_string(), "/tmp".to_string(),
+        "--bind".to_string(), sandbox_dir.to_string(), root.display().to_string(),
+    ]);
+    args
+}
+
+fn seccomp_filter_path(profile: SeccompProfile) -> String {
+    format!("/var/lib/crucible/seccomp/{}.bpf", profile.name())
+}
+
+fn sandbox_cgroup(id: &SandboxId) -> String {
+    format!("/sys/fs/cgroup/crucible/{}", id)
+}
+
+fn egress_chain(id: &SandboxId) -> String {
+    format!("sandbox_{}", id.replace('-', "_"))
+}
+
+/// Packet mark of the sandbox's loopback traffic; never zero, which is "no mark"
+fn loopback_mark(id: &SandboxId) -> u32 {
+    let digest = Sha256::digest(id.as_bytes());
+    u32::from_be_bytes([digest[0], digest[1], digest[2], digest[3]]) | 1
+}
+
+fn sandbox_disk_image(id: &SandboxId) -> String {
+    format!("/var/lib/crucible/disks/{}.img", id)
+}
+
+/// Per-process rlimits used when the guest has no cgroup v2. Weaker: RLIMIT_NPROC counts all
+/// processes of the guest user and RLIMIT_AS caps address space rather than resident memory.
+fn prlimit_args(limits: &ResourceLimits) -> Vec<String> {
+    let mut args = vec!["prlimit".to_string()];
+    if limits.pids_max > 0 {
+        args.push(format!("--nproc={}", limits.pids_max));
+    }
+    if limits.memory_mb > 0 {
+        args.push(format!("--as={}", limits.memory_mb * 1024 * 1024));
+    }
+    args
+}
+
+/// Build the argv handed to `limactl shell`. Lima shell-quotes every element before the guest
+/// login shell evaluates it, so each element (including hostile ones) reaches bwrap unchanged.
+/// A non-empty `session_file` runs the exec in its own audit session (EXEC_SESSION_SCRIPT).
+fn exec_argv(
+    session_file: &str,
+    pidfile: &str,
+    backstop_secs: u64,
+    cgroup: &str,
+    seccomp_filter: &str,
+    bwrap_args: Vec<String>,
+    argv: &[String],
+) -> Vec<String> {
+    let mut guest_argv = Vec::new();
+    if !session_file.is_empty() {
+        guest_argv.extend([
+            "sudo".to_string(),
+            "-n".to_string(),
+            "-E".to_string(),
+            "sh".to_string(),
+            "-c".to_string(),
+            EXEC_SESSION_SCRIPT.to_string(),
+            "sh".to_string(),
+            session_file.to_string(),
+        ]);
+    }
+    guest_argv.extend([
+        "sh".to_string(),
+        "-c".to_string(),
+        EXEC_WRAPPER_SCRIPT.to_string(),
+        "sh".to_string(),
+        pidfile.to_string(),
+        backstop_secs.to_string(),
+        cgroup.to_string(),
+        seccomp_filter.to_string(),
+    ]);
+    guest_argv.extend(bwrap_args);
+    guest_argv.push("--".to_string());
+    guest_argv.extend(argv.iter().cloned());
+    guest_argv
+}
+
+fn exec_pidfile(exec_id: &str) -> String {
+    format!("/tmp/crucible_exec_{}.pid", exec_id)
+}
+
+fn exec_session_file(exec_id: &str) -> String {
+    format!("/tmp/crucible_exec_{}.ses", exec_id)
+}
+
+/// The sandbox's working directory in the guest, outside bwrap
+fn sandbox_dir(id: &SandboxId) -> String {
+    format!("/tmp/crucible_sandbox_{}", id)
+}
+
+/// Resolve a guest path relative to the sandbox directory, refusing to escape it lexically.
+/// Symlinks are only resolved in the guest, by the file scripts.
+fn sandbox_path(id: &SandboxId, guest_path: &Path) -> Result<String> {
+    let mut full_path = PathBuf::from(sandbox_dir(id));
+    for component in guest_path.components() {
+        match component {
+            Component::Normal(part) => full_path.push(part),
+            Component::RootDir | Component::CurDir => {}
+            Component::ParentDir | Component::Prefix(_) => {
+                let message = format!("Path escapes the sandbox: {}", guest_path.display());
+                return Err(io::Error::new(io::ErrorKind::PermissionDenied, message).into());
+            }
+        }
+    }
+    Ok(full_path.display().to_string())
+}
+
+/// Parse NUL-terminated `find -printf '%y %s %T@ %f\0'` records.
+fn parse_find_entries(raw: &[u8]) -> Vec<DirEntry> {
+    raw.split(|b| *b == 0)
+        .filter(|record| !record.is_empty())
+        .filter_map(|record| {
+            let record = String::from_utf8_lossy(record);
+            let mut fields = record.splitn(4, ' ');
+            let kind = fields.next()?;
+            let size_bytes = fields.next()?.parse().ok()?;
+            let mtime: f64 = fields.next()?.parse().ok()?;
+            let name = fields.next()?.to_string();
+            Some(DirEntry {
+                name,
+                is_dir: kind == "d",
+                size_bytes,
+                modified_at: UNIX_EPOCH + Duration::from_secs_f64(mtime.max(0.0)),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    const HOSTILE: &[&str] = &[
+        "a b",
+        "'single'",
+        "\"double\"",
+        "; touch /tmp/crucible_pwned",
+        "$(touch /tmp/crucible_pwned)",
+        "`touch /tmp/crucible_pwned`",
+        "$HOME",
+        "line\nbreak",
+        "*",
+        "",
+        "--",
+        "\\",
+        "&& exit 1 |",
+    ];
+
+    /// POSIX single-quoting, as Lima applies to each `limactl shell` argument
+    fn lima_quote(arg: &str) -> String {
+        format!("'{}'", arg.replace('\'', r#"'\''"#))
+    }
+
+    fn hostile_argv() -> Vec<String> {
+        let mut argv = vec!["printf".to_string(), "%s\\0".to_string()];
+        argv.extend(HOSTILE.iter().map(|a| a.to_string()));
+        argv
+    }
+
+    #[test]
+    fn exec_argv_keeps_hostile_elements_intact() {
+        let argv = hostile_argv();
+        let guest_argv = exec_argv("", "/tmp/pid", 5, "", "", vec!["bwrap".into(), "--unshare-pid".into()], &argv);
+
+        let sep = guest_argv.iter().position(|a| a == "--").unwrap();
+        assert_eq!(&guest_argv[sep + 1..], argv.as_slice());
+        assert_eq!(guest_argv[2], EXEC_WRAPPER_SCRIPT);
+    }
+
+    #[test]
+    fn hostile_argv_survives_guest_shell() {
+        let pidfile = std::env::temp_dir().join(format!("crucible_test_{}.pid", uuid::Uuid::new_v4()));
+        // `env` stands in for bwrap: it treats `--` as end of options and execs the rest
+        let guest_argv = exec_argv("", &pidfile.display().to_string(), 5, "", "", vec!["env".into()], &hostile_argv());
+
+        // Reproduce Lima's transport: quote each element, let a shell evaluate the line
+        let line: Vec<String> = guest_argv.iter().map(|a| lima_quote(a)).collect();
+        let output = std::process::Command::new("sh").arg("-c").arg(line.join(" ")).output().unwrap();
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+
+        let received: Vec<&[u8]> = output.stdout.split(|b| *b == 0).collect();
+        let expected: Vec<&[u8]> = HOSTILE.iter().map(|a| a.as_bytes()).chain([&b""[..]]).collect();
+        assert_eq!(received, expected);
+        assert!(!Path::new("/tmp/crucible_pwned").exists());
+        assert!(!pidfile.exists());
+    }
+
+    #[test]
+    fn env_values_are_not_interpreted() {
+        let pidfile = std::env::temp_dir().join(format!("crucible_test_{}.pid", uuid::Uuid::new_v4()));
+        let argv = vec!["X=$(id); `id` 'q'".to_string(), "printenv".to_string(), "X".to_string()];
+        let guest_argv = exec_argv("", &pidfile.display().to_string(), 5, "", "", vec!["env".into()], &argv);
+
+        let line: Vec<String> = guest_argv.iter().map(|a| lima_quote(a)).collect();
+        let output = std::process::Command::new("sh").arg("-c").arg(line.join(" ")).output().unwrap();
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "$(id); `id` 'q'\n");
+    }
+
+    #[test]
+    fn guest_backstop_kills_runaway_execs() {
+        let pidfile = std::env::temp_dir().join(format!("crucible_test_{}.pid", uuid::Uuid::new_v4()));
+        let argv = vec!["sh".to_string(), "-c".to_string(), "while true; do sleep 1; done".to_string()];
+        let guest_argv = exec_argv("", &pidfile.display().to_string(), 1, "", "", vec!["env".into()], &argv);
+
+        let line: Vec<String> = guest_argv.iter().map(|a| lima_quote(a)).collect();
+        let started = std::time::Instant::now();
+        let status = std::process::Command::new("sh").arg("-c").arg(line.join(" ")).status().unwrap();
+        assert_eq!(status.code(), Some(128 + 9));
+        assert!(started.elapsed() < Duration::from_secs(10));
+        assert!(!pidfile.exists());
+    }
+
+    const TRAILER: &str = "5b0d6bd1a4f0";
+
+    /// Run a file script the way the guest does, returning its exit code
+    fn run_file_script(script: &str, args: &[&Path], overwrite: bool, input: &[u8]) -> i32 {
+        use std::io::Write;
+        let mut child = std::process::Command::new("sh")
+            .arg("-c").arg(script).arg("sh").args(args).arg(if overwrite { "1" } else { "0" }).arg(TRAILER)
+            .stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::null())
+            .spawn()
+            .unwrap();
+        let _ = child.stdin.take().unwrap().write_all(input);
+        child.wait().unwrap().code().unwrap()
+    }
+
+    /// Upload `data` with PUT_FILE_SCRIPT, complete with its trailer
+    fn put(root: &Path, path: &Path, overwrite: bool, data: &[u8]) -> i32 {
+        run_file_script(PUT_FILE_SCRIPT, &[root, path], overwrite, &[data, TRAILER.as_bytes()].concat())
+    }
+
+    #[test]
+    fn file_scripts_refuse_planted_symlinks() {
+        let dir = TempDir::new("links");
+        let (root, outside) = (dir.join("sandbox"), dir.join("outside"));
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::create_dir_all(&outside).unwrap();
+        std::fs::write(outside.join("secret"), b"host").unwrap();
+        std::os::unix::fs::symlink(&outside, root.join("dir")).unwrap();
+        std::os::unix::fs::symlink(outside.join("secret"), root.join("file")).unwrap();
+        std::os::unix::fs::symlink(outside.join("new"), root.join("dangling")).unwrap();
+
+        for path in [root.join("dir/secret"), root.join("file")] {
+            assert_eq!(run_file_script(GET_FILE_SCRIPT, &[&root, &path], false, b""), EXIT_ESCAPES);
+            assert_eq!(put(&root, &path, true, b"sandbox"), EXIT_ESCAPES);
+        }
+        for path in [root.join("dir/new"), root.join("dir/sub/new"), root.join("dangling")] {
+            assert_eq!(put(&root, &path, false, b"sandbox"), EXIT_ESCAPES);
+        }
+        assert_eq!(run_file_script(LIST_DIR_SCRIPT, &[&root, &root.join("dir")], false, b""), EXIT_ESCAPES);
+        assert_eq!(std::fs::read(outside.join("secret")).unwrap(), b"host");
+        assert!(!outside.join("new").exists() && !outside.join("sub").exists());
+
+        // Paths that stay inside still work
+        let inside = root.join("data/x.csv");
+        assert_eq!(put(&root, &inside, false, b"1,2"), 0);
+        assert_eq!(std::fs::read(&inside).unwrap(), b"1,2");
+        assert_eq!(run_file_script(GET_FILE_SCRIPT, &[&root, &inside], false, b""), 0);
+        assert_eq!(run_file_script(LIST_DIR_SCRIPT, &[&root, &root], false, b""), 0);
+    }
+
+    #[test]
+    fn put_file_script_only_keeps_complete_uploads() {
+        let dir = TempDir::new("put");
+        let root = dir.join("sandbox");
+        std::fs::create_dir_all(&root).unwrap();
+        let target = root.join("data/sub/x.csv");
+        let leftovers = || std::fs::read_dir(root.join("data/sub")).unwrap()
+            .filter(|e| e.as_ref().unwrap().file_name().to_string_lossy().starts_with(".crucible-put"))
+            .count();
+
+        assert_eq!(put(&root, &target, false, b"1,2"), 0);
+        assert_eq!(std::fs::read(&target).unwrap(), b"1,2");
+
+        // Taken names are refused without overwrite and replaced with it
+        assert_eq!(put(&root, &target, false, b"3,4"), EXIT_ALREADY_EXISTS);
+        assert_eq!(put(&root, &target, true, b"3,4"), 0);
+        assert_eq!(std::fs::read(&target).unwrap(), b"3,4");
+        assert_eq!(put(&root, &root.join("empty"), false, b""), 0);
+        assert_eq!(std::fs::read(root.join("empty")).unwrap(), b"");
+
+        // An upload that never got its trailer leaves the old file alone
+        assert_ne!(run_file_script(PUT_FILE_SCRIPT, &[&root, &target], true, b"5,"), 0);
+        assert_ne!(run_file_script(PUT_FILE_SCRIPT, &[&root, &root.join("data/sub/y.csv")], false, b""), 0);
+        assert_eq!(std::fs::read(&target).unwrap(), b"3,4");
+        assert!(!root.join("data/sub/y.csv").exists());
+        assert_eq!(leftovers(), 0);
+
+        // A symlink at the name is followed for the check, so one pointing out is refused
+        let outside = dir.join("outside");
+        std::fs::write(&outside, b"host").unwrap();
+        std::os::unix::fs::symlink(&outside, root.join("data/link")).unwrap();
+        assert_eq!(put(&root, &root.join("data/link"), false, b"x"), EXIT_ESCAPES);
+        assert_eq!(std::fs::read(&outside).unwrap(), b"host");
+    }
+
+    #[test]
+    fn exec_workdir_stays_inside_the_root() {
+        let root = Path::new("/workspace");
+        assert_eq!(exec_workdir(root, Path::new("src/./app")).unwrap(), Path::new("/workspace/src/app"));
+        assert_eq!(exec_workdir(root, Path::new("/workspace/src")).unwrap(), Path::new("/workspace/src"));
+        assert_eq!(exec_workdir(root, Path::new("/workspace")).unwrap(), root);
+        assert!(exec_workdir(root, Path::new("/etc")).is_err());
+        assert!(exec_workdir(root, Path::new("/workspace2")).is_err());
+        assert!(exec_workdir(root, Path::new("..")).is_err());
+        assert!(exec_workdir(root, Path::new("src/../../tmp")).is_err());
+        assert!(exec_workdir(root, Path::new("/workspace/../etc")).is_err());
+    }
+
+    #[test]
+    fn sandbox_path_rejects_escapes() {
+        let id = "abc".to_string();
+        assert_eq!(sandbox_path(&id, Path::new("/data/x.csv")).unwrap(), "/tmp/crucible_sandbox_abc/data/x.csv");
+        assert!(sandbox_path(&id, Path::new("../other/x")).is_err());
+        assert!(sandbox_path(&id, Path::new("/data/../../etc/passwd")).is_err());
+    }
 }
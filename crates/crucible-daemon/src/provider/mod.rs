@@ -1,8 +1,14 @@
+pub mod agent;
+pub mod delta;
+pub mod firecracker;
+pub mod krunvm;
 pub mod lima;
 
+use crate::policy::seccomp::SeccompProfile;
 use async_trait::async_trait;
-use std::path::PathBuf;
-use std::time::Duration;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite};
 
 pub type SandboxId = String;
 pub type SnapshotId = String;
@@ -13,15 +19,27 @@ pub struct ResourceLimits {
     pub vcpu: u32,
     pub memory_mb: u64,
     pub disk_mb: u64,
+    pub pids_max: u32,
     pub sandbox_ttl: Option<Duration>,
     pub idle_ttl: Option<Duration>,
 }
 
+/// Process cap applied when a spec leaves `pids_max` unset, so a fork bomb can't exhaust the worker
+pub const DEFAULT_PIDS_MAX: u32 = 1024;
+
+/// Limits for a sandbox whose spec sets none
+impl Default for ResourceLimits {
+    fn default() -> Self {
+        Self { vcpu: 1, memory_mb: 2048, disk_mb: 2048, pids_max: DEFAULT_PIDS_MAX, sandbox_ttl: None, idle_ttl: None }
+    }
+}
+
 #[derive(Clone)]
 pub struct NetworkPolicy {
     pub deny_all: bool,
     pub allow_domains: Vec<String>,
     pub allow_cidrs: Vec<String>,
+    pub allow_loopback: bool,
 }
 
 #[derive(Clone)]
@@ -35,10 +53,24 @@ pub struct MountSpec {
 pub struct SandboxPolicy {
     pub network: NetworkPolicy,
     pub mounts: Vec<MountSpec>,
+    pub seccomp: SeccompProfile,
     pub enable_gpu: bool,
     pub enable_snapshotting: bool,
 }
 
+/// Where the sandbox working directory appears to sandboxed code when the spec sets none
+pub const DEFAULT_WORKING_DIR: &str = "/workspace";
+
+/// Path of the sandbox working directory as seen by sandboxed code: `working_dir` if it is
+/// absolute, otherwise `DEFAULT_WORKING_DIR`
+pub fn sandbox_root(working_dir: &Path) -> PathBuf {
+    if working_dir.is_absolute() {
+        working_dir.to_path_buf()
+    } else {
+        PathBuf::from(DEFAULT_WORKING_DIR)
+    }
+}
+
 #[derive(Clone)]
 pub struct SandboxSpec {
     pub base_image: String,
@@ -48,6 +80,7 @@ pub struct SandboxSpec {
 }
 
 pub struct ExecSpec {
+    pub exec_id: ExecId,
     pub argv: Vec<String>,
     pub env: Vec<(String, String)>,
     pub cwd: Option<PathBuf>,
@@ -57,12 +90,105 @@ pub struct ExecSpec {
 pub struct ExecResult {
     pub exec_id: ExecId,
     pub exit_code: i32,
+    /// The provider killed the process tree because `ExecSpec::timeout` elapsed
+    pub timed_out: bool,
+    pub violations: Vec<Violation>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ViolationKind {
+    ResourceLimit,
+    EgressBlocked,
+    MountDenied,
+    SyscallDenied,
+}
+
+/// A sandbox policy or limit that the exec ran into
+pub struct Violation {
+    pub kind: ViolationKind,
+    pub message: String,
+    pub ts: SystemTime,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum OutputStream {
+    Stdout,
+    Stderr,
+}
+
+/// A piece of process output, forwarded as soon as the provider reads it
+pub struct OutputChunk {
+    pub stream: OutputStream,
+    pub data: Vec<u8>,
+    pub ts: SystemTime,
+}
+
+pub type OutputSink = tokio::sync::mpsc::Sender<OutputChunk>;
+
+/// Forward a child pipe to the output sink until EOF. Keeps reading after the sink
+/// closes so the process never blocks on a full pipe.
+pub(crate) async fn pump_output(mut pipe: impl AsyncRead + Unpin, stream: OutputStream, output: OutputSink) {
+    let mut buf = vec![0u8; 16 * 1024];
+    let mut forwarding = true;
+    loop {
+        match pipe.read(&mut buf).await {
+            Ok(0) | Err(_) => break,
+            Ok(n) => {
+                if forwarding {
+                    let chunk = OutputChunk { stream, data: buf[..n].to_vec(), ts: SystemTime::now() };
+                    forwarding = output.send(chunk).await.is_ok();
+                }
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SnapshotMode {
+    /// Memory, device state and a copy of the disk
+    Full,
+    /// Memory and device state only; restores reuse the source sandbox's disk
+    MemoryOnly,
+}
+
+/// An ancestor of a snapshot being restored, checked out to `dir`
+pub struct SnapshotRef {
+    pub snapshot_id: SnapshotId,
+    pub dir: PathBuf,
+}
+
+/// One part of a snapshot (memory, disk, ...) as the provider stored it
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+pub struct SnapshotComponent {
+    pub name: String,
+    /// Bytes actually stored, which for deltas is far less than the part's full size
+    pub size_bytes: u64,
+    /// Ancestor this part is stored as a delta against; None if stored whole
+    pub delta_of: Option<SnapshotId>,
 }
 
 pub struct SnapshotMeta {
     pub snapshot_id: SnapshotId,
     pub sandbox_id: SandboxId,
     pub size_bytes: u64,
+    pub components: Vec<SnapshotComponent>,
+}
+
+pub struct DirEntry {
+    pub name: String,
+    pub is_dir: bool,
+    pub size_bytes: u64,
+    pub modified_at: SystemTime,
+}
+
+/// Point-in-time resource usage of a sandbox. Fields a provider can't measure stay zero.
+#[derive(Default)]
+pub struct SandboxUsage {
+    pub cpu_percent: f64,
+    pub memory_mb: u64,
+    pub disk_mb: u64,
+    pub net_rx_bytes: u64,
+    pub net_tx_bytes: u64,
 }
 
 pub struct ProviderHealth {
@@ -79,47 +205,81 @@ pub trait SandboxProvider: Send + Sync {
     async fn probe(&self) -> anyhow::Result<ProviderHealth>;
 
     // --- Lifecycle ---
-    async fn list_sandboxes(&self) -> anyhow::Result<Vec<(SandboxId, SandboxSpec)>>;
-    async fn create_sandbox(&self, spec: SandboxSpec) -> anyhow::Result<SandboxId>;
+    // Sandboxes that actually exist in the backend, including ones left by an earlier daemon process.
+    async fn list_sandboxes(&self) -> anyhow::Result<Vec<SandboxId>>;
+    async fn create_sandbox(&self, id: &SandboxId, spec: SandboxSpec) -> anyhow::Result<()>;
+    // Re-attach a sandbox that survived a daemon restart, using the spec it was created with.
+    // Returns whether it is still up; one that isn't stays down until start_sandbox.
+    async fn adopt_sandbox(&self, id: &SandboxId, spec: SandboxSpec) -> anyhow::Result<bool>;
+    async fn usage(&self, id: &SandboxId) -> anyhow::Result<SandboxUsage>;
     async fn start_sandbox(&self, id: &SandboxId) -> anyhow::Result<()>;
     async fn stop_sandbox(&self, id: &SandboxId, force: bool) -> anyhow::Result<()>;
+    // Whether stop_sandbox releases anything. Providers whose sandboxes hold nothing beyond
+    // their files keep every resource until destroy_sandbox.
+    fn stop_reclaims(&self) -> bool {
+        true
+    }
     async fn destroy_sandbox(&self, id: &SandboxId, force: bool) -> anyhow::Result<()>;
 
     // --- Execution ---
-    async fn exec(
+    // Output chunks are sent to `output` while the process runs; the returned result is final.
+    // Providers must keep draining the process even if the receiving end has gone away.
+    async fn exec_stream(
         &self,
         id: &SandboxId,
         spec: ExecSpec,
+        output: OutputSink,
     ) -> anyhow::Result<ExecResult>;
 
+    // Kill a running exec and everything it spawned. Must be a no-op for unknown or finished execs.
+    async fn cancel_exec(&self, id: &SandboxId, exec_id: &ExecId) -> anyhow::Result<()>;
+
     // --- Snapshot ---
+    // Snapshot files are written under `dst_path`, which the store commits once this returns.
+    // `ancestors` (nearest first) are the snapshots this one descends from; a provider may store
+    // parts as deltas against them, recording so in `SnapshotComponent::delta_of`. Restores get
+    // the ancestors those deltas need checked out; the checkout is removed once they return.
     async fn create_snapshot(
         &self, 
         id: &SandboxId, 
-        dst_path: &std::path::Path
+        dst_path: &std::path::Path,
+        mode: SnapshotMode,
+        ancestors: &[SnapshotId],
     ) -> anyhow::Result<SnapshotMeta>;
 
     async fn restore_snapshot(
         &self,
         snapshot_id: &SnapshotId,
         new_sandbox_id: &SandboxId,
-        snapshot_dir: &std::path::Path
+        snapshot_dir: &std::path::Path,
+        ancestors: &[SnapshotRef],
     ) -> anyhow::Result<()>;
 
     async fn delete_snapshot(&self, snapshot_id: &SnapshotId)
         -> anyhow::Result<()>;
 
     // --- Files ---
+    // File transfers are streamed so that large datasets never have to be held in memory.
+    // Missing paths should surface as `std::io::ErrorKind::NotFound` and refused overwrites
+    // as `AlreadyExists` so the gRPC layer can map them to proper status codes.
     async fn put_file(
         &self,
         id: &SandboxId,
         guest_path: PathBuf,
-        content: Vec<u8>,
-    ) -> anyhow::Result<()>;
+        content: &mut (dyn AsyncRead + Send + Unpin),
+        overwrite: bool,
+    ) -> anyhow::Result<u64>;
 
     async fn get_file(
         &self,
         id: &SandboxId,
         guest_path: PathBuf,
-    ) -> anyhow::Result<Vec<u8>>;
+        sink: &mut (dyn AsyncWrite + Send + Unpin),
+    ) -> anyhow::Result<u64>;
+
+    async fn list_dir(
+        &self,
+        id: &SandboxId,
+        guest_path: PathBuf,
+    ) -> anyhow::Result<Vec<DirEntry>>;
 }
@@ -1,20 +1,529 @@
+use crate::config::ExecConfig;
+use crate::db::{ArtifactRecord, Db, ExecRecord, ViolationRecord};
 use crate::pb::execution_server::Execution;
 use crate::pb::{
-    CancelExecRequest, ExecRequest, ExecResult, ExecStreamResponse, FollowOutputRequest, GetExecRequest,
-    ListExecsRequest, ListExecsResponse, OutputChunk, ExecState
+    exec_stream_response, output_chunk, ArtifactKind, CancelExecRequest, ExecRequest, ExecResult,
+    ExecSpec, ExecState, ExecStreamResponse, FollowOutputRequest, GetExecRequest,
+    ListExecsRequest, ListExecsResponse, OutputChunk, PolicyViolation, policy_violation,
 };
-use crate::provider::{SandboxProvider, ExecSpec as ProviderExecSpec};
-use std::sync::Arc;
+use crate::provider::{
+    ExecId, ExecSpec as ProviderExecSpec, OutputChunk as ProviderChunk, OutputStream, SandboxProvider,
+    Violation, ViolationKind,
+};
+use crate::server::lifecycle::Lifecycle;
+use crate::server::output::{CapturedOutput, ExecOutputs, OutputCapture, OutputLog};
+use crate::server::{to_timestamp, PageWindow};
+use crate::store::ArtifactStore;
+use chrono::Utc;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
+use tokio::io::AsyncReadExt;
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinHandle;
+use tokio_stream::wrappers::ReceiverStream;
 use tonic::{Request, Response, Status};
 
+/// Applied when a request leaves `timeout_ms` unset
+const DEFAULT_EXEC_TIMEOUT: Duration = Duration::from_secs(600);
+/// How long CancelExec waits for the exec to wind down before reporting its current state
+const CANCEL_WAIT: Duration = Duration::from_secs(10);
+/// Size of the chunks stored logs are replayed in
+const REPLAY_CHUNK_SIZE: usize = 64 * 1024;
+
+#[derive(Clone)]
 pub struct ExecutionService {
     provider: Arc<dyn SandboxProvider>,
+    db: Db,
+    artifacts: Arc<ArtifactStore>,
+    outputs: Arc<ExecOutputs>,
+    running: Arc<Mutex<HashMap<ExecId, Arc<RunningExec>>>>,
+    lifecycle: Arc<Lifecycle>,
+    config: Arc<ExecConfig>,
+}
+
+/// Handle to an exec running in the background
+struct StartedExec {
+    log: Arc<OutputLog>,
+    done: JoinHandle<Result<ExecResult, Status>>,
+}
+
+/// In-memory state of an exec that has not reached a terminal state yet
+struct RunningExec {
+    sandbox_id: String,
+    canceled: AtomicBool,
+    finished: watch::Sender<bool>,
 }
 
 impl ExecutionService {
-    pub fn new(provider: Arc<dyn SandboxProvider>) -> Self {
-        Self { provider }
+    pub fn new(
+        provider: Arc<dyn SandboxProvider>,
+        db: Db,
+        artifacts: Arc<ArtifactStore>,
+        lifecycle: Arc<Lifecycle>,
+        config: ExecConfig,
+    ) -> Self {
+        Self {
+            provider,
+            db,
+            artifacts,
+            outputs: Arc::new(ExecOutputs::default()),
+            running: Arc::new(Mutex::new(HashMap::new())),
+            lifecycle,
+            config: Arc::new(config),
+        }
+    }
+
+    /// Mark execs left QUEUED/RUNNING by a previous daemon process as failed
+    pub async fn recover(&self) -> anyhow::Result<()> {
+        let active = [ExecState::ExecQueued.as_str_name(), ExecState::ExecRunning.as_str_name()];
+        let failed = self.db.fail_interrupted_execs(ExecState::ExecFailed.as_str_name(), &active).await?;
+        if failed > 0 {
+            println!("Marked {} interrupted execs as failed", failed);
+        }
+        Ok(())
+    }
+
+    /// The argv to run: either the request's own, or its shell string behind the interpreter
+    fn command_argv(&self, spec: &mut ExecSpec) -> Vec<String> {
+        if spec.argv.is_empty() {
+            let mut argv = self.config.shell.clone();
+            argv.push(std::mem::take(&mut spec.shell));
+            argv
+        } else {
+            std::mem::take(&mut spec.argv)
+        }
+    }
+
+    /// Register an exec as QUEUED and launch it in a background task so it outlives the RPC
+    async fn start_exec(&self, mut spec: ExecSpec) -> Result<StartedExec, Status> {
+        let exec_id = uuid::Uuid::new_v4().to_string();
+        let argv = self.command_argv(&mut spec);
+
+        // Rejects execs on sandboxes that are stopped, failed or gone
+        self.lifecycle.begin_exec(&spec.sandbox_id).await?;
+        if let Err(e) = self.db.insert_exec(&exec_id, &spec.sandbox_id, ExecState::ExecQueued.as_str_name(), &argv).await {
+            self.lifecycle.end_exec(&spec.sandbox_id).await;
+            return Err(Status::internal(format!("DB error: {}", e)));
+        }
+
+        let log = self.outputs.register(&exec_id);
+        let running = Arc::new(RunningExec {
+            sandbox_id: spec.sandbox_id.clone(),
+            canceled: AtomicBool::new(false),
+            finished: watch::channel(false).0,
+        });
+        self.running.lock().unwrap().insert(exec_id.clone(), running.clone());
+
+        let service = self.clone();
+        let task_log = log.clone();
+        let done = tokio::spawn(async move {
+            let sandbox_id = spec.sandbox_id.clone();
+            let record = match service.run_exec(&exec_id, spec, argv, &task_log, &running).await {
+                Ok(record) => Ok(record),
+                Err(status) => {
+                    let mut record = service.persisted(&exec_id, &sandbox_id).await;
+                    record.state = ExecState::ExecFailed.as_str_name().to_string();
+                    record.finished_at = Some(Utc::now());
+                    record.last_error = Some(status.message().to_string());
+                    Err((record, status))
+                }
+            };
+
+            let stored = match &record {
+                Ok(record) | Err((record, _)) => service.db.finish_exec(record).await,
+            };
+            if let Err(e) = stored {
+                println!("Failed to record result of exec {}: {}", exec_id, e);
+            }
+
+            task_log.finish();
+            service.running.lock().unwrap().remove(&exec_id);
+            // Accounted before waiters are woken, so a cancel returns with the sandbox no longer RUNNING
+            service.lifecycle.end_exec(&sandbox_id).await;
+            running.finished.send_replace(true);
+            service.outputs.retire(exec_id);
+
+            record.map(exec_result).map_err(|(_, status)| status)
+        });
+
+        Ok(StartedExec { log, done })
+    }
+
+    /// The exec as inserted by `start_exec`, so results carry its argv and creation time
+    async fn persisted(&self, exec_id: &str, sandbox_id: &str) -> ExecRecord {
+        match self.db.get_exec(exec_id).await {
+            Ok(Some(record)) => record,
+            // The row is written before the exec task starts; this only stands in if it can't be read
+            _ => ExecRecord {
+                exec_id: exec_id.to_string(),
+                sandbox_id: sandbox_id.to_string(),
+                state: ExecState::ExecQueued.as_str_name().to_string(),
+                argv: "[]".to_string(),
+                exit_code: None,
+                created_at: Utc::now(),
+                started_at: None,
+                finished_at: None,
+                output_artifact_ids: "[]".to_string(),
+                stdout_preview: String::new(),
+                stderr_preview: String::new(),
+                violations: "[]".to_string(),
+                last_error: None,
+            },
+        }
+    }
+
+    async fn run_exec(
+        &self,
+        exec_id: &str,
+        spec: ExecSpec,
+        argv: Vec<String>,
+        log: &Arc<OutputLog>,
+        running: &RunningExec,
+    ) -> Result<ExecRecord, Status> {
+        let sandbox_id = spec.sandbox_id;
+        let mut record = self.persisted(exec_id, &sandbox_id).await;
+        record.state = ExecState::ExecCanceled.as_str_name().to_string();
+        record.finished_at = Some(Utc::now());
+
+        // Canceled while still queued: never start it
+        if running.canceled.load(Ordering::SeqCst) {
+            return Ok(record);
+        }
+
+        let provider_spec = ProviderExecSpec {
+            exec_id: exec_id.to_string(),
+            argv,
+            env: spec.env.into_iter().collect(),
+            cwd: if spec.cwd.is_empty() { None } else { Some(spec.cwd.into()) },
+            timeout: exec_timeout(spec.timeout_ms),
+        };
+
+        let mut stdout = self.open_capture().await?;
+        let mut stderr = self.open_capture().await?;
+
+        let started_at = Utc::now();
+        self.db.set_exec_running(exec_id, ExecState::ExecRunning.as_str_name(), started_at).await
+            .map_err(|e| Status::internal(format!("DB error: {}", e)))?;
+
+        // Every chunk is persisted to the log artifacts before being fanned out to followers
+        let (tx, mut rx) = mpsc::channel::<ProviderChunk>(64);
+        let pump_log = log.clone();
+        let pump_exec_id = exec_id.to_string();
+        let pump = tokio::spawn(async move {
+            while let Some(chunk) = rx.recv().await {
+                match chunk.stream {
+                    OutputStream::Stdout => stdout.record(&chunk.data).await,
+                    OutputStream::Stderr => stderr.record(&chunk.data).await,
+                }
+                pump_log.publish(to_proto_chunk(&pump_exec_id, chunk));
+            }
+            (stdout, stderr)
+        });
+
+        let result = self.provider.exec_stream(&sandbox_id, provider_spec, tx).await;
+        let (stdout, stderr) = pump.await
+            .map_err(|e| Status::internal(format!("Output capture failed: {}", e)))?;
+
+        let result = match result {
+            Ok(result) => result,
+            Err(e) => {
+                stdout.discard().await;
+                stderr.discard().await;
+                return Err(Status::internal(format!("Exec failed: {}", e)));
+            }
+        };
+        let finished_at = Utc::now();
+
+        let state = if running.canceled.load(Ordering::SeqCst) {
+            ExecState::ExecCanceled
+        } else if result.timed_out {
+            ExecState::ExecTimedOut
+        } else if result.exit_code == 0 {
+            ExecState::ExecSucceeded
+        } else {
+            ExecState::ExecFailed
+        };
+
+        record.state = state.as_str_name().to_string();
+        record.exit_code = Some(result.exit_code);
+        record.started_at = Some(started_at);
+        record.finished_at = Some(finished_at);
+        let violations: Vec<ViolationRecord> = result.violations.into_iter().map(violation_record).collect();
+        record.violations = serde_json::to_string(&violations).unwrap_or_else(|_| "[]".to_string());
+
+        // A log that can't be kept doesn't change how the exec ended; its id is left empty and
+        // the failure goes to last_error. Each stream is stored on its own so neither is lost to the other.
+        let mut artifact_ids = Vec::new();
+        let mut capture_errors = Vec::new();
+        let captures = [
+            (stdout, "stdout.log", &mut record.stdout_preview),
+            (stderr, "stderr.log", &mut record.stderr_preview),
+        ];
+        for (capture, filename, preview) in captures {
+            match self.store_capture(capture, filename, &sandbox_id, exec_id).await {
+                Ok(captured) => {
+                    artifact_ids.push(captured.artifact_id);
+                    *preview = captured.preview;
+                }
+                Err(status) => {
+                    artifact_ids.push(String::new());
+                    capture_errors.push(status.message().to_string());
+                }
+            }
+        }
+        record.output_artifact_ids = serde_json::to_string(&artifact_ids).unwrap_or_else(|_| "[]".to_string());
+        if !capture_errors.is_empty() {
+            record.last_error = Some(capture_errors.join("; "));
+        }
+        Ok(record)
+    }
+
+    /// Kill an exec if it is still in flight and wait (bounded) for it to be recorded
+    async fn cancel(&self, exec_id: &str) -> Result<(), Status> {
+        let running = self.running.lock().unwrap().get(exec_id).cloned();
+        if let Some(running) = running {
+            running.canceled.store(true, Ordering::SeqCst);
+            self.provider.cancel_exec(&running.sandbox_id, &exec_id.to_string()).await
+                .map_err(|e| Status::internal(format!("Cancel failed: {}", e)))?;
+
+            let mut finished = running.finished.subscribe();
+            let _ = tokio::time::timeout(CANCEL_WAIT, finished.wait_for(|done| *done)).await;
+        }
+        Ok(())
+    }
+
+    /// Cancel every exec still in flight in a sandbox
+    pub async fn cancel_sandbox_execs(&self, sandbox_id: &str) -> Result<(), Status> {
+        let exec_ids: Vec<ExecId> = self.running.lock().unwrap().iter()
+            .filter(|(_, running)| running.sandbox_id == sandbox_id)
+            .map(|(exec_id, _)| exec_id.clone())
+            .collect();
+        for exec_id in exec_ids {
+            self.cancel(&exec_id).await?;
+        }
+        Ok(())
+    }
+
+    async fn open_capture(&self) -> Result<OutputCapture, Status> {
+        let artifact_id = uuid::Uuid::new_v4().to_string();
+        let writer = self.artifacts.create(&artifact_id).await
+            .map_err(|e| Status::internal(format!("Failed to open log artifact: {}", e)))?;
+        Ok(OutputCapture::new(artifact_id, writer))
+    }
+
+    /// Stream the stored logs of an exec whose live output is no longer kept
+    async fn replay_output(
+        &self,
+        exec_id: &str,
+        filter: StreamFilter,
+    ) -> Result<ReceiverStream<Result<OutputChunk, Status>>, Status> {
+        let record = self.db.get_exec(exec_id).await
+            .map_err(|e| Status::internal(format!("DB error: {}", e)))?
+            .ok_or_else(|| Status::not_found(format!("No output for exec {}", exec_id)))?;
+        let artifact_ids: Vec<String> = serde_json::from_str(&record.output_artifact_ids).unwrap_or_default();
+
+        // Opened up front so missing logs fail the call instead of cutting the stream short
+        let mut logs = Vec::new();
+        let streams = [output_chunk::Stream::Stdout, output_chunk::Stream::Stderr];
+        for (stream, artifact_id) in streams.into_iter().zip(&artifact_ids) {
+            if artifact_id.is_empty() || !filter.wants(stream) {
+                continue;
+            }
+            let file = self.artifacts.open(artifact_id).await
+                .map_err(|e| Status::data_loss(format!("Log artifact {} missing: {}", artifact_id, e)))?;
+            logs.push((stream, file));
+        }
+
+        let exec_id = exec_id.to_string();
+        let ts = record.finished_at.map(to_timestamp);
+        let (tx, rx) = mpsc::channel(16);
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; REPLAY_CHUNK_SIZE];
+            for (stream, mut file) in logs {
+                loop {
+                    let n = match file.read(&mut buf).await {
+                        Ok(0) => break,
+                        Ok(n) => n,
+                        Err(e) => {
+                            let _ = tx.send(Err(Status::internal(format!("Log read failed: {}", e)))).await;
+                            return;
+                        }
+                    };
+                    let chunk = OutputChunk {
+                        exec_id: exec_id.clone(),
+                        stream: stream as i32,
+                        data: buf[..n].to_vec(),
+                        ts,
+                        skipped_chunks: 0,
+                    };
+                    if tx.send(Ok(chunk)).await.is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+
+        Ok(ReceiverStream::new(rx))
+    }
+
+    /// Finalize a captured stream and register it as an `ARTIFACT_LOG`
+    async fn store_capture(
+        &self,
+        capture: OutputCapture,
+        filename: &str,
+        sandbox_id: &str,
+        exec_id: &str,
+    ) -> Result<CapturedOutput, Status> {
+        let captured = capture.finish().await
+            .map_err(|e| Status::internal(format!("Failed to store {}: {}", filename, e)))?;
+
+        let registered = self.db.insert_artifact(&ArtifactRecord {
+            artifact_id: captured.artifact_id.clone(),
+            kind: ArtifactKind::ArtifactLog.as_str_name().to_string(),
+            filename: filename.to_string(),
+            mime_type: "text/plain".to_string(),
+            size_bytes: captured.size_bytes as i64,
+            sha256: captured.sha256.clone(),
+            sandbox_id: Some(sandbox_id.to_string()),
+            exec_id: Some(exec_id.to_string()),
+            created_at: chrono::Utc::now(),
+        }).await;
+        if let Err(e) = registered {
+            // Unregistered content would never be cleaned up
+            if let Err(e) = self.artifacts.remove(&captured.artifact_id).await {
+                println!("Failed to remove unregistered log artifact {}: {}", captured.artifact_id, e);
+            }
+            return Err(Status::internal(format!("DB error: {}", e)));
+        }
+
+        Ok(captured)
+    }
+}
+
+fn exec_result(record: ExecRecord) -> ExecResult {
+    ExecResult {
+        exec_id: record.exec_id,
+        sandbox_id: record.sandbox_id,
+        state: ExecState::from_str_name(&record.state).unwrap_or(ExecState::Unspecified) as i32,
+        exit_code: record.exit_code.unwrap_or_default(),
+        started_at: record.started_at.map(to_timestamp),
+        finished_at: record.finished_at.map(to_timestamp),
+        output_artifact_ids: serde_json::from_str(&record.output_artifact_ids).unwrap_or_default(),
+        stdout_preview: record.stdout_preview,
+        stderr_preview: record.stderr_preview,
+        violations: serde_json::from_str::<Vec<ViolationRecord>>(&record.violations)
+            .unwrap_or_default()
+            .into_iter()
+            .map(|v| PolicyViolation {
+                kind: policy_violation::Kind::from_str_name(&v.kind).unwrap_or(policy_violation::Kind::Unspecified) as i32,
+                message: v.message,
+                ts: Some(to_timestamp(v.ts)),
+                details: None,
+            })
+            .collect(),
+        last_error: record.last_error.unwrap_or_default(),
+    }
+}
+
+pub(crate) fn violation_kind(kind: ViolationKind) -> policy_violation::Kind {
+    match kind {
+        ViolationKind::ResourceLimit => policy_violation::Kind::ResourceLimit,
+        ViolationKind::EgressBlocked => policy_violation::Kind::EgressBlocked,
+        ViolationKind::MountDenied => policy_violation::Kind::MountDenied,
+        ViolationKind::SyscallDenied => policy_violation::Kind::SyscallDenied,
+    }
+}
+
+fn violation_record(violation: Violation) -> ViolationRecord {
+    ViolationRecord {
+        kind: violation_kind(violation.kind).as_str_name().to_string(),
+        message: violation.message,
+        ts: violation.ts.into(),
+    }
+}
+
+/// Reject specs that the provider could not pass through faithfully
+fn validate_spec(spec: &ExecSpec) -> Result<(), Status> {
+    if spec.sandbox_id.is_empty() {
+        return Err(Status::invalid_argument("sandbox_id is required"));
+    }
+    match (spec.argv.is_empty(), spec.shell.is_empty()) {
+        (false, false) => return Err(Status::invalid_argument("Set exactly one of argv or shell, not both")),
+        (true, true) => return Err(Status::invalid_argument("One of argv or shell is required")),
+        _ => {}
+    }
+    for (key, value) in &spec.env {
+        if key.is_empty() || key.contains('=') || key.contains('\0') || value.contains('\0') {
+            return Err(Status::invalid_argument(format!("Invalid environment variable: {:?}", key)));
+        }
+    }
+    Ok(())
+}
+
+fn exec_timeout(timeout_ms: u64) -> Duration {
+    if timeout_ms == 0 {
+        DEFAULT_EXEC_TIMEOUT
+    } else {
+        Duration::from_millis(timeout_ms)
+    }
+}
+
+fn to_proto_chunk(exec_id: &str, chunk: ProviderChunk) -> OutputChunk {
+    let stream = match chunk.stream {
+        OutputStream::Stdout => output_chunk::Stream::Stdout,
+        OutputStream::Stderr => output_chunk::Stream::Stderr,
+    };
+    OutputChunk {
+        exec_id: exec_id.to_string(),
+        stream: stream as i32,
+        data: chunk.data,
+        ts: Some(chunk.ts.into()),
+        skipped_chunks: 0,
+    }
+}
+
+/// Picks the streams a caller asked for out of an exec's output
+struct StreamFilter {
+    stdout: bool,
+    stderr: bool,
+    // Chunks skipped by the follower but not reported yet
+    skipped: u64,
+}
+
+impl StreamFilter {
+    fn new(stdout: bool, stderr: bool) -> Self {
+        Self { stdout, stderr, skipped: 0 }
+    }
+
+    /// Asking for neither stream means both
+    fn wants(&self, stream: output_chunk::Stream) -> bool {
+        if !self.stdout && !self.stderr {
+            return true;
+        }
+        match stream {
+            output_chunk::Stream::Stdout => self.stdout,
+            output_chunk::Stream::Stderr => self.stderr,
+            output_chunk::Stream::Unspecified => false,
+        }
+    }
+
+    /// The chunk to send, if wanted; gaps reported on dropped chunks carry over to the next one sent
+    fn pass(&mut self, mut chunk: OutputChunk) -> Option<OutputChunk> {
+        self.skipped += chunk.skipped_chunks;
+        if !self.wants(chunk.stream()) {
+            return None;
+        }
+        chunk.skipped_chunks = std::mem::take(&mut self.skipped);
+        Some(chunk)
+    }
+}
+
+async fn join_exec(done: JoinHandle<Result<ExecResult, Status>>) -> Result<ExecResult, Status> {
+    match done.await {
+        Ok(result) => result,
+        Err(e) => Err(Status::internal(format!("Exec task failed: {}", e))),
     }
 }
 
@@ -26,67 +535,314 @@ impl Execution for ExecutionService {
     ) -> Result<Response<ExecResult>, Status> {
         let req = request.into_inner();
         let spec = req.spec.ok_or_else(|| Status::invalid_argument("Missing exec spec"))?;
+        validate_spec(&spec)?;
 
-        let provider_spec = ProviderExecSpec {
-            argv: spec.argv,
-            env: spec.env.into_iter().collect(),
-            cwd: if spec.cwd.is_empty() { None } else { Some(spec.cwd.into()) },
-            timeout: Duration::from_millis(spec.timeout_ms.max(1)),
-        };
-
-        let result = self.provider.exec(&spec.sandbox_id, provider_spec).await
-            .map_err(|e| Status::internal(format!("Exec failed: {}", e)))?;
-
-        Ok(Response::new(ExecResult {
-            exec_id: result.exec_id,
-            sandbox_id: spec.sandbox_id,
-            state: ExecState::ExecSucceeded as i32,
-            exit_code: result.exit_code,
-            started_at: None,
-            finished_at: None,
-            output_artifact_ids: vec![],
-            stdout_preview: String::new(),
-            stderr_preview: String::new(),
-            violations: vec![],
-        }))
+        let started = self.start_exec(spec).await?;
+        Ok(Response::new(join_exec(started.done).await?))
     }
 
-    type ExecStreamStream = tonic::Streaming<ExecStreamResponse>;
+    type ExecStreamStream = ReceiverStream<Result<ExecStreamResponse, Status>>;
 
     async fn exec_stream(
         &self,
-        _request: Request<ExecRequest>,
+        request: Request<ExecRequest>,
     ) -> Result<Response<Self::ExecStreamStream>, Status> {
-         Err(Status::unimplemented("Not yet implemented"))
+        let req = request.into_inner();
+        let spec = req.spec.ok_or_else(|| Status::invalid_argument("Missing exec spec"))?;
+        validate_spec(&spec)?;
+        let (want_stdout, want_stderr) = (spec.stream_stdout, spec.stream_stderr);
+
+        let started = self.start_exec(spec).await?;
+        let mut follower = started.log.follow();
+        let mut filter = StreamFilter::new(want_stdout, want_stderr);
+
+        let (tx, rx) = mpsc::channel(16);
+        tokio::spawn(async move {
+            while let Some(chunk) = follower.next().await {
+                let Some(chunk) = filter.pass(chunk) else {
+                    continue;
+                };
+                let msg = ExecStreamResponse { payload: Some(exec_stream_response::Payload::Chunk(chunk)) };
+                if tx.send(Ok(msg)).await.is_err() {
+                    // Client went away; the exec keeps running and stays followable
+                    return;
+                }
+            }
+
+            let last = match join_exec(started.done).await {
+                Ok(result) => Ok(ExecStreamResponse { payload: Some(exec_stream_response::Payload::Final(result)) }),
+                Err(status) => Err(status),
+            };
+            let _ = tx.send(last).await;
+        });
+
+        Ok(Response::new(ReceiverStream::new(rx)))
     }
 
     async fn cancel_exec(
         &self,
-        _request: Request<CancelExecRequest>,
+        request: Request<CancelExecRequest>,
     ) -> Result<Response<ExecResult>, Status> {
-        Err(Status::unimplemented("Not yet implemented"))
+        let req = request.into_inner();
+        self.cancel(&req.exec_id).await?;
+
+        // Finished execs are returned unchanged; cancel is idempotent
+        let record = self.db.get_exec(&req.exec_id).await
+            .map_err(|e| Status::internal(format!("DB error: {}", e)))?
+            .ok_or_else(|| Status::not_found(format!("Exec {} not found", req.exec_id)))?;
+        Ok(Response::new(exec_result(record)))
     }
 
     async fn get_exec(
         &self,
-        _request: Request<GetExecRequest>,
+        request: Request<GetExecRequest>,
     ) -> Result<Response<ExecResult>, Status> {
-        Err(Status::unimplemented("Not yet implemented"))
+        let req = request.into_inner();
+        let record = self.db.get_exec(&req.exec_id).await
+            .map_err(|e| Status::internal(format!("DB error: {}", e)))?
+            .ok_or_else(|| Status::not_found(format!("Exec {} not found", req.exec_id)))?;
+        Ok(Response::new(exec_result(record)))
     }
 
     async fn list_execs(
         &self,
-        _request: Request<ListExecsRequest>,
+        request: Request<ListExecsRequest>,
     ) -> Result<Response<ListExecsResponse>, Status> {
-       Err(Status::unimplemented("Not yet implemented"))
+        let req = request.into_inner();
+        let window = PageWindow::from_paging(req.paging.as_ref())?;
+        let sandbox_id = if req.sandbox_id.is_empty() { None } else { Some(req.sandbox_id.as_str()) };
+
+        let mut records = self.db.list_execs(sandbox_id, window.fetch_limit(), window.offset).await
+            .map_err(|e| Status::internal(format!("DB error: {}", e)))?;
+        let page = window.finish(&mut records);
+
+        Ok(Response::new(ListExecsResponse {
+            execs: records.into_iter().map(exec_result).collect(),
+            page,
+        }))
     }
 
-    type FollowOutputStream = tonic::Streaming<OutputChunk>;
+    type FollowOutputStream = ReceiverStream<Result<OutputChunk, Status>>;
 
     async fn follow_output(
         &self,
-        _request: Request<FollowOutputRequest>,
+        request: Request<FollowOutputRequest>,
     ) -> Result<Response<Self::FollowOutputStream>, Status> {
-         Err(Status::unimplemented("Not yet implemented"))
+        let req = request.into_inner();
+        let mut filter = StreamFilter::new(req.stdout, req.stderr);
+        let Some(log) = self.outputs.get(&req.exec_id) else {
+            return self.replay_output(&req.exec_id, filter).await.map(Response::new);
+        };
+        let mut follower = log.follow();
+
+        let (tx, rx) = mpsc::channel(16);
+        tokio::spawn(async move {
+            while let Some(chunk) = follower.next().await {
+                if let Some(chunk) = filter.pass(chunk)
+                    && tx.send(Ok(chunk)).await.is_err()
+                {
+                    return;
+                }
+            }
+        });
+
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pb::{Paging, SandboxState};
+    use crate::server::lifecycle::USABLE;
+    use crate::testing::{self, FakeProvider, TempDir};
+    use tokio_stream::StreamExt;
+
+    /// An execution service over a READY sandbox "sb", as a daemon rooted in `dir` would build it
+    async fn service(dir: &TempDir, provider: Arc<FakeProvider>) -> ExecutionService {
+        let db = testing::db(dir).await;
+        if db.get_sandbox("sb").await.unwrap().is_none() {
+            db.insert_sandbox("sb", "fake", SandboxState::SandboxReady.as_str_name(), b"", &HashMap::new()).await.unwrap();
+        }
+        let artifacts = Arc::new(ArtifactStore::new(dir.join("artifacts")).await.unwrap());
+        let lifecycle = Arc::new(Lifecycle::new(db.clone()));
+        ExecutionService::new(provider, db, artifacts, lifecycle, ExecConfig::default())
+    }
+
+    fn spec(argv: &[&str]) -> ExecSpec {
+        ExecSpec {
+            sandbox_id: "sb".to_string(),
+            argv: argv.iter().map(|arg| arg.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+
+    async fn follow(service: &ExecutionService, exec_id: &str, stdout: bool, stderr: bool) -> Result<Vec<OutputChunk>, Status> {
+        let request = FollowOutputRequest { exec_id: exec_id.to_string(), stdout, stderr };
+        let stream = service.follow_output(Request::new(request)).await?.into_inner();
+        stream.collect::<Result<Vec<_>, _>>().await
+    }
+
+    async fn run(service: &ExecutionService, argv: &[&str]) -> ExecResult {
+        service.exec(Request::new(ExecRequest { spec: Some(spec(argv)) })).await.unwrap().into_inner()
+    }
+
+    async fn get(service: &ExecutionService, exec_id: &str) -> Result<ExecResult, Status> {
+        let request = GetExecRequest { exec_id: exec_id.to_string() };
+        Ok(service.get_exec(Request::new(request)).await?.into_inner())
+    }
+
+    async fn list(service: &ExecutionService, sandbox_id: &str, page_size: u32, page_token: &str) -> ListExecsResponse {
+        let paging = Paging { page_size, page_token: page_token.to_string() };
+        let request = ListExecsRequest { paging: Some(paging), sandbox_id: sandbox_id.to_string() };
+        service.list_execs(Request::new(request)).await.unwrap().into_inner()
+    }
+
+    /// Start an exec that runs until canceled and wait for it to be RUNNING
+    async fn start_hanging(service: &ExecutionService) -> (String, JoinHandle<Result<ExecResult, Status>>) {
+        let started = service.start_exec(spec(&["hang"])).await.unwrap();
+        let exec_id = loop {
+            let running = service.running.lock().unwrap().keys().next().cloned();
+            if let Some(exec_id) = running
+                && get(service, &exec_id).await.unwrap().state() == ExecState::ExecRunning
+            {
+                break exec_id;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        };
+        (exec_id, started.done)
+    }
+
+    fn chunk(stream: output_chunk::Stream, skipped_chunks: u64) -> OutputChunk {
+        OutputChunk { stream: stream as i32, skipped_chunks, ..Default::default() }
+    }
+
+    #[test]
+    fn specs_need_one_command_and_clean_env() {
+        assert!(validate_spec(&spec(&["true"])).is_ok());
+        assert!(validate_spec(&ExecSpec { shell: "true".to_string(), ..spec(&[]) }).is_ok());
+
+        let invalid = [
+            ExecSpec { sandbox_id: String::new(), ..spec(&["true"]) },
+            spec(&[]),
+            ExecSpec { shell: "true".to_string(), ..spec(&["true"]) },
+        ];
+        for bad_env in [("", "x"), ("A=B", "x"), ("A\0", "x"), ("A", "x\0y")] {
+            let env = HashMap::from([(bad_env.0.to_string(), bad_env.1.to_string())]);
+            let bad = ExecSpec { env, ..spec(&["true"]) };
+            assert_eq!(validate_spec(&bad).unwrap_err().code(), tonic::Code::InvalidArgument, "{:?}", bad_env);
+        }
+        for bad in invalid {
+            assert_eq!(validate_spec(&bad).unwrap_err().code(), tonic::Code::InvalidArgument);
+        }
+
+        // Values may hold anything but NUL
+        let env = HashMap::from([("X".to_string(), "a=b; $(id)\n".to_string())]);
+        assert!(validate_spec(&ExecSpec { env, ..spec(&["true"]) }).is_ok());
+    }
+
+    #[test]
+    fn unset_timeout_gets_the_default() {
+        assert_eq!(exec_timeout(0), DEFAULT_EXEC_TIMEOUT);
+        assert_eq!(exec_timeout(1500), Duration::from_millis(1500));
+    }
+
+    #[test]
+    fn gaps_are_reported_on_the_next_chunk_sent() {
+        use output_chunk::Stream::*;
+        let mut filter = StreamFilter::new(false, true);
+        assert!(filter.pass(chunk(Stdout, 3)).is_none());
+        assert!(filter.pass(chunk(Stdout, 0)).is_none());
+        assert_eq!(filter.pass(chunk(Stderr, 2)).unwrap().skipped_chunks, 5);
+        assert_eq!(filter.pass(chunk(Stderr, 0)).unwrap().skipped_chunks, 0);
+
+        let mut both = StreamFilter::new(false, false);
+        assert!(both.pass(chunk(Stdout, 0)).is_some() && both.pass(chunk(Stderr, 0)).is_some());
+    }
+
+    #[tokio::test]
+    async fn finished_execs_are_replayed_from_their_logs() {
+        let dir = TempDir::new("exec-replay");
+        let provider = FakeProvider::with_sandboxes(&[("sb", true)]);
+        let before = service(&dir, provider.clone()).await;
+        let result = before.exec(Request::new(ExecRequest { spec: Some(spec(&["echo", "hello"])) })).await.unwrap().into_inner();
+
+        // A restarted daemon no longer has the live output
+        let after = service(&dir, provider).await;
+        let chunks = follow(&after, &result.exec_id, false, false).await.unwrap();
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks[0].data, b"echo hello\n");
+        assert_eq!(chunks[0].stream(), output_chunk::Stream::Stdout);
+        assert_eq!(chunks[0].ts, result.finished_at);
+        assert!(follow(&after, &result.exec_id, false, true).await.unwrap().is_empty());
+
+        let missing = follow(&after, "nope", false, false).await.unwrap_err();
+        assert_eq!(missing.code(), tonic::Code::NotFound);
+    }
+
+    #[tokio::test]
+    async fn finished_execs_can_be_looked_up_and_listed() {
+        let dir = TempDir::new("exec-get");
+        let service = service(&dir, FakeProvider::with_sandboxes(&[("sb", true)])).await;
+        let ok = run(&service, &["echo", "hi"]).await;
+        let failed = run(&service, &["false"]).await;
+
+        let got = get(&service, &ok.exec_id).await.unwrap();
+        assert_eq!((got.state(), got.exit_code), (ExecState::ExecSucceeded, 0));
+        assert_eq!(got.stdout_preview, "echo hi\n");
+        assert_eq!(got.output_artifact_ids.len(), 2);
+        let got = get(&service, &failed.exec_id).await.unwrap();
+        assert_eq!((got.state(), got.exit_code), (ExecState::ExecFailed, 1));
+        assert_eq!(get(&service, "nope").await.unwrap_err().code(), tonic::Code::NotFound);
+
+        let first = list(&service, "sb", 1, "").await;
+        let next_page_token = first.page.unwrap().next_page_token;
+        let second = list(&service, "sb", 1, &next_page_token).await;
+        assert_eq!(second.page.unwrap().next_page_token, "");
+        let mut listed: Vec<_> = first.execs.iter().chain(&second.execs).map(|e| e.exec_id.clone()).collect();
+        listed.sort();
+        let mut expected = vec![ok.exec_id, failed.exec_id];
+        expected.sort();
+        assert_eq!(listed, expected);
+        assert!(list(&service, "other", 0, "").await.execs.is_empty());
+    }
+
+    #[tokio::test]
+    async fn cancel_stops_a_running_exec_and_is_idempotent() {
+        let dir = TempDir::new("exec-cancel");
+        let service = service(&dir, FakeProvider::with_sandboxes(&[("sb", true)])).await;
+        let (exec_id, done) = start_hanging(&service).await;
+
+        let cancel = || service.cancel_exec(Request::new(CancelExecRequest { exec_id: exec_id.clone() }));
+        let canceled = cancel().await.unwrap().into_inner();
+        assert_eq!((canceled.state(), canceled.exit_code), (ExecState::ExecCanceled, 137));
+        assert_eq!(join_exec(done).await.unwrap().state(), ExecState::ExecCanceled);
+        assert_eq!(cancel().await.unwrap().into_inner(), canceled);
+        let sandbox = service.lifecycle.require("sb", USABLE).await.unwrap();
+        assert_eq!(sandbox.state, SandboxState::SandboxIdle.as_str_name());
+
+        let request = CancelExecRequest { exec_id: "nope".to_string() };
+        assert_eq!(service.cancel_exec(Request::new(request)).await.unwrap_err().code(), tonic::Code::NotFound);
+    }
+
+    #[tokio::test]
+    async fn a_lost_log_does_not_change_how_the_exec_ended() {
+        let dir = TempDir::new("exec-lost-log");
+        let service = service(&dir, FakeProvider::with_sandboxes(&[("sb", true)])).await;
+        let (exec_id, done) = start_hanging(&service).await;
+
+        // Lose one of the two logs being written
+        let lost = std::fs::read_dir(dir.join("artifacts/.tmp")).unwrap().next().unwrap().unwrap();
+        std::fs::remove_file(lost.path()).unwrap();
+        service.cancel(&exec_id).await.unwrap();
+        let result = join_exec(done).await.unwrap();
+
+        assert_eq!((result.state(), result.exit_code), (ExecState::ExecCanceled, 137));
+        assert!(result.last_error.starts_with("Failed to store"), "{}", result.last_error);
+        let kept: Vec<_> = result.output_artifact_ids.iter().filter(|id| !id.is_empty()).collect();
+        assert_eq!((result.output_artifact_ids.len(), kept.len()), (2, 1));
+        assert!(service.db.get_artifact(kept[0]).await.unwrap().is_some());
+        assert!(service.artifacts.open(kept[0]).await.is_ok());
+        assert_eq!(get(&service, &exec_id).await.unwrap(), result);
     }
 }
@@ -0,0 +1,316 @@
+use crate::config::FilesConfig;
+use crate::db::{ArtifactRecord, Db};
+use crate::pb::files_server::Files;
+use crate::pb::{
+    put_file_chunk, ArtifactKind, ArtifactMeta, DirEntry, DownloadArtifactRequest, FileChunk,
+    GetArtifactMetaRequest, GetFileRequest, ListDirRequest, ListDirResponse, PutFileChunk,
+    PutFileResult,
+};
+use crate::provider::SandboxProvider;
+use crate::server::lifecycle::{Lifecycle, USABLE};
+use crate::server::to_timestamp;
+use crate::store::{hex, ArtifactStore};
+use sha2::{Digest, Sha256};
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{Request, Response, Status, Streaming};
+
+/// Upper bound for a single chunk on the wire and for in-flight buffering
+const CHUNK_SIZE: usize = 64 * 1024;
+
+pub struct FileService {
+    provider: Arc<dyn SandboxProvider>,
+    db: Db,
+    artifacts: Arc<ArtifactStore>,
+    lifecycle: Arc<Lifecycle>,
+    /// Also store each upload as an artifact on the daemon host
+    keep_uploads: bool,
+}
+
+impl FileService {
+    pub fn new(
+        provider: Arc<dyn SandboxProvider>,
+        db: Db,
+        artifacts: Arc<ArtifactStore>,
+        lifecycle: Arc<Lifecycle>,
+        config: &FilesConfig,
+    ) -> Self {
+        Self { provider, db, artifacts, lifecycle, keep_uploads: config.keep_uploads }
+    }
+}
+
+/// Map provider errors onto gRPC codes, keeping `NotFound`/`AlreadyExists`/`PermissionDenied`
+/// distinguishable
+fn file_status(context: &str, e: anyhow::Error) -> Status {
+    match e.downcast_ref::<std::io::Error>().map(|io| io.kind()) {
+        Some(std::io::ErrorKind::NotFound) => Status::not_found(format!("{}: {}", context, e)),
+        Some(std::io::ErrorKind::AlreadyExists) => Status::already_exists(format!("{}: {}", context, e)),
+        Some(std::io::ErrorKind::PermissionDenied) => Status::permission_denied(format!("{}: {}", context, e)),
+        _ => Status::internal(format!("{}: {}", context, e)),
+    }
+}
+
+/// Forward a reader to a gRPC stream in bounded chunks. Returns false if the client went away.
+async fn forward_chunks(
+    reader: &mut (dyn AsyncRead + Send + Unpin),
+    tx: &mpsc::Sender<Result<FileChunk, Status>>,
+) -> std::io::Result<bool> {
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    loop {
+        let n = reader.read(&mut buf).await?;
+        if n == 0 {
+            return Ok(true);
+        }
+        if tx.send(Ok(FileChunk { data: buf[..n].to_vec() })).await.is_err() {
+            return Ok(false);
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl Files for FileService {
+    async fn put_file(
+        &self,
+        request: Request<Streaming<PutFileChunk>>,
+    ) -> Result<Response<PutFileResult>, Status> {
+        let mut stream = request.into_inner();
+
+        // The first message must carry the spec, everything after it is data
+        let spec = match stream.message().await? {
+            Some(PutFileChunk { payload: Some(put_file_chunk::Payload::Spec(spec)) }) => spec,
+            _ => return Err(Status::invalid_argument("First PutFile message must be a spec")),
+        };
+        if spec.sandbox_id.is_empty() || spec.guest_path.is_empty() {
+            return Err(Status::invalid_argument("sandbox_id and guest_path are required"));
+        }
+        self.lifecycle.require(&spec.sandbox_id, USABLE).await?;
+
+        let guest_path = PathBuf::from(&spec.guest_path);
+        let filename = if spec.filename.is_empty() {
+            guest_path.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default()
+        } else {
+            spec.filename.clone()
+        };
+        let mime_type = if spec.mime_type.is_empty() {
+            "application/octet-stream".to_string()
+        } else {
+            spec.mime_type.clone()
+        };
+
+        let artifact_id = uuid::Uuid::new_v4().to_string();
+        let mut artifact = match self.keep_uploads {
+            true => Some(self.artifacts.create(&artifact_id).await
+                .map_err(|e| Status::internal(format!("Failed to open artifact: {}", e)))?),
+            false => None,
+        };
+        let mut hasher = Sha256::new();
+        let mut size_bytes = 0u64;
+
+        // The provider consumes one end of a bounded pipe while we feed it from the client
+        let (mut pipe_tx, mut pipe_rx) = tokio::io::duplex(CHUNK_SIZE);
+        let provider = self.provider.clone();
+        let sandbox_id = spec.sandbox_id.clone();
+        let overwrite = spec.overwrite;
+        let upload = tokio::spawn(async move {
+            provider.put_file(&sandbox_id, guest_path, &mut pipe_rx, overwrite).await
+        });
+
+        let mut received: Result<(), Status> = Ok(());
+        loop {
+            let chunk = match stream.message().await {
+                Ok(Some(chunk)) => chunk,
+                Ok(None) => break,
+                Err(e) => {
+                    received = Err(e);
+                    break;
+                }
+            };
+            let data = match chunk.payload {
+                Some(put_file_chunk::Payload::Data(data)) => data,
+                Some(put_file_chunk::Payload::Spec(_)) => {
+                    received = Err(Status::invalid_argument("PutFile spec sent more than once"));
+                    break;
+                }
+                None => continue,
+            };
+            hasher.update(&data);
+            size_bytes += data.len() as u64;
+            if let Some(artifact) = artifact.as_mut()
+                && let Err(e) = artifact.write(&data).await
+            {
+                received = Err(Status::internal(format!("Failed to store artifact: {}", e)));
+                break;
+            }
+            // A write error means the provider side stopped reading; its own error wins below
+            if pipe_tx.write_all(&data).await.is_err() {
+                break;
+            }
+        }
+        let _ = pipe_tx.shutdown().await;
+        drop(pipe_tx);
+
+        if let Err(status) = received {
+            upload.abort();
+            if let Some(artifact) = artifact {
+                artifact.discard().await;
+            }
+            return Err(status);
+        }
+
+        let uploaded = match upload.await {
+            Ok(result) => result.map_err(|e| file_status("PutFile failed", e)),
+            Err(e) => Err(Status::internal(format!("Upload task failed: {}", e))),
+        };
+        if let Err(status) = uploaded {
+            if let Some(artifact) = artifact {
+                artifact.discard().await;
+            }
+            return Err(status);
+        }
+
+        let mut record = ArtifactRecord {
+            artifact_id: String::new(),
+            kind: ArtifactKind::ArtifactFile.as_str_name().to_string(),
+            filename,
+            mime_type,
+            size_bytes: size_bytes as i64,
+            sha256: hex(&hasher.finalize()),
+            sandbox_id: Some(spec.sandbox_id),
+            exec_id: None,
+            created_at: chrono::Utc::now(),
+        };
+        // Without a stored copy the result still describes what was written, under no artifact id
+        if let Some(artifact) = artifact {
+            artifact.finish().await
+                .map_err(|e| Status::internal(format!("Failed to finalize artifact: {}", e)))?;
+            record.artifact_id = artifact_id;
+            self.db.insert_artifact(&record).await
+                .map_err(|e| Status::internal(format!("DB error: {}", e)))?;
+        }
+
+        Ok(Response::new(PutFileResult {
+            artifact_id: record.artifact_id.clone(),
+            meta: Some(artifact_meta(record)),
+        }))
+    }
+
+    type GetFileStream = ReceiverStream<Result<FileChunk, Status>>;
+
+    async fn get_file(
+        &self,
+        request: Request<GetFileRequest>,
+    ) -> Result<Response<Self::GetFileStream>, Status> {
+        let req = request.into_inner();
+        if req.sandbox_id.is_empty() || req.guest_path.is_empty() {
+            return Err(Status::invalid_argument("sandbox_id and guest_path are required"));
+        }
+        self.lifecycle.require(&req.sandbox_id, USABLE).await?;
+
+        let (tx, rx) = mpsc::channel(4);
+        let provider = self.provider.clone();
+
+        tokio::spawn(async move {
+            let (mut pipe_tx, mut pipe_rx) = tokio::io::duplex(CHUNK_SIZE);
+
+            // Dropping the write half when the provider finishes signals EOF to the forwarder
+            let download = async move {
+                provider.get_file(&req.sandbox_id, req.guest_path.into(), &mut pipe_tx).await
+            };
+            let chunk_tx = tx.clone();
+            let forward = async move { forward_chunks(&mut pipe_rx, &chunk_tx).await };
+
+            let (downloaded, forwarded) = tokio::join!(download, forward);
+            match (downloaded, forwarded) {
+                (_, Ok(false)) => {} // client disconnected
+                (Err(e), _) => {
+                    let _ = tx.send(Err(file_status("GetFile failed", e))).await;
+                }
+                (Ok(_), Err(e)) => {
+                    let _ = tx.send(Err(Status::internal(format!("GetFile failed: {}", e)))).await;
+                }
+                (Ok(_), Ok(true)) => {}
+            }
+        });
+
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+
+    async fn list_dir(
+        &self,
+        request: Request<ListDirRequest>,
+    ) -> Result<Response<ListDirResponse>, Status> {
+        let req = request.into_inner();
+        if req.sandbox_id.is_empty() {
+            return Err(Status::invalid_argument("sandbox_id is required"));
+        }
+        self.lifecycle.require(&req.sandbox_id, USABLE).await?;
+
+        let entries = self.provider.list_dir(&req.sandbox_id, req.guest_path.into()).await
+            .map_err(|e| file_status("ListDir failed", e))?;
+
+        Ok(Response::new(ListDirResponse {
+            entries: entries.into_iter().map(|e| DirEntry {
+                name: e.name,
+                is_dir: e.is_dir,
+                size_bytes: e.size_bytes,
+                modified_at: Some(e.modified_at.into()),
+            }).collect(),
+        }))
+    }
+
+    async fn get_artifact_meta(
+        &self,
+        request: Request<GetArtifactMetaRequest>,
+    ) -> Result<Response<ArtifactMeta>, Status> {
+        let req = request.into_inner();
+        let record = self.db.get_artifact(&req.artifact_id).await
+            .map_err(|e| Status::internal(format!("DB error: {}", e)))?
+            .ok_or_else(|| Status::not_found(format!("Artifact {} not found", req.artifact_id)))?;
+
+        Ok(Response::new(artifact_meta(record)))
+    }
+
+    type DownloadArtifactStream = ReceiverStream<Result<FileChunk, Status>>;
+
+    async fn download_artifact(
+        &self,
+        request: Request<DownloadArtifactRequest>,
+    ) -> Result<Response<Self::DownloadArtifactStream>, Status> {
+        let req = request.into_inner();
+        if self.db.get_artifact(&req.artifact_id).await
+            .map_err(|e| Status::internal(format!("DB error: {}", e)))?
+            .is_none()
+        {
+            return Err(Status::not_found(format!("Artifact {} not found", req.artifact_id)));
+        }
+
+        let mut file = self.artifacts.open(&req.artifact_id).await
+            .map_err(|e| Status::data_loss(format!("Artifact content missing: {}", e)))?;
+
+        let (tx, rx) = mpsc::channel(4);
+        tokio::spawn(async move {
+            if let Err(e) = forward_chunks(&mut file, &tx).await {
+                let _ = tx.send(Err(Status::internal(format!("Artifact read failed: {}", e)))).await;
+            }
+        });
+
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+}
+
+fn artifact_meta(record: ArtifactRecord) -> ArtifactMeta {
+    ArtifactMeta {
+        artifact_id: record.artifact_id,
+        kind: ArtifactKind::from_str_name(&record.kind).unwrap_or(ArtifactKind::Unspecified) as i32,
+        filename: record.filename,
+        mime_type: record.mime_type,
+        size_bytes: record.size_bytes as u64,
+        created_at: Some(to_timestamp(record.created_at)),
+        sandbox_id: record.sandbox_id.unwrap_or_default(),
+        exec_id: record.exec_id.unwrap_or_default(),
+        sha256: record.sha256,
+    }
+}
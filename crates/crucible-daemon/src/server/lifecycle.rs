@@ -0,0 +1,188 @@
+use crate::db::{Db, SandboxRecord};
+use crate::pb::SandboxState;
+use std::collections::HashMap;
+use tokio::sync::{broadcast, Mutex};
+use tonic::Status;
+
+/// Transitions buffered per watcher before it starts lagging
+const EVENT_CAPACITY: usize = 256;
+
+/// States in which a sandbox accepts execs and file operations
+pub const USABLE: &[SandboxState] = &[SandboxState::SandboxReady, SandboxState::SandboxRunning, SandboxState::SandboxIdle];
+
+/// Sandbox lifecycle state machine. Every state change goes through here so illegal
+/// transitions are rejected in one place and RUNNING/IDLE track the execs actually in flight.
+pub struct Lifecycle {
+    db: Db,
+    // Serializes transitions; also holds the number of in-flight execs per sandbox
+    active_execs: Mutex<HashMap<String, usize>>,
+    // Every applied transition, for WatchSandbox
+    events: broadcast::Sender<SandboxRecord>,
+}
+
+/// Whether `from -> to` is a legal transition
+pub fn can_transition(from: SandboxState, to: SandboxState) -> bool {
+    use SandboxState::*;
+    if to == SandboxError {
+        return !matches!(from, SandboxDestroyed | SandboxError);
+    }
+    matches!(
+        (from, to),
+        (SandboxCreating, SandboxBooting)
+            | (SandboxBooting, SandboxReady)
+            | (SandboxReady | SandboxIdle, SandboxRunning)
+            | (SandboxRunning, SandboxIdle)
+            | (SandboxReady | SandboxIdle, SandboxStopping)
+            | (SandboxStopping, SandboxStopped)
+            | (SandboxStopped, SandboxBooting)
+            | (SandboxStopped | SandboxError, SandboxDestroyed)
+    )
+}
+
+pub fn state_of(record: &SandboxRecord) -> SandboxState {
+    SandboxState::from_str_name(&record.state).unwrap_or(SandboxState::Unspecified)
+}
+
+impl Lifecycle {
+    pub fn new(db: Db) -> Self {
+        Self {
+            db,
+            active_execs: Mutex::new(HashMap::new()),
+            events: broadcast::channel(EVENT_CAPACITY).0,
+        }
+    }
+
+    /// Receive the sandbox record after each transition, across all sandboxes
+    pub fn subscribe(&self) -> broadcast::Receiver<SandboxRecord> {
+        self.events.subscribe()
+    }
+
+    async fn load(&self, sandbox_id: &str) -> Result<SandboxRecord, Status> {
+        self.db.get_sandbox(sandbox_id).await
+            .map_err(|e| Status::internal(format!("DB error: {}", e)))?
+            .ok_or_else(|| Status::not_found(format!("Sandbox {} not found", sandbox_id)))
+    }
+
+    async fn apply(&self, record: &SandboxRecord, to: SandboxState, last_error: Option<&str>) -> Result<SandboxRecord, Status> {
+        let from = state_of(record);
+        if !can_transition(from, to) {
+            return Err(Status::failed_precondition(format!(
+                "Sandbox {} is {}, cannot move to {}",
+                record.sandbox_id, from.as_str_name(), to.as_str_name()
+            )));
+        }
+        self.db.set_sandbox_state(&record.sandbox_id, to.as_str_name(), last_error).await
+            .map_err(|e| Status::internal(format!("DB error: {}", e)))?;
+        let updated = self.load(&record.sandbox_id).await?;
+        let _ = self.events.send(updated.clone());
+        Ok(updated)
+    }
+
+    /// Move a sandbox to `to`, failing with FAILED_PRECONDITION if that is not a legal step
+    pub async fn transition(&self, sandbox_id: &str, to: SandboxState) -> Result<SandboxRecord, Status> {
+        self.transition_noting(sandbox_id, to, None).await
+    }
+
+    /// Like `transition`, recording in `last_error` why the daemon moved the sandbox
+    pub async fn transition_noting(&self, sandbox_id: &str, to: SandboxState, note: Option<&str>) -> Result<SandboxRecord, Status> {
+        let _guard = self.active_execs.lock().await;
+        let record = self.load(sandbox_id).await?;
+        self.apply(&record, to, note).await
+    }
+
+    /// Put a sandbox into ERROR, recording why
+    pub async fn fail(&self, sandbox_id: &str, error: &str) -> Result<SandboxRecord, Status> {
+        let _guard = self.active_execs.lock().await;
+        let record = self.load(sandbox_id).await?;
+        self.apply(&record, SandboxState::SandboxError, Some(error)).await
+    }
+
+    /// The sandbox, provided it is in one of `allowed`
+    pub async fn require(&self, sandbox_id: &str, allowed: &[SandboxState]) -> Result<SandboxRecord, Status> {
+        let record = self.load(sandbox_id).await?;
+        let state = state_of(&record);
+        if !allowed.contains(&state) {
+            return Err(Status::failed_precondition(format!(
+                "Sandbox {} is {}", sandbox_id, state.as_str_name()
+            )));
+        }
+        Ok(record)
+    }
+
+    /// Account for a new exec; the sandbox becomes RUNNING while any exec is in flight
+    pub async fn begin_exec(&self, sandbox_id: &str) -> Result<(), Status> {
+        let mut active = self.active_execs.lock().await;
+        let record = self.load(sandbox_id).await?;
+        let state = state_of(&record);
+        if !USABLE.contains(&state) {
+            return Err(Status::failed_precondition(format!(
+                "Sandbox {} is {}, cannot run execs", sandbox_id, state.as_str_name()
+            )));
+        }
+        if state != SandboxState::SandboxRunning {
+            self.apply(&record, SandboxState::SandboxRunning, None).await?;
+        }
+        *active.entry(sandbox_id.to_string()).or_default() += 1;
+        Ok(())
+    }
+
+    /// Account for a finished exec; the last one to finish leaves the sandbox IDLE
+    pub async fn end_exec(&self, sandbox_id: &str) {
+        let mut active = self.active_execs.lock().await;
+        let remaining = match active.get_mut(sandbox_id) {
+            Some(count) => {
+                *count -= 1;
+                *count
+            }
+            None => 0,
+        };
+        if remaining > 0 {
+            return;
+        }
+        active.remove(sandbox_id);
+
+        // The sandbox may have failed meanwhile; only a RUNNING one goes idle
+        if let Ok(record) = self.load(sandbox_id).await
+            && state_of(&record) == SandboxState::SandboxRunning
+            && let Err(e) = self.apply(&record, SandboxState::SandboxIdle, None).await
+        {
+            println!("Failed to mark sandbox {} idle: {}", sandbox_id, e.message());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use SandboxState::*;
+
+    const ALL: [SandboxState; 10] = [
+        Unspecified, SandboxCreating, SandboxBooting, SandboxReady, SandboxRunning,
+        SandboxIdle, SandboxStopping, SandboxStopped, SandboxDestroyed, SandboxError,
+    ];
+
+    #[test]
+    fn legal_transitions() {
+        let table: &[(SandboxState, &[SandboxState])] = &[
+            (Unspecified, &[SandboxError]),
+            (SandboxCreating, &[SandboxBooting, SandboxError]),
+            (SandboxBooting, &[SandboxReady, SandboxError]),
+            (SandboxReady, &[SandboxRunning, SandboxStopping, SandboxError]),
+            (SandboxRunning, &[SandboxIdle, SandboxError]),
+            (SandboxIdle, &[SandboxRunning, SandboxStopping, SandboxError]),
+            (SandboxStopping, &[SandboxStopped, SandboxError]),
+            (SandboxStopped, &[SandboxBooting, SandboxDestroyed, SandboxError]),
+            (SandboxDestroyed, &[]),
+            (SandboxError, &[SandboxDestroyed]),
+        ];
+        for (from, legal) in table {
+            for to in ALL {
+                assert_eq!(
+                    can_transition(*from, to),
+                    legal.contains(&to),
+                    "{} -> {}", from.as_str_name(), to.as_str_name()
+                );
+            }
+        }
+    }
+}
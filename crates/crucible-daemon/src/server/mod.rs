@@ -1,3 +1,64 @@
 pub mod sandboxes;
 pub mod execution;
 pub mod snapshots;
+pub mod files;
+pub mod output;
+pub mod lifecycle;
+pub mod reaper;
+
+use crate::pb::{PageInfo, Paging, ProviderType};
+use crate::provider::SandboxProvider;
+use chrono::{DateTime, Utc};
+use prost_types::Timestamp;
+use tonic::Status;
+
+/// Convert a stored UTC datetime into a protobuf timestamp
+pub(crate) fn to_timestamp(dt: DateTime<Utc>) -> Timestamp {
+    Timestamp {
+        seconds: dt.timestamp(),
+        nanos: dt.timestamp_subsec_nanos() as i32,
+    }
+}
+
+/// Proto enum for a provider, derived from its `provider_name` ("local_lima" -> PROVIDER_LOCAL_LIMA)
+pub(crate) fn provider_type(provider: &dyn SandboxProvider) -> ProviderType {
+    let name = format!("PROVIDER_{}", provider.provider_name().to_uppercase());
+    ProviderType::from_str_name(&name).unwrap_or(ProviderType::Unspecified)
+}
+
+const DEFAULT_PAGE_SIZE: u32 = 50;
+const MAX_PAGE_SIZE: u32 = 500;
+
+/// Offset-based page window; `page_token` is the offset of the first row as a decimal string
+pub(crate) struct PageWindow {
+    pub limit: i64,
+    pub offset: i64,
+}
+
+impl PageWindow {
+    pub fn from_paging(paging: Option<&Paging>) -> Result<Self, Status> {
+        let (size, token) = paging.map(|p| (p.page_size, p.page_token.as_str())).unwrap_or((0, ""));
+        let limit = if size == 0 { DEFAULT_PAGE_SIZE } else { size.min(MAX_PAGE_SIZE) };
+        let offset = if token.is_empty() {
+            0
+        } else {
+            token.parse::<u64>().map_err(|_| Status::invalid_argument("Invalid page_token"))?
+        };
+        Ok(Self { limit: limit as i64, offset: offset as i64 })
+    }
+
+    /// Rows to fetch: one extra tells us whether another page exists
+    pub fn fetch_limit(&self) -> i64 {
+        self.limit + 1
+    }
+
+    /// Trim the look-ahead row and produce the token for the next page
+    pub fn finish<T>(&self, rows: &mut Vec<T>) -> Option<PageInfo> {
+        if rows.len() as i64 > self.limit {
+            rows.truncate(self.limit as usize);
+            Some(PageInfo { next_page_token: (self.offset + self.limit).to_string() })
+        } else {
+            Some(PageInfo { next_page_token: String::new() })
+        }
+    }
+}
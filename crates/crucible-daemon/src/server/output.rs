@@ -0,0 +1,336 @@
+use crate::pb::OutputChunk;
+use crate::provider::ExecId;
+use crate::store::ArtifactWriter;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::broadcast;
+
+/// Bytes of recent output kept per exec for late joiners
+const BACKLOG_BYTES: usize = 1024 * 1024;
+/// Live chunks buffered per follower before it starts lagging
+const FOLLOWER_CAPACITY: usize = 1024;
+/// How long finished execs stay followable
+const RETENTION: Duration = Duration::from_secs(300);
+
+/// Registry of output logs for running and recently finished execs
+#[derive(Default)]
+pub struct ExecOutputs {
+    logs: Mutex<HashMap<ExecId, Arc<OutputLog>>>,
+}
+
+impl ExecOutputs {
+    pub fn register(&self, exec_id: &ExecId) -> Arc<OutputLog> {
+        let log = Arc::new(OutputLog::new());
+        self.logs.lock().unwrap().insert(exec_id.clone(), log.clone());
+        log
+    }
+
+    pub fn get(&self, exec_id: &str) -> Option<Arc<OutputLog>> {
+        self.logs.lock().unwrap().get(exec_id).cloned()
+    }
+
+    /// Forget a finished exec once the retention window has passed
+    pub fn retire(self: &Arc<Self>, exec_id: ExecId) {
+        let outputs = self.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(RETENTION).await;
+            outputs.logs.lock().unwrap().remove(&exec_id);
+        });
+    }
+}
+
+/// Output of a single exec: a bounded backlog plus a live broadcast
+pub struct OutputLog {
+    state: Mutex<LogState>,
+}
+
+struct LogState {
+    backlog: VecDeque<OutputChunk>,
+    backlog_bytes: usize,
+    // None once the exec has finished
+    live: Option<broadcast::Sender<OutputChunk>>,
+}
+
+impl OutputLog {
+    fn new() -> Self {
+        let (live, _) = broadcast::channel(FOLLOWER_CAPACITY);
+        Self {
+            state: Mutex::new(LogState {
+                backlog: VecDeque::new(),
+                backlog_bytes: 0,
+                live: Some(live),
+            }),
+        }
+    }
+
+    pub fn publish(&self, chunk: OutputChunk) {
+        // Backlog and broadcast are updated under one lock so followers see no gaps or repeats
+        let mut state = self.state.lock().unwrap();
+        state.backlog_bytes += chunk.data.len();
+        state.backlog.push_back(chunk.clone());
+        while state.backlog_bytes > BACKLOG_BYTES {
+            match state.backlog.pop_front() {
+                Some(old) => state.backlog_bytes -= old.data.len(),
+                None => break,
+            }
+        }
+        if let Some(live) = &state.live {
+            let _ = live.send(chunk);
+        }
+    }
+
+    /// Close the live stream; followers drain what they have and then end
+    pub fn finish(&self) {
+        self.state.lock().unwrap().live = None;
+    }
+
+    pub fn follow(&self) -> OutputFollower {
+        let state = self.state.lock().unwrap();
+        OutputFollower {
+            backlog: state.backlog.clone(),
+            live: state.live.as_ref().map(|live| live.subscribe()),
+            skipped: 0,
+        }
+    }
+}
+
+pub struct OutputFollower {
+    backlog: VecDeque<OutputChunk>,
+    live: Option<broadcast::Receiver<OutputChunk>>,
+    // Chunks lost to lagging since the last one delivered
+    skipped: u64,
+}
+
+impl OutputFollower {
+    /// Next chunk in order, or None once the exec has finished and everything was delivered
+    pub async fn next(&mut self) -> Option<OutputChunk> {
+        if let Some(chunk) = self.backlog.pop_front() {
+            return Some(chunk);
+        }
+        let live = self.live.as_mut()?;
+        loop {
+            match live.recv().await {
+                Ok(mut chunk) => {
+                    chunk.skipped_chunks = std::mem::take(&mut self.skipped);
+                    return Some(chunk);
+                }
+                // A slow follower skips what it missed rather than stalling the exec, and is
+                // told how much on the next chunk. The channel still holds newer chunks after a lag.
+                Err(broadcast::error::RecvError::Lagged(n)) => self.skipped += n,
+                Err(broadcast::error::RecvError::Closed) => {
+                    self.live = None;
+                    return None;
+                }
+            }
+        }
+    }
+}
+
+/// Bytes kept from the start and the end of each stream for previews
+const PREVIEW_HEAD_BYTES: usize = 4 * 1024;
+const PREVIEW_TAIL_BYTES: usize = 4 * 1024;
+
+/// Tees one output stream into a log artifact while keeping a bounded head/tail preview
+pub struct OutputCapture {
+    artifact_id: String,
+    artifact: ArtifactWriter,
+    error: Option<anyhow::Error>,
+    head: Vec<u8>,
+    tail: Vec<u8>,
+    total_bytes: u64,
+}
+
+pub struct CapturedOutput {
+    pub artifact_id: String,
+    pub size_bytes: u64,
+    pub sha256: String,
+    pub preview: String,
+}
+
+impl OutputCapture {
+    pub fn new(artifact_id: String, artifact: ArtifactWriter) -> Self {
+        Self {
+            artifact_id,
+            artifact,
+            error: None,
+            head: Vec::new(),
+            tail: Vec::new(),
+            total_bytes: 0,
+        }
+    }
+
+    pub async fn record(&mut self, data: &[u8]) {
+        if self.error.is_none()
+            && let Err(e) = self.artifact.write(data).await
+        {
+            self.error = Some(e);
+        }
+
+        self.total_bytes += data.len() as u64;
+        let to_head = data.len().min(PREVIEW_HEAD_BYTES - self.head.len());
+        self.head.extend_from_slice(&data[..to_head]);
+        self.tail.extend_from_slice(&data[to_head..]);
+        if self.tail.len() > PREVIEW_TAIL_BYTES {
+            let excess = self.tail.len() - PREVIEW_TAIL_BYTES;
+            self.tail.drain(..excess);
+        }
+    }
+
+    pub async fn finish(self) -> anyhow::Result<CapturedOutput> {
+        if let Some(e) = self.error {
+            self.artifact.discard().await;
+            return Err(e);
+        }
+        let preview = render_preview(&self.head, &self.tail, self.total_bytes);
+        let (size_bytes, sha256) = self.artifact.finish().await?;
+        Ok(CapturedOutput { artifact_id: self.artifact_id, size_bytes, sha256, preview })
+    }
+
+    pub async fn discard(self) {
+        self.artifact.discard().await;
+    }
+}
+
+/// Join head and tail into a preview, cutting only on UTF-8 character boundaries
+fn render_preview(head: &[u8], tail: &[u8], total_bytes: u64) -> String {
+    let kept = (head.len() + tail.len()) as u64;
+    if kept == total_bytes {
+        let mut all = head.to_vec();
+        all.extend_from_slice(tail);
+        return String::from_utf8_lossy(&all).into_owned();
+    }
+
+    // Drop a multi-byte sequence split at the end of the head...
+    let head = match std::str::from_utf8(head) {
+        Err(e) if e.error_len().is_none() => &head[..e.valid_up_to()],
+        _ => head,
+    };
+    // ...and continuation bytes orphaned at the start of the tail
+    let skip = tail.iter().take(3).take_while(|b| (**b & 0xC0) == 0x80).count();
+    let tail = &tail[skip..];
+
+    format!(
+        "{}\n... [{} bytes truncated] ...\n{}",
+        String::from_utf8_lossy(head),
+        total_bytes - (head.len() + tail.len()) as u64,
+        String::from_utf8_lossy(tail),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::ArtifactStore;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn short_output_is_previewed_whole() {
+        assert_eq!(render_preview(b"hello ", b"world", 11), "hello world");
+        assert_eq!(render_preview(b"", b"", 0), "");
+        assert_eq!(render_preview(b"bad \xff", b"", 5), "bad \u{fffd}");
+    }
+
+    #[test]
+    fn truncated_output_is_cut_on_character_boundaries() {
+        // "é" (c3 a9) split between head and tail
+        let head = [&[b'a'; 4095][..], &[0xc3]].concat();
+        let tail = [&[0xa9][..], b"z!"].concat();
+        let preview = render_preview(&head, &tail, 10_000);
+        let (shown_head, rest) = preview.split_once('\n').unwrap();
+        assert_eq!(shown_head, "a".repeat(4095));
+        // Dropped partial characters count as truncated
+        assert_eq!(rest, format!("... [{} bytes truncated] ...\nz!", 10_000 - 4095 - 2));
+
+        // Tail starting inside "😀" (f0 9f 98 80)
+        let tail = [&[0x9f, 0x98, 0x80][..], b"end"].concat();
+        let preview = render_preview(b"start", &tail, 100);
+        assert_eq!(preview, format!("start\n... [{} bytes truncated] ...\nend", 100 - 5 - 3));
+    }
+
+    fn chunk(data: &str) -> OutputChunk {
+        OutputChunk { exec_id: "exec".to_string(), data: data.as_bytes().to_vec(), ..Default::default() }
+    }
+
+    async fn drain(follower: &mut OutputFollower) -> Vec<(String, u64)> {
+        let mut chunks = Vec::new();
+        while let Some(chunk) = follower.next().await {
+            chunks.push((String::from_utf8(chunk.data).unwrap(), chunk.skipped_chunks));
+        }
+        chunks
+    }
+
+    #[tokio::test]
+    async fn late_followers_get_the_backlog_then_live_output() {
+        let log = OutputLog::new();
+        log.publish(chunk("a"));
+        log.publish(chunk("b"));
+        let mut early = log.follow();
+        assert_eq!(early.next().await.unwrap().data, b"a");
+
+        let mut late = log.follow();
+        log.publish(chunk("c"));
+        log.finish();
+        let mut after_finish = log.follow();
+
+        let all = vec![("a".to_string(), 0), ("b".to_string(), 0), ("c".to_string(), 0)];
+        assert_eq!(drain(&mut early).await, all[1..]);
+        assert_eq!(drain(&mut late).await, all);
+        assert_eq!(drain(&mut after_finish).await, all);
+        assert!(early.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn lagging_followers_are_told_what_they_missed() {
+        let log = OutputLog::new();
+        let mut follower = log.follow();
+        for i in 0..FOLLOWER_CAPACITY + 10 {
+            log.publish(chunk(&i.to_string()));
+        }
+        log.publish(chunk("end"));
+        log.finish();
+
+        let chunks = drain(&mut follower).await;
+        assert_eq!(chunks.len(), FOLLOWER_CAPACITY);
+        assert_eq!(chunks[0], ("11".to_string(), 11));
+        assert!(chunks[1..].iter().all(|(_, skipped)| *skipped == 0));
+        assert_eq!(chunks.last().unwrap().0, "end");
+    }
+
+    #[test]
+    fn backlog_keeps_the_latest_output() {
+        let log = OutputLog::new();
+        let piece = "x".repeat(BACKLOG_BYTES / 4);
+        for _ in 0..6 {
+            log.publish(chunk(&piece));
+        }
+        log.publish(chunk("tail"));
+        let state = log.state.lock().unwrap();
+        assert_eq!(state.backlog.len(), 4);
+        assert_eq!(state.backlog_bytes, 3 * piece.len() + 4);
+        assert_eq!(state.backlog.back().unwrap().data, b"tail");
+    }
+
+    #[tokio::test]
+    async fn capture_keeps_the_head_and_tail() {
+        let dir = TempDir::new("capture");
+        let store = ArtifactStore::new(dir.join("artifacts")).await.unwrap();
+        let mut capture = OutputCapture::new("log".to_string(), store.create("log").await.unwrap());
+
+        let data: Vec<u8> = (0..10_000u32).map(|i| b'a' + (i % 26) as u8).collect();
+        for piece in data.chunks(1000) {
+            capture.record(piece).await;
+        }
+        let captured = capture.finish().await.unwrap();
+        assert_eq!(captured.size_bytes, 10_000);
+        assert_eq!(captured.preview, format!(
+            "{}\n... [{} bytes truncated] ...\n{}",
+            String::from_utf8_lossy(&data[..PREVIEW_HEAD_BYTES]),
+            10_000 - PREVIEW_HEAD_BYTES - PREVIEW_TAIL_BYTES,
+            String::from_utf8_lossy(&data[10_000 - PREVIEW_TAIL_BYTES..]),
+        ));
+        let mut stored = Vec::new();
+        tokio::io::AsyncReadExt::read_to_end(&mut store.open("log").await.unwrap(), &mut stored).await.unwrap();
+        assert!(stored == data);
+    }
+}
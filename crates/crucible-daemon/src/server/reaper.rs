@@ -0,0 +1,187 @@
+use crate::db::{Db, SandboxRecord};
+use crate::pb::{SandboxSpec, SandboxState};
+use crate::server::lifecycle::{state_of, Lifecycle};
+use crate::server::sandboxes::{provider_spec, SandboxService};
+use chrono::Utc;
+use prost::Message;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+
+/// How often sandboxes are checked against their TTLs
+const REAP_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How long an expired sandbox may sit in CREATING, BOOTING or STOPPING before the reaper
+/// gives up on it and moves it to ERROR, from where it can be destroyed
+const STUCK_AFTER: Duration = Duration::from_secs(600);
+
+/// Background task that stops sandboxes idle past `idle_ttl` and destroys ones older than
+/// `sandbox_ttl`. The reason ends up in `last_error`, and the transitions reach WatchSandbox.
+pub struct Reaper {
+    db: Db,
+    sandboxes: SandboxService,
+    lifecycle: Arc<Lifecycle>,
+}
+
+enum Verdict {
+    Stop(String),
+    Destroy(String),
+    Fail(String),
+}
+
+impl Reaper {
+    pub fn new(db: Db, sandboxes: SandboxService, lifecycle: Arc<Lifecycle>) -> Self {
+        Self { db, sandboxes, lifecycle }
+    }
+
+    pub fn spawn(self) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(REAP_INTERVAL);
+            loop {
+                ticker.tick().await;
+                if let Err(e) = self.reap().await {
+                    println!("Reaper pass failed: {}", e);
+                }
+            }
+        })
+    }
+
+    async fn reap(&self) -> anyhow::Result<()> {
+        // ERROR sandboxes are included: their guest state leaks just the same
+        for record in self.db.sandboxes_not_in(&[SandboxState::SandboxDestroyed.as_str_name()]).await? {
+            let Some(verdict) = verdict(&record) else { continue };
+            let sandbox_id = &record.sandbox_id;
+
+            let result = match &verdict {
+                Verdict::Destroy(reason) => {
+                    // A hard lifetime limit wins over whatever is still running: forcing cancels it
+                    println!("Reaper: destroying sandbox {}: {}", sandbox_id, reason);
+                    self.sandboxes.destroy(sandbox_id, true, Some(reason)).await
+                }
+                Verdict::Stop(reason) => {
+                    println!("Reaper: stopping sandbox {}: {}", sandbox_id, reason);
+                    // idle_ttl only blocks execs there; sandbox_ttl is what frees the sandbox
+                    if !self.sandboxes.stop_reclaims() {
+                        println!("Reaper: stopping sandbox {} reclaims nothing on this provider; it is kept until destroyed", sandbox_id);
+                    }
+                    self.sandboxes.stop(sandbox_id, false, Some(reason)).await
+                }
+                Verdict::Fail(reason) => {
+                    println!("Reaper: giving up on sandbox {}: {}", sandbox_id, reason);
+                    self.lifecycle.fail(sandbox_id, reason).await
+                }
+            };
+            // An exec may have started since we looked; the next pass will see the new state
+            if let Err(status) = result {
+                println!("Reaper: could not reap sandbox {}: {}", sandbox_id, status.message());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// What, if anything, the reaper should do with a sandbox right now
+fn verdict(record: &SandboxRecord) -> Option<Verdict> {
+    let spec = SandboxSpec::decode(record.spec.as_slice()).ok()?;
+    let limits = provider_spec(&spec).limits;
+    let now = Utc::now();
+    let state = state_of(record);
+
+    if let Some(ttl) = limits.sandbox_ttl
+        && elapsed(record.created_at, now) > ttl
+    {
+        // Mid-transition there is no legal way to DESTROYED; leave the operation in flight
+        // to finish, unless it has plainly hung
+        if matches!(state, SandboxState::SandboxCreating | SandboxState::SandboxBooting | SandboxState::SandboxStopping) {
+            return (elapsed(record.updated_at, now) > STUCK_AFTER).then(|| {
+                Verdict::Fail(format!(
+                    "Sandbox exceeded its sandbox_ttl of {}s and was stuck in {} for over {}s",
+                    ttl.as_secs(), state.as_str_name(), STUCK_AFTER.as_secs()
+                ))
+            });
+        }
+        return Some(Verdict::Destroy(format!("Sandbox exceeded its sandbox_ttl of {}s", ttl.as_secs())));
+    }
+
+    // READY counts as idle too: a sandbox that was never used is as abandoned as one gone quiet.
+    // `updated_at` is the time it entered that state.
+    if let Some(ttl) = limits.idle_ttl
+        && matches!(state, SandboxState::SandboxReady | SandboxState::SandboxIdle)
+        && elapsed(record.updated_at, now) > ttl
+    {
+        return Some(Verdict::Stop(format!("Sandbox was idle longer than its idle_ttl of {}s", ttl.as_secs())));
+    }
+    None
+}
+
+fn elapsed(since: chrono::DateTime<Utc>, now: chrono::DateTime<Utc>) -> Duration {
+    (now - since).to_std().unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pb::ResourceLimits;
+
+    /// A sandbox in `state` with the given TTLs, created `age` ago and in `state` for `quiet`
+    fn record(state: SandboxState, sandbox_ttl_sec: u64, idle_ttl_sec: u64, age: u64, quiet: u64) -> SandboxRecord {
+        let spec = SandboxSpec {
+            limits: Some(ResourceLimits { sandbox_ttl_sec, idle_ttl_sec, ..Default::default() }),
+            ..Default::default()
+        };
+        let now = Utc::now();
+        SandboxRecord {
+            sandbox_id: "sb-1".to_string(),
+            provider: "test".to_string(),
+            state: state.as_str_name().to_string(),
+            spec: spec.encode_to_vec(),
+            labels: "{}".to_string(),
+            created_at: now - chrono::Duration::seconds(age as i64),
+            updated_at: now - chrono::Duration::seconds(quiet as i64),
+            last_error: None,
+        }
+    }
+
+    #[test]
+    fn sandbox_ttl_destroys_and_wins_over_idle_ttl() {
+        use SandboxState::*;
+        for state in [SandboxReady, SandboxRunning, SandboxIdle, SandboxStopped, SandboxError] {
+            let verdict = verdict(&record(state, 60, 30, 120, 120));
+            assert!(matches!(&verdict, Some(Verdict::Destroy(reason)) if reason.contains("sandbox_ttl of 60s")), "{}", state.as_str_name());
+        }
+        assert!(verdict_is_none(SandboxRunning, 60, 0, 30, 30));
+        assert!(verdict_is_none(SandboxReady, 0, 0, 100_000, 100_000));
+    }
+
+    #[test]
+    fn idle_ttl_only_stops_ready_or_idle_sandboxes() {
+        use SandboxState::*;
+        for state in [SandboxReady, SandboxIdle] {
+            let verdict = verdict(&record(state, 0, 30, 120, 60));
+            assert!(matches!(&verdict, Some(Verdict::Stop(reason)) if reason.contains("idle_ttl of 30s")), "{}", state.as_str_name());
+            // Quiet for less than idle_ttl
+            assert!(verdict_is_none(state, 0, 30, 120, 10));
+        }
+        for state in [SandboxRunning, SandboxStopped, SandboxBooting, SandboxError] {
+            assert!(verdict_is_none(state, 0, 30, 120, 60), "{}", state.as_str_name());
+        }
+    }
+
+    #[test]
+    fn expired_sandboxes_stuck_mid_transition_fail() {
+        use SandboxState::*;
+        let stuck = STUCK_AFTER.as_secs() + 1;
+        for state in [SandboxCreating, SandboxBooting, SandboxStopping] {
+            // Still within the grace period: the operation gets to finish
+            assert!(verdict_is_none(state, 60, 0, stuck + 60, 60), "{}", state.as_str_name());
+            let verdict = verdict(&record(state, 60, 0, stuck + 60, stuck));
+            assert!(matches!(&verdict, Some(Verdict::Fail(reason)) if reason.contains(state.as_str_name())), "{}", state.as_str_name());
+        }
+        // Not expired, so not the reaper's business however long it takes
+        assert!(verdict_is_none(SandboxBooting, 0, 30, stuck, stuck));
+    }
+
+    fn verdict_is_none(state: SandboxState, sandbox_ttl_sec: u64, idle_ttl_sec: u64, age: u64, quiet: u64) -> bool {
+        verdict(&record(state, sandbox_ttl_sec, idle_ttl_sec, age, quiet)).is_none()
+    }
+}
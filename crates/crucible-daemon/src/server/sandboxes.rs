@@ -1,22 +1,304 @@
+use crate::db::{Db, SandboxRecord};
 use crate::pb::sandboxes_server::Sandboxes;
 use crate::pb::{
     CreateSandboxRequest, CreateSandboxResponse, DestroySandboxRequest, DestroySandboxResponse,
     GetSandboxRequest, ListSandboxesRequest, ListSandboxesResponse, Sandbox, StopSandboxRequest,
-    WatchSandboxRequest, SandboxState, ProviderType,
+    WatchSandboxRequest, SandboxState, ProviderType, SandboxSpec, ResourceUsage, PolicyViolation,
+    PolicyViolations,
 };
+use crate::config::MountConfig;
+use crate::policy::egress::EgressRules;
+use crate::policy::mounts::MountValidator;
+use crate::policy::registry::PolicyRegistry;
+use crate::policy::seccomp::SeccompProfile;
 use crate::provider::{SandboxProvider, SandboxSpec as ProviderSandboxSpec, ResourceLimits as ProviderLimits, SandboxPolicy as ProviderPolicy, NetworkPolicy as ProviderNet, MountSpec};
-use prost_types::Timestamp;
+use crate::server::execution::{violation_kind, ExecutionService};
+use crate::server::lifecycle::{state_of, Lifecycle, USABLE};
+use crate::server::snapshots::SANDBOX_REF;
+use crate::server::{provider_type, to_timestamp, PageWindow};
+use prost::Message;
+use std::collections::HashSet;
 use std::sync::Arc;
 use std::time::Duration;
+use chrono::Utc;
+use tokio::sync::{broadcast, mpsc};
+use tokio_stream::wrappers::ReceiverStream;
 use tonic::{Request, Response, Status};
 
+/// How often WatchSandbox samples resource usage of a live sandbox
+const USAGE_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Clone)]
 pub struct SandboxService {
     provider: Arc<dyn SandboxProvider>,
+    db: Db,
+    lifecycle: Arc<Lifecycle>,
+    mounts: Arc<MountValidator>,
+    policies: Arc<PolicyRegistry>,
+    execution: ExecutionService,
 }
 
 impl SandboxService {
-    pub fn new(provider: Arc<dyn SandboxProvider>) -> Self {
-        Self { provider }
+    pub fn new(
+        provider: Arc<dyn SandboxProvider>,
+        db: Db,
+        lifecycle: Arc<Lifecycle>,
+        mounts: &MountConfig,
+        policies: Arc<PolicyRegistry>,
+        execution: ExecutionService,
+    ) -> Self {
+        Self { provider, db, lifecycle, mounts: Arc::new(MountValidator::new(mounts)), policies, execution }
+    }
+
+    /// Reject mounts the operator hasn't allowed, with the MOUNT_DENIED violations as status details
+    fn check_mounts(&self, spec: &ProviderSandboxSpec) -> Result<(), Status> {
+        let violations = self.mounts.validate(&spec.policy.mounts, &spec.working_dir);
+        if violations.is_empty() {
+            return Ok(());
+        }
+        let message = violations.iter().map(|v| v.message.as_str()).collect::<Vec<_>>().join("; ");
+        println!("Rejected sandbox mounts: {}", message);
+        let details = PolicyViolations {
+            violations: violations.into_iter().map(|v| PolicyViolation {
+                kind: violation_kind(v.kind) as i32,
+                message: v.message,
+                ts: Some(to_timestamp(v.ts.into())),
+                details: None,
+            }).collect(),
+        };
+        Err(Status::with_details(
+            tonic::Code::InvalidArgument,
+            format!("Mount denied: {}", message),
+            details.encode_to_vec().into(),
+        ))
+    }
+
+    /// `spec` with its named policy merged in, once its network, seccomp and mount settings
+    /// have passed the checks every new sandbox has to
+    pub async fn admit(&self, spec: SandboxSpec) -> Result<SandboxSpec, Status> {
+        let spec = self.policies.resolve(spec).await
+            .map_err(|e| Status::invalid_argument(format!("{:#}", e)))?;
+        let provider_spec = provider_spec(&spec);
+        EgressRules::from_policy(&provider_spec.policy.network)
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
+        if let Some(policy) = &spec.policy {
+            SeccompProfile::from_name(&policy.seccomp_profile)
+                .map_err(|e| Status::invalid_argument(e.to_string()))?;
+        }
+        self.check_mounts(&provider_spec)?;
+        Ok(spec)
+    }
+
+    /// Bring the sandboxes table in line with what the provider actually has after a restart
+    pub async fn reconcile(&self) -> anyhow::Result<()> {
+        let live: HashSet<String> = match self.provider.list_sandboxes().await {
+            Ok(ids) => ids.into_iter().collect(),
+            Err(e) => {
+                // Without a reliable listing we must not touch anything
+                println!("Skipping sandbox reconcile, provider unavailable: {}", e);
+                return Ok(());
+            }
+        };
+
+        let records = self.db.sandboxes_not_in(&[SandboxState::SandboxDestroyed.as_str_name()]).await?;
+        let known: HashSet<String> = records.iter().map(|r| r.sandbox_id.clone()).collect();
+        let (mut adopted, mut lost) = (0, 0);
+
+        for record in records {
+            let state = state_of(&record);
+            if !live.contains(&record.sandbox_id) {
+                if state != SandboxState::SandboxError {
+                    self.lifecycle.fail(&record.sandbox_id, "Sandbox no longer exists in the provider").await?;
+                }
+                lost += 1;
+                continue;
+            }
+
+            let spec = SandboxSpec::decode(record.spec.as_slice()).unwrap_or_default();
+            let up = match self.provider.adopt_sandbox(&record.sandbox_id, provider_spec(&spec)).await {
+                Ok(up) => up,
+                Err(e) => {
+                    // One sandbox that can't be re-attached must not keep the daemon from starting
+                    println!("Failed to adopt sandbox {}: {:#}", record.sandbox_id, e);
+                    if state != SandboxState::SandboxError {
+                        self.lifecycle.fail(&record.sandbox_id, &format!("Failed to re-attach after daemon restart: {:#}", e)).await?;
+                    }
+                    lost += 1;
+                    continue;
+                }
+            };
+            adopted += 1;
+
+            // Execs don't survive a restart, so nothing is running any more
+            if state == SandboxState::SandboxRunning {
+                self.lifecycle.transition(&record.sandbox_id, SandboxState::SandboxIdle).await?;
+            }
+            match state {
+                // Its VM went down with the daemon; StartSandbox brings it back
+                SandboxState::SandboxReady | SandboxState::SandboxIdle | SandboxState::SandboxRunning if !up => {
+                    self.lifecycle.transition(&record.sandbox_id, SandboxState::SandboxStopping).await?;
+                    self.lifecycle.transition_noting(
+                        &record.sandbox_id,
+                        SandboxState::SandboxStopped,
+                        Some("Sandbox was not running after a daemon restart"),
+                    ).await?;
+                }
+                // A create or stop that was in flight can't be resumed; surface it instead of guessing
+                SandboxState::SandboxCreating | SandboxState::SandboxBooting | SandboxState::SandboxStopping => {
+                    self.lifecycle.fail(
+                        &record.sandbox_id,
+                        &format!("Daemon restarted while sandbox was in {}", record.state),
+                    ).await?;
+                }
+                _ => {}
+            }
+        }
+
+        // Guest state without a row can't be addressed through the API; reclaim it
+        for orphan in live.difference(&known) {
+            println!("Destroying orphaned sandbox {}", orphan);
+            if let Err(e) = self.provider.destroy_sandbox(orphan, true).await {
+                println!("Failed to destroy orphaned sandbox {}: {}", orphan, e);
+            }
+        }
+
+        println!("Reconciled sandboxes: {} adopted, {} lost", adopted, lost);
+        Ok(())
+    }
+
+    async fn load(&self, sandbox_id: &str) -> Result<SandboxRecord, Status> {
+        self.db.get_sandbox(sandbox_id).await
+            .map_err(|e| Status::internal(format!("DB error: {}", e)))?
+            .ok_or_else(|| Status::not_found(format!("Sandbox {} not found", sandbox_id)))
+    }
+
+    /// Whether stopping a sandbox frees any of its resources on this provider
+    pub fn stop_reclaims(&self) -> bool {
+        self.provider.stop_reclaims()
+    }
+
+    /// Stop a sandbox; `reason` is recorded in `last_error` when the daemon decided to stop it.
+    /// `force` cancels execs still running instead of refusing to stop a RUNNING sandbox.
+    pub async fn stop(&self, sandbox_id: &str, force: bool, reason: Option<&str>) -> Result<SandboxRecord, Status> {
+        if force {
+            self.execution.cancel_sandbox_execs(sandbox_id).await?;
+        }
+        self.lifecycle.transition(sandbox_id, SandboxState::SandboxStopping).await?;
+        let stopped = self.provider.stop_sandbox(&sandbox_id.to_string(), force).await;
+        self.provider_step(sandbox_id, "Stop", stopped).await?;
+        self.lifecycle.transition_noting(sandbox_id, SandboxState::SandboxStopped, reason).await
+    }
+
+    /// Stop (if needed) and destroy a sandbox; `reason` as for `stop`
+    pub async fn destroy(&self, sandbox_id: &str, force: bool, reason: Option<&str>) -> Result<SandboxRecord, Status> {
+        if force {
+            self.execution.cancel_sandbox_execs(sandbox_id).await?;
+        }
+        let record = self.load(sandbox_id).await?;
+
+        // A live sandbox is stopped first; STOPPING also keeps new execs out while we tear down
+        if !matches!(state_of(&record), SandboxState::SandboxStopped | SandboxState::SandboxError) {
+            self.stop(sandbox_id, force, None).await?;
+        }
+
+        let destroyed = self.provider.destroy_sandbox(&sandbox_id.to_string(), force).await;
+        self.provider_step(sandbox_id, "Destroy", destroyed).await?;
+        // The snapshot it was restored from no longer has to be kept for it
+        self.db.drop_snapshot_refs(SANDBOX_REF, sandbox_id).await
+            .map_err(|e| Status::internal(format!("DB error: {}", e)))?;
+        self.lifecycle.transition_noting(sandbox_id, SandboxState::SandboxDestroyed, reason).await
+    }
+
+    /// Check the outcome of a provider call, moving the sandbox to ERROR if it failed
+    async fn provider_step(&self, sandbox_id: &str, what: &str, result: anyhow::Result<()>) -> Result<(), Status> {
+        if let Err(e) = result {
+            let error = format!("{} failed: {}", what, e);
+            let _ = self.lifecycle.fail(sandbox_id, &error).await;
+            return Err(Status::internal(error));
+        }
+        Ok(())
+    }
+}
+
+/// Sample usage of a live sandbox; None when it isn't running or the provider can't tell
+async fn sample_usage(provider: &dyn SandboxProvider, record: &SandboxRecord) -> Option<ResourceUsage> {
+    if !USABLE.contains(&state_of(record)) {
+        return None;
+    }
+    let usage = provider.usage(&record.sandbox_id).await.ok()?;
+    Some(ResourceUsage {
+        cpu_percent: usage.cpu_percent,
+        memory_mb: usage.memory_mb,
+        disk_mb: usage.disk_mb,
+        net_rx_bytes: usage.net_rx_bytes,
+        net_tx_bytes: usage.net_tx_bytes,
+        uptime_sec: (Utc::now() - record.created_at).num_seconds().max(0) as u64,
+    })
+}
+
+/// Usage changed in a way worth pushing; uptime alone ticking doesn't count
+fn usage_changed(last: &Option<ResourceUsage>, next: &Option<ResourceUsage>) -> bool {
+    let strip = |u: &Option<ResourceUsage>| u.map(|u| ResourceUsage { uptime_sec: 0, ..u });
+    strip(last) != strip(next)
+}
+
+/// Map the protobuf spec to the internal provider struct
+pub(crate) fn provider_spec(spec: &SandboxSpec) -> ProviderSandboxSpec {
+    // Unset (zero) limits get their default one by one; to providers zero means unlimited
+    let defaults = ProviderLimits::default();
+    let provider_limits = spec.limits.as_ref().map(|l| ProviderLimits {
+        vcpu: if l.vcpu > 0 { l.vcpu } else { defaults.vcpu },
+        memory_mb: if l.memory_mb > 0 { l.memory_mb } else { defaults.memory_mb },
+        disk_mb: if l.disk_mb > 0 { l.disk_mb } else { defaults.disk_mb },
+        pids_max: if l.pids_max > 0 { l.pids_max } else { defaults.pids_max },
+        sandbox_ttl: if l.sandbox_ttl_sec > 0 { Some(Duration::from_secs(l.sandbox_ttl_sec)) } else { None },
+        idle_ttl: if l.idle_ttl_sec > 0 { Some(Duration::from_secs(l.idle_ttl_sec)) } else { None },
+    }).unwrap_or_default();
+
+    let provider_policy = spec.policy.clone().map(|p| ProviderPolicy {
+        network: p.network.map(|n| ProviderNet {
+            // An allowlisted sandbox gets no loopback unless asked for: it would share the
+            // guest's, resolver included
+            allow_loopback: n.allow_loopback.unwrap_or(n.allow_domains.is_empty() && n.allow_cidrs.is_empty()),
+            deny_all: n.deny_all,
+            allow_domains: n.allow_domains,
+            allow_cidrs: n.allow_cidrs,
+        }).unwrap_or(ProviderNet { deny_all: false, allow_domains: vec![], allow_cidrs: vec![], allow_loopback: true }),
+        mounts: p.mounts.map(|m| m.mounts.into_iter().map(|mnt| MountSpec {
+            host_path: mnt.host_path.into(),
+            guest_path: mnt.guest_path.into(),
+            read_only: mnt.read_only.unwrap_or(true),
+        }).collect()).unwrap_or_default(),
+        // Unknown names are rejected at create time; fail closed for anything that slips through
+        seccomp: SeccompProfile::from_name(&p.seccomp_profile).unwrap_or(SeccompProfile::Strict),
+        enable_gpu: p.enable_gpu,
+        enable_snapshotting: p.enable_snapshotting,
+    }).unwrap_or(ProviderPolicy {
+        network: ProviderNet { deny_all: false, allow_domains: vec![], allow_cidrs: vec![], allow_loopback: true },
+        mounts: vec![],
+        seccomp: SeccompProfile::Default,
+        enable_gpu: false,
+        enable_snapshotting: false,
+    });
+
+    ProviderSandboxSpec {
+        base_image: spec.base_image.clone(),
+        working_dir: spec.working_dir.clone().into(),
+        limits: provider_limits,
+        policy: provider_policy,
+    }
+}
+
+pub(crate) fn sandbox_message(record: SandboxRecord) -> Sandbox {
+    Sandbox {
+        sandbox_id: record.sandbox_id,
+        provider: ProviderType::from_str_name(&record.provider).unwrap_or(ProviderType::Unspecified) as i32,
+        state: SandboxState::from_str_name(&record.state).unwrap_or(SandboxState::Unspecified) as i32,
+        spec: SandboxSpec::decode(record.spec.as_slice()).ok(),
+        created_at: Some(to_timestamp(record.created_at)),
+        updated_at: Some(to_timestamp(record.updated_at)),
+        last_error: record.last_error.unwrap_or_default(),
+        usage: None,
     }
 }
 
@@ -28,115 +310,73 @@ impl Sandboxes for SandboxService {
     ) -> Result<Response<CreateSandboxResponse>, Status> {
         let req = request.into_inner();
         let spec = req.spec.ok_or_else(|| Status::invalid_argument("Missing spec"))?;
-        
-        // Map protobuf struct to internal provider struct
-        let provider_limits = spec.limits.clone().map(|l| ProviderLimits {
-            vcpu: l.vcpu,
-            memory_mb: l.memory_mb,
-            disk_mb: l.disk_mb,
-            sandbox_ttl: if l.sandbox_ttl_sec > 0 { Some(Duration::from_secs(l.sandbox_ttl_sec)) } else { None },
-            idle_ttl: if l.idle_ttl_sec > 0 { Some(Duration::from_secs(l.idle_ttl_sec)) } else { None },
-        }).unwrap_or(ProviderLimits {
-            vcpu: 1, memory_mb: 2048, disk_mb: 2048, sandbox_ttl: None, idle_ttl: None,
-        });
-
-        let provider_policy = spec.policy.clone().map(|p| ProviderPolicy {
-            network: p.network.map(|n| ProviderNet {
-                deny_all: n.deny_all,
-                allow_domains: n.allow_domains,
-                allow_cidrs: n.allow_cidrs,
-            }).unwrap_or(ProviderNet { deny_all: false, allow_domains: vec![], allow_cidrs: vec![] }),
-            mounts: p.mounts.map(|m| m.mounts.into_iter().map(|mnt| MountSpec {
-                host_path: mnt.host_path.into(),
-                guest_path: mnt.guest_path.into(),
-                read_only: mnt.read_only,
-            }).collect()).unwrap_or_default(),
-            enable_gpu: p.enable_gpu,
-            enable_snapshotting: p.enable_snapshotting,
-        }).unwrap_or(ProviderPolicy {
-            network: ProviderNet { deny_all: false, allow_domains: vec![], allow_cidrs: vec![] },
-            mounts: vec![],
-            enable_gpu: false,
-            enable_snapshotting: false,
-        });
+        let spec = self.admit(spec).await?;
+        let provider_spec = provider_spec(&spec);
+        let sandbox_id = uuid::Uuid::new_v4().to_string();
+        let labels = spec.labels.as_ref().map(|l| l.items.clone()).unwrap_or_default();
 
-        let provider_spec = ProviderSandboxSpec {
-            base_image: spec.base_image.clone(),
-            working_dir: spec.working_dir.clone().into(),
-            limits: provider_limits,
-            policy: provider_policy,
-        };
+        // Record the sandbox before touching the provider so a crash mid-create is visible
+        self.db.insert_sandbox(
+            &sandbox_id,
+            provider_type(self.provider.as_ref()).as_str_name(),
+            SandboxState::SandboxCreating.as_str_name(),
+            &spec.encode_to_vec(),
+            &labels,
+        ).await
+            .map_err(|e| Status::internal(format!("DB error: {}", e)))?;
 
         // Hand off to the provider to actually execute
-        let sandbox_id = self.provider.create_sandbox(provider_spec).await
-            .map_err(|e| Status::internal(format!("Failed to create sandbox: {}", e)))?;
-
-        // Construct the generated output sandbox representation
-        let sandbox = Sandbox {
-            sandbox_id,
-            provider: ProviderType::ProviderLocalLima as i32,
-            state: SandboxState::SandboxReady as i32,
-            spec: Some(spec),
-            created_at: Some(Timestamp::date_time_nanos(2026, 1, 1, 0, 0, 0, 0).unwrap()),
-            updated_at: Some(Timestamp::date_time_nanos(2026, 1, 1, 0, 0, 0, 0).unwrap()),
-            last_error: String::new(),
-            usage: None,
-        };
+        self.lifecycle.transition(&sandbox_id, SandboxState::SandboxBooting).await?;
+        let created = self.provider.create_sandbox(&sandbox_id, provider_spec).await;
+        self.provider_step(&sandbox_id, "Create", created).await?;
+        let started = self.provider.start_sandbox(&sandbox_id).await;
+        self.provider_step(&sandbox_id, "Boot", started).await?;
+        self.lifecycle.transition(&sandbox_id, SandboxState::SandboxReady).await?;
 
         Ok(Response::new(CreateSandboxResponse {
-            sandbox: Some(sandbox),
+            sandbox: Some(sandbox_message(self.load(&sandbox_id).await?)),
         }))
     }
 
     async fn get_sandbox(
         &self,
-        _request: Request<GetSandboxRequest>,
+        request: Request<GetSandboxRequest>,
     ) -> Result<Response<Sandbox>, Status> {
-        Err(Status::unimplemented("Not yet implemented"))
+        let req = request.into_inner();
+        Ok(Response::new(sandbox_message(self.load(&req.sandbox_id).await?)))
     }
 
     async fn list_sandboxes(
         &self,
-        _request: Request<ListSandboxesRequest>,
+        request: Request<ListSandboxesRequest>,
     ) -> Result<Response<ListSandboxesResponse>, Status> {
-        let sandboxes_data = self.provider.list_sandboxes().await
-            .map_err(|e| Status::internal(format!("Failed to list sandboxes: {}", e)))?;
-
-        let mut sandboxes = Vec::new();
-        for (id, spec) in sandboxes_data {
-            // Note: In a complete implementation, we'd persist the proto `SandboxSpec` somewhere.
-            // For now, reconstruct a generic representation back from the provider spec.
-            let proto_spec = crate::pb::SandboxSpec {
-                base_image: spec.base_image,
-                working_dir: spec.working_dir.display().to_string(),
-                provider: ProviderType::ProviderLocalLima as i32,
-                labels: None,
-                limits: None, // Simplified for now
-                policy: Some(crate::pb::SandboxPolicy {
-                    policy_id: String::new(),
-                    network: None,
-                    mounts: None,
-                    enable_gpu: spec.policy.enable_gpu,
-                    enable_snapshotting: spec.policy.enable_snapshotting,
-                    strict_no_fallback: true,
-                }),
-                allow_pool_reuse: false,
-                init_cmd: vec![],
-            };
+        let req = request.into_inner();
+        let window = PageWindow::from_paging(req.paging.as_ref())?;
+        let state = match req.state() {
+            SandboxState::Unspecified => None,
+            state => Some(state.as_str_name()),
+        };
+        let provider = match req.provider() {
+            ProviderType::Unspecified => None,
+            provider => Some(provider.as_str_name()),
+        };
+        let labels = req.labels.map(|l| l.items).unwrap_or_default();
 
-            sandboxes.push(Sandbox {
-                sandbox_id: id,
-                provider: ProviderType::ProviderLocalLima as i32,
-                state: SandboxState::SandboxReady as i32,
-                spec: Some(proto_spec),
-                created_at: Some(Timestamp::date_time_nanos(2026, 1, 1, 0, 0, 0, 0).unwrap()),
-                updated_at: Some(Timestamp::date_time_nanos(2026, 1, 1, 0, 0, 0, 0).unwrap()),
-                last_error: String::new(),
-                usage: None,
-            });
-        }
+        let mut records = self.db.list_sandboxes(
+            state,
+            SandboxState::SandboxDestroyed.as_str_name(),
+            provider,
+            &labels,
+            window.fetch_limit(),
+            window.offset,
+        ).await
+            .map_err(|e| Status::internal(format!("Failed to list sandboxes: {}", e)))?;
+        let page = window.finish(&mut records);
 
-        Ok(Response::new(ListSandboxesResponse { sandboxes, page: None }))
+        Ok(Response::new(ListSandboxesResponse {
+            sandboxes: records.into_iter().map(sandbox_message).collect(),
+            page,
+        }))
     }
 
     async fn stop_sandbox(
@@ -144,9 +384,8 @@ impl Sandboxes for SandboxService {
         request: Request<StopSandboxRequest>,
     ) -> Result<Response<Sandbox>, Status> {
         let req = request.into_inner();
-        self.provider.stop_sandbox(&req.sandbox_id, req.force).await
-             .map_err(|e| Status::internal(format!("Stop failed: {}", e)))?;
-        Err(Status::unimplemented("Mock implementation"))
+        let record = self.stop(&req.sandbox_id, req.force, None).await?;
+        Ok(Response::new(sandbox_message(record)))
     }
 
     async fn destroy_sandbox(
@@ -154,19 +393,239 @@ impl Sandboxes for SandboxService {
         request: Request<DestroySandboxRequest>,
     ) -> Result<Response<DestroySandboxResponse>, Status> {
         let req = request.into_inner();
-        self.provider.destroy_sandbox(&req.sandbox_id, req.force).await
-             .map_err(|e| Status::internal(format!("Destroy failed: {}", e)))?;
+        self.destroy(&req.sandbox_id, req.force, None).await?;
         Ok(Response::new(DestroySandboxResponse {
             sandbox_id: req.sandbox_id,
         }))
     }
 
-    type WatchSandboxStream = tonic::Streaming<Sandbox>;
+    type WatchSandboxStream = ReceiverStream<Result<Sandbox, Status>>;
 
     async fn watch_sandbox(
         &self,
-        _request: Request<WatchSandboxRequest>,
+        request: Request<WatchSandboxRequest>,
     ) -> Result<Response<Self::WatchSandboxStream>, Status> {
-         Err(Status::unimplemented("Not yet implemented"))
+        let req = request.into_inner();
+        // Subscribe before reading the current state so no transition falls in between
+        let mut events = self.lifecycle.subscribe();
+        let mut record = self.load(&req.sandbox_id).await?;
+
+        let (tx, rx) = mpsc::channel(16);
+        let provider = self.provider.clone();
+        let db = self.db.clone();
+
+        tokio::spawn(async move {
+            let mut usage = sample_usage(provider.as_ref(), &record).await;
+            let mut ticker = tokio::time::interval(USAGE_INTERVAL);
+            ticker.tick().await;
+
+            loop {
+                let destroyed = state_of(&record) == SandboxState::SandboxDestroyed;
+                let message = Sandbox { usage, ..sandbox_message(record.clone()) };
+                if tx.send(Ok(message)).await.is_err() || destroyed {
+                    return;
+                }
+
+                // Wait for the next thing worth reporting
+                loop {
+                    tokio::select! {
+                        event = events.recv() => match event {
+                            Ok(updated) if updated.sandbox_id == record.sandbox_id => {
+                                record = updated;
+                                usage = sample_usage(provider.as_ref(), &record).await;
+                                break;
+                            }
+                            Ok(_) => {}
+                            // Missed some transitions; the stored row is the truth
+                            Err(broadcast::error::RecvError::Lagged(_)) => {
+                                match db.get_sandbox(&record.sandbox_id).await {
+                                    Ok(Some(current)) => {
+                                        record = current;
+                                        break;
+                                    }
+                                    _ => return,
+                                }
+                            }
+                            Err(broadcast::error::RecvError::Closed) => return,
+                        },
+                        _ = ticker.tick() => {
+                            let sampled = sample_usage(provider.as_ref(), &record).await;
+                            if usage_changed(&usage, &sampled) {
+                                usage = sampled;
+                                break;
+                            }
+                        }
+                        _ = tx.closed() => return,
+                    }
+                }
+            }
+        });
+
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pb::{Labels, NetworkPolicy, ResourceLimits, SandboxPolicy};
+    use crate::testing::{self, FakeProvider, TempDir};
+    use std::collections::HashMap;
+
+    async fn service(dir: &TempDir, provider: Arc<FakeProvider>) -> SandboxService {
+        testing::sandbox_service(dir, testing::db(dir).await, provider).await
+    }
+
+    async fn insert(service: &SandboxService, sandbox_id: &str, state: SandboxState) {
+        let spec = SandboxSpec { base_image: "ubuntu".to_string(), ..Default::default() };
+        service.db.insert_sandbox(sandbox_id, "fake", state.as_str_name(), &spec.encode_to_vec(), &HashMap::new()).await.unwrap();
+    }
+
+    async fn state(service: &SandboxService, sandbox_id: &str) -> (SandboxState, String) {
+        let record = service.load(sandbox_id).await.unwrap();
+        (state_of(&record), record.last_error.unwrap_or_default())
+    }
+
+    fn network(allow_domains: &[&str], allow_loopback: Option<bool>) -> ProviderNet {
+        let spec = SandboxSpec {
+            policy: Some(SandboxPolicy {
+                network: Some(NetworkPolicy {
+                    deny_all: false,
+                    allow_domains: allow_domains.iter().map(|d| d.to_string()).collect(),
+                    allow_cidrs: vec![],
+                    allow_loopback,
+                }),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        provider_spec(&spec).policy.network
+    }
+
+    #[test]
+    fn unset_limits_get_their_own_default() {
+        let spec = SandboxSpec {
+            limits: Some(ResourceLimits { memory_mb: 512, idle_ttl_sec: 60, ..Default::default() }),
+            ..Default::default()
+        };
+        let limits = provider_spec(&spec).limits;
+        let defaults = ProviderLimits::default();
+        assert_eq!(limits.memory_mb, 512);
+        assert_eq!((limits.vcpu, limits.disk_mb, limits.pids_max), (defaults.vcpu, defaults.disk_mb, defaults.pids_max));
+        assert_eq!((limits.sandbox_ttl, limits.idle_ttl), (None, Some(Duration::from_secs(60))));
+
+        let unset = provider_spec(&SandboxSpec::default()).limits;
+        assert_eq!((unset.vcpu, unset.memory_mb, unset.disk_mb), (defaults.vcpu, defaults.memory_mb, defaults.disk_mb));
+    }
+
+    #[test]
+    fn loopback_is_off_by_default_with_an_allowlist() {
+        assert!(network(&[], None).allow_loopback);
+        assert!(!network(&["pypi.org"], None).allow_loopback);
+        assert!(network(&["pypi.org"], Some(true)).allow_loopback);
+        assert!(!network(&[], Some(false)).allow_loopback);
+        assert!(provider_spec(&SandboxSpec::default()).policy.network.allow_loopback);
+    }
+
+    #[tokio::test]
+    async fn reconcile_matches_the_table_to_the_provider() {
+        use SandboxState::*;
+        let dir = TempDir::new("reconcile");
+        let provider = FakeProvider::with_sandboxes(&[
+            ("ready", true), ("running", true), ("went-down", false), ("booting", true),
+            ("stopped", false), ("broken", true), ("orphan", true),
+        ]);
+        provider.broken.lock().unwrap().insert("broken".to_string());
+        let service = service(&dir, provider.clone()).await;
+        for (sandbox_id, state) in [
+            ("ready", SandboxReady), ("running", SandboxRunning), ("went-down", SandboxIdle),
+            ("booting", SandboxBooting), ("stopped", SandboxStopped), ("broken", SandboxReady),
+            ("gone", SandboxReady), ("destroyed", SandboxDestroyed),
+        ] {
+            insert(&service, sandbox_id, state).await;
+        }
+
+        service.reconcile().await.unwrap();
+
+        assert_eq!(state(&service, "ready").await.0, SandboxReady);
+        // Execs don't survive a restart
+        assert_eq!(state(&service, "running").await.0, SandboxIdle);
+        assert_eq!(state(&service, "went-down").await, (SandboxStopped, "Sandbox was not running after a daemon restart".to_string()));
+        assert_eq!(state(&service, "stopped").await.0, SandboxStopped);
+        assert_eq!(state(&service, "destroyed").await.0, SandboxDestroyed);
+
+        let (booting, error) = state(&service, "booting").await;
+        assert_eq!(booting, SandboxError);
+        assert!(error.contains("SANDBOX_BOOTING"), "{}", error);
+        let (broken, error) = state(&service, "broken").await;
+        assert_eq!(broken, SandboxError);
+        assert!(error.starts_with("Failed to re-attach"), "{}", error);
+        assert_eq!(state(&service, "gone").await, (SandboxError, "Sandbox no longer exists in the provider".to_string()));
+
+        // Provider state without a row is reclaimed; rows are never created for it
+        assert_eq!(provider.is_up("orphan"), None);
+        assert!(service.db.get_sandbox("orphan").await.unwrap().is_none());
+
+        // Running it again changes nothing
+        service.reconcile().await.unwrap();
+        assert_eq!(state(&service, "went-down").await.0, SandboxStopped);
+        assert_eq!(state(&service, "broken").await.0, SandboxError);
+    }
+
+    #[tokio::test]
+    async fn created_sandboxes_keep_their_spec_and_labels() {
+        let dir = TempDir::new("sandbox-get");
+        let service = service(&dir, Arc::new(FakeProvider::default())).await;
+        let spec = SandboxSpec {
+            base_image: "ubuntu".to_string(),
+            limits: Some(ResourceLimits { memory_mb: 512, ..Default::default() }),
+            labels: Some(Labels { items: HashMap::from([("team".to_string(), "ml".to_string())]) }),
+            ..Default::default()
+        };
+        let created = service.create_sandbox(Request::new(CreateSandboxRequest { spec: Some(spec.clone()) }))
+            .await.unwrap().into_inner().sandbox.unwrap();
+        assert_eq!(created.state(), SandboxState::SandboxReady);
+
+        let request = GetSandboxRequest { sandbox_id: created.sandbox_id.clone() };
+        let got = service.get_sandbox(Request::new(request)).await.unwrap().into_inner();
+        assert_eq!(got.spec, Some(spec));
+        assert_eq!(got.state(), SandboxState::SandboxReady);
+
+        let request = GetSandboxRequest { sandbox_id: "nope".to_string() };
+        assert_eq!(service.get_sandbox(Request::new(request)).await.unwrap_err().code(), tonic::Code::NotFound);
+    }
+
+    #[test]
+    fn uptime_alone_is_not_a_usage_change() {
+        let usage = |memory_mb, uptime_sec| Some(ResourceUsage { memory_mb, uptime_sec, ..Default::default() });
+        assert!(!usage_changed(&usage(100, 1), &usage(100, 60)));
+        assert!(usage_changed(&usage(100, 1), &usage(200, 1)));
+        assert!(usage_changed(&usage(100, 1), &None));
+        assert!(!usage_changed(&None, &None));
+    }
+
+    #[tokio::test]
+    async fn watch_follows_one_sandbox_until_it_is_destroyed() {
+        use SandboxState::*;
+        let dir = TempDir::new("watch");
+        let service = service(&dir, FakeProvider::with_sandboxes(&[("sb", true), ("other", true)])).await;
+        insert(&service, "sb", SandboxReady).await;
+        insert(&service, "other", SandboxReady).await;
+
+        let request = WatchSandboxRequest { sandbox_id: "sb".to_string() };
+        let stream = service.watch_sandbox(Request::new(request)).await.unwrap().into_inner();
+        service.stop("other", false, None).await.unwrap();
+        service.destroy("sb", false, None).await.unwrap();
+
+        let updates: Vec<Sandbox> = tokio_stream::StreamExt::collect::<Result<Vec<_>, _>>(stream).await.unwrap();
+        let states: Vec<SandboxState> = updates.iter().map(|u| u.state()).collect();
+        assert_eq!(states, [SandboxReady, SandboxStopping, SandboxStopped, SandboxDestroyed]);
+        assert!(updates.iter().all(|u| u.sandbox_id == "sb"));
+        // Usage is only sampled while the sandbox is live
+        assert!(updates[0].usage.is_some());
+        assert!(updates[1..].iter().all(|u| u.usage.is_none()));
+
+        let request = WatchSandboxRequest { sandbox_id: "nope".to_string() };
+        assert_eq!(service.watch_sandbox(Request::new(request)).await.unwrap_err().code(), tonic::Code::NotFound);
     }
 }
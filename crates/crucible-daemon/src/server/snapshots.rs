@@ -1,25 +1,386 @@
-use crate::db::Db;
+use crate::db::{Db, SnapshotRecord};
 use crate::pb::snapshots_server::Snapshots;
+use crate::pb::fsck_issue::Kind as FsckKind;
 use crate::pb::{
-    CreateSnapshotRequest, DeleteSnapshotRequest, DeleteSnapshotResponse, 
-    GarbageCollectSnapshotsRequest, GarbageCollectSnapshotsResponse, 
-    GetSnapshotRequest, ListSnapshotsRequest, ListSnapshotsResponse, 
-    RestoreSnapshotRequest, Snapshot, SnapshotSpec
+    CreateSnapshotRequest, DeleteSnapshotRequest, DeleteSnapshotResponse, FsckIssue, FsckSnapshotsRequest,
+    FsckSnapshotsResponse, GarbageCollectSnapshotsRequest, GarbageCollectSnapshotsResponse, 
+    GetSnapshotRequest, GetSnapshotTreeRequest, GetSnapshotTreeResponse, Labels, ListSnapshotsRequest,
+    ListSnapshotsResponse, RestoreSnapshotRequest, SandboxState, Snapshot, SnapshotFile, SnapshotTreeNode,
+    VerifySnapshotRequest, VerifySnapshotResponse
 };
-use crate::provider::{SandboxId, SandboxProvider};
-use crate::store::SnapshotStore;
-use std::sync::Arc;
+use crate::provider::{SandboxProvider, SnapshotMode, SnapshotRef};
+use crate::server::{provider_type, to_timestamp, PageWindow};
+use crate::server::lifecycle::Lifecycle;
+use crate::server::sandboxes::{sandbox_message, SandboxService};
+use crate::store::{FileCheck, SnapshotManifest, SnapshotStore};
+use chrono::Utc;
+use prost::Message;
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
 use tonic::{Request, Response, Status};
 
+/// Deleted snapshots keep their row for lineage but are invisible to clients
+const DELETED: &str = "DELETED";
+const READY: &str = "READY";
+const CREATING: &str = "CREATING";
+
+/// What fsck repairs on startup: leftovers of interrupted snapshots. Damage to committed ones is
+/// only reported, and repaired through `FsckSnapshots`.
+const STARTUP_REPAIRS: [FsckKind; 3] = [FsckKind::StaleCreating, FsckKind::OrphanTmpDir, FsckKind::OrphanChunk];
+const ALL_REPAIRS: [FsckKind; 5] = [
+    FsckKind::StaleCreating,
+    FsckKind::OrphanTmpDir,
+    FsckKind::IncompleteReady,
+    FsckKind::UntrackedDir,
+    FsckKind::OrphanChunk,
+];
+
+/// `snapshot_refs.ref_type` of a sandbox restored from the snapshot
+pub(crate) const SANDBOX_REF: &str = "sandbox";
+
 pub struct SnapshotService {
     provider: Arc<dyn SandboxProvider>,
     db: Db,
     store: Arc<SnapshotStore>,
+    sandboxes: SandboxService,
+    lifecycle: Arc<Lifecycle>,
+    // Snapshots being created right now; fsck leaves their rows and staging directories alone
+    creating: Mutex<HashSet<String>>,
+}
+
+/// Registers a snapshot as being created until dropped
+struct Creating<'a> {
+    creating: &'a Mutex<HashSet<String>>,
+    snapshot_id: String,
+}
+
+impl Drop for Creating<'_> {
+    fn drop(&mut self) {
+        self.creating.lock().unwrap().remove(&self.snapshot_id);
+    }
 }
 
 impl SnapshotService {
-    pub fn new(provider: Arc<dyn SandboxProvider>, db: Db, store: Arc<SnapshotStore>) -> Self {
-        Self { provider, db, store }
+    pub fn new(
+        provider: Arc<dyn SandboxProvider>,
+        db: Db,
+        store: Arc<SnapshotStore>,
+        sandboxes: SandboxService,
+        lifecycle: Arc<Lifecycle>,
+    ) -> Self {
+        Self { provider, db, store, sandboxes, lifecycle, creating: Mutex::new(HashSet::new()) }
+    }
+
+    fn begin_creating(&self, snapshot_id: &str) -> Creating<'_> {
+        self.creating.lock().unwrap().insert(snapshot_id.to_string());
+        Creating { creating: &self.creating, snapshot_id: snapshot_id.to_string() }
+    }
+
+    /// Clean up after snapshots interrupted by a crash, and warn about damage to committed ones
+    pub async fn recover(&self) -> anyhow::Result<()> {
+        let report = self.fsck(&STARTUP_REPAIRS).await.map_err(|e| anyhow::anyhow!("{}", e.message()))?;
+        for issue in &report.issues {
+            let kind = FsckKind::try_from(issue.kind).unwrap_or_default().as_str_name();
+            if issue.repaired {
+                println!("Snapshot recovery: {} {}: {} (repaired)", kind, issue.id, issue.detail);
+            } else {
+                println!("Snapshot Warning: {} {}: {} (run snapshot fsck --repair)", kind, issue.id, issue.detail);
+            }
+        }
+        Ok(())
+    }
+
+    /// Check the snapshot rows, the store's directories and its chunks against each other,
+    /// repairing issues of the kinds in `repair`
+    async fn fsck(&self, repair: &[FsckKind]) -> Result<FsckSnapshotsResponse, Status> {
+        let db_err = |e: anyhow::Error| Status::internal(format!("DB error: {}", e));
+        let store_err = |e: anyhow::Error| Status::internal(format!("Snapshot store error: {}", e));
+        let creating_rows = self.db.snapshots_in_state(CREATING).await.map_err(db_err)?;
+        let ready_rows = self.db.snapshots_in_state(READY).await.map_err(db_err)?;
+        let states = self.db.snapshot_states().await.map_err(db_err)?;
+        let staged = self.store.staged_snapshots().await.map_err(store_err)?;
+        let dirs = self.store.snapshot_dirs().await.map_err(store_err)?;
+        // Taken after listing: anything listed that is still in progress registered before
+        let creating = self.creating.lock().unwrap().clone();
+
+        let mut issues = Vec::new();
+        let mut repaired_any = false;
+        let mut issue = |kind: FsckKind, id: &str, detail: String, repaired: bool| {
+            repaired_any |= repaired;
+            issues.push(FsckIssue { kind: kind as i32, id: id.to_string(), detail, repaired });
+        };
+
+        for record in creating_rows.iter().filter(|r| !creating.contains(&r.snapshot_id)) {
+            let detail = "Snapshot creation was interrupted".to_string();
+            let repaired = repair.contains(&FsckKind::StaleCreating)
+                && self.db.mark_snapshot_failed(&record.snapshot_id, CREATING, &detail, &[]).await.map_err(db_err)?;
+            issue(FsckKind::StaleCreating, &record.snapshot_id, detail, repaired);
+        }
+
+        for snapshot_id in staged.iter().filter(|id| !creating.contains(*id)) {
+            let repaired = repair.contains(&FsckKind::OrphanTmpDir);
+            if repaired {
+                self.store.abort_snapshot(snapshot_id).await.map_err(store_err)?;
+            }
+            issue(FsckKind::OrphanTmpDir, snapshot_id, "Staging directory of an interrupted snapshot".to_string(), repaired);
+        }
+
+        for record in &ready_rows {
+            if self.store.is_snapshot_ready(&record.snapshot_id).await {
+                continue;
+            }
+            let detail = "Snapshot directory is missing or lacks the COMPLETE marker".to_string();
+            let repaired = repair.contains(&FsckKind::IncompleteReady)
+                && self.db.mark_snapshot_failed(&record.snapshot_id, READY, &detail, &chunk_ids(record)).await.map_err(db_err)?;
+            if repaired {
+                self.store.delete_snapshot(&record.snapshot_id).await.map_err(store_err)?;
+            }
+            issue(FsckKind::IncompleteReady, &record.snapshot_id, detail, repaired);
+        }
+
+        for (snapshot_id, complete) in &dirs {
+            let tracked = match states.get(snapshot_id).map(String::as_str) {
+                Some(READY) => true,
+                Some(CREATING) => creating.contains(snapshot_id),
+                _ => false,
+            };
+            if tracked {
+                continue;
+            }
+            let detail = match (states.get(snapshot_id), complete) {
+                (None, true) => "Committed snapshot directory has no row".to_string(),
+                (None, false) => "Incomplete snapshot directory has no row".to_string(),
+                (Some(state), _) => format!("Directory left behind by a {} snapshot", state),
+            };
+            let repaired = repair.contains(&FsckKind::UntrackedDir);
+            if repaired {
+                self.store.delete_snapshot(snapshot_id).await.map_err(store_err)?;
+            }
+            issue(FsckKind::UntrackedDir, snapshot_id, detail, repaired);
+        }
+
+        // Chunks are only unknown between being written and referenced, which the lock excludes
+        let mut reclaimed_bytes = 0;
+        {
+            let _sweeping = self.store.lock_chunks_exclusive().await;
+            let known = self.db.known_chunks().await.map_err(db_err)?.into_iter().collect();
+            let orphans = self.store.unknown_chunk_files(known).await.map_err(store_err)?;
+            let repaired = repair.contains(&FsckKind::OrphanChunk);
+            if repaired {
+                let paths = orphans.iter().map(|(_, path, _)| path.clone()).collect();
+                self.store.remove_files(paths).await.map_err(store_err)?;
+            }
+            for (name, _, size) in &orphans {
+                if repaired {
+                    reclaimed_bytes += size;
+                }
+                issue(FsckKind::OrphanChunk, name, format!("{} bytes", size), repaired);
+            }
+        }
+
+        // Failing READY snapshots released their chunks
+        if repaired_any {
+            reclaimed_bytes += self.sweep_chunks().await?;
+        }
+        Ok(FsckSnapshotsResponse { issues, reclaimed_bytes })
+    }
+
+    /// The snapshot's record, or NOT_FOUND if it doesn't exist or was deleted
+    async fn load(&self, snapshot_id: &str) -> Result<SnapshotRecord, Status> {
+        self.db.get_snapshot(snapshot_id).await
+            .map_err(|e| Status::internal(format!("DB error: {}", e)))?
+            .filter(|record| record.state != DELETED)
+            .ok_or_else(|| Status::not_found(format!("Snapshot {} not found", snapshot_id)))
+    }
+
+    /// The snapshot a new snapshot of the sandbox descends from: its latest one, or else the one
+    /// it was restored from
+    async fn parent_of(&self, sandbox_id: &str) -> Result<Option<SnapshotRecord>, Status> {
+        let db_err = |e: anyhow::Error| Status::internal(format!("DB error: {}", e));
+        if let Some(latest) = self.db.latest_snapshot(sandbox_id, READY).await.map_err(db_err)? {
+            return Ok(Some(latest));
+        }
+        let Some(origin) = self.db.referenced_snapshot(SANDBOX_REF, sandbox_id).await.map_err(db_err)? else {
+            return Ok(None);
+        };
+        Ok(self.db.get_snapshot(&origin).await.map_err(db_err)?.filter(|record| record.state == READY))
+    }
+
+    /// `from` and its ancestors, nearest first, for as long as they're all committed
+    async fn lineage(&self, from: Option<SnapshotRecord>) -> Result<Vec<SnapshotRecord>, Status> {
+        let mut lineage = Vec::new();
+        let mut next = from;
+        while let Some(record) = next {
+            if record.state != READY || !self.store.is_snapshot_ready(&record.snapshot_id).await {
+                break;
+            }
+            let parent = record.parent_snapshot_id.clone();
+            lineage.push(record);
+            next = match parent {
+                Some(parent) => self.db.get_snapshot(&parent).await
+                    .map_err(|e| Status::internal(format!("DB error: {}", e)))?,
+                None => None,
+            };
+        }
+        Ok(lineage)
+    }
+
+    /// Rebuild a snapshot and its delta ancestors from the chunk store and have the provider
+    /// restore it as `new_sandbox_id`
+    async fn restore_into(&self, record: &SnapshotRecord, new_sandbox_id: &str) -> Result<(), Status> {
+        let lineage = match &record.parent_snapshot_id {
+            Some(parent) => {
+                let parent = self.db.get_snapshot(parent).await
+                    .map_err(|e| Status::internal(format!("DB error: {}", e)))?;
+                self.lineage(parent).await?
+            }
+            None => vec![],
+        };
+        let ancestors = delta_ancestors(record, lineage);
+
+        // Providers get plain files, rebuilt from the chunk store for the length of the restore
+        let mut ids = vec![record.snapshot_id.clone()];
+        ids.extend(ancestors.iter().map(|a| a.snapshot_id.clone()));
+        let checkout = self.store.checkout(&ids).await
+            .map_err(|e| Status::internal(format!("Failed to check out snapshot: {}", e)))?;
+        // The provider only gets files proven identical to what was captured
+        let verified = match checkout.verify().await {
+            Ok(checks) => check_integrity(&self.db, &checks).await,
+            Err(e) => Err(Status::internal(format!("Failed to verify snapshot: {}", e))),
+        };
+        if let Err(status) = verified {
+            checkout.remove().await;
+            return Err(status);
+        }
+        let refs: Vec<SnapshotRef> = ancestors.iter().filter_map(|a| {
+            let dir = checkout.dir(&a.snapshot_id)?.to_path_buf();
+            Some(SnapshotRef { snapshot_id: a.snapshot_id.clone(), dir })
+        }).collect();
+        let snapshot_dir = checkout.dir(&record.snapshot_id).map(Path::to_path_buf).unwrap_or_default();
+        let restored = self.provider.restore_snapshot(&record.snapshot_id, &new_sandbox_id.to_string(), &snapshot_dir, &refs).await;
+        checkout.remove().await;
+        restored.map_err(|e| Status::internal(format!("Provider restore failed: {}", e)))
+    }
+
+    /// Remove a snapshot from the provider, the store and the DB. Its chunks are left for
+    /// `sweep_chunks`, as other snapshots may share them.
+    async fn remove_snapshot(&self, record: &SnapshotRecord) -> Result<(), Status> {
+        // Snapshots taken by another backend have no state in this one
+        if record.provider == self.provider.provider_name() {
+            self.provider.delete_snapshot(&record.snapshot_id).await
+                .map_err(|e| Status::internal(format!("Provider delete failed: {}", e)))?;
+        }
+        self.store.delete_snapshot(&record.snapshot_id).await
+            .map_err(|e| Status::internal(format!("Failed to delete snapshot files: {}", e)))?;
+        self.db.mark_snapshot_deleted(&record.snapshot_id, &chunk_ids(record)).await
+            .map_err(|e| Status::internal(format!("DB error: {}", e)))?;
+        Ok(())
+    }
+
+    /// Remove chunks no snapshot references any more, returning the bytes freed
+    async fn sweep_chunks(&self) -> Result<u64, Status> {
+        let _sweeping = self.store.lock_chunks_exclusive().await;
+        let unreferenced = self.db.unreferenced_chunks().await
+            .map_err(|e| Status::internal(format!("DB error: {}", e)))?;
+        let freed = unreferenced.iter().map(|(_, size)| size).sum();
+        let sha256s: Vec<String> = unreferenced.into_iter().map(|(sha256, _)| sha256).collect();
+        self.store.remove_chunks(sha256s.clone()).await
+            .map_err(|e| Status::internal(format!("Failed to remove chunks: {}", e)))?;
+        self.db.drop_chunks(&sha256s).await
+            .map_err(|e| Status::internal(format!("DB error: {}", e)))?;
+        Ok(freed)
+    }
+}
+
+/// Check a READY snapshot's files in the store. One whose manifest or files can't even be read
+/// has lost data just as much as one that fails a check, so both are DATA_LOSS.
+async fn verify_stored(db: &Db, store: &SnapshotStore, snapshot_id: &str) -> Result<Vec<FileCheck>, Status> {
+    let checks = match store.verify_snapshot(snapshot_id).await {
+        Ok(checks) => checks,
+        Err(e) => {
+            let _ = db.set_snapshot_error(snapshot_id, Some(&format!("Failed verification: {}", e))).await;
+            return Err(Status::data_loss(format!("Cannot verify snapshot {}: {}", snapshot_id, e)));
+        }
+    };
+    let checks: Vec<(String, FileCheck)> = checks.into_iter().map(|c| (snapshot_id.to_string(), c)).collect();
+    check_integrity(db, &checks).await?;
+    Ok(checks.into_iter().map(|(_, check)| check).collect())
+}
+
+/// DATA_LOSS if any file failed its check, after noting the problems on each snapshot
+async fn check_integrity(db: &Db, checks: &[(String, FileCheck)]) -> Result<(), Status> {
+    let mut problems: HashMap<&str, Vec<String>> = HashMap::new();
+    for (snapshot_id, check) in checks {
+        if let Some(problem) = &check.problem {
+            problems.entry(snapshot_id).or_default().push(format!("{}: {}", check.name, problem));
+        }
+    }
+    if problems.is_empty() {
+        return Ok(());
+    }
+    let mut details = Vec::new();
+    for (snapshot_id, files) in problems {
+        let error = format!("Failed verification: {}", files.join("; "));
+        let _ = db.set_snapshot_error(snapshot_id, Some(&error)).await;
+        details.push(format!("snapshot {}: {}", snapshot_id, files.join("; ")));
+    }
+    Err(Status::data_loss(format!("Snapshot data does not match its manifest ({})", details.join("; "))))
+}
+
+/// A non-empty id of letters, digits and '-' that is safe to use as a path component
+fn is_plain_id(id: &str) -> bool {
+    !id.is_empty() && id.len() <= 64 && id.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-')
+}
+
+/// The manifest in a snapshot's `components` column; rows from before the chunk store only
+/// list components
+fn manifest_of(record: &SnapshotRecord) -> SnapshotManifest {
+    let Some(raw) = record.components.as_deref() else {
+        return SnapshotManifest::default();
+    };
+    serde_json::from_str(raw)
+        .or_else(|_| serde_json::from_str(raw).map(|components| SnapshotManifest { files: vec![], components }))
+        .unwrap_or_default()
+}
+
+fn chunk_ids(record: &SnapshotRecord) -> Vec<String> {
+    manifest_of(record).chunks().into_iter().map(|(sha256, _)| sha256).collect()
+}
+
+fn delta_bases(record: &SnapshotRecord) -> impl Iterator<Item = String> {
+    manifest_of(record).components.into_iter().filter_map(|c| c.delta_of)
+}
+
+/// The ancestors in `lineage` a snapshot's deltas are stored against, including those their
+/// own deltas need
+fn delta_ancestors(record: &SnapshotRecord, lineage: Vec<SnapshotRecord>) -> Vec<SnapshotRecord> {
+    let mut pending: HashSet<String> = delta_bases(record).collect();
+    let mut needed = Vec::new();
+    for ancestor in lineage {
+        if pending.is_empty() {
+            break;
+        }
+        if pending.remove(&ancestor.snapshot_id) {
+            pending.extend(delta_bases(&ancestor));
+            needed.push(ancestor);
+        }
+    }
+    needed
+}
+
+pub(crate) fn snapshot_message(record: SnapshotRecord) -> Snapshot {
+    let labels = record.labels.as_deref().and_then(|l| serde_json::from_str(l).ok()).unwrap_or_default();
+    Snapshot {
+        snapshot_id: record.snapshot_id,
+        sandbox_id: record.source_sandbox_id,
+        name: record.name.unwrap_or_default(),
+        labels: Some(Labels { items: labels }),
+        created_at: Some(to_timestamp(record.created_at)),
+        size_bytes: record.size_bytes as u64,
+        parent_snapshot_id: record.parent_snapshot_id.unwrap_or_default(),
+        last_error: record.last_error.unwrap_or_default(),
+        root_snapshot_id: record.root_snapshot_id,
     }
 }
 
@@ -32,6 +393,7 @@ impl Snapshots for SnapshotService {
         let req = request.into_inner();
         let spec = req.spec.ok_or_else(|| Status::invalid_argument("Missing spec"))?;
         let snapshot_id = uuid::Uuid::new_v4().to_string();
+        let _creating = self.begin_creating(&snapshot_id);
 
         let tmp_dir = self.store.begin_snapshot(&snapshot_id).await
             .map_err(|e| Status::internal(format!("Failed to prepare tmp dir: {}", e)))?;
@@ -45,58 +407,92 @@ impl Snapshots for SnapshotService {
             2 => "MEMORY_ONLY",
             _ => "FULL", // default
         };
+        let mode = match spec.mode {
+            2 => SnapshotMode::MemoryOnly,
+            _ => SnapshotMode::Full,
+        };
 
-        self.db.insert_snapshot(
-            &snapshot_id,
-            provider_name,
-            &spec.sandbox_id,
-            mode_str,
-            &snapshot_id, // simple root for now
-        ).await.map_err(|e| Status::internal(format!("DB error: {}", e)))?;
+        let parent = self.parent_of(&spec.sandbox_id).await?;
+        let root_snapshot_id = parent.as_ref().map_or_else(|| snapshot_id.clone(), |p| p.root_snapshot_id.clone());
+        let parent_snapshot_id = parent.as_ref().map(|p| p.snapshot_id.clone());
+        let ancestors: Vec<String> = self.lineage(parent).await?.into_iter().map(|r| r.snapshot_id).collect();
+
+        let labels = spec.labels.as_ref().map(|l| l.items.clone()).unwrap_or_default();
+        self.db.insert_snapshot(&SnapshotRecord {
+            snapshot_id: snapshot_id.clone(),
+            provider: provider_name.to_string(),
+            source_sandbox_id: spec.sandbox_id.clone(),
+            created_at: Utc::now(),
+            mode: mode_str.to_string(),
+            name: Some(spec.name.clone()).filter(|n| !n.is_empty()),
+            labels: Some(serde_json::to_string(&labels).map_err(|e| Status::internal(e.to_string()))?),
+            parent_snapshot_id,
+            root_snapshot_id,
+            state: CREATING.to_string(),
+            size_bytes: 0,
+            components: None,
+            pinned: false,
+            ttl_expires_at: None,
+            last_error: None,
+        }).await.map_err(|e| Status::internal(format!("DB error: {}", e)))?;
 
         // 2. Call `self.provider.create_snapshot(&spec.sandbox_id, &tmp_dir)`
-        let meta = match self.provider.create_snapshot(&spec.sandbox_id, &tmp_dir).await {
+        let meta = match self.provider.create_snapshot(&spec.sandbox_id, &tmp_dir, mode, &ancestors).await {
             Ok(m) => m,
             Err(e) => {
                 let _ = self.store.abort_snapshot(&snapshot_id).await;
-                // Ideally also update DB to FAILED here
-                return Err(Status::internal(format!("Provider snapshot failed: {}", e)));
+                let error = format!("Provider snapshot failed: {}", e);
+                let _ = self.db.mark_snapshot_failed(&snapshot_id, CREATING, &error, &[]).await;
+                return Err(Status::internal(error));
             }
         };
 
-        // 3. Gather hashes and metadata
-        // 4. `self.store.commit_snapshot(&snapshot_id)`
-        let _final_dir = self.store.commit_snapshot(&snapshot_id).await
-            .map_err(|e| Status::internal(format!("Failed to commit disk store: {}", e)))?;
+        // 3. Hash and chunk the files into the store, keeping sweeps out until the chunks are
+        //    referenced
+        let chunks_lock = self.store.lock_chunks().await;
+        let manifest = match self.store.commit_snapshot(&snapshot_id, meta.components).await {
+            Ok(manifest) => manifest,
+            Err(e) => {
+                let _ = self.store.abort_snapshot(&snapshot_id).await;
+                let error = format!("Failed to commit disk store: {}", e);
+                let _ = self.db.mark_snapshot_failed(&snapshot_id, CREATING, &error, &[]).await;
+                return Err(Status::internal(error));
+            }
+        };
 
-        // 5. Write `state=READY` to DB
-        self.db.set_snapshot_ready(&snapshot_id, meta.size_bytes).await
+        // 4. Write `state=READY` and the manifest to DB
+        let components = serde_json::to_string(&manifest).map_err(|e| Status::internal(e.to_string()))?;
+        self.db.set_snapshot_ready(&snapshot_id, meta.size_bytes, &components, &manifest.chunks()).await
             .map_err(|e| Status::internal(format!("DB finalize error: {}", e)))?;
+        drop(chunks_lock);
 
-        Ok(Response::new(Snapshot {
-            snapshot_id,
-            sandbox_id: spec.sandbox_id,
-            name: spec.name,
-            labels: spec.labels,
-            created_at: Some(prost_types::Timestamp::date_time_nanos(2026, 1, 1, 0, 0, 0, 0).unwrap()),
-            size_bytes: meta.size_bytes,
-            parent_snapshot_id: String::new(),
-            last_error: String::new(),
-        }))
+        Ok(Response::new(snapshot_message(self.load(&snapshot_id).await?)))
     }
 
     async fn get_snapshot(
         &self,
-        _request: Request<GetSnapshotRequest>,
+        request: Request<GetSnapshotRequest>,
     ) -> Result<Response<Snapshot>, Status> {
-        Err(Status::unimplemented("Not implemented"))
+        let req = request.into_inner();
+        Ok(Response::new(snapshot_message(self.load(&req.snapshot_id).await?)))
     }
 
     async fn list_snapshots(
         &self,
-        _request: Request<ListSnapshotsRequest>,
+        request: Request<ListSnapshotsRequest>,
     ) -> Result<Response<ListSnapshotsResponse>, Status> {
-        Err(Status::unimplemented("Not implemented"))
+        let req = request.into_inner();
+        let window = PageWindow::from_paging(req.paging.as_ref())?;
+        let sandbox_id = if req.sandbox_id.is_empty() { None } else { Some(req.sandbox_id.as_str()) };
+
+        let mut records = self.db.list_snapshots(sandbox_id, DELETED, window.fetch_limit(), window.offset).await
+            .map_err(|e| Status::internal(format!("DB error: {}", e)))?;
+        let page = window.finish(&mut records);
+
+        Ok(Response::new(ListSnapshotsResponse {
+            snapshots: records.into_iter().map(snapshot_message).collect(),
+            page,
+        }))
     }
 
     async fn restore_snapshot(
@@ -106,42 +502,147 @@ impl Snapshots for SnapshotService {
         let req = request.into_inner();
         let spec = req.spec.ok_or_else(|| Status::invalid_argument("Missing restore spec"))?;
         
-        let state = self.db.get_snapshot_state(&spec.snapshot_id).await
-            .map_err(|e| Status::not_found(format!("Snapshot not found: {}", e)))?;
-            
-        if state != "READY" {
+        let record = self.load(&spec.snapshot_id).await?;
+        if record.state != READY {
             return Err(Status::failed_precondition("Snapshot is not READY"));
         }
         
-        let snapshot_dir = self.store.get_snapshot_dir(&spec.snapshot_id)
-            .ok_or_else(|| Status::internal("Snapshot directory missing COMPLETE marker"))?;
-            
+        if !self.store.is_snapshot_ready(&spec.snapshot_id).await {
+            // The DB says READY, so the committed files existed once and have since been lost
+            return Err(Status::data_loss(format!(
+                "Snapshot {} directory is missing or lacks the COMPLETE marker", spec.snapshot_id
+            )));
+        }
+
+        // Providers turn the id into host paths, so a caller-chosen id must be a plain token
         let new_sandbox_id = if spec.target_sandbox_id.is_empty() {
             uuid::Uuid::new_v4().to_string()
+        } else if !is_plain_id(&spec.target_sandbox_id) {
+            return Err(Status::invalid_argument("target_sandbox_id may only contain letters, digits and '-'"));
         } else {
             spec.target_sandbox_id
         };
-        
-        self.provider.restore_snapshot(&spec.snapshot_id, &new_sandbox_id, &snapshot_dir).await
-            .map_err(|e| Status::internal(format!("Provider restore failed: {}", e)))?;
-            
-        Ok(Response::new(crate::pb::Sandbox {
-            sandbox_id: new_sandbox_id,
-            provider: crate::pb::ProviderType::ProviderLocalLima as i32,
-            state: crate::pb::SandboxState::SandboxReady as i32,
-            spec: spec.new_sandbox_spec,
-            created_at: Some(prost_types::Timestamp::date_time_nanos(2026, 1, 1, 0, 0, 0, 0).unwrap()),
-            updated_at: Some(prost_types::Timestamp::date_time_nanos(2026, 1, 1, 0, 0, 0, 0).unwrap()),
-            last_error: String::new(),
-            usage: None,
-        }))
+        // Held to the same policy, mount and seccomp checks as CreateSandbox
+        let new_spec = self.sandboxes.admit(spec.new_sandbox_spec.unwrap_or_default()).await?;
+        let labels = new_spec.labels.as_ref().map(|l| l.items.clone()).unwrap_or_default();
+
+        // The row reserves the id: restoring over a live sandbox would clobber its disk, so a
+        // second restore to the same id fails here before the provider is touched
+        self.db.insert_sandbox(
+            &new_sandbox_id,
+            provider_type(self.provider.as_ref()).as_str_name(),
+            SandboxState::SandboxCreating.as_str_name(),
+            &new_spec.encode_to_vec(),
+            &labels,
+        ).await
+            .map_err(|e| match e.downcast_ref::<sqlx::Error>().and_then(|e| e.as_database_error()) {
+                Some(db_error) if db_error.is_unique_violation() => {
+                    Status::already_exists(format!("Sandbox {} already exists", new_sandbox_id))
+                }
+                _ => Status::internal(format!("DB error: {}", e)),
+            })?;
+
+        self.lifecycle.transition(&new_sandbox_id, SandboxState::SandboxBooting).await?;
+        if let Err(status) = self.restore_into(&record, &new_sandbox_id).await {
+            let _ = self.lifecycle.fail(&new_sandbox_id, status.message()).await;
+            return Err(status);
+        }
+        // Keeps the snapshot alive for the sandbox and makes it the parent of its snapshots
+        self.db.add_snapshot_ref(&record.snapshot_id, SANDBOX_REF, &new_sandbox_id).await
+            .map_err(|e| Status::internal(format!("DB error: {}", e)))?;
+
+        let sandbox = self.lifecycle.transition(&new_sandbox_id, SandboxState::SandboxReady).await?;
+        Ok(Response::new(sandbox_message(sandbox)))
     }
 
     async fn delete_snapshot(
         &self,
-        _request: Request<DeleteSnapshotRequest>,
+        request: Request<DeleteSnapshotRequest>,
     ) -> Result<Response<DeleteSnapshotResponse>, Status> {
-        Err(Status::unimplemented("Not implemented"))
+        let req = request.into_inner();
+        let record = self.load(&req.snapshot_id).await?;
+        if record.state == CREATING && self.creating.lock().unwrap().contains(&record.snapshot_id) {
+            return Err(Status::failed_precondition("Snapshot is still being created"));
+        }
+        if !req.force {
+            let (children, refs) = self.db.snapshot_dependents(&record.snapshot_id, DELETED).await
+                .map_err(|e| Status::internal(format!("DB error: {}", e)))?;
+            if children > 0 || refs > 0 {
+                return Err(Status::failed_precondition(format!(
+                    "Snapshot has {} child snapshot(s) and {} ref(s); use force to delete it anyway",
+                    children, refs
+                )));
+            }
+        }
+
+        self.remove_snapshot(&record).await?;
+        self.sweep_chunks().await?;
+
+        Ok(Response::new(DeleteSnapshotResponse { snapshot_id: record.snapshot_id }))
+    }
+
+    async fn verify_snapshot(
+        &self,
+        request: Request<VerifySnapshotRequest>,
+    ) -> Result<Response<VerifySnapshotResponse>, Status> {
+        let req = request.into_inner();
+        let record = self.load(&req.snapshot_id).await?;
+        if record.state != READY {
+            return Err(Status::failed_precondition("Snapshot is not READY"));
+        }
+        let checks = verify_stored(&self.db, &self.store, &record.snapshot_id).await?;
+
+        // A clean scrub clears what an earlier one found
+        if record.last_error.as_deref().is_some_and(|e| e.starts_with("Failed verification")) {
+            let _ = self.db.set_snapshot_error(&record.snapshot_id, None).await;
+        }
+        Ok(Response::new(VerifySnapshotResponse {
+            snapshot_id: record.snapshot_id,
+            files: checks.into_iter().map(|check| SnapshotFile {
+                name: check.name,
+                size_bytes: check.size_bytes,
+                sha256: check.sha256,
+            }).collect(),
+        }))
+    }
+
+    async fn get_snapshot_tree(
+        &self,
+        request: Request<GetSnapshotTreeRequest>,
+    ) -> Result<Response<GetSnapshotTreeResponse>, Status> {
+        let req = request.into_inner();
+        let root_snapshot_id = self.load(&req.snapshot_id).await?.root_snapshot_id;
+        let records = self.db.snapshot_tree(&root_snapshot_id).await
+            .map_err(|e| Status::internal(format!("DB error: {}", e)))?;
+        let refs = self.db.tree_refs(&root_snapshot_id, SANDBOX_REF).await
+            .map_err(|e| Status::internal(format!("DB error: {}", e)))?;
+
+        let mut children: HashMap<String, Vec<String>> = HashMap::new();
+        for record in &records {
+            if let Some(parent) = &record.parent_snapshot_id {
+                children.entry(parent.clone()).or_default().push(record.snapshot_id.clone());
+            }
+        }
+        let mut sandboxes: HashMap<String, Vec<String>> = HashMap::new();
+        for (snapshot_id, sandbox_id) in refs {
+            sandboxes.entry(snapshot_id).or_default().push(sandbox_id);
+        }
+
+        let nodes = records.into_iter().map(|record| SnapshotTreeNode {
+            child_snapshot_ids: children.remove(&record.snapshot_id).unwrap_or_default(),
+            restored_sandbox_ids: sandboxes.remove(&record.snapshot_id).unwrap_or_default(),
+            state: record.state.clone(),
+            snapshot: Some(snapshot_message(record)),
+        }).collect();
+        Ok(Response::new(GetSnapshotTreeResponse { root_snapshot_id, nodes }))
+    }
+
+    async fn fsck_snapshots(
+        &self,
+        request: Request<FsckSnapshotsRequest>,
+    ) -> Result<Response<FsckSnapshotsResponse>, Status> {
+        let repair: &[FsckKind] = if request.into_inner().repair { &ALL_REPAIRS } else { &[] };
+        Ok(Response::new(self.fsck(repair).await?))
     }
 
     async fn garbage_collect_snapshots(
@@ -155,20 +656,310 @@ impl Snapshots for SnapshotService {
             .map_err(|e| Status::internal(format!("DB query failed: {}", e)))?;
             
         let mut deleted_snapshot_ids = Vec::new();
-        let mut reclaimed_bytes = 0;
+        let mut released = Vec::new();
+        let mut errors = Vec::new();
         
-        for (id, size) in candidates {
-            if !req.dry_run {
-                let _ = self.store.delete_snapshot(&id).await;
-                let _ = self.db.mark_snapshot_deleted(&id).await;
+        // One snapshot that can't be removed doesn't hold up the rest
+        for (id, _) in candidates {
+            let record = match self.load(&id).await {
+                Ok(record) => record,
+                Err(status) => {
+                    errors.push(format!("{}: {}", id, status.message()));
+                    continue;
+                }
+            };
+            if !req.dry_run
+                && let Err(status) = self.remove_snapshot(&record).await
+            {
+                errors.push(format!("{}: {}", id, status.message()));
+                continue;
             }
+            released.extend(chunk_ids(&record));
             deleted_snapshot_ids.push(id);
-            reclaimed_bytes += size;
         }
 
+        // Only chunks no surviving snapshot shares are freed
+        let reclaimed_bytes = if req.dry_run {
+            self.db.reclaimable_chunk_bytes(&released).await
+                .map_err(|e| Status::internal(format!("DB query failed: {}", e)))?
+        } else {
+            self.sweep_chunks().await?
+        };
+
         Ok(Response::new(GarbageCollectSnapshotsResponse {
             deleted_snapshot_ids,
             reclaimed_bytes,
+            errors,
         }))
     }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pb::Paging;
+    use crate::testing::{self, FakeProvider, TempDir};
+    use tonic::Code;
+
+    struct Fixture {
+        dir: TempDir,
+        db: Db,
+        store: Arc<SnapshotStore>,
+        manifest: SnapshotManifest,
+    }
+
+    impl Fixture {
+        /// A committed snapshot "snap" holding one file, "disk"
+        async fn new() -> Self {
+            let dir = TempDir::new("snapshots");
+            let db = testing::db(&dir).await;
+            let store = Arc::new(SnapshotStore::new(dir.join("snapshots")).await.unwrap());
+            let mut fx = Self { dir, db, store, manifest: SnapshotManifest::default() };
+
+            let data: Vec<u8> = (0..300 * 1024u32).map(|i| (i.wrapping_mul(2_654_435_761) >> 13) as u8).collect();
+            fx.manifest = fx.commit("snap", &data).await;
+            fx.insert("snap", "sandbox", None, Some(&fx.manifest)).await;
+            fx
+        }
+
+        /// Store `data` as file "disk" of a snapshot
+        async fn commit(&self, snapshot_id: &str, data: &[u8]) -> SnapshotManifest {
+            let staging = self.store.begin_snapshot(snapshot_id).await.unwrap();
+            std::fs::write(staging.join("disk"), data).unwrap();
+            self.store.commit_snapshot(snapshot_id, vec![]).await.unwrap()
+        }
+
+        /// Give a snapshot its row: READY with `manifest`'s chunks referenced, or else CREATING
+        async fn insert(&self, snapshot_id: &str, sandbox_id: &str, parent: Option<&str>, manifest: Option<&SnapshotManifest>) {
+            self.db.insert_snapshot(&SnapshotRecord {
+                snapshot_id: snapshot_id.into(),
+                provider: "test".into(),
+                source_sandbox_id: sandbox_id.into(),
+                created_at: Utc::now(),
+                mode: "FULL".into(),
+                name: None,
+                labels: None,
+                parent_snapshot_id: parent.map(Into::into),
+                root_snapshot_id: parent.unwrap_or(snapshot_id).into(),
+                state: CREATING.into(),
+                size_bytes: 0,
+                components: None,
+                pinned: false,
+                ttl_expires_at: None,
+                last_error: None,
+            }).await.unwrap();
+            if let Some(manifest) = manifest {
+                let components = serde_json::to_string(manifest).unwrap();
+                self.db.set_snapshot_ready(snapshot_id, 0, &components, &manifest.chunks()).await.unwrap();
+            }
+        }
+
+        /// A service over the fixture's database and store
+        async fn service(&self) -> SnapshotService {
+            let provider = Arc::new(FakeProvider::default());
+            let sandboxes = testing::sandbox_service(&self.dir, self.db.clone(), provider.clone()).await;
+            let lifecycle = Arc::new(Lifecycle::new(self.db.clone()));
+            SnapshotService::new(provider, self.db.clone(), self.store.clone(), sandboxes, lifecycle)
+        }
+
+        fn chunk_path(&self, index: usize) -> std::path::PathBuf {
+            let sha256 = &self.manifest.files[0].extents[0].chunks[index].sha256;
+            self.dir.join("snapshots/chunks").join(&sha256[..2]).join(sha256)
+        }
+
+        async fn scrub(&self) -> Result<(), Status> {
+            verify_stored(&self.db, &self.store, "snap").await.map(|_| ())
+        }
+
+        /// Assert DATA_LOSS naming the file, noted on the snapshot too
+        async fn assert_data_loss(&self, result: Result<(), Status>, problem: &str) {
+            let status = result.unwrap_err();
+            assert_eq!(status.code(), Code::DataLoss);
+            assert!(status.message().contains(&format!("disk: {}", problem)), "{}", status.message());
+            let last_error = self.db.get_snapshot("snap").await.unwrap().unwrap().last_error.unwrap();
+            assert!(last_error.contains(&format!("disk: {}", problem)), "{}", last_error);
+        }
+    }
+
+    #[tokio::test]
+    async fn corrupt_chunks_are_data_loss() {
+        let fx = Fixture::new().await;
+        assert!(fx.scrub().await.is_ok());
+
+        let path = fx.chunk_path(1);
+        let mut data = std::fs::read(&path).unwrap();
+        data[0] ^= 0xff;
+        std::fs::write(&path, data).unwrap();
+        let sha256 = &fx.manifest.files[0].extents[0].chunks[1].sha256;
+        fx.assert_data_loss(fx.scrub().await, &format!("chunk {} is corrupt", sha256)).await;
+    }
+
+    #[tokio::test]
+    async fn missing_chunks_are_data_loss() {
+        let fx = Fixture::new().await;
+        std::fs::remove_file(fx.chunk_path(0)).unwrap();
+        let sha256 = &fx.manifest.files[0].extents[0].chunks[0].sha256;
+        fx.assert_data_loss(fx.scrub().await, &format!("chunk {} is missing", sha256)).await;
+    }
+
+    #[tokio::test]
+    async fn unreadable_manifests_are_data_loss() {
+        let fx = Fixture::new().await;
+        std::fs::write(fx.dir.join("snapshots/snap/manifest.json"), b"{ not json").unwrap();
+        let status = fx.scrub().await.unwrap_err();
+        assert_eq!(status.code(), Code::DataLoss);
+        let last_error = fx.db.get_snapshot("snap").await.unwrap().unwrap().last_error.unwrap();
+        assert!(last_error.starts_with("Failed verification"), "{}", last_error);
+
+        // Without its commit marker the snapshot's data can't be trusted either
+        std::fs::remove_file(fx.dir.join("snapshots/snap/COMPLETE")).unwrap();
+        assert_eq!(fx.scrub().await.unwrap_err().code(), Code::DataLoss);
+    }
+
+    #[tokio::test]
+    async fn truncated_checkouts_are_data_loss() {
+        let fx = Fixture::new().await;
+        let checkout = fx.store.checkout(&["snap".to_string()]).await.unwrap();
+        let disk = checkout.dir("snap").unwrap().join("disk");
+        std::fs::File::options().write(true).open(&disk).unwrap().set_len(4096).unwrap();
+
+        let checks = checkout.verify().await.unwrap();
+        checkout.remove().await;
+        let size = fx.manifest.files[0].size_bytes;
+        fx.assert_data_loss(check_integrity(&fx.db, &checks).await, &format!("4096 bytes, expected {}", size)).await;
+    }
+
+    async fn list(service: &SnapshotService, sandbox_id: &str, page_size: u32, page_token: &str) -> (Vec<String>, String) {
+        let paging = Paging { page_size, page_token: page_token.to_string() };
+        let request = ListSnapshotsRequest { paging: Some(paging), sandbox_id: sandbox_id.to_string() };
+        let response = service.list_snapshots(Request::new(request)).await.unwrap().into_inner();
+        let ids = response.snapshots.into_iter().map(|s| s.snapshot_id).collect();
+        (ids, response.page.unwrap().next_page_token)
+    }
+
+    async fn delete(service: &SnapshotService, snapshot_id: &str, force: bool) -> Result<(), Status> {
+        let request = DeleteSnapshotRequest { snapshot_id: snapshot_id.to_string(), force };
+        service.delete_snapshot(Request::new(request)).await.map(|_| ())
+    }
+
+    #[tokio::test]
+    async fn list_pages_through_visible_snapshots() {
+        let fx = Fixture::new().await;
+        for (snapshot_id, sandbox_id) in [("a", "sandbox"), ("b", "other"), ("gone", "sandbox")] {
+            let manifest = fx.commit(snapshot_id, snapshot_id.as_bytes()).await;
+            fx.insert(snapshot_id, sandbox_id, None, Some(&manifest)).await;
+        }
+        let service = fx.service().await;
+        delete(&service, "gone", false).await.unwrap();
+
+        let (first, token) = list(&service, "", 2, "").await;
+        assert_eq!(first.len(), 2);
+        let (second, token) = list(&service, "", 2, &token).await;
+        assert_eq!((second.len(), token.as_str()), (1, ""));
+        let mut all: Vec<_> = first.into_iter().chain(second).collect();
+        all.sort();
+        assert_eq!(all, ["a", "b", "snap"]);
+
+        let (mut own, _) = list(&service, "sandbox", 0, "").await;
+        own.sort();
+        assert_eq!(own, ["a", "snap"]);
+        let request = ListSnapshotsRequest { paging: Some(Paging { page_size: 1, page_token: "x".to_string() }), sandbox_id: String::new() };
+        assert_eq!(service.list_snapshots(Request::new(request)).await.unwrap_err().code(), Code::InvalidArgument);
+    }
+
+    #[tokio::test]
+    async fn delete_refuses_snapshots_others_depend_on() {
+        let fx = Fixture::new().await;
+        let manifest = fx.commit("child", b"child").await;
+        fx.insert("child", "sandbox", Some("snap"), Some(&manifest)).await;
+        let manifest = fx.commit("restored", b"restored").await;
+        fx.insert("restored", "sandbox", None, Some(&manifest)).await;
+        fx.db.add_snapshot_ref("restored", SANDBOX_REF, "new-sandbox").await.unwrap();
+        let service = fx.service().await;
+
+        assert_eq!(delete(&service, "snap", false).await.unwrap_err().code(), Code::FailedPrecondition);
+        assert_eq!(delete(&service, "restored", false).await.unwrap_err().code(), Code::FailedPrecondition);
+        assert_eq!(delete(&service, "nope", false).await.unwrap_err().code(), Code::NotFound);
+
+        delete(&service, "snap", true).await.unwrap();
+        let request = GetSnapshotRequest { snapshot_id: "snap".to_string() };
+        assert_eq!(service.get_snapshot(Request::new(request)).await.unwrap_err().code(), Code::NotFound);
+        assert!(!fx.store.is_snapshot_ready("snap").await);
+        // The row stays behind for lineage
+        assert_eq!(fx.db.get_snapshot("snap").await.unwrap().unwrap().state, DELETED);
+
+        // A child whose parent is gone no longer holds anything up
+        delete(&service, "child", false).await.unwrap();
+        delete(&service, "restored", true).await.unwrap();
+        assert!(fx.store.unknown_chunk_files(HashSet::new()).await.unwrap().is_empty());
+    }
+
+    /// (kind, id, repaired) of each issue, sorted
+    fn issues(report: &FsckSnapshotsResponse) -> Vec<(FsckKind, String, bool)> {
+        let mut issues: Vec<_> = report.issues.iter()
+            .filter(|issue| issue.kind() != FsckKind::OrphanChunk)
+            .map(|issue| (issue.kind(), issue.id.clone(), issue.repaired))
+            .collect();
+        issues.sort_by_key(|(kind, id, _)| (*kind as i32, id.clone()));
+        issues
+    }
+
+    fn orphan_chunks(report: &FsckSnapshotsResponse) -> (usize, usize) {
+        let orphans: Vec<_> = report.issues.iter().filter(|issue| issue.kind() == FsckKind::OrphanChunk).collect();
+        (orphans.len(), orphans.iter().filter(|issue| issue.repaired).count())
+    }
+
+    #[tokio::test]
+    async fn fsck_reports_everything_and_repairs_only_what_it_is_asked_to() {
+        use FsckKind::*;
+        let fx = Fixture::new().await;
+        // A crash between the row and the commit, and one during staging
+        fx.insert("stale", "sandbox", None, None).await;
+        fx.store.begin_snapshot("staged").await.unwrap();
+        // A READY snapshot whose commit marker is gone
+        let manifest = fx.commit("incomplete", b"incomplete").await;
+        fx.insert("incomplete", "sandbox", None, Some(&manifest)).await;
+        std::fs::remove_file(fx.dir.join("snapshots/incomplete/COMPLETE")).unwrap();
+        // A committed directory with no row, whose chunks nothing references either
+        fx.commit("untracked", b"untracked").await;
+        let service = fx.service().await;
+        // Snapshots being created right now are left alone
+        fx.insert("busy", "sandbox", None, None).await;
+        fx.store.begin_snapshot("busy").await.unwrap();
+        let _busy = service.begin_creating("busy");
+
+        let found = [
+            (StaleCreating, "stale".to_string()),
+            (OrphanTmpDir, "staged".to_string()),
+            (IncompleteReady, "incomplete".to_string()),
+            (UntrackedDir, "untracked".to_string()),
+        ];
+        let report = service.fsck(&[]).await.unwrap();
+        assert_eq!(issues(&report), found.iter().map(|(k, id)| (*k, id.clone(), false)).collect::<Vec<_>>());
+        assert_eq!(orphan_chunks(&report), (1, 0));
+        assert_eq!(report.reclaimed_bytes, 0);
+        // Reporting changes nothing
+        assert_eq!(issues(&service.fsck(&[]).await.unwrap()), issues(&report));
+
+        // Startup only cleans up after interrupted snapshots
+        let report = service.fsck(&STARTUP_REPAIRS).await.unwrap();
+        let repaired: Vec<_> = found.iter().map(|(k, id)| (*k, id.clone(), STARTUP_REPAIRS.contains(k))).collect();
+        assert_eq!(issues(&report), repaired);
+        assert_eq!(orphan_chunks(&report), (1, 1));
+        let stale = fx.db.get_snapshot("stale").await.unwrap().unwrap();
+        assert_eq!((stale.state.as_str(), stale.last_error.as_deref()), ("FAILED", Some("Snapshot creation was interrupted")));
+        assert_eq!(fx.db.get_snapshot("incomplete").await.unwrap().unwrap().state, READY);
+
+        let report = service.fsck(&ALL_REPAIRS).await.unwrap();
+        assert_eq!(issues(&report), [(IncompleteReady, "incomplete".to_string(), true), (UntrackedDir, "untracked".to_string(), true)]);
+        assert!(report.reclaimed_bytes > 0);
+        assert_eq!(fx.db.get_snapshot("incomplete").await.unwrap().unwrap().state, "FAILED");
+        assert_eq!(fx.db.get_snapshot("busy").await.unwrap().unwrap().state, CREATING);
+
+        let report = service.fsck(&ALL_REPAIRS).await.unwrap();
+        assert!(report.issues.is_empty(), "{:?}", issues(&report));
+        assert!(fx.store.is_snapshot_ready("snap").await);
+        assert!(fx.scrub().await.is_ok());
+    }
+}
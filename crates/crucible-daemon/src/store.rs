@@ -1,9 +1,90 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::path::{Path, PathBuf};
 use tokio::fs;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
 use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use crate::provider::SnapshotComponent;
+use chunks::ChunkStore;
 
+mod chunks;
+
+pub use chunks::{hex, FileCheck, ManifestFile};
+
+/// Name of a committed snapshot's manifest in its directory
+const MANIFEST: &str = "manifest.json";
+
+/// Snapshots as manifests over a shared, content-addressed chunk store:
+///
+/// - `.tmp/<id>/`: files a provider is writing, chunked into the store on commit
+/// - `chunks/`: chunks by sha256, shared by every snapshot
+/// - `<id>/`: `manifest.json` and the COMPLETE marker of a committed snapshot
+/// - `.checkout/<n>/`: files rebuilt for a restore, removed once it is done
+///
+/// Chunk refcounts are kept in the database; the store only removes chunks it is told to.
 pub struct SnapshotStore {
     base_dir: PathBuf,
+    chunks: ChunkStore,
+    // Shared while chunks are written and their refs recorded, exclusive while sweeping, so a
+    // sweep never removes a chunk a new snapshot is about to reference
+    chunk_lock: RwLock<()>,
+}
+
+/// What a committed snapshot is made of, also kept in the `components` column
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct SnapshotManifest {
+    pub files: Vec<ManifestFile>,
+    /// The provider's view of the snapshot: its parts and what they are deltas of
+    pub components: Vec<SnapshotComponent>,
+}
+
+impl SnapshotManifest {
+    /// Distinct chunks as (sha256, size); a snapshot holds one ref to each
+    pub fn chunks(&self) -> Vec<(String, u64)> {
+        let mut chunks = BTreeMap::new();
+        for chunk in self.files.iter().flat_map(|f| &f.extents).flat_map(|e| &e.chunks) {
+            chunks.insert(chunk.sha256.clone(), chunk.size_bytes);
+        }
+        chunks.into_iter().collect()
+    }
+}
+
+/// Snapshots rebuilt into plain files for a restore
+pub struct Checkout {
+    root: PathBuf,
+    dirs: HashMap<String, PathBuf>,
+    // Manifests of the rebuilt snapshots, for `verify`
+    manifests: HashMap<String, SnapshotManifest>,
+}
+
+impl Checkout {
+    pub fn dir(&self, snapshot_id: &str) -> Option<&Path> {
+        self.dirs.get(snapshot_id).map(PathBuf::as_path)
+    }
+
+    /// Hash every rebuilt file and compare it with its manifest, returning (snapshot id, check)
+    /// for each file. Snapshots used in place have no manifest and are not checked.
+    pub async fn verify(&self) -> Result<Vec<(String, FileCheck)>> {
+        let mut files = Vec::new();
+        for (snapshot_id, manifest) in &self.manifests {
+            let dir = self.dirs[snapshot_id].clone();
+            for file in &manifest.files {
+                files.push((snapshot_id.clone(), file.clone(), dir.join(&file.name)));
+            }
+        }
+        let checks = tokio::task::spawn_blocking(move || {
+            files.into_iter()
+                .map(|(snapshot_id, file, path)| Ok((snapshot_id, chunks::verify_written(&file, &path)?)))
+                .collect::<std::io::Result<Vec<_>>>()
+        }).await??;
+        Ok(checks)
+    }
+
+    pub async fn remove(self) {
+        let _ = fs::remove_dir_all(&self.root).await;
+    }
 }
 
 impl SnapshotStore {
@@ -13,8 +94,12 @@ impl SnapshotStore {
         
         let tmp_dir = base_dir.join(".tmp");
         fs::create_dir_all(&tmp_dir).await?;
+        fs::create_dir_all(base_dir.join("chunks")).await?;
+        // Checkouts only live for the length of a restore
+        let _ = fs::remove_dir_all(base_dir.join(".checkout")).await;
 
-        Ok(Self { base_dir })
+        let chunks = ChunkStore::new(base_dir.join("chunks"));
+        Ok(Self { base_dir, chunks, chunk_lock: RwLock::new(()) })
     }
 
     /// Prepare a temporary directory for snapshot creation (Phase 1)
@@ -27,13 +112,36 @@ impl SnapshotStore {
         Ok(tmp_path)
     }
 
-    /// Write the COMPLETE marker and rename the directory into its final location (Phase 2)
-    pub async fn commit_snapshot(&self, snapshot_id: &str) -> Result<PathBuf> {
+    /// Chunk the snapshot's files into the store, replace them with a manifest, then write the
+    /// COMPLETE marker and rename the directory into its final location (Phase 2). Hold
+    /// `lock_chunks` until the returned manifest's chunk refs are recorded.
+    pub async fn commit_snapshot(&self, snapshot_id: &str, components: Vec<SnapshotComponent>) -> Result<SnapshotManifest> {
         let tmp_path = self.base_dir.join(".tmp").join(snapshot_id);
         if !tmp_path.exists() {
             return Err(anyhow!("Cannot commit missing snapshot tmp dir: {}", tmp_path.display()));
         }
 
+        let mut names = Vec::new();
+        let mut entries = fs::read_dir(&tmp_path).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            if !entry.file_type().await?.is_file() {
+                return Err(anyhow!("Snapshot {} holds {:?}, which is not a file", snapshot_id, entry.file_name()));
+            }
+            names.push(entry.file_name().to_string_lossy().to_string());
+        }
+        names.sort();
+
+        let chunks = self.chunks.clone();
+        let dir = tmp_path.clone();
+        let files = tokio::task::spawn_blocking(move || {
+            names.iter().map(|name| chunks.put_file(&dir.join(name), name)).collect::<std::io::Result<Vec<_>>>()
+        }).await??;
+        for file in &files {
+            fs::remove_file(tmp_path.join(&file.name)).await?;
+        }
+        let manifest = SnapshotManifest { files, components };
+        fs::write(tmp_path.join(MANIFEST), serde_json::to_vec(&manifest)?).await?;
+
         let marker_path = tmp_path.join("COMPLETE");
         fs::write(&marker_path, b"").await?;
 
@@ -46,7 +154,7 @@ impl SnapshotStore {
         }
 
         fs::rename(&tmp_path, &final_path).await?;
-        Ok(final_path)
+        Ok(manifest)
     }
 
     /// Abort a snapshot and clean up the temp directory
@@ -58,7 +166,7 @@ impl SnapshotStore {
         Ok(())
     }
 
-    /// Delete a finalized snapshot
+    /// Delete a finalized snapshot's manifest; its chunks stay until swept
     pub async fn delete_snapshot(&self, snapshot_id: &str) -> Result<()> {
         let path = self.base_dir.join(snapshot_id);
         if path.exists() {
@@ -73,13 +181,198 @@ impl SnapshotStore {
         path.join("COMPLETE").exists()
     }
 
-    /// Get the final path to a ready snapshot
-    pub fn get_snapshot_dir(&self, snapshot_id: &str) -> Option<PathBuf> {
-        let path = self.base_dir.join(snapshot_id);
-        if path.join("COMPLETE").exists() {
-            Some(path)
-        } else {
-            None
+    /// Rebuild the files of committed snapshots, one directory each. Snapshots committed before
+    /// the chunk store existed are used in place.
+    pub async fn checkout(&self, snapshot_ids: &[String]) -> Result<Checkout> {
+        let root = self.base_dir.join(".checkout").join(uuid::Uuid::new_v4().to_string());
+        fs::create_dir_all(&root).await?;
+        let mut checkout = Checkout { root, dirs: HashMap::new(), manifests: HashMap::new() };
+        for snapshot_id in snapshot_ids {
+            match self.check_out(&checkout.root, snapshot_id).await {
+                Ok((dir, manifest)) => {
+                    checkout.dirs.insert(snapshot_id.clone(), dir);
+                    if let Some(manifest) = manifest {
+                        checkout.manifests.insert(snapshot_id.clone(), manifest);
+                    }
+                }
+                Err(e) => {
+                    checkout.remove().await;
+                    return Err(e);
+                }
+            }
+        }
+        Ok(checkout)
+    }
+
+    async fn check_out(&self, root: &Path, snapshot_id: &str) -> Result<(PathBuf, Option<SnapshotManifest>)> {
+        let Some(manifest) = self.manifest(snapshot_id).await? else {
+            return Ok((self.base_dir.join(snapshot_id), None));
+        };
+
+        let dir = root.join(snapshot_id);
+        fs::create_dir_all(&dir).await?;
+        let chunks = self.chunks.clone();
+        let target = dir.clone();
+        let files = manifest.files.clone();
+        tokio::task::spawn_blocking(move || {
+            files.iter().try_for_each(|file| chunks.write_file(file, &target.join(&file.name)))
+        }).await??;
+        Ok((dir, Some(manifest)))
+    }
+
+    /// The manifest of a committed snapshot; None for snapshots from before the chunk store,
+    /// whose files sit in the directory as they are
+    pub async fn manifest(&self, snapshot_id: &str) -> Result<Option<SnapshotManifest>> {
+        let committed = self.base_dir.join(snapshot_id);
+        if !committed.join("COMPLETE").exists() {
+            return Err(anyhow!("Snapshot {} is not committed", snapshot_id));
+        }
+        match fs::read(committed.join(MANIFEST)).await {
+            Ok(raw) => Ok(Some(serde_json::from_slice(&raw)?)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Check every file of a committed snapshot, and each chunk it is made of, against its
+    /// manifest without checking it out
+    pub async fn verify_snapshot(&self, snapshot_id: &str) -> Result<Vec<FileCheck>> {
+        let manifest = self.manifest(snapshot_id).await?
+            .ok_or_else(|| anyhow!("Snapshot {} predates manifests and can't be verified", snapshot_id))?;
+        let chunks = self.chunks.clone();
+        let checks = tokio::task::spawn_blocking(move || {
+            manifest.files.iter().map(|file| chunks.verify_file(file)).collect::<std::io::Result<Vec<_>>>()
+        }).await??;
+        Ok(checks)
+    }
+
+    /// Ids of snapshots with a staging directory under `.tmp`
+    pub async fn staged_snapshots(&self) -> Result<Vec<String>> {
+        let mut ids = Vec::new();
+        let mut entries = fs::read_dir(self.base_dir.join(".tmp")).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            ids.push(entry.file_name().to_string_lossy().to_string());
+        }
+        Ok(ids)
+    }
+
+    /// Ids of snapshot directories outside `.tmp`, with whether they have the COMPLETE marker
+    pub async fn snapshot_dirs(&self) -> Result<Vec<(String, bool)>> {
+        let mut dirs = Vec::new();
+        let mut entries = fs::read_dir(&self.base_dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let name = entry.file_name().to_string_lossy().to_string();
+            if name.starts_with('.') || name == "chunks" || !entry.file_type().await?.is_dir() {
+                continue;
+            }
+            let complete = entry.path().join("COMPLETE").exists();
+            dirs.push((name, complete));
         }
+        Ok(dirs)
     }
+
+    /// Chunk files whose name isn't in `known`, as (name, path, size); hold
+    /// `lock_chunks_exclusive` so in-flight commits don't count
+    pub async fn unknown_chunk_files(&self, known: HashSet<String>) -> Result<Vec<(String, PathBuf, u64)>> {
+        let chunks = self.chunks.clone();
+        let files = tokio::task::spawn_blocking(move || chunks.list()).await??;
+        Ok(files.into_iter().filter(|(name, _, _)| !known.contains(name)).collect())
+    }
+
+    pub async fn remove_files(&self, paths: Vec<PathBuf>) -> Result<()> {
+        for path in paths {
+            match fs::remove_file(&path).await {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Held by snapshot commits until their chunk refs are recorded
+    pub async fn lock_chunks(&self) -> RwLockReadGuard<'_, ()> {
+        self.chunk_lock.read().await
+    }
+
+    /// Held while unreferenced chunks are looked up and removed
+    pub async fn lock_chunks_exclusive(&self) -> RwLockWriteGuard<'_, ()> {
+        self.chunk_lock.write().await
+    }
+
+    /// Remove chunks that no snapshot references any more
+    pub async fn remove_chunks(&self, sha256s: Vec<String>) -> Result<()> {
+        let chunks = self.chunks.clone();
+        tokio::task::spawn_blocking(move || sha256s.iter().try_for_each(|sha256| chunks.remove(sha256))).await??;
+        Ok(())
+    }
+}
+
+/// Flat on-disk store for artifacts (uploaded files, exec logs, exported manifests)
+pub struct ArtifactStore {
+    base_dir: PathBuf,
 }
+
+impl ArtifactStore {
+    pub async fn new(base_path: impl AsRef<Path>) -> Result<Self> {
+        let base_dir = base_path.as_ref().to_path_buf();
+        fs::create_dir_all(base_dir.join(".tmp")).await?;
+        Ok(Self { base_dir })
+    }
+
+    /// Open a writer that hashes content as it lands in the tmp dir
+    pub async fn create(&self, artifact_id: &str) -> Result<ArtifactWriter> {
+        let tmp_path = self.base_dir.join(".tmp").join(artifact_id);
+        let file = fs::File::create(&tmp_path).await?;
+        Ok(ArtifactWriter {
+            tmp_path,
+            final_path: self.base_dir.join(artifact_id),
+            file,
+            hasher: Sha256::new(),
+            size_bytes: 0,
+        })
+    }
+
+    /// Open a stored artifact for reading
+    pub async fn open(&self, artifact_id: &str) -> Result<fs::File> {
+        Ok(fs::File::open(self.base_dir.join(artifact_id)).await?)
+    }
+
+    /// Remove a stored artifact
+    pub async fn remove(&self, artifact_id: &str) -> Result<()> {
+        Ok(fs::remove_file(self.base_dir.join(artifact_id)).await?)
+    }
+}
+
+pub struct ArtifactWriter {
+    tmp_path: PathBuf,
+    final_path: PathBuf,
+    file: fs::File,
+    hasher: Sha256,
+    size_bytes: u64,
+}
+
+impl ArtifactWriter {
+    pub async fn write(&mut self, data: &[u8]) -> Result<()> {
+        self.file.write_all(data).await?;
+        self.hasher.update(data);
+        self.size_bytes += data.len() as u64;
+        Ok(())
+    }
+
+    /// Flush and move the artifact into place, returning its size and hex sha256
+    pub async fn finish(mut self) -> Result<(u64, String)> {
+        self.file.flush().await?;
+        self.file.sync_all().await?;
+        fs::rename(&self.tmp_path, &self.final_path).await?;
+        let digest = self.hasher.finalize();
+        let sha256 = chunks::hex(&digest);
+        Ok((self.size_bytes, sha256))
+    }
+
+    /// Drop a partially written artifact
+    pub async fn discard(self) {
+        drop(self.file);
+        let _ = fs::remove_file(&self.tmp_path).await;
+    }
+}
+
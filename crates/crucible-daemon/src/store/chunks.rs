@@ -0,0 +1,406 @@
+//! Content-defined chunking of snapshot files into a store of sha256-named chunks.
+//!
+//! Chunk boundaries come from a gear rolling hash over the data, so an edit only changes the
+//! chunks around it and files built from the same base image share most of theirs. Only a
+//! file's data extents are chunked; holes are recorded as such and come back as holes, which
+//! keeps sparse files (and the provider's sparse deltas) sparse.
+
+use crate::provider::delta;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+
+const MIN_CHUNK: usize = 16 * 1024;
+const MAX_CHUNK: usize = 256 * 1024;
+/// A boundary is where the low 16 bits of the hash are zero, so chunks average 64 KiB
+const BOUNDARY_MASK: u64 = (1 << 16) - 1;
+const READ_BUF: usize = 1024 * 1024;
+static ZEROS: [u8; 64 * 1024] = [0; 64 * 1024];
+
+const GEAR: [u64; 256] = gear_table();
+
+/// Fixed pseudo-random table (splitmix64). Changing it moves every chunk boundary, so new
+/// snapshots would stop sharing chunks with existing ones.
+const fn gear_table() -> [u64; 256] {
+    let mut table = [0u64; 256];
+    let mut state: u64 = 0;
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ChunkRef {
+    pub sha256: String,
+    pub size_bytes: u64,
+}
+
+/// A run of data starting at `offset`, stored as consecutive chunks
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Extent {
+    pub offset: u64,
+    pub chunks: Vec<ChunkRef>,
+}
+
+/// One snapshot file; everything outside its extents is a hole
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ManifestFile {
+    pub name: String,
+    pub size_bytes: u64,
+    /// Of the whole file as read, holes included, so it matches `sha256sum` of a checkout.
+    /// Empty for files committed before it was recorded.
+    #[serde(default)]
+    pub sha256: String,
+    pub extents: Vec<Extent>,
+}
+
+/// Outcome of checking one file against its manifest entry
+pub struct FileCheck {
+    pub name: String,
+    pub size_bytes: u64,
+    /// What the file actually hashes to
+    pub sha256: String,
+    pub problem: Option<String>,
+}
+
+impl FileCheck {
+    fn new(file: &ManifestFile, size_bytes: u64, sha256: String, mut problem: Option<String>) -> Self {
+        if problem.is_none() && size_bytes != file.size_bytes {
+            problem = Some(format!("{} bytes, expected {}", size_bytes, file.size_bytes));
+        } else if problem.is_none() && !file.sha256.is_empty() && sha256 != file.sha256 {
+            problem = Some(format!("sha256 {}, expected {}", sha256, file.sha256));
+        }
+        Self { name: file.name.clone(), size_bytes, sha256, problem }
+    }
+}
+
+/// Chunks under `<dir>/<first two hex digits>/<sha256>`
+#[derive(Clone)]
+pub struct ChunkStore {
+    dir: PathBuf,
+}
+
+impl ChunkStore {
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+
+    pub fn chunk_path(&self, sha256: &str) -> PathBuf {
+        self.dir.join(&sha256[..2]).join(sha256)
+    }
+
+    /// Chunk `path` into the store, writing only chunks it doesn't have yet
+    pub fn put_file(&self, path: &Path, name: &str) -> io::Result<ManifestFile> {
+        let mut file = File::open(path)?;
+        let size_bytes = file.metadata()?.len();
+        let mut hasher = Sha256::new();
+        let mut extents = Vec::new();
+        for (start, end) in delta::data_extents(&file)? {
+            hash_zeros(&mut hasher, start - hashed(&extents));
+            file.seek(SeekFrom::Start(start))?;
+            let chunks = self.put_stream((&mut file).take(end - start), &mut hasher)?;
+            extents.push(Extent { offset: start, chunks });
+        }
+        hash_zeros(&mut hasher, size_bytes - hashed(&extents));
+        Ok(ManifestFile { name: name.to_string(), size_bytes, sha256: hex(&hasher.finalize()), extents })
+    }
+
+    fn put_stream(&self, mut reader: impl Read, hasher: &mut Sha256) -> io::Result<Vec<ChunkRef>> {
+        let mut chunks = Vec::new();
+        let mut buf = vec![0u8; READ_BUF];
+        let mut chunk = Vec::with_capacity(MAX_CHUNK);
+        let mut hash = 0u64;
+        loop {
+            let n = reader.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buf[..n]);
+            for &byte in &buf[..n] {
+                chunk.push(byte);
+                hash = (hash << 1).wrapping_add(GEAR[byte as usize]);
+                if (chunk.len() >= MIN_CHUNK && hash & BOUNDARY_MASK == 0) || chunk.len() >= MAX_CHUNK {
+                    chunks.push(self.put_chunk(&chunk)?);
+                    chunk.clear();
+                    hash = 0;
+                }
+            }
+        }
+        if !chunk.is_empty() {
+            chunks.push(self.put_chunk(&chunk)?);
+        }
+        Ok(chunks)
+    }
+
+    fn put_chunk(&self, data: &[u8]) -> io::Result<ChunkRef> {
+        let sha256 = hex(&Sha256::digest(data));
+        let path = self.chunk_path(&sha256);
+        // A chunk damaged on disk would otherwise be shared into every new snapshot; put it right
+        if !holds(&path, &sha256)? {
+            fs::create_dir_all(path.parent().unwrap_or(&self.dir))?;
+            // Written aside and renamed, so a chunk under its final name is always whole
+            let tmp = self.dir.join(format!(".{}.{}", sha256, uuid::Uuid::new_v4()));
+            fs::write(&tmp, data)?;
+            File::open(&tmp)?.sync_all()?;
+            fs::rename(&tmp, &path)?;
+        }
+        Ok(ChunkRef { sha256, size_bytes: data.len() as u64 })
+    }
+
+    /// Rebuild a file from its chunks at `target`. A missing or damaged chunk doesn't stop it:
+    /// the file is written as best it can be and `verify_written` reports the mismatch.
+    pub fn write_file(&self, file: &ManifestFile, target: &Path) -> io::Result<()> {
+        let out = OpenOptions::new().write(true).create_new(true).open(target)?;
+        out.set_len(file.size_bytes)?;
+        for extent in &file.extents {
+            let mut at = extent.offset;
+            for chunk in &extent.chunks {
+                let data = match fs::read(self.chunk_path(&chunk.sha256)) {
+                    Ok(data) => data,
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+                    Err(e) => return Err(e),
+                };
+                let len = data.len().min(chunk.size_bytes as usize);
+                out.write_all_at(&data[..len], at)?;
+                at += chunk.size_bytes;
+            }
+        }
+        out.sync_all()
+    }
+
+    /// Check a file and each of its chunks against the manifest without writing anything out
+    pub fn verify_file(&self, file: &ManifestFile) -> io::Result<FileCheck> {
+        let mut hasher = Sha256::new();
+        let mut pos = 0u64;
+        for extent in &file.extents {
+            hash_zeros(&mut hasher, extent.offset.saturating_sub(pos));
+            pos = extent.offset;
+            for chunk in &extent.chunks {
+                let data = match fs::read(self.chunk_path(&chunk.sha256)) {
+                    Ok(data) => data,
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                        let problem = format!("chunk {} is missing", chunk.sha256);
+                        return Ok(FileCheck::new(file, pos, String::new(), Some(problem)));
+                    }
+                    Err(e) => return Err(e),
+                };
+                if hex(&Sha256::digest(&data)) != chunk.sha256 {
+                    let problem = format!("chunk {} is corrupt", chunk.sha256);
+                    return Ok(FileCheck::new(file, pos, String::new(), Some(problem)));
+                }
+                hasher.update(&data);
+                pos += data.len() as u64;
+            }
+        }
+        hash_zeros(&mut hasher, file.size_bytes.saturating_sub(pos));
+        Ok(FileCheck::new(file, pos.max(file.size_bytes), hex(&hasher.finalize()), None))
+    }
+
+    /// Every file in the store as (name, path, size); names are sha256s except for chunks that
+    /// were never finished
+    pub fn list(&self) -> io::Result<Vec<(String, PathBuf, u64)>> {
+        let mut files = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                for chunk in fs::read_dir(entry.path())? {
+                    let chunk = chunk?;
+                    files.push((chunk.file_name().to_string_lossy().to_string(), chunk.path(), chunk.metadata()?.len()));
+                }
+            } else {
+                files.push((entry.file_name().to_string_lossy().to_string(), entry.path(), entry.metadata()?.len()));
+            }
+        }
+        Ok(files)
+    }
+
+    /// Remove a chunk; one that is already gone is fine
+    pub fn remove(&self, sha256: &str) -> io::Result<()> {
+        match fs::remove_file(self.chunk_path(sha256)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Whether the chunk at `path` exists and its content still hashes to `sha256`
+fn holds(path: &Path, sha256: &str) -> io::Result<bool> {
+    match fs::read(path) {
+        Ok(data) => Ok(hex(&Sha256::digest(&data)) == sha256),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Check a rebuilt file on disk against its manifest entry
+pub fn verify_written(file: &ManifestFile, path: &Path) -> io::Result<FileCheck> {
+    let mut reader = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; READ_BUF];
+    let mut size_bytes = 0u64;
+    loop {
+        let n = reader.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+        size_bytes += n as u64;
+    }
+    Ok(FileCheck::new(file, size_bytes, hex(&hasher.finalize()), None))
+}
+
+/// End of the data hashed so far: that of the last extent
+fn hashed(extents: &[Extent]) -> u64 {
+    extents.last().map_or(0, |e| e.offset + e.chunks.iter().map(|c| c.size_bytes).sum::<u64>())
+}
+
+/// Feed `n` zero bytes, standing in for a hole
+fn hash_zeros(hasher: &mut Sha256, mut n: u64) {
+    while n > 0 {
+        let take = n.min(ZEROS.len() as u64) as usize;
+        hasher.update(&ZEROS[..take]);
+        n -= take as u64;
+    }
+}
+
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    struct Fixture {
+        dir: TempDir,
+        store: ChunkStore,
+    }
+
+    impl Fixture {
+        fn new() -> Self {
+            let dir = TempDir::new("chunks");
+            fs::create_dir_all(dir.join("chunks")).unwrap();
+            Self { store: ChunkStore::new(dir.join("chunks")), dir }
+        }
+
+        /// `<dir>/<name>`, created with `len` bytes of which only `data` ranges are written
+        fn file(&self, name: &str, len: u64, data: &[(u64, &[u8])]) -> PathBuf {
+            let path = self.dir.join(name);
+            let file = File::create(&path).unwrap();
+            file.set_len(len).unwrap();
+            for (offset, bytes) in data {
+                file.write_all_at(bytes, *offset).unwrap();
+            }
+            path
+        }
+    }
+
+    /// Deterministic bytes that don't repeat, so chunk boundaries fall as they would on real data
+    fn noise(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed;
+        (0..len).map(|_| {
+            state = state.wrapping_mul(6_364_136_223_846_793_005).wrapping_add(1_442_695_040_888_963_407);
+            (state >> 33) as u8
+        }).collect()
+    }
+
+    #[test]
+    fn sparse_files_round_trip() {
+        let fx = Fixture::new();
+        let head = noise(200 * 1024, 1);
+        let tail = noise(100 * 1024, 2);
+        let len = 8 * 1024 * 1024;
+        let path = fx.file("disk", len, &[(0, &head), (6 * 1024 * 1024, &tail)]);
+
+        let manifest = fx.store.put_file(&path, "disk").unwrap();
+        assert_eq!(manifest.size_bytes, len);
+        // Holes stay out of the store
+        let stored: u64 = manifest.extents.iter().flat_map(|e| &e.chunks).map(|c| c.size_bytes).sum();
+        assert!(stored < len / 2, "{} bytes stored", stored);
+
+        let target = fx.dir.join("restored");
+        fx.store.write_file(&manifest, &target).unwrap();
+        assert!(fs::read(&target).unwrap() == fs::read(&path).unwrap());
+        let check = verify_written(&manifest, &target).unwrap();
+        assert!(check.problem.is_none(), "{:?}", check.problem);
+        assert_eq!(check.sha256, manifest.sha256);
+        assert!(fx.store.verify_file(&manifest).unwrap().problem.is_none());
+    }
+
+    #[test]
+    fn similar_files_share_chunks() {
+        let fx = Fixture::new();
+        let base = noise(2 * 1024 * 1024, 3);
+        let mut edited = base.clone();
+        edited[1024 * 1024..1024 * 1024 + 16].copy_from_slice(b"sixteen new byte");
+        let a = fx.store.put_file(&fx.file("a", base.len() as u64, &[(0, &base)]), "a").unwrap();
+        let b = fx.store.put_file(&fx.file("b", edited.len() as u64, &[(0, &edited)]), "b").unwrap();
+
+        let chunks = |m: &ManifestFile| m.extents.iter().flat_map(|e| &e.chunks).map(|c| c.sha256.clone()).collect::<Vec<_>>();
+        let (a, b) = (chunks(&a), chunks(&b));
+        let shared = b.iter().filter(|c| a.contains(c)).count();
+        // The edit only reaches the chunk it lands in and maybe the boundary after it
+        assert!(shared + 2 >= b.len(), "{} of {} chunks shared", shared, b.len());
+        assert!(shared < b.len());
+        let stored = fx.store.list().unwrap().len();
+        assert!(stored <= a.len() + 2, "{} chunks stored for {} + {}", stored, a.len(), b.len());
+    }
+
+    #[test]
+    fn verification_finds_damaged_missing_and_truncated_data() {
+        let fx = Fixture::new();
+        let data = noise(300 * 1024, 4);
+        let path = fx.file("disk", data.len() as u64, &[(0, &data)]);
+        let manifest = fx.store.put_file(&path, "disk").unwrap();
+        let chunks: Vec<_> = manifest.extents[0].chunks.iter().map(|c| fx.store.chunk_path(&c.sha256)).collect();
+        assert!(chunks.len() > 2);
+
+        let target = fx.dir.join("restored");
+        fx.store.write_file(&manifest, &target).unwrap();
+        File::options().write(true).open(&target).unwrap().set_len(1000).unwrap();
+        let check = verify_written(&manifest, &target).unwrap();
+        assert_eq!(check.name, "disk");
+        assert_eq!(check.problem.as_deref(), Some(format!("1000 bytes, expected {}", data.len()).as_str()));
+
+        // Same size, different content
+        fs::write(&target, vec![0u8; data.len()]).unwrap();
+        assert!(verify_written(&manifest, &target).unwrap().problem.unwrap().starts_with("sha256 "));
+
+        let mut corrupt = fs::read(&chunks[1]).unwrap();
+        corrupt[0] ^= 0xff;
+        fs::write(&chunks[1], corrupt).unwrap();
+        let problem = fx.store.verify_file(&manifest).unwrap().problem.unwrap();
+        assert_eq!(problem, format!("chunk {} is corrupt", manifest.extents[0].chunks[1].sha256));
+
+        fx.store.put_file(&path, "disk").unwrap();
+        fs::remove_file(&chunks[2]).unwrap();
+        let problem = fx.store.verify_file(&manifest).unwrap().problem.unwrap();
+        assert_eq!(problem, format!("chunk {} is missing", manifest.extents[0].chunks[2].sha256));
+    }
+
+    #[test]
+    fn damaged_chunks_are_rewritten() {
+        let fx = Fixture::new();
+        let data = noise(300 * 1024, 1);
+        let path = fx.file("disk", data.len() as u64, &[(0, &data)]);
+        let manifest = fx.store.put_file(&path, "disk").unwrap();
+
+        let chunk = fx.store.chunk_path(&manifest.extents[0].chunks[0].sha256);
+        fs::write(&chunk, b"garbage").unwrap();
+        assert!(fx.store.verify_file(&manifest).unwrap().problem.is_some());
+
+        fx.store.put_file(&path, "disk").unwrap();
+        assert!(fx.store.verify_file(&manifest).unwrap().problem.is_none());
+    }
+}
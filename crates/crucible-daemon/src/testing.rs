@@ -0,0 +1,209 @@
+//! Helpers shared by the unit tests
+
+use crate::config::{ExecConfig, MountConfig};
+use crate::db::Db;
+use crate::policy::registry::PolicyRegistry;
+use crate::provider::{
+    DirEntry, ExecId, ExecResult, ExecSpec, OutputChunk, OutputSink, OutputStream, ProviderHealth,
+    SandboxId, SandboxProvider, SandboxSpec, SandboxUsage, SnapshotId, SnapshotMeta, SnapshotMode,
+    SnapshotRef,
+};
+use crate::server::execution::ExecutionService;
+use crate::server::lifecycle::Lifecycle;
+use crate::server::sandboxes::SandboxService;
+use crate::store::ArtifactStore;
+use async_trait::async_trait;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// A fresh directory under the system temp dir, removed with everything in it when dropped
+pub struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    pub fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("crucible-{}-{}", name, uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&path).unwrap();
+        Self { path }
+    }
+
+    pub fn join(&self, path: impl AsRef<Path>) -> PathBuf {
+        self.path.join(path)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}
+
+/// A database with the full schema, stored in `dir`
+pub async fn db(dir: &TempDir) -> Db {
+    Db::new(&format!("sqlite:{}?mode=rwc", dir.join("crucible.db").display())).await.unwrap()
+}
+
+/// A sandbox service over `provider` with the daemon's default configuration, keeping its
+/// artifacts and policies in `dir`
+pub async fn sandbox_service(dir: &TempDir, db: Db, provider: Arc<FakeProvider>) -> SandboxService {
+    let lifecycle = Arc::new(Lifecycle::new(db.clone()));
+    let artifacts = Arc::new(ArtifactStore::new(dir.join("artifacts")).await.unwrap());
+    let execution = ExecutionService::new(provider.clone(), db.clone(), artifacts, lifecycle.clone(), ExecConfig::default());
+    let policies = Arc::new(PolicyRegistry::load(db.clone(), &dir.join("policies.toml")).await.unwrap());
+    SandboxService::new(provider, db, lifecycle, &MountConfig::default(), policies, execution)
+}
+
+/// An in-memory provider for service tests. Execs print their argv to stdout and exit 0, except
+/// `["false"]`, which exits 1, and `["hang"]`, which runs until canceled.
+#[derive(Default)]
+pub struct FakeProvider {
+    /// Sandboxes that exist in the backend, and whether each is up
+    pub sandboxes: Mutex<HashMap<SandboxId, bool>>,
+    /// Sandboxes that adopt_sandbox fails for
+    pub broken: Mutex<HashSet<SandboxId>>,
+    canceled: Mutex<HashSet<ExecId>>,
+}
+
+impl FakeProvider {
+    pub fn with_sandboxes(sandboxes: &[(&str, bool)]) -> Arc<Self> {
+        let provider = Self::default();
+        provider.sandboxes.lock().unwrap()
+            .extend(sandboxes.iter().map(|(id, up)| (id.to_string(), *up)));
+        Arc::new(provider)
+    }
+
+    pub fn is_up(&self, id: &str) -> Option<bool> {
+        self.sandboxes.lock().unwrap().get(id).copied()
+    }
+
+    fn set_up(&self, id: &SandboxId, up: bool) -> anyhow::Result<()> {
+        match self.sandboxes.lock().unwrap().get_mut(id) {
+            Some(state) => {
+                *state = up;
+                Ok(())
+            }
+            None => anyhow::bail!("No sandbox {}", id),
+        }
+    }
+}
+
+#[async_trait]
+impl SandboxProvider for FakeProvider {
+    fn provider_name(&self) -> &'static str {
+        "fake"
+    }
+
+    async fn probe(&self) -> anyhow::Result<ProviderHealth> {
+        Ok(ProviderHealth { healthy: true, version: None, snapshot_capable: false, gpu_capable: false })
+    }
+
+    async fn list_sandboxes(&self) -> anyhow::Result<Vec<SandboxId>> {
+        Ok(self.sandboxes.lock().unwrap().keys().cloned().collect())
+    }
+
+    async fn create_sandbox(&self, id: &SandboxId, _spec: SandboxSpec) -> anyhow::Result<()> {
+        self.sandboxes.lock().unwrap().insert(id.clone(), true);
+        Ok(())
+    }
+
+    async fn adopt_sandbox(&self, id: &SandboxId, _spec: SandboxSpec) -> anyhow::Result<bool> {
+        if self.broken.lock().unwrap().contains(id) {
+            anyhow::bail!("Sandbox {} is broken", id);
+        }
+        self.is_up(id).ok_or_else(|| anyhow::anyhow!("No sandbox {}", id))
+    }
+
+    async fn usage(&self, _id: &SandboxId) -> anyhow::Result<SandboxUsage> {
+        Ok(SandboxUsage::default())
+    }
+
+    async fn start_sandbox(&self, id: &SandboxId) -> anyhow::Result<()> {
+        self.set_up(id, true)
+    }
+
+    async fn stop_sandbox(&self, id: &SandboxId, _force: bool) -> anyhow::Result<()> {
+        self.set_up(id, false)
+    }
+
+    async fn destroy_sandbox(&self, id: &SandboxId, _force: bool) -> anyhow::Result<()> {
+        self.sandboxes.lock().unwrap().remove(id);
+        Ok(())
+    }
+
+    async fn exec_stream(&self, id: &SandboxId, spec: ExecSpec, output: OutputSink) -> anyhow::Result<ExecResult> {
+        if self.is_up(id) != Some(true) {
+            anyhow::bail!("Sandbox {} is not running", id);
+        }
+        let exit_code = match spec.argv.first().map(String::as_str) {
+            Some("false") => 1,
+            Some("hang") => {
+                while !self.canceled.lock().unwrap().contains(&spec.exec_id) {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+                137
+            }
+            _ => {
+                let data = format!("{}\n", spec.argv.join(" ")).into_bytes();
+                let _ = output.send(OutputChunk { stream: OutputStream::Stdout, data, ts: SystemTime::now() }).await;
+                0
+            }
+        };
+        Ok(ExecResult { exec_id: spec.exec_id, exit_code, timed_out: false, violations: vec![] })
+    }
+
+    async fn cancel_exec(&self, _id: &SandboxId, exec_id: &ExecId) -> anyhow::Result<()> {
+        self.canceled.lock().unwrap().insert(exec_id.clone());
+        Ok(())
+    }
+
+    async fn create_snapshot(
+        &self,
+        _id: &SandboxId,
+        _dst_path: &Path,
+        _mode: SnapshotMode,
+        _ancestors: &[SnapshotId],
+    ) -> anyhow::Result<SnapshotMeta> {
+        anyhow::bail!("Snapshots are not supported")
+    }
+
+    async fn restore_snapshot(
+        &self,
+        _snapshot_id: &SnapshotId,
+        _new_sandbox_id: &SandboxId,
+        _snapshot_dir: &Path,
+        _ancestors: &[SnapshotRef],
+    ) -> anyhow::Result<()> {
+        anyhow::bail!("Snapshots are not supported")
+    }
+
+    async fn delete_snapshot(&self, _snapshot_id: &SnapshotId) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    async fn put_file(
+        &self,
+        _id: &SandboxId,
+        _guest_path: PathBuf,
+        _content: &mut (dyn AsyncRead + Send + Unpin),
+        _overwrite: bool,
+    ) -> anyhow::Result<u64> {
+        anyhow::bail!("Files are not supported")
+    }
+
+    async fn get_file(
+        &self,
+        _id: &SandboxId,
+        _guest_path: PathBuf,
+        _sink: &mut (dyn AsyncWrite + Send + Unpin),
+    ) -> anyhow::Result<u64> {
+        anyhow::bail!("Files are not supported")
+    }
+
+    async fn list_dir(&self, _id: &SandboxId, _guest_path: PathBuf) -> anyhow::Result<Vec<DirEntry>> {
+        anyhow::bail!("Files are not supported")
+    }
+}